@@ -0,0 +1,88 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use stalwart_imap_proxy::core::uid_map::UidMap;
+
+const MESSAGES: u32 = 1_000_000;
+
+fn jmap_ids() -> Vec<String> {
+    (0..MESSAGES).map(|id| format!("b{:x}", id * 7)).collect()
+}
+
+fn uid_map(c: &mut Criterion) {
+    let jmap_ids = jmap_ids();
+    let map = UidMap::new(
+        jmap_ids
+            .iter()
+            .enumerate()
+            .map(|(pos, id)| (pos as u32 * 2 + 1, id.as_str())),
+    );
+
+    // Size of the previous Vec<String> + Vec<u32> representation
+    let vec_size = jmap_ids
+        .iter()
+        .map(|id| std::mem::size_of::<String>() + id.capacity() + std::mem::size_of::<u32>())
+        .sum::<usize>();
+    println!(
+        "{} messages: UidMap {} bytes, Vec<String> + Vec<u32> {} bytes",
+        MESSAGES,
+        map.heap_size(),
+        vec_size
+    );
+
+    c.bench_function("uid_map build", |b| {
+        b.iter(|| {
+            UidMap::new(
+                jmap_ids
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, id)| (pos as u32 * 2 + 1, id.as_str())),
+            )
+        })
+    });
+    c.bench_function("uid_map uid_to_pos", |b| {
+        let mut uid = 1;
+        b.iter(|| {
+            uid = (uid + 7919) % (MESSAGES * 2);
+            black_box(map.uid_to_pos(uid))
+        })
+    });
+    c.bench_function("uid_map jmap_id_to_pos", |b| {
+        let mut pos = 0;
+        b.iter(|| {
+            pos = (pos + 7919) % jmap_ids.len();
+            black_box(map.jmap_id_to_pos(&jmap_ids[pos]))
+        })
+    });
+    c.bench_function("vec jmap_id position", |b| {
+        let mut pos = 0;
+        b.iter(|| {
+            pos = (pos + 7919) % jmap_ids.len();
+            black_box(jmap_ids.iter().position(|id| id == &jmap_ids[pos]))
+        })
+    });
+}
+
+criterion_group!(benches, uid_map);
+criterion_main!(benches);
@@ -30,6 +30,7 @@ use crate::{
     core::{
         client::{Session, SessionData},
         message::MailboxId,
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, StatusResponse,
     },
@@ -47,7 +48,7 @@ impl Session {
                 let data = self.state.session_data();
                 let is_rev2 = self.version.is_rev2();
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let mailbox = match data.get_acl_mailbox(&arguments).await {
                         Ok(mailbox) => mailbox,
                         Err(err) => {
@@ -58,13 +59,13 @@ impl Session {
                             return;
                         }
                     };
-                    let mut request = data.client.build();
+                    let mut request = data.user.client.build();
                     request
                         .get_mailbox()
                         .account_id(&mailbox.account_id)
                         .ids([mailbox.mailbox_id.as_ref().unwrap()])
                         .properties([Property::ACL]);
-                    match request.send_get_mailbox().await {
+                    match measure_jmap(request.send_get_mailbox()).await {
                         Ok(mut response) => {
                             if let Some(mut mailbox) = response.take_list().pop() {
                                 let mut permissions = Vec::new();
@@ -127,7 +128,7 @@ impl Session {
                 let data = self.state.session_data();
                 let is_rev2 = self.version.is_rev2();
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let mailbox = match data.get_acl_mailbox(&arguments).await {
                         Ok(mailbox) => mailbox,
                         Err(err) => {
@@ -138,13 +139,13 @@ impl Session {
                             return;
                         }
                     };
-                    let mut request = data.client.build();
+                    let mut request = data.user.client.build();
                     request
                         .get_mailbox()
                         .account_id(&mailbox.account_id)
                         .ids([mailbox.mailbox_id.as_ref().unwrap()])
                         .properties([Property::MyRights]);
-                    match request.send_get_mailbox().await {
+                    match measure_jmap(request.send_get_mailbox()).await {
                         Ok(mut response) => {
                             if let Some(mailbox) = response.take_list().pop() {
                                 data.write_bytes(
@@ -196,7 +197,7 @@ impl Session {
             Ok(arguments) => {
                 let data = self.state.session_data();
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let mailbox = match data.get_acl_mailbox(&arguments).await {
                         Ok(mailbox) => mailbox,
                         Err(err) => {
@@ -209,7 +210,7 @@ impl Session {
                     };
                     let mailbox_id = mailbox.mailbox_id.as_ref().unwrap();
                     let mod_rights = arguments.mod_rights.unwrap();
-                    let mut request = data.client.build();
+                    let mut request = data.user.client.build();
                     let set_mailbox = request
                         .set_mailbox()
                         .account_id(&mailbox.account_id)
@@ -246,7 +247,7 @@ impl Session {
                         }
                     }
 
-                    match request.send_set_mailbox().await {
+                    match measure_jmap(request.send_set_mailbox()).await {
                         Ok(mut response) => match response.updated(mailbox_id) {
                             Ok(_) => {
                                 data.write_bytes(
@@ -288,7 +289,7 @@ impl Session {
             Ok(arguments) => {
                 let data = self.state.session_data();
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let mailbox = match data.get_acl_mailbox(&arguments).await {
                         Ok(mailbox) => mailbox,
                         Err(err) => {
@@ -300,14 +301,14 @@ impl Session {
                         }
                     };
                     let mailbox_id = mailbox.mailbox_id.as_ref().unwrap();
-                    let mut request = data.client.build();
+                    let mut request = data.user.client.build();
                     request
                         .set_mailbox()
                         .account_id(&mailbox.account_id)
                         .update(mailbox_id)
                         .acl(arguments.identifier.as_ref().unwrap(), Vec::new());
 
-                    match request.send_set_mailbox().await {
+                    match measure_jmap(request.send_set_mailbox()).await {
                         Ok(mut response) => match response.updated(mailbox_id) {
                             Ok(_) => {
                                 data.write_bytes(
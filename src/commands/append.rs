@@ -23,15 +23,14 @@
 
 use std::sync::Arc;
 
-use jmap_client::client::Client;
 use tracing::debug;
 
 use crate::{
     core::{
-        client::Session, message::MappingOptions, receiver::Request, Command, IntoStatusResponse,
-        ResponseCode, StatusResponse,
+        client::Session, message::MappingOptions, metrics::measure_jmap, receiver::Request,
+        session_cache::UserSession, Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
-    protocol::select::Exists,
+    protocol::{append::Literal, select::Exists},
 };
 
 impl Session {
@@ -84,14 +83,14 @@ impl Session {
                 let is_dest_selected = matches!(&selected_mailbox, Some(selected_mailbox)
                                 if selected_mailbox.id.as_ref() == mailbox.as_ref());
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let mut created_jmap_ids = Vec::with_capacity(arguments.messages.len());
                     let mut response =
                         StatusResponse::completed(Command::Append).with_tag(arguments.tag);
 
                     for message in arguments.messages {
                         match append_message(
-                            &data.client,
+                            &data.user,
                             &mailbox.account_id,
                             message.message,
                             [mailbox.mailbox_id.as_ref().unwrap()],
@@ -113,8 +112,7 @@ impl Session {
                                 }
                             }
                             Err(err) => {
-                                response =
-                                    err.into_status_response().with_tag(response.tag.unwrap());
+                                response = err.with_tag(response.tag.unwrap());
                                 break;
                             }
                         }
@@ -124,7 +122,7 @@ impl Session {
                         let uids = if let Ok((_, uids)) = data
                             .core
                             .jmap_to_imap(
-                                mailbox.clone(),
+                                data.cache_id(&mailbox),
                                 created_jmap_ids,
                                 MappingOptions::AddIfMissing,
                             )
@@ -160,11 +158,8 @@ impl Session {
                                         return;
                                     }
                                 };
-                                let (new_message_count, _) = selected_mailbox.synchronize_uids(
-                                    new_state.jmap_ids,
-                                    new_state.imap_uids,
-                                    false,
-                                );
+                                let (new_message_count, _) =
+                                    selected_mailbox.synchronize_uids(&data, &new_state.ids, false);
 
                                 if let Some(new_message_count) = new_message_count {
                                     data.write_bytes(
@@ -179,7 +174,8 @@ impl Session {
                                 new_state.uid_validity
                             }
                             _ => {
-                                if let Ok((uid_validity, _)) = data.core.uids(mailbox.clone()).await
+                                if let Ok((uid_validity, _)) =
+                                    data.core.uids(data.cache_id(&mailbox)).await
                                 {
                                     uid_validity
                                 } else {
@@ -207,20 +203,35 @@ impl Session {
 }
 
 async fn append_message<T, U, V, W>(
-    client: &Client,
+    user: &UserSession,
     account_id: &str,
-    raw_message: Vec<u8>,
+    message: Literal,
     mailbox_ids: T,
     keywords: Option<V>,
     received_at: Option<i64>,
-) -> jmap_client::Result<(jmap_client::email::Email, String)>
+) -> crate::core::Result<(jmap_client::email::Email, String)>
 where
     T: IntoIterator<Item = U>,
     U: Into<String>,
     V: IntoIterator<Item = W>,
     W: Into<String>,
 {
-    let blob_id = client.upload(None, raw_message, None).await?.take_blob_id();
+    let client = &user.client;
+    let blob_id = match message {
+        Literal::Bytes(raw_message) => measure_jmap(client.upload(None, raw_message, None))
+            .await
+            .map_err(|err| err.into_status_response())?
+            .take_blob_id(),
+        Literal::Spooled(literal) => {
+            user.upload_spooled(account_id, &literal)
+                .await
+                .map_err(|err| {
+                    debug!("Failed to upload spooled message: {}", err);
+                    StatusResponse::no("Failed to upload message.")
+                        .with_code(ResponseCode::ContactAdmin)
+                })?
+        }
+    };
     let mut request = client.build();
     let import_request = request
         .import_email()
@@ -239,7 +250,13 @@ where
     let id = import_request.create_id();
     let mut response = request
         .send_single::<jmap_client::email::import::EmailImportResponse>()
-        .await?;
+        .await
+        .map_err(|err| err.into_status_response())?;
 
-    Ok((response.created(&id)?, response.take_new_state()))
+    Ok((
+        response
+            .created(&id)
+            .map_err(|err| err.into_status_response())?,
+        response.take_new_state(),
+    ))
 }
@@ -21,15 +21,18 @@
  * for more details.
 */
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Instant};
 
 use jmap_client::client::{Client, Credentials};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     core::{
         client::{Session, SessionData, State},
+        metrics::METRICS,
         receiver::{self, Request},
+        router::{clone_credentials, BackendConnection, Route},
+        session_cache::UserSession,
         Command, ResponseCode, StatusResponse,
     },
     protocol::{authenticate::Mechanism, capability::Capability},
@@ -96,59 +99,12 @@ impl Session {
     }
 
     pub async fn authenticate(&mut self, credentials: Credentials, tag: String) -> Result<(), ()> {
-        match Client::new()
-            .follow_redirects(&self.core.trusted_hosts)
-            .forwarded_for(self.peer_addr.ip())
-            .credentials(credentials)
-            .connect(&self.core.jmap_url)
-            .await
-        {
-            Ok(client) => {
-                // Fetch mailboxes
-                let mailboxes = self
-                    .core
-                    .fetch_mailboxes(&client, &self.core.folder_shared)
-                    .await
-                    .ok_or(())?;
-
-                // Delete from cache mailboxes that no longer exist on the main account
-                if self
-                    .core
-                    .purge_deleted_mailboxes(mailboxes.first().unwrap())
-                    .await
-                    .is_err()
-                {
-                    self.write_bytes(
-                        StatusResponse::database_failure()
-                            .with_tag(tag)
-                            .into_bytes(),
-                    )
-                    .await?;
-                    return Err(());
-                }
-
-                // Create session
-                self.state = State::Authenticated {
-                    data: Arc::new(SessionData {
-                        mailboxes: parking_lot::Mutex::new(mailboxes),
-                        client,
-                        core: self.core.clone(),
-                        writer: self.writer.clone(),
-                    }),
-                };
-                self.write_bytes(
-                    StatusResponse::ok("Authentication successful")
-                        .with_code(ResponseCode::Capability {
-                            capabilities: Capability::all_capabilities(true, self.is_tls),
-                        })
-                        .with_tag(tag)
-                        .into_bytes(),
-                )
-                .await?;
-                Ok(())
-            }
-            Err(err) => {
-                debug!("Failed to connect to {}: {}", self.core.jmap_url, err,);
+        // Every login is authenticated by the JMAP server, even when the session is cached
+        let route = self.core.router.route(&credentials);
+        let (client, backend) = match self.connect(&route, &credentials).await {
+            Some(Ok(connection)) => connection,
+            Some(Err(err)) => {
+                debug!("Failed to authenticate: {}", err);
                 self.write_bytes(
                     StatusResponse::no("Authentication failed")
                         .with_tag(tag)
@@ -158,7 +114,7 @@ impl Session {
                 .await?;
 
                 let auth_failures = self.state.auth_failures();
-                if auth_failures < 3 {
+                return if auth_failures < 3 {
                     self.state = State::NotAuthenticated {
                         auth_failures: auth_failures + 1,
                     };
@@ -173,9 +129,145 @@ impl Session {
                         self.peer_addr
                     );
                     Err(())
+                };
+            }
+            None => {
+                return self
+                    .write_bytes(
+                        StatusResponse::no("JMAP server unavailable, please try again later.")
+                            .with_tag(tag)
+                            .with_code(ResponseCode::Unavailable)
+                            .into_bytes(),
+                    )
+                    .await;
+            }
+        };
+
+        // Reuse the mailboxes of another connection from the same user
+        let cached_user = self
+            .core
+            .session_cache
+            .get(&credentials)
+            .filter(|user| user.client.session().username() == client.session().username());
+        let is_cached = cached_user.is_some();
+        let user = if let Some(user) = cached_user {
+            user
+        } else {
+            // Fetch mailboxes
+            let mailboxes = self
+                .core
+                .fetch_mailboxes(&client, &route.folder_shared)
+                .await
+                .ok_or(())?;
+
+            // Delete from cache mailboxes that no longer exist on the main account
+            let account = mailboxes.first().unwrap();
+            if self
+                .core
+                .purge_deleted_mailboxes(
+                    route.cache_key(&account.account_id),
+                    account.mailbox_data.keys().cloned().collect(),
+                )
+                .await
+                .is_err()
+            {
+                self.write_bytes(
+                    StatusResponse::database_failure()
+                        .with_tag(tag)
+                        .into_bytes(),
+                )
+                .await?;
+                return Err(());
+            }
+
+            let push = self
+                .core
+                .push_hubs
+                .get(route.cache_key(&account.account_id));
+            let user = Arc::new(UserSession::new(
+                client,
+                mailboxes,
+                route,
+                backend,
+                push,
+                &credentials,
+            ));
+            self.core.session_cache.insert(&credentials, &user);
+            user
+        };
+
+        // Record the login on every account the user has access to
+        let account_ids = user
+            .mailboxes
+            .lock()
+            .iter()
+            .map(|account| user.route.cache_key(&account.account_id))
+            .collect::<Vec<_>>();
+        // Not worth refusing the login over, connected sessions are marked as
+        // active again before inactive accounts are purged.
+        if self.core.set_last_login(account_ids).await.is_err() {
+            warn!(
+                "Failed to record login of {} from {}.",
+                user.client.session().username(),
+                self.peer_addr
+            );
+        }
+
+        // Create session
+        let data = Arc::new(SessionData {
+            mailbox_queue: user.mailbox_queues.register(),
+            user,
+            core: self.core.clone(),
+            writer: self.writer.clone(),
+            session_id: self.session_id,
+        });
+
+        // Bring the cached mailboxes up to date if their state changed since
+        if is_cached {
+            if let Err(err) = data.synchronize_mailboxes(false, false).await {
+                debug!("Failed to refresh cached mailboxes: {}", err);
+            }
+        }
+        self.state = State::Authenticated { data };
+        self.write_bytes(
+            StatusResponse::ok("Authentication successful")
+                .with_code(ResponseCode::Capability {
+                    capabilities: Capability::all_capabilities(true, self.is_tls),
+                })
+                .with_tag(tag)
+                .into_bytes(),
+        )
+        .await
+    }
+
+    // Connects to the first reachable backend of the user's route.
+    async fn connect(
+        &self,
+        route: &Route,
+        credentials: &Credentials,
+    ) -> Option<jmap_client::Result<(Client, BackendConnection)>> {
+        for backend in route.backends() {
+            let started = Instant::now();
+            let response = Client::new()
+                .follow_redirects(&route.trusted_hosts)
+                .forwarded_for(self.peer_addr.ip())
+                .credentials(clone_credentials(credentials))
+                .connect(&backend.url)
+                .await;
+            METRICS.jmap_connect(started.elapsed());
+
+            // Try the next backend if this one could not be reached
+            match response {
+                Err(jmap_client::Error::Transport(err)) => {
+                    debug!("Failed to connect to {}: {}", backend.url, err);
+                    backend.set_healthy(false);
+                }
+                response => {
+                    return Some(response.map(|client| (client, backend.connect())));
                 }
             }
         }
+        None
     }
 
     pub async fn handle_unauthenticate(&mut self, request: Request<Command>) -> Result<(), ()> {
@@ -30,6 +30,7 @@ use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
         message::{MailboxId, MappingOptions},
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -102,7 +103,7 @@ impl Session {
                 }
 
                 let is_qresync = self.is_qresync;
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     if let Err(err) = data
                         .copy_move(
                             arguments,
@@ -159,6 +160,7 @@ impl SessionData {
         });
 
         let max_objects_in_set = self
+            .user
             .client
             .session()
             .core_capabilities()
@@ -169,7 +171,7 @@ impl SessionData {
         let (mut copied_ids, destroyed_ids) =
             if src_mailbox.id.account_id == dest_mailbox.account_id {
                 // Mailboxes are in the same account, send a Email/set request.
-                let mut request = self.client.build();
+                let mut request = self.user.client.build();
                 let ids_vec = ids.keys().collect::<Vec<_>>();
                 for jmap_ids in ids_vec.chunks(max_objects_in_set) {
                     let set_request = request.set_email().account_id(&src_mailbox.id.account_id);
@@ -184,8 +186,7 @@ impl SessionData {
                     }
                 }
                 let mut copied_ids = Vec::with_capacity(ids.len());
-                for response in request
-                    .send()
+                for response in measure_jmap(request.send())
                     .await
                     .map_err(|err| {
                         err.into_status_response()
@@ -215,7 +216,7 @@ impl SessionData {
                 (copied_ids, None)
             } else {
                 // Mailboxes are in different accounts, send a Email/copy request.
-                let mut request = self.client.build();
+                let mut request = self.user.client.build();
 
                 let ids_vec = ids.keys().collect::<Vec<_>>();
                 for jmap_ids in ids_vec.chunks(max_objects_in_set) {
@@ -232,8 +233,7 @@ impl SessionData {
                 let mut copied_ids = Vec::with_capacity(ids.len());
                 let mut destroyed_ids = Vec::new();
 
-                for response in request
-                    .send()
+                for response in measure_jmap(request.send())
                     .await
                     .map_err(|err| {
                         err.into_status_response()
@@ -279,12 +279,12 @@ impl SessionData {
         let uid_copy = if let (Ok((copied_ids_, mut dest_uids)), Ok((uid_validity, _))) = (
             self.core
                 .jmap_to_imap(
-                    dest_mailbox.clone(),
+                    self.cache_id(&dest_mailbox),
                     copied_ids,
                     MappingOptions::AddIfMissing,
                 )
                 .await,
-            self.core.uids(dest_mailbox).await,
+            self.core.uids(self.cache_id(&dest_mailbox)).await,
         ) {
             copied_ids = copied_ids_;
             src_uids.sort_unstable();
@@ -305,40 +305,20 @@ impl SessionData {
             } else {
                 copied_ids
             };
-            let mut expunged_ids = Vec::with_capacity(destroyed_ids.len());
-            {
-                let mut state = src_mailbox.state.lock();
-                let mut new_jmap_ids = Vec::with_capacity(state.jmap_ids.len());
-                let mut new_imap_uids = Vec::with_capacity(state.imap_uids.len());
-
-                for (pos, (jmap_id, imap_uid)) in std::mem::take(&mut state.jmap_ids)
-                    .into_iter()
-                    .zip(std::mem::take(&mut state.imap_uids))
-                    .enumerate()
-                {
-                    if !destroyed_ids.contains(&jmap_id) {
-                        new_jmap_ids.push(jmap_id);
-                        new_imap_uids.push(imap_uid);
-                    } else {
-                        expunged_ids.push(if is_qresync {
-                            imap_uid
-                        } else {
-                            (pos + 1) as u32
-                        });
-                    }
-                }
-
-                state.total_messages = state.total_messages.saturating_sub(expunged_ids.len());
-                state.jmap_ids = new_jmap_ids;
-                state.imap_uids = new_imap_uids;
-
-                expunged_ids.sort_unstable();
-            }
+            let deletions = src_mailbox.remove_ids(self, |_, jmap_id| {
+                destroyed_ids.iter().any(|id| id == jmap_id)
+            });
+            let expunged_uids = deletions.iter().map(|id| id.uid).collect::<Vec<_>>();
+            let expunged_ids = deletions
+                .into_iter()
+                .map(|id| if is_qresync { id.uid } else { id.seqnum })
+                .collect::<Vec<_>>();
 
             self.core
-                .delete_ids(src_mailbox.id.clone(), destroyed_ids)
+                .delete_ids(self.cache_id(&src_mailbox.id), destroyed_ids)
                 .await
                 .ok();
+            self.publish_message_changes(&src_mailbox, expunged_uids, Vec::new());
 
             response.with_tag(arguments.tag).serialize(
                 StatusResponse::ok("Copied UIDs")
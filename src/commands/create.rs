@@ -31,6 +31,7 @@ use crate::{
     core::{
         client::{Session, SessionData},
         mailbox::{Account, Mailbox},
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -55,7 +56,7 @@ impl Session {
 
         if !arguments.is_empty() {
             let data = self.state.session_data();
-            tokio::spawn(async move {
+            self.spawn_task(async move {
                 for argument in arguments {
                     data.write_bytes(data.create_folder(argument).await.into_bytes())
                         .await;
@@ -84,7 +85,7 @@ impl SessionData {
         debug_assert!(!params.path.is_empty());
 
         // Build request
-        let mut request = self.client.build();
+        let mut request = self.user.client.build();
         let mut create_ids: Vec<String> = Vec::with_capacity(params.path.len());
         let set_request = request.set_mailbox().account_id(&params.account_id);
         for (pos, path_item) in params.path.iter().enumerate() {
@@ -100,7 +101,7 @@ impl SessionData {
             create_ids.push(create_item.create_id().unwrap());
         }
 
-        match request.send_set_mailbox().await {
+        match measure_jmap(request.send_set_mailbox()).await {
             Ok(mut response) => {
                 match self.add_created_mailboxes(&mut params, create_ids, &mut response) {
                     Ok((_, mailbox_id)) => StatusResponse::ok("Mailbox created.")
@@ -133,7 +134,7 @@ impl SessionData {
         }
 
         // Lock mailboxes
-        let mut mailboxes = self.mailboxes.lock();
+        let mut mailboxes = self.user.mailboxes.lock();
         let account = if let Some(account) = mailboxes
             .iter_mut()
             .find(|account| account.account_id == params.account_id)
@@ -238,13 +239,13 @@ impl SessionData {
         // Validate special folders
         let mut parent_mailbox_id = None;
         let mut parent_mailbox_name = None;
-        let mailboxes = self.mailboxes.lock();
+        let mailboxes = self.user.mailboxes.lock();
         let first_path_item = path.first().unwrap();
-        let account = if first_path_item == &self.core.folder_all {
+        let account = if first_path_item == &self.user.route.folder_all {
             return Err(Cow::from(
                 "Mailboxes cannot be created under virtual folders.",
             ));
-        } else if first_path_item == &self.core.folder_shared {
+        } else if first_path_item == &self.user.route.folder_shared {
             // Shared Folders/<username>/<folder>
             if path.len() < 3 {
                 return Err(Cow::from(
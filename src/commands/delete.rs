@@ -26,6 +26,7 @@ use tracing::debug;
 use crate::{
     core::{
         client::{Session, SessionData},
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -47,7 +48,7 @@ impl Session {
 
         if !arguments.is_empty() {
             let data = self.state.session_data();
-            tokio::spawn(async move {
+            self.spawn_task(async move {
                 for argument in arguments {
                     data.write_bytes(data.delete_folder(argument).await.into_bytes())
                         .await;
@@ -71,7 +72,7 @@ impl SessionData {
         let (account_id, mailbox_id) = {
             let prefix = format!("{}/", arguments.mailbox_name);
             let mut mailbox_id = None;
-            'outer: for account in self.mailboxes.lock().iter() {
+            'outer: for account in self.user.mailboxes.lock().iter() {
                 if account
                     .prefix
                     .as_ref()
@@ -101,20 +102,20 @@ impl SessionData {
         };
 
         // Delete mailbox
-        if let Err(err) = self.client.mailbox_destroy(&mailbox_id, true).await {
+        if let Err(err) = measure_jmap(self.user.client.mailbox_destroy(&mailbox_id, true)).await {
             return err.into_status_response().with_tag(arguments.tag);
         }
 
         // Delete UID cache
         if delete_uid_cache {
             self.core
-                .delete_mailbox(&account_id, &mailbox_id)
+                .delete_mailbox(&self.user.route.cache_key(&account_id), &mailbox_id)
                 .await
                 .ok();
         }
 
         // Update mailbox cache
-        for account in self.mailboxes.lock().iter_mut() {
+        for account in self.user.mailboxes.lock().iter_mut() {
             if account.account_id == account_id {
                 account.mailbox_names.remove(&arguments.mailbox_name);
                 account.mailbox_data.remove(&mailbox_id);
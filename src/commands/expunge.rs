@@ -28,6 +28,7 @@ use jmap_client::{core::query, email::query::Filter};
 use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
+        metrics::measure_jmap,
         receiver::{Request, Token},
         Command, Flag, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -67,15 +68,17 @@ impl Session {
         match data.synchronize_messages(mailbox.id.clone()).await {
             Ok(mut new_state) => {
                 let mut buf = Vec::with_capacity(64);
+                let mut expunged_uids = Vec::new();
 
                 {
                     let mut deleted_ids = Vec::new();
                     let mut state = mailbox.state.lock();
 
-                    for (seqnum, uid) in state.imap_uids.iter().enumerate() {
-                        if !new_state.imap_uids.contains(uid) {
+                    for (seqnum, &uid) in state.ids.uids().iter().enumerate() {
+                        if !new_state.ids.contains_uid(uid) {
+                            expunged_uids.push(uid);
                             deleted_ids.push(if self.is_qresync {
-                                *uid
+                                uid
                             } else {
                                 (seqnum + 1) as u32
                             });
@@ -101,6 +104,9 @@ impl Session {
                     *state = new_state;
                 }
 
+                // Let other sessions with this mailbox selected know right away
+                data.publish_message_changes(&mailbox, expunged_uids, Vec::new());
+
                 self.write_bytes(
                     StatusResponse::completed(Command::Expunge(is_uid))
                         .with_tag(request.tag)
@@ -122,7 +128,7 @@ impl SessionData {
         mailbox: Arc<SelectedMailbox>,
         sequence: Option<Sequence>,
     ) -> crate::core::Result<String> {
-        let mut request = self.client.build();
+        let mut request = self.user.client.build();
         let result_ref = request
             .query_email()
             .account_id(&mailbox.id.account_id)
@@ -150,8 +156,7 @@ impl SessionData {
             .set_email()
             .account_id(&mailbox.id.account_id)
             .destroy_ref(result_ref);
-        let mut response = request
-            .send()
+        let mut response = measure_jmap(request.send())
             .await
             .map_err(|err| err.into_status_response())?
             .unwrap_method_responses();
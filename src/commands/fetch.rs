@@ -26,12 +26,14 @@ use std::{borrow::Cow, sync::Arc};
 use ahash::AHashMap;
 use jmap_client::email::{self, Header, Property};
 use mail_parser::{GetHeader, Message, PartType, RfcHeader};
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
         message::MappingOptions,
+        metadata_cache::MessageMetadata,
+        metrics::measure_jmap,
         receiver::Request,
         Command, Flag, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -40,7 +42,7 @@ use crate::{
         expunge::Vanished,
         fetch::{
             self, Arguments, Attribute, BodyContents, BodyPart, BodyPartExtension, BodyPartFields,
-            DataItem, Envelope, FetchItem, Section,
+            DataItem, Envelope, FetchItem, Section, StreamedItem,
         },
     },
 };
@@ -65,7 +67,7 @@ impl Session {
                     false
                 };
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     data.write_bytes(
                         data.fetch(arguments, mailbox, is_uid, is_qresync, enabled_condstore)
                             .await
@@ -116,7 +118,10 @@ impl SessionData {
             // Convert MODSEQ to JMAP State
             let state = match self
                 .core
-                .modseq_to_state(&mailbox.id.account_id, changed_since as u32)
+                .modseq_to_state(
+                    &self.user.route.cache_key(&mailbox.id.account_id),
+                    changed_since as u32,
+                )
                 .await
             {
                 Ok(Some(state)) => state,
@@ -131,17 +136,20 @@ impl SessionData {
             };
 
             // Obtain changes since the modseq.
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             request
                 .changes_email(state)
                 .account_id(&mailbox.id.account_id);
-            match request.send_changes_email().await {
+            match measure_jmap(request.send_changes_email()).await {
                 Ok(mut changes) => {
                     // Condstore was just enabled, return higest modseq.
                     if enabled_condstore {
                         if let Ok(modseq) = self
                             .core
-                            .state_to_modseq(&mailbox.id.account_id, changes.take_new_state())
+                            .state_to_modseq(
+                                &self.user.route.cache_key(&mailbox.id.account_id),
+                                changes.take_new_state(),
+                            )
                             .await
                         {
                             self.write_bytes(
@@ -167,7 +175,7 @@ impl SessionData {
                             if let Ok((_, mut vanished)) = self
                                 .core
                                 .jmap_to_imap(
-                                    mailbox.id.clone(),
+                                    self.cache_id(&mailbox.id),
                                     destroyed_ids,
                                     MappingOptions::OnlyIncludeDeleted,
                                 )
@@ -222,7 +230,10 @@ impl SessionData {
         // Build properties list
         let mut properties = Vec::with_capacity(arguments.attributes.len());
         let mut set_seen_flags = false;
-        let mut needs_blobs = false;
+        let mut needs_headers = false;
+        let mut needs_structure = false;
+        let mut needs_message = false;
+        let mut streamed_items = 0;
         let mut needs_modseq = false;
         properties.push(Property::Id);
 
@@ -254,10 +265,7 @@ impl SessionData {
                 Attribute::Rfc822Size => {
                     properties.push(Property::Size);
                 }
-                Attribute::Rfc822Header
-                | Attribute::Body
-                | Attribute::BodyStructure
-                | Attribute::BinarySize { .. } => {
+                Attribute::Rfc822Header => {
                     /*
                         Note that this did not result in \Seen being set, because
                         RFC822.HEADER response data occurs as a result of a FETCH
@@ -265,22 +273,47 @@ impl SessionData {
                         result of a FETCH of BODY[HEADER] (which sets \Seen) or
                         BODY.PEEK[HEADER] (which does not set \Seen).
                     */
-                    needs_blobs = true;
-                    properties.push_unique(Property::BlobId);
+                    needs_headers = true;
+                }
+                Attribute::Body | Attribute::BodyStructure => {
+                    needs_structure = true;
                 }
-                Attribute::BodySection { peek, .. } | Attribute::Binary { peek, .. } => {
+                Attribute::BinarySize { .. } => {
+                    needs_message = true;
+                }
+                Attribute::BodySection { peek, sections, .. } => {
                     if mailbox.is_select && !*peek {
                         set_seen_flags = true;
                     }
-                    needs_blobs = true;
-                    properties.push_unique(Property::BlobId);
+                    match sections.as_slice() {
+                        [] => {
+                            streamed_items += 1;
+                        }
+                        [Section::Header] | [Section::HeaderFields { .. }] => {
+                            needs_headers = true;
+                        }
+                        _ => {
+                            needs_message = true;
+                        }
+                    }
                 }
-                Attribute::Rfc822Text | Attribute::Rfc822 => {
+                Attribute::Binary { peek, .. } => {
+                    if mailbox.is_select && !*peek {
+                        set_seen_flags = true;
+                    }
+                    needs_message = true;
+                }
+                Attribute::Rfc822Text => {
                     if mailbox.is_select {
                         set_seen_flags = true;
                     }
-                    needs_blobs = true;
-                    properties.push_unique(Property::BlobId);
+                    needs_message = true;
+                }
+                Attribute::Rfc822 => {
+                    if mailbox.is_select {
+                        set_seen_flags = true;
+                    }
+                    streamed_items += 1;
                 }
                 Attribute::Uid | Attribute::EmailId => (),
                 Attribute::ModSeq => {
@@ -298,8 +331,48 @@ impl SessionData {
             arguments.attributes.push_unique(Attribute::Uid);
         }
 
+        // A full message requested without any other body parts is streamed
+        // from the JMAP server rather than downloaded and parsed.
+        let stream_item = if streamed_items == 1 && !needs_structure && !needs_message {
+            arguments
+                .attributes
+                .iter()
+                .find_map(|attribute| match attribute {
+                    Attribute::Rfc822 => Some((StreamedItem::Rfc822, None)),
+                    Attribute::BodySection {
+                        sections, partial, ..
+                    } if sections.is_empty() => Some((
+                        StreamedItem::BodySection {
+                            origin_octet: partial.map(|(start, _)| start),
+                        },
+                        *partial,
+                    )),
+                    _ => None,
+                })
+        } else {
+            needs_message |= streamed_items > 0;
+            None
+        };
+
+        // Header sections are rebuilt from the raw headers returned by Email/get,
+        // blobs are only downloaded when the message structure or body is needed
+        // or the headers are not 7-bit clean.
+        let needs_blobs = needs_structure || needs_message;
+        if needs_blobs || needs_headers || stream_item.is_some() {
+            properties.push(Property::BlobId);
+        }
+        if needs_headers && !needs_blobs {
+            properties.push(Property::Headers);
+        }
+
+        // Structures and headers can be read from the metadata cache, unless
+        // other attributes require downloading the message anyway.
+        let cache_metadata = needs_structure && self.core.metadata_cache.is_enabled();
+        let use_metadata_cache = cache_metadata && !needs_message;
+
         // Send request to JMAP server
         let max_objects_in_get = self
+            .user
             .client
             .session()
             .core_capabilities()
@@ -310,13 +383,13 @@ impl SessionData {
         let mut set_seen_ids = Vec::new();
         let ids_vec = ids.keys().collect::<Vec<_>>();
         for jmap_ids in ids_vec.chunks(max_objects_in_get) {
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             request
                 .get_email()
                 .account_id(&mailbox.id.account_id)
                 .ids(jmap_ids.iter().cloned())
                 .properties(properties.clone());
-            let mut response = match request.send_get_email().await {
+            let mut response = match measure_jmap(request.send_get_email()).await {
                 Ok(response) => response,
                 Err(response) => {
                     return response.into_status_response().with_tag(arguments.tag);
@@ -327,7 +400,10 @@ impl SessionData {
             if needs_modseq && modseq == u32::MAX {
                 modseq = self
                     .core
-                    .state_to_modseq(&mailbox.id.account_id, response.take_state())
+                    .state_to_modseq(
+                        &self.user.route.cache_key(&mailbox.id.account_id),
+                        response.take_state(),
+                    )
                     .await
                     .unwrap_or(u32::MAX)
             }
@@ -346,10 +422,35 @@ impl SessionData {
                     continue;
                 };
 
-                // Fetch and parse blob
+                // Fetch and parse the blob, its cached metadata or the message headers
+                let blob_id = if needs_blobs || stream_item.is_some() {
+                    if let Some(blob_id) = email.blob_id() {
+                        blob_id
+                    } else {
+                        debug!(
+                            "JMAP server returned missing blobId for email Id {:?}, account {:?}",
+                            email.id().unwrap_or(""),
+                            mailbox.id.account_id,
+                        );
+                        continue;
+                    }
+                } else {
+                    ""
+                };
+                let mut metadata = None;
                 let raw_message = if needs_blobs {
-                    match email.blob_id() {
-                        Some(blob_id) => match self.client.download(blob_id).await {
+                    if use_metadata_cache {
+                        metadata = self
+                            .core
+                            .get_metadata(self.user.route.cache_key(blob_id))
+                            .await
+                            .unwrap_or_default();
+                    }
+
+                    if let Some(metadata) = &mut metadata {
+                        std::mem::take(&mut metadata.header).into()
+                    } else {
+                        match measure_jmap(self.user.client.download(blob_id)).await {
                             Ok(raw_message) => raw_message.into(),
                             Err(err) => {
                                 debug!(
@@ -360,16 +461,35 @@ impl SessionData {
                                 );
                                 continue;
                             }
-                        },
-                        None => {
-                            debug!(
-                                "JMAP server returned missing blobId for email Id {:?}, account {:?}",
-                                email.id().unwrap_or(""),
-                                mailbox.id.account_id,
-                            );
-                            continue;
                         }
                     }
+                } else if needs_headers {
+                    let header = email.headers().and_then(|headers| {
+                        header_block(headers.iter().map(|header| (header.name(), header.value())))
+                    });
+                    if let Some(header) = header {
+                        header.into()
+                    } else if let Some(blob_id) = email.blob_id() {
+                        match measure_jmap(self.user.client.download(blob_id)).await {
+                            Ok(raw_message) => raw_message.into(),
+                            Err(err) => {
+                                debug!(
+                                    "Failed to download blob for email Id {:?}, account {:?}: {}",
+                                    email.id().unwrap_or(""),
+                                    mailbox.id.account_id,
+                                    err
+                                );
+                                continue;
+                            }
+                        }
+                    } else {
+                        debug!(
+                            "JMAP server returned missing headers for email Id {:?}, account {:?}",
+                            email.id().unwrap_or(""),
+                            mailbox.id.account_id,
+                        );
+                        continue;
+                    }
                 } else {
                     None
                 };
@@ -388,12 +508,27 @@ impl SessionData {
                     None
                 };
 
+                // Add the message's structure and header to the metadata cache
+                if cache_metadata && metadata.is_none() {
+                    if let (Some(message), Some(blob_id)) = (&message, email.blob_id()) {
+                        if let Some(metadata) = message.metadata() {
+                            self.core
+                                .set_metadata(self.user.route.cache_key(blob_id), metadata)
+                                .await
+                                .ok();
+                        }
+                    }
+                }
+
                 // Build response
                 let mut items = Vec::with_capacity(arguments.attributes.len());
                 let set_seen_flag =
                     set_seen_flags && !email.keywords().iter().any(|&k| k == Flag::Seen.to_jmap());
                 for attribute in &arguments.attributes {
                     match attribute {
+                        Attribute::Rfc822 if stream_item.is_some() => (),
+                        Attribute::BodySection { sections, .. }
+                            if stream_item.is_some() && sections.is_empty() => {}
                         Attribute::Envelope => {
                             items.push(DataItem::Envelope {
                                 envelope: Envelope {
@@ -507,13 +642,25 @@ impl SessionData {
                             }
                         }
                         Attribute::Body => {
-                            items.push(DataItem::Body {
-                                part: message.as_ref().unwrap().body_structure(false),
+                            items.push(if let Some(metadata) = &metadata {
+                                DataItem::Serialized {
+                                    contents: metadata.body.as_slice().into(),
+                                }
+                            } else {
+                                DataItem::Body {
+                                    part: message.as_ref().unwrap().body_structure(false),
+                                }
                             });
                         }
                         Attribute::BodyStructure => {
-                            items.push(DataItem::BodyStructure {
-                                part: message.as_ref().unwrap().body_structure(true),
+                            items.push(if let Some(metadata) = &metadata {
+                                DataItem::Serialized {
+                                    contents: metadata.body_structure.as_slice().into(),
+                                }
+                            } else {
+                                DataItem::BodyStructure {
+                                    part: message.as_ref().unwrap().body_structure(true),
+                                }
                             });
                         }
                         Attribute::BodySection {
@@ -601,9 +748,22 @@ impl SessionData {
                 }
 
                 // Serialize fetch item
-                let mut buf = Vec::with_capacity(128);
-                FetchItem { id: seqnum, items }.serialize(&mut buf);
-                if !self.write_bytes(buf).await {
+                let fetch_item = FetchItem { id: seqnum, items };
+                let is_written = if let Some((stream_item, partial)) = &stream_item {
+                    self.write_streamed(
+                        &mailbox.id.account_id,
+                        blob_id,
+                        fetch_item,
+                        stream_item,
+                        *partial,
+                    )
+                    .await
+                } else {
+                    let mut buf = Vec::with_capacity(128);
+                    fetch_item.serialize(&mut buf);
+                    self.write_bytes(buf).await
+                };
+                if !is_written {
                     return StatusResponse::completed(Command::Fetch(is_uid))
                         .with_tag(arguments.tag);
                 }
@@ -618,13 +778,14 @@ impl SessionData {
         // Set Seen ids
         if !set_seen_ids.is_empty() {
             let max_objects_in_set = self
+                .user
                 .client
                 .session()
                 .core_capabilities()
                 .map(|c| c.max_objects_in_set())
                 .unwrap_or(500);
 
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             for set_seen_ids in set_seen_ids.chunks(max_objects_in_set) {
                 let set_request = request.set_email().account_id(&mailbox.id.account_id);
                 for set_seen_id in set_seen_ids {
@@ -634,7 +795,7 @@ impl SessionData {
                 }
             }
 
-            match request.send().await {
+            match measure_jmap(request.send()).await {
                 Ok(responses) => {
                     for response in responses.unwrap_method_responses() {
                         match response.unwrap_set_email() {
@@ -643,7 +804,7 @@ impl SessionData {
                                     modseq = self
                                         .core
                                         .state_to_modseq(
-                                            &mailbox.id.account_id,
+                                            &self.user.route.cache_key(&mailbox.id.account_id),
                                             response.take_new_state(),
                                         )
                                         .await
@@ -683,6 +844,78 @@ impl SessionData {
 
         StatusResponse::completed(Command::Fetch(is_uid)).with_tag(arguments.tag)
     }
+
+    // Writes a fetch item followed by a message literal that is streamed from the
+    // JMAP server chunk by chunk. Returns false if the client is no longer connected,
+    // which is also the case when the download fails halfway through the literal.
+    async fn write_streamed(
+        &self,
+        account_id: &str,
+        blob_id: &str,
+        fetch_item: FetchItem<'_>,
+        stream_item: &StreamedItem,
+        partial: Option<(u32, u32)>,
+    ) -> bool {
+        let mut buf = Vec::with_capacity(128);
+        if let Some(mut stream) = self
+            .user
+            .download_stream(account_id, blob_id, partial)
+            .await
+        {
+            fetch_item.serialize_streamed(&mut buf, stream_item, stream.size());
+            if !self.write_bytes(buf).await {
+                return false;
+            }
+
+            let mut remaining = stream.size();
+            while remaining > 0 {
+                match stream.next_chunk().await {
+                    Ok(Some(chunk)) => {
+                        remaining -= chunk.len();
+                        if !self.write_bytes(chunk).await {
+                            return false;
+                        }
+                    }
+                    result => {
+                        warn!(
+                            "Failed to stream blob {:?}, account {:?}, closing connection: {}",
+                            blob_id,
+                            account_id,
+                            result
+                                .err()
+                                .map(|err| err.to_string())
+                                .unwrap_or_else(|| "Unexpected end of stream".to_string())
+                        );
+
+                        // The literal size was already sent, so the client can't be kept
+                        // in sync and anything else written would end up in the literal
+                        self.disconnect().await;
+                        return false;
+                    }
+                }
+            }
+
+            self.write_bytes(b")\r\n".to_vec()).await
+        } else {
+            // The blob size is not known in advance, download it in full
+            match measure_jmap(self.user.client.download(blob_id)).await {
+                Ok(raw_message) => {
+                    let contents = get_partial_bytes(&raw_message, partial);
+                    fetch_item.serialize_streamed(&mut buf, stream_item, contents.len());
+                    buf.extend_from_slice(contents);
+                    buf.extend_from_slice(b")\r\n");
+                    self.write_bytes(buf).await
+                }
+                Err(err) => {
+                    debug!(
+                        "Failed to download blob {:?}, account {:?}: {}",
+                        blob_id, account_id, err
+                    );
+                    true
+                }
+            }
+        }
+    }
 }
 
 trait AsImapDataItem<'x> {
@@ -700,6 +933,7 @@ trait AsImapDataItem<'x> {
     fn binary_size(&self, sections: &[u32]) -> Option<usize>;
     fn as_body_part(&self, part_id: usize, is_extended: bool) -> BodyPart;
     fn envelope(&self) -> Envelope;
+    fn metadata(&self) -> Option<MessageMetadata>;
 }
 
 impl<'x> AsImapDataItem<'x> for Message<'x> {
@@ -1157,6 +1391,49 @@ impl<'x> AsImapDataItem<'x> for Message<'x> {
             message_id: self.get_message_id().map(|id| format!("<{}>", id).into()),
         }
     }
+
+    fn metadata(&self) -> Option<MessageMetadata> {
+        let root_part = self.get_root_part();
+        let mut body = Vec::with_capacity(128);
+        let mut body_structure = Vec::with_capacity(256);
+        DataItem::Body {
+            part: self.body_structure(false),
+        }
+        .serialize(&mut body);
+        DataItem::BodyStructure {
+            part: self.body_structure(true),
+        }
+        .serialize(&mut body_structure);
+
+        MessageMetadata {
+            body,
+            body_structure,
+            header: self
+                .raw_message
+                .get(root_part.offset_header..root_part.offset_body)?
+                .to_vec(),
+        }
+        .into()
+    }
+}
+
+// Rebuilds the header block of a message from its JMAP raw header values,
+// which start right after the colon and exclude the terminating CRLF.
+// Returns None if a header is not 7-bit clean, as its raw value might not
+// match the bytes of the message.
+fn header_block<'x>(headers: impl IntoIterator<Item = (&'x str, &'x str)>) -> Option<Vec<u8>> {
+    let mut block = Vec::with_capacity(1024);
+    for (name, value) in headers {
+        if !name.is_ascii() || !value.is_ascii() {
+            return None;
+        }
+        block.extend_from_slice(name.as_bytes());
+        block.push(b':');
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    block.extend_from_slice(b"\r\n");
+    Some(block)
 }
 
 #[inline(always)]
@@ -1505,4 +1782,109 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn header_sections() {
+        let mut test_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        test_dir.push("src");
+        test_dir.push("tests");
+        test_dir.push("resources");
+        test_dir.push("messages");
+        'outer: for file_name in fs::read_dir(&test_dir).unwrap() {
+            let file_name = file_name.as_ref().unwrap().path();
+            if file_name.extension().map_or(true, |e| e != "txt") {
+                continue;
+            }
+
+            let mut raw_message = Vec::new();
+            for ch in fs::read(&file_name).unwrap() {
+                if ch == b'\n' {
+                    raw_message.push(b'\r');
+                }
+                raw_message.push(ch);
+            }
+            let message = Message::parse(&raw_message).unwrap();
+            let root_part = message.get_root_part();
+            let header = String::from_utf8_lossy(
+                &raw_message[root_part.offset_header..root_part.offset_body],
+            );
+
+            // Split headers into their JMAP raw form
+            let mut headers: Vec<(&str, String)> = Vec::new();
+            for line in header.split_inclusive("\r\n") {
+                if line == "\r\n" {
+                    break;
+                } else if line.starts_with([' ', '\t']) {
+                    headers.last_mut().unwrap().1.push_str(line);
+                } else if let Some((name, value)) = line.split_once(':') {
+                    headers.push((name, value.to_string()));
+                } else {
+                    continue 'outer;
+                }
+            }
+            for (_, value) in &mut headers {
+                value.truncate(value.len() - 2);
+            }
+
+            // Header sections served from JMAP properties must match the blob,
+            // headers that are not 7-bit clean are read from the blob instead
+            let header_block = if let Some(header_block) =
+                super::header_block(headers.iter().map(|(name, value)| (*name, value.as_str())))
+            {
+                header_block
+            } else {
+                assert!(!header.is_ascii(), "{}", file_name.display());
+                continue;
+            };
+            assert_eq!(
+                String::from_utf8_lossy(&header_block),
+                header,
+                "{}",
+                file_name.display()
+            );
+            let header_message = Message::parse(&header_block).unwrap();
+            let fields = [
+                "From",
+                "To",
+                "Subject",
+                "Date",
+                "Message-ID",
+                "Content-Type",
+            ]
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<_>>();
+            for sections in [
+                vec![Section::Header],
+                vec![Section::HeaderFields {
+                    not: false,
+                    fields: fields.clone(),
+                }],
+                vec![Section::HeaderFields {
+                    not: true,
+                    fields: fields.clone(),
+                }],
+            ] {
+                for partial in [None, Some((10, 25))] {
+                    let mut expected = Vec::new();
+                    let mut result = Vec::new();
+                    for (message, buf) in
+                        [(&message, &mut expected), (&header_message, &mut result)]
+                    {
+                        DataItem::BodySection {
+                            contents: message.body_section(&sections, partial).unwrap(),
+                            sections: sections.clone(),
+                            origin_octet: partial.map(|(start, _)| start),
+                        }
+                        .serialize(buf);
+                    }
+                    assert_eq!(
+                        String::from_utf8(result).unwrap(),
+                        String::from_utf8(expected).unwrap(),
+                        "{}",
+                        file_name.display()
+                    );
+                }
+            }
+        }
+    }
 }
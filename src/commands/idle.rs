@@ -21,16 +21,20 @@
  * for more details.
 */
 
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 
-use futures::{Stream, StreamExt};
-use jmap_client::{event_source::Changes, TypeState};
-use tokio::sync::watch;
+use ahash::AHashSet;
+use jmap_client::TypeState;
+use tokio::sync::{broadcast, watch};
 use tracing::debug;
 
 use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData, State},
+        mailbox::MailboxSync,
+        message::MessageChanges,
+        metrics::METRICS,
+        push::PushChanges,
         receiver::Request,
         Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -45,22 +49,14 @@ use crate::{
 
 impl Session {
     pub async fn handle_idle(&mut self, request: Request<Command>) -> Result<(), ()> {
-        let (data, mailbox, subscriptions) = match &self.state {
-            State::Authenticated { data } => (data.clone(), None, vec![TypeState::Mailbox]),
-            State::Selected { data, mailbox, .. } => (
-                data.clone(),
-                mailbox.clone().into(),
-                vec![TypeState::Email, TypeState::Mailbox],
-            ),
+        let (data, mailbox) = match &self.state {
+            State::Authenticated { data } => (data.clone(), None),
+            State::Selected { data, mailbox, .. } => (data.clone(), mailbox.clone().into()),
             _ => unreachable!(),
         };
 
-        // Start event source
-        let changes = match data
-            .client
-            .event_source(subscriptions.into(), false, 30.into(), None)
-            .await
-        {
+        // Subscribe to the user's push notifications
+        let changes = match data.user.subscribe_push().await {
             Ok(changes) => changes,
             Err(err) => {
                 debug!("Error starting event source: {}", err);
@@ -86,8 +82,10 @@ impl Session {
         let is_qresync = self.is_qresync;
 
         tokio::spawn(async move {
+            METRICS.idle_sessions.fetch_add(1, Ordering::Relaxed);
             data.idle(mailbox, changes, idle_rx, request.tag, is_qresync, is_rev2)
                 .await;
+            METRICS.idle_sessions.fetch_sub(1, Ordering::Relaxed);
         });
         Ok(())
     }
@@ -97,34 +95,39 @@ impl SessionData {
     pub async fn idle(
         &self,
         mailbox: Option<Arc<SelectedMailbox>>,
-        mut changes: impl Stream<Item = jmap_client::Result<Changes>> + Unpin,
+        mut changes: broadcast::Receiver<PushChanges>,
         mut idle_rx: watch::Receiver<bool>,
         tag: String,
         is_qresync: bool,
         is_rev2: bool,
     ) {
+        // Listen for changes made by the user's other connections
+        let mut mailbox_changes = self.user.mailbox_changes.subscribe();
+        let mut message_changes = self.user.message_changes.subscribe();
+
         // Write any pending changes
-        self.write_changes(mailbox.as_ref(), true, true, is_qresync, is_rev2)
+        self.write_changes(mailbox.as_ref(), true, true, is_qresync, is_rev2, None)
             .await;
 
+        // Last change received from another session, not yet seen in a push
+        let mut applied_changes: Option<Arc<MessageChanges>> = None;
+
         loop {
             tokio::select! {
-                changes = changes.next() => {
+                changes = changes.recv() => {
                     match changes {
-                        Some(Ok(changes)) => {
+                        Ok(changes) => {
                             let mut has_mailbox_changes = false;
                             let mut has_email_changes = false;
-                            for (account_id, changes) in changes.into_inner() {
-                                for (type_state, _) in changes {
-                                    match type_state {
-                                        TypeState::Mailbox => {
-                                            has_mailbox_changes = true;
-                                        }
-                                        TypeState::Email if mailbox.as_ref().map_or(false, |m| m.id.account_id == account_id) => {
-                                            has_email_changes = true;
-                                        }
-                                        _ => (),
+                            for (account_id, type_state) in changes.iter() {
+                                match type_state {
+                                    TypeState::Mailbox => {
+                                        has_mailbox_changes = true;
                                     }
+                                    TypeState::Email if mailbox.as_ref().map_or(false, |m| &m.id.account_id == account_id) => {
+                                        has_email_changes = true;
+                                    }
+                                    _ => (),
                                 }
                             }
 
@@ -133,19 +136,63 @@ impl SessionData {
                                 has_mailbox_changes,
                                 has_email_changes,
                                 is_qresync,
-                                is_rev2
+                                is_rev2,
+                                applied_changes.as_deref(),
                             ).await;
+                            if has_email_changes {
+                                applied_changes = None;
+                            }
 
                         },
-                        Some(Err(err)) => {
-                            debug!("EventSource error: {}", err);
+                        Err(broadcast::error::RecvError::Lagged(_)) => {
+                            self.write_changes(
+                                mailbox.as_ref(),
+                                true,
+                                true,
+                                is_qresync,
+                                is_rev2,
+                                applied_changes.take().as_deref(),
+                            ).await;
                         }
-                        None => {
+                        Err(broadcast::error::RecvError::Closed) => {
                             debug!("EventSource connection unexpectedly closed.");
                             break;
                         },
                     }
                 },
+                session_id = mailbox_changes.recv() => {
+                    match session_id {
+                        Ok(session_id) if session_id == self.session_id => (),
+                        // Lagged receivers still find every change in their queue
+                        _ => {
+                            let changes = self.take_mailbox_changes();
+                            if !changes.is_empty() {
+                                self.write_mailbox_changes(&changes, is_rev2).await;
+                            }
+                        }
+                    }
+                },
+                changes = message_changes.recv() => {
+                    if let (Ok(changes), Some(mailbox)) = (changes, mailbox.as_ref()) {
+                        // Skip changes that a push notification already delivered
+                        if changes.session_id != self.session_id
+                            && changes.mailbox == mailbox.id
+                            && mailbox.state.lock().last_state != changes.state
+                        {
+                            // Mailbox status goes first, the same as when the push arrives
+                            self.write_changes(
+                                Some(mailbox),
+                                true,
+                                false,
+                                is_qresync,
+                                is_rev2,
+                                None,
+                            ).await;
+                            self.write_message_changes(mailbox, &changes, is_qresync).await;
+                            applied_changes = changes.into();
+                        }
+                    }
+                },
                 _ = idle_rx.changed() => {
                     self.write_bytes(StatusResponse::completed(Command::Idle).with_tag(tag).into_bytes())
                         .await;
@@ -165,6 +212,103 @@ impl SessionData {
         .await;
     }
 
+    pub async fn write_mailbox_changes(&self, changes: &MailboxSync, is_rev2: bool) {
+        let mut buf = Vec::with_capacity(64);
+
+        // List deleted mailboxes
+        for mailbox_name in &changes.deleted {
+            ListItem {
+                mailbox_name: mailbox_name.to_string(),
+                attributes: vec![Attribute::NonExistent],
+                tags: vec![],
+            }
+            .serialize(&mut buf, is_rev2, false);
+        }
+
+        // List added mailboxes
+        for mailbox_name in &changes.added {
+            ListItem {
+                mailbox_name: mailbox_name.to_string(),
+                attributes: vec![],
+                tags: vec![],
+            }
+            .serialize(&mut buf, is_rev2, false);
+        }
+        // Obtain status of changed mailboxes
+        for mailbox_name in &changes.changed {
+            if let Ok(status) = self
+                .status(
+                    mailbox_name.to_string(),
+                    &[
+                        Status::Messages,
+                        Status::Unseen,
+                        Status::UidNext,
+                        Status::UidValidity,
+                    ],
+                )
+                .await
+            {
+                status.serialize(&mut buf, is_rev2);
+            }
+        }
+
+        if !buf.is_empty() {
+            self.write_bytes(buf).await;
+        }
+    }
+
+    pub async fn write_message_changes(
+        &self,
+        mailbox: &Arc<SelectedMailbox>,
+        changes: &MessageChanges,
+        is_qresync: bool,
+    ) {
+        // Expunge messages removed by the other session
+        if !changes.expunged.is_empty() {
+            let deletions = mailbox.remove_uids(self, &changes.expunged);
+            if !deletions.is_empty() {
+                let mut buf = Vec::with_capacity(64);
+                expunge::Response {
+                    is_qresync,
+                    ids: deletions
+                        .into_iter()
+                        .map(|id| if !is_qresync { id.seqnum } else { id.uid })
+                        .collect(),
+                }
+                .serialize_to(&mut buf);
+                Exists {
+                    total_messages: mailbox.state.lock().total_messages,
+                }
+                .serialize(&mut buf);
+                self.write_bytes(buf).await;
+            }
+        }
+
+        // Send the new flags of updated messages
+        if !changes.flags_changed.is_empty() {
+            self.fetch(
+                fetch::Arguments {
+                    tag: String::new(),
+                    sequence_set: Sequence::List {
+                        items: changes
+                            .flags_changed
+                            .iter()
+                            .map(|&uid| Sequence::Number { value: uid })
+                            .collect(),
+                    },
+                    attributes: vec![fetch::Attribute::Flags, fetch::Attribute::Uid],
+                    changed_since: None,
+                    include_vanished: false,
+                },
+                mailbox.clone(),
+                true,
+                is_qresync,
+                false,
+            )
+            .await;
+        }
+    }
+
     pub async fn write_changes(
         &self,
         mailbox: Option<&Arc<SelectedMailbox>>,
@@ -172,53 +316,13 @@ impl SessionData {
         check_emails: bool,
         is_qresync: bool,
         is_rev2: bool,
+        applied_changes: Option<&MessageChanges>,
     ) {
         // Fetch all changed mailboxes
         if check_mailboxes {
             match self.synchronize_mailboxes(true, false).await {
                 Ok(Some(changes)) => {
-                    let mut buf = Vec::with_capacity(64);
-
-                    // List deleted mailboxes
-                    for mailbox_name in changes.deleted {
-                        ListItem {
-                            mailbox_name,
-                            attributes: vec![Attribute::NonExistent],
-                            tags: vec![],
-                        }
-                        .serialize(&mut buf, is_rev2, false);
-                    }
-
-                    // List added mailboxes
-                    for mailbox_name in changes.added {
-                        ListItem {
-                            mailbox_name: mailbox_name.to_string(),
-                            attributes: vec![],
-                            tags: vec![],
-                        }
-                        .serialize(&mut buf, is_rev2, false);
-                    }
-                    // Obtain status of changed mailboxes
-                    for mailbox_name in changes.changed {
-                        if let Ok(status) = self
-                            .status(
-                                mailbox_name,
-                                &[
-                                    Status::Messages,
-                                    Status::Unseen,
-                                    Status::UidNext,
-                                    Status::UidValidity,
-                                ],
-                            )
-                            .await
-                        {
-                            status.serialize(&mut buf, is_rev2);
-                        }
-                    }
-
-                    if !buf.is_empty() {
-                        self.write_bytes(buf).await;
-                    }
+                    self.write_mailbox_changes(&changes, is_rev2).await;
                 }
                 Err(err) => {
                     debug!("Failed to refresh mailboxes: {}", err);
@@ -231,24 +335,13 @@ impl SessionData {
         if check_emails {
             // Synchronize emails
             if let Some(mailbox) = mailbox {
-                // Obtain changes since last sync
-                let mut request = self.client.build();
-                request
-                    .changes_email(&mailbox.state.lock().last_state)
-                    .account_id(&mailbox.id.account_id);
-                let mut response = match request.send_changes_email().await {
-                    Ok(response) => response,
-                    Err(err) => {
-                        debug!("Failed to obtain emails changes: {}", err);
-                        self.write_bytes(err.into_status_response().into_bytes())
-                            .await;
-                        return;
-                    }
-                };
-
-                // Synchronize messages
-                let new_state = match self.synchronize_messages(mailbox.id.clone()).await {
-                    Ok(new_state) => new_state,
+                // Synchronize messages and obtain the changes since the last sync
+                let last_state = mailbox.state.lock().last_state.clone();
+                let (new_state, changed_ids) = match self
+                    .synchronize_changes(mailbox.id.clone(), Some(&last_state))
+                    .await
+                {
+                    Ok(changes) => changes,
                     Err(err) => {
                         self.write_bytes(err.into_bytes()).await;
                         return;
@@ -258,7 +351,7 @@ impl SessionData {
                 // Update UIDs
                 let mut buf = Vec::with_capacity(64);
                 let (new_message_count, deletions) =
-                    mailbox.synchronize_uids(new_state.jmap_ids, new_state.imap_uids, true);
+                    mailbox.synchronize_uids(self, &new_state.ids, true);
                 if let Some(deletions) = deletions {
                     expunge::Response {
                         is_qresync,
@@ -279,29 +372,39 @@ impl SessionData {
                     self.write_bytes(buf).await;
                 }
 
-                if response.total_changes() > 0 {
+                if !changed_ids.is_empty() || new_state.last_state != last_state {
                     // Obtain ids of changed emails
-                    let mut changed_ids = Vec::with_capacity(response.total_changes());
+                    let changed_ids = changed_ids
+                        .iter()
+                        .map(|id| id.as_str())
+                        .collect::<AHashSet<_>>();
+                    let mut changed_uids = Vec::with_capacity(changed_ids.len());
                     {
                         // Update state
                         let mut state = mailbox.state.lock();
-                        state.last_state = response.take_new_state();
-                        for (pos, jmap_id) in state.jmap_ids.iter().enumerate() {
-                            if response.updated().contains(jmap_id)
-                                || response.created().contains(jmap_id)
-                            {
-                                changed_ids.push(Sequence::Number {
-                                    value: state.imap_uids[pos],
-                                });
+                        state.last_state = new_state.last_state;
+
+                        // Flags sent earlier on behalf of another session are not repeated
+                        let sent_uids = applied_changes
+                            .filter(|changes| {
+                                changes.mailbox == mailbox.id && changes.state == state.last_state
+                            })
+                            .map_or(&[][..], |changes| &changes.flags_changed[..]);
+
+                        for (uid, jmap_id) in state.ids.iter() {
+                            if changed_ids.contains(jmap_id) && !sent_uids.contains(&uid) {
+                                changed_uids.push(Sequence::Number { value: uid });
                             }
                         }
                     }
 
-                    if !changed_ids.is_empty() {
+                    if !changed_uids.is_empty() {
                         self.fetch(
                             fetch::Arguments {
                                 tag: String::new(),
-                                sequence_set: Sequence::List { items: changed_ids },
+                                sequence_set: Sequence::List {
+                                    items: changed_uids,
+                                },
                                 attributes: vec![fetch::Attribute::Flags, fetch::Attribute::Uid],
                                 changed_since: None,
                                 include_vanished: false,
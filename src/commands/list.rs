@@ -51,7 +51,7 @@ impl Session {
                 if !arguments.is_separator_query() {
                     let data = self.state.session_data();
                     let version = self.version;
-                    tokio::spawn(async move {
+                    self.spawn_task(async move {
                         data.list(arguments, is_lsub, version).await;
                     });
                     Ok(())
@@ -188,9 +188,9 @@ impl SessionData {
         let mut list_items = Vec::with_capacity(10);
 
         // Add "All Mail" folder
-        if !filter_subscribed && matches_pattern(&patterns, &self.core.folder_all) {
+        if !filter_subscribed && matches_pattern(&patterns, &self.user.route.folder_all) {
             list_items.push(ListItem {
-                mailbox_name: self.core.folder_all.clone(),
+                mailbox_name: self.user.route.folder_all.clone(),
                 attributes: vec![Attribute::All, Attribute::NoInferiors],
                 tags: vec![],
             });
@@ -198,12 +198,14 @@ impl SessionData {
 
         // Add mailboxes
         let mut added_shared_folder = false;
-        for account in self.mailboxes.lock().iter() {
+        for account in self.user.mailboxes.lock().iter() {
             if let Some(prefix) = &account.prefix {
                 if !added_shared_folder {
-                    if !filter_subscribed && matches_pattern(&patterns, &self.core.folder_shared) {
+                    if !filter_subscribed
+                        && matches_pattern(&patterns, &self.user.route.folder_shared)
+                    {
                         list_items.push(ListItem {
-                            mailbox_name: self.core.folder_shared.clone(),
+                            mailbox_name: self.user.route.folder_shared.clone(),
                             attributes: if include_children {
                                 vec![Attribute::HasChildren, Attribute::NoSelect]
                             } else {
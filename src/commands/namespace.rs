@@ -33,8 +33,15 @@ impl Session {
                 .with_tag(request.tag)
                 .serialize(
                     Response {
-                        shared_prefix: if self.state.session_data().mailboxes.lock().len() > 1 {
-                            self.core.folder_shared.clone().into()
+                        shared_prefix: if self.state.session_data().user.mailboxes.lock().len() > 1
+                        {
+                            self.state
+                                .session_data()
+                                .user
+                                .route
+                                .folder_shared
+                                .clone()
+                                .into()
                         } else {
                             None
                         },
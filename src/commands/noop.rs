@@ -35,8 +35,15 @@ impl Session {
     ) -> Result<(), ()> {
         match &self.state {
             State::Authenticated { data } => {
-                data.write_changes(None, true, true, self.is_qresync, self.version.is_rev2())
-                    .await;
+                data.write_changes(
+                    None,
+                    true,
+                    true,
+                    self.is_qresync,
+                    self.version.is_rev2(),
+                    None,
+                )
+                .await;
             }
             State::Selected { data, mailbox, .. } => {
                 data.write_changes(
@@ -45,6 +52,7 @@ impl Session {
                     true,
                     self.is_qresync,
                     self.version.is_rev2(),
+                    None,
                 )
                 .await;
             }
@@ -27,6 +27,7 @@ use tracing::debug;
 use crate::{
     core::{
         client::{Session, SessionData},
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -39,7 +40,7 @@ impl Session {
         match request.parse_rename(self.version) {
             Ok(arguments) => {
                 let data = self.state.session_data();
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     data.write_bytes(data.rename_folder(arguments).await.into_bytes())
                         .await;
                 });
@@ -70,7 +71,7 @@ impl SessionData {
         // Validate source mailbox
         let mailbox_id = {
             let mut mailbox_id = None;
-            for account in self.mailboxes.lock().iter() {
+            for account in self.user.mailboxes.lock().iter() {
                 if let Some(mailbox_id_) = account.mailbox_names.get(&arguments.mailbox_name) {
                     if account.account_id == params.account_id {
                         mailbox_id = mailbox_id_.to_string().into();
@@ -98,7 +99,7 @@ impl SessionData {
         let new_mailbox_name = params.path.pop().unwrap();
 
         // Build request
-        let mut request = self.client.build();
+        let mut request = self.user.client.build();
         let mut create_ids: Vec<String> = Vec::with_capacity(params.path.len());
         let set_request = request.set_mailbox().account_id(&params.account_id);
         for path_item in &params.path {
@@ -117,7 +118,7 @@ impl SessionData {
             update_item.parent_id(params.parent_mailbox_id.as_ref());
         }
 
-        match request.send_set_mailbox().await {
+        match measure_jmap(request.send_set_mailbox()).await {
             Ok(mut response) => {
                 let mut mailboxes = if !create_ids.is_empty() {
                     match self.add_created_mailboxes(&mut params, create_ids, &mut response) {
@@ -127,7 +128,7 @@ impl SessionData {
                         }
                     }
                 } else {
-                    self.mailboxes.lock()
+                    self.user.mailboxes.lock()
                 };
                 if let Err(err) = response.updated(&mailbox_id) {
                     return err.into_status_response().with_tag(arguments.tag);
@@ -33,6 +33,7 @@ use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
         message::ImapId,
+        metrics::measure_jmap,
         receiver::Request,
         Command, Flag, IntoStatusResponse, StatusResponse,
     },
@@ -79,7 +80,7 @@ impl Session {
                         (None, None)
                     };
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let tag = std::mem::take(&mut arguments.tag);
                     let bytes = match data
                         .search(
@@ -165,7 +166,7 @@ impl SessionData {
             filter => {
                 let mut position = 0;
                 loop {
-                    let mut request = self.client.build();
+                    let mut request = self.user.client.build();
                     let query_request = request
                         .query_email()
                         .filter(filter.clone())
@@ -174,7 +175,7 @@ impl SessionData {
                     if let Some(sort) = &sort {
                         query_request.sort(sort.clone());
                     }
-                    let mut response = match request.send_query_email().await {
+                    let mut response = match measure_jmap(request.send_query_email()).await {
                         Ok(response) => response,
                         Err(err) => return Err(err.into_status_response()),
                     };
@@ -198,8 +199,7 @@ impl SessionData {
         if imap_ids.len() != jmap_ids.len() {
             // Mailbox is out of sync
             let new_state = self.synchronize_messages(mailbox.id.clone()).await?;
-            let (new_message_count, _) =
-                mailbox.synchronize_uids(new_state.jmap_ids, new_state.imap_uids, false);
+            let (new_message_count, _) = mailbox.synchronize_uids(self, &new_state.ids, false);
             imap_ids = mailbox.jmap_to_imap(&jmap_ids);
 
             if let Some(new_message_count) = new_message_count {
@@ -512,7 +512,10 @@ impl SessionData {
                         // Convert MODSEQ to JMAP State
                         let state = match self
                             .core
-                            .modseq_to_state(&mailbox.id.account_id, modseq as u32)
+                            .modseq_to_state(
+                                &self.user.route.cache_key(&mailbox.id.account_id),
+                                modseq as u32,
+                            )
                             .await
                         {
                             Ok(Some(state)) => state,
@@ -528,19 +531,21 @@ impl SessionData {
                         };
 
                         // Obtain changes since the modseq.
-                        let mut request = self.client.build();
+                        let mut request = self.user.client.build();
                         request
                             .changes_email(state)
                             .account_id(&mailbox.id.account_id);
-                        let mut response = request
-                            .send_changes_email()
+                        let mut response = measure_jmap(request.send_changes_email())
                             .await
                             .map_err(|err| err.into_status_response())?;
 
                         // Obtain highest modseq
                         highest_modseq = self
                             .core
-                            .state_to_modseq(&mailbox.id.account_id, response.take_new_state())
+                            .state_to_modseq(
+                                &self.user.route.cache_key(&mailbox.id.account_id),
+                                response.take_new_state(),
+                            )
                             .await
                             .map_err(|_| StatusResponse::database_failure())?
                             .into();
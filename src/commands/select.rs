@@ -59,25 +59,18 @@ impl Session {
                     // Syncronize messages
                     let mailbox = Arc::new(mailbox);
                     match data.synchronize_messages(mailbox.clone()).await {
-                        Ok(mut state) => {
+                        Ok(state) => {
                             let closed_previous = self.state.is_mailbox_selected();
                             let is_condstore = self.is_condstore || arguments.condstore;
 
-                            // Obtain JMAP state
-                            state.last_state = match data.get_jmap_state(&mailbox.account_id).await
-                            {
-                                Ok(jmap_state) => jmap_state,
-                                Err(mut response) => {
-                                    response.tag = arguments.tag.into();
-                                    return self.write_bytes(response.into_bytes()).await;
-                                }
-                            };
-
                             // Obtain highest modseq
                             let highest_modseq = if is_condstore {
                                 match data
                                     .core
-                                    .state_to_modseq(&mailbox.account_id, state.last_state.clone())
+                                    .state_to_modseq(
+                                        &data.user.route.cache_key(&mailbox.account_id),
+                                        state.last_state.clone(),
+                                    )
                                     .await
                                 {
                                     Ok(highest_modseq) => highest_modseq.into(),
@@ -98,7 +91,7 @@ impl Session {
                             // Build new state
                             let uid_validity = state.uid_validity;
                             let uid_next = state.uid_next;
-                            let total_messages = state.imap_uids.len();
+                            let total_messages = state.ids.len();
                             let mailbox = Arc::new(SelectedMailbox {
                                 id: mailbox,
                                 state: parking_lot::Mutex::new(state),
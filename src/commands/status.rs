@@ -33,6 +33,7 @@ use crate::{
     core::{
         client::{Session, SessionData},
         mailbox::Mailbox,
+        metrics::measure_jmap,
         receiver::Request,
         Command, Flag, IntoStatusResponse, ResponseCode, StatusResponse,
     },
@@ -45,7 +46,7 @@ impl Session {
             Ok(arguments) => {
                 let version = self.version;
                 let data = self.state.session_data();
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     // Refresh mailboxes
                     if let Err(err) = data.synchronize_mailboxes(false, false).await {
                         debug!("Failed to refresh mailboxes: {}", err);
@@ -102,7 +103,7 @@ impl SessionData {
         let mut items_update = Vec::with_capacity(items.len());
         let mut items_response = Vec::with_capacity(items.len());
 
-        for account in self.mailboxes.lock().iter_mut() {
+        for account in self.user.mailboxes.lock().iter_mut() {
             if account.account_id == mailbox.account_id {
                 let mailbox_data = account
                     .mailbox_data
@@ -186,7 +187,7 @@ impl SessionData {
             || items_update.contains(&Status::Messages)
         {
             let status = self.synchronize_messages(mailbox.clone()).await?;
-            for account in self.mailboxes.lock().iter_mut() {
+            for account in self.user.mailboxes.lock().iter_mut() {
                 if account.account_id == mailbox.account_id {
                     let mailbox_data = account
                         .mailbox_data
@@ -218,7 +219,7 @@ impl SessionData {
 
         // Update Unseen
         if items_update.contains(&Status::Unseen) || items_update.contains(&Status::Deleted) {
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             if items_update.contains(&Status::Unseen) {
                 request
                     .query_email()
@@ -253,15 +254,14 @@ impl SessionData {
                     .calculate_total(true)
                     .limit(1);
             }
-            let mut responses = request
-                .send()
+            let mut responses = measure_jmap(request.send())
                 .await
                 .map_err(|err| err.into_status_response())?
                 .unwrap_method_responses()
                 .into_iter();
 
             // Update cache
-            for account in self.mailboxes.lock().iter_mut() {
+            for account in self.user.mailboxes.lock().iter_mut() {
                 if account.account_id == mailbox.account_id {
                     let mailbox_data = account
                         .mailbox_data
@@ -310,6 +310,7 @@ impl SessionData {
         // Update Size
         if items_update.contains(&Status::Size) {
             let max_objects_in_get = self
+                .user
                 .client
                 .session()
                 .core_capabilities()
@@ -320,7 +321,7 @@ impl SessionData {
 
             // Fetch email sizes
             for _ in 0..100 {
-                let mut request = self.client.build().account_id(&mailbox.account_id);
+                let mut request = self.user.client.build().account_id(&mailbox.account_id);
                 let query_request = request
                     .query_email()
                     .calculate_total(true)
@@ -336,8 +337,7 @@ impl SessionData {
                     .ids_ref(query_reference)
                     .properties([Property::Size]);
 
-                let mut response = request
-                    .send()
+                let mut response = measure_jmap(request.send())
                     .await
                     .map_err(|err| err.into_status_response())?
                     .unwrap_method_responses();
@@ -373,7 +373,7 @@ impl SessionData {
             }
 
             // Update cache
-            for account in self.mailboxes.lock().iter_mut() {
+            for account in self.user.mailboxes.lock().iter_mut() {
                 if account.account_id == mailbox.account_id {
                     account
                         .mailbox_data
@@ -391,7 +391,7 @@ impl SessionData {
         if items_update.contains(&Status::HighestModSeq) {
             let modseq = self.synchronize_state(&mailbox.account_id).await?;
             // Update cache
-            for account in self.mailboxes.lock().iter_mut() {
+            for account in self.user.mailboxes.lock().iter_mut() {
                 if account.account_id == mailbox.account_id {
                     account.modseq = modseq.into();
                     break;
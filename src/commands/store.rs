@@ -30,6 +30,7 @@ use tracing::debug;
 use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
+        metrics::measure_jmap,
         receiver::Request,
         Command, Flag, IntoStatusResponse, ResponseCode, ResponseType, StatusResponse,
     },
@@ -51,7 +52,7 @@ impl Session {
                 let (data, mailbox) = self.state.select_data();
                 let is_condstore = self.is_condstore || mailbox.is_condstore;
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let bytes = match data.store(arguments, mailbox, is_uid, is_condstore).await {
                         Ok(response) => response,
                         Err(response) => response.into_bytes(),
@@ -74,12 +75,14 @@ impl SessionData {
         is_condstore: bool,
     ) -> Result<Vec<u8>, StatusResponse> {
         let max_objects_in_get = self
+            .user
             .client
             .session()
             .core_capabilities()
             .map(|c| c.max_objects_in_get())
             .unwrap_or(500);
         let max_objects_in_set = self
+            .user
             .client
             .session()
             .core_capabilities()
@@ -117,7 +120,10 @@ impl SessionData {
             // Convert MODSEQ to JMAP State
             let state = match self
                 .core
-                .modseq_to_state(&mailbox.id.account_id, unchanged_since as u32)
+                .modseq_to_state(
+                    &self.user.route.cache_key(&mailbox.id.account_id),
+                    unchanged_since as u32,
+                )
                 .await
             {
                 Ok(Some(state)) => state,
@@ -132,18 +138,18 @@ impl SessionData {
             };
 
             // Obtain changes since the modseq.
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             request
                 .changes_email(state)
                 .account_id(&mailbox.id.account_id);
-            match request.send_changes_email().await {
+            match measure_jmap(request.send_changes_email()).await {
                 Ok(changes) => {
                     let mut modified = Vec::new();
                     let mut unchanged_ids = AHashMap::with_capacity(ids.len());
                     let mut sequence_set = arguments.sequence_set.expand(if is_uid {
-                        mailbox.state.lock().imap_uids.last().copied().unwrap_or(0)
+                        mailbox.state.lock().ids.last_uid().unwrap_or(0)
                     } else {
-                        mailbox.state.lock().imap_uids.len() as u32
+                        mailbox.state.lock().ids.len() as u32
                     });
 
                     // Add all IDs that changed in this mailbox
@@ -200,7 +206,7 @@ impl SessionData {
         }
 
         // Update
-        let mut request = self.client.build();
+        let mut request = self.user.client.build();
         let ids_vec = ids.keys().collect::<Vec<_>>();
         for jmap_ids_chunk in ids_vec.chunks(max_objects_in_set) {
             let set_request = request.set_email().account_id(&mailbox.id.account_id);
@@ -230,7 +236,7 @@ impl SessionData {
             }
         }
 
-        match request.send().await {
+        match measure_jmap(request.send()).await {
             Ok(set_response) => {
                 let mut emails = Vec::new();
                 let mut new_state = String::new();
@@ -267,13 +273,24 @@ impl SessionData {
                 if is_condstore {
                     if let Ok(new_modseq) = self
                         .core
-                        .state_to_modseq(&mailbox.id.account_id, new_state.clone())
+                        .state_to_modseq(
+                            &self.user.route.cache_key(&mailbox.id.account_id),
+                            new_state.clone(),
+                        )
                         .await
                     {
                         modseq = new_modseq;
                     }
                 }
                 mailbox.state.lock().last_state = new_state;
+                self.publish_message_changes(
+                    &mailbox,
+                    Vec::new(),
+                    updated_ids
+                        .iter()
+                        .filter_map(|jmap_id| ids.get(jmap_id).map(|imap_id| imap_id.uid))
+                        .collect(),
+                );
 
                 // Verify that all IDs were updated
                 if ids.len() != updated_ids.len() && response.rtype == ResponseType::Ok {
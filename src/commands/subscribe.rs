@@ -25,6 +25,7 @@ use tracing::debug;
 
 use crate::core::{
     client::{Session, SessionData},
+    metrics::measure_jmap,
     receiver::Request,
     Command, IntoStatusResponse, ResponseCode, StatusResponse,
 };
@@ -38,7 +39,7 @@ impl Session {
         match request.parse_subscribe(self.version) {
             Ok(arguments) => {
                 let data = self.state.session_data();
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     data.write_bytes(
                         data.subscribe_folder(arguments.tag, arguments.mailbox_name, is_subscribe)
                             .await
@@ -85,12 +86,14 @@ impl SessionData {
         };
 
         // [Un]subscribe mailbox
-        if let Err(err) = self.client.mailbox_subscribe(&mailbox_id, subscribe).await {
+        if let Err(err) =
+            measure_jmap(self.user.client.mailbox_subscribe(&mailbox_id, subscribe)).await
+        {
             return err.into_status_response().with_tag(tag);
         }
 
         // Update mailbox cache
-        for account in self.mailboxes.lock().iter_mut() {
+        for account in self.user.mailboxes.lock().iter_mut() {
             if account.account_id == account_id {
                 if let Some(mailbox) = account.mailbox_data.get_mut(&mailbox_id) {
                     mailbox.is_subscribed = subscribe;
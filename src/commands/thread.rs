@@ -30,6 +30,7 @@ use tracing::debug;
 use crate::{
     core::{
         client::{SelectedMailbox, Session, SessionData},
+        metrics::measure_jmap,
         receiver::Request,
         Command, IntoStatusResponse, StatusResponse,
     },
@@ -51,7 +52,7 @@ impl Session {
             Ok(arguments) => {
                 let (data, mailbox) = self.state.mailbox_data();
 
-                tokio::spawn(async move {
+                self.spawn_task(async move {
                     let bytes = match data.thread(arguments, mailbox, is_uid).await {
                         Ok((response, tag)) => StatusResponse::completed(command)
                             .with_tag(tag)
@@ -81,6 +82,7 @@ impl SessionData {
 
         // Build query
         let max_objects_in_get = self
+            .user
             .client
             .session()
             .core_capabilities()
@@ -91,7 +93,7 @@ impl SessionData {
         let mut threads = AHashMap::new();
         loop {
             let mut total = 0;
-            let mut request = self.client.build();
+            let mut request = self.user.client.build();
             let query_result = request
                 .query_email()
                 .filter(filter.clone())
@@ -105,8 +107,7 @@ impl SessionData {
                 .properties([Property::Id, Property::ThreadId]);
 
             let mut results_len = 0;
-            for response in request
-                .send()
+            for response in measure_jmap(request.send())
                 .await
                 .map_err(|err| {
                     err.into_status_response()
@@ -159,8 +160,7 @@ impl SessionData {
                 .synchronize_messages(mailbox.id.clone())
                 .await
                 .map_err(|err| err.with_tag(arguments.tag.to_string()))?;
-            let (new_message_count, _) =
-                mailbox.synchronize_uids(new_state.jmap_ids, new_state.imap_uids, false);
+            let (new_message_count, _) = mailbox.synchronize_uids(self, &new_state.ids, false);
 
             if let Some(new_message_count) = new_message_count {
                 self.write_bytes(
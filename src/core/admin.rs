@@ -0,0 +1,195 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{net::IpAddr, sync::Arc, time::Instant};
+
+use serde_json::json;
+use tracing::info;
+
+use super::{
+    housekeeper::DEFAULT_REMOVED_ID_TTL,
+    http::{parse_query, HttpRequest, HttpResponse},
+    metrics::METRICS,
+    Core,
+};
+
+pub async fn handle_admin_request(core: Arc<Core>, request: HttpRequest) -> HttpResponse {
+    // Only bearer tokens matching the configured secret are accepted
+    let is_authorized = match (
+        &core.admin_secret,
+        request
+            .headers
+            .get("authorization")
+            .and_then(|value| value.strip_prefix("Bearer ")),
+    ) {
+        (Some(secret), Some(token)) => secure_eq(secret.as_bytes(), token.trim().as_bytes()),
+        _ => false,
+    };
+    if !is_authorized {
+        return HttpResponse::text(401, "Unauthorized");
+    }
+
+    let params = parse_query(&request.query);
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/sessions") => {
+            let sessions = core
+                .sessions
+                .list()
+                .into_iter()
+                .map(|(id, info)| {
+                    json!({
+                        "id": id,
+                        "protocol": info.protocol,
+                        "peer": info.peer_addr.to_string(),
+                        "tls": info.is_tls,
+                        "user": info.user,
+                        "mailbox": info
+                            .mailbox
+                            .and_then(|(data, mailbox)| data.get_mailbox_name(&mailbox.id)),
+                        "idle": info.is_idle,
+                        "connectedAt": info.connected_at,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json_response(json!({ "sessions": sessions }))
+        }
+        ("POST", "/sessions/terminate") => {
+            let id = match params.get("id").map(|id| id.parse::<u64>()).transpose() {
+                Ok(id) => id,
+                Err(_) => return HttpResponse::bad_request("Invalid 'id' parameter."),
+            };
+            let ip = match params.get("ip").map(|ip| ip.parse::<IpAddr>()).transpose() {
+                Ok(ip) => ip,
+                Err(_) => return HttpResponse::bad_request("Invalid 'ip' parameter."),
+            };
+            let user = params.get("user");
+            if id.is_none() && ip.is_none() && user.is_none() {
+                return HttpResponse::bad_request("Specify 'id', 'user' and/or 'ip'.");
+            }
+
+            let terminated = core.sessions.terminate(|session_id, info| {
+                id.map_or(true, |id| id == session_id)
+                    && ip.map_or(true, |ip| ip == info.peer_addr.ip())
+                    && user.map_or(true, |user| info.user.as_ref() == Some(user))
+            });
+            info!(
+                "Administrator terminated {} session(s) (id: {:?}, user: {:?}, ip: {:?}).",
+                terminated, id, user, ip
+            );
+            json_response(json!({ "terminated": terminated }))
+        }
+        ("POST", "/housekeeper/purge-deleted-ids") => {
+            let ttl = match params.get("ttl").map(|ttl| ttl.parse::<u64>()).transpose() {
+                Ok(ttl) => ttl.unwrap_or(DEFAULT_REMOVED_ID_TTL),
+                Err(_) => return HttpResponse::bad_request("Invalid 'ttl' parameter."),
+            };
+            info!("Administrator requested purge of deleted ids.");
+            let started = Instant::now();
+            let result = core.purge_deleted_ids(ttl).await;
+            METRICS.housekeeper_run(started.elapsed(), result.is_ok());
+            match result {
+                Ok(purged) => json_response(json!({ "purged": purged })),
+                Err(_) => HttpResponse::text(500, "Failed to purge deleted ids."),
+            }
+        }
+        ("GET", "/housekeeper/tasks") => {
+            let tasks = core
+                .housekeeper
+                .tasks
+                .iter()
+                .map(|task| {
+                    let runs = task
+                        .runs
+                        .lock()
+                        .iter()
+                        .map(|run| {
+                            json!({
+                                "startedAt": run.started_at,
+                                "durationMs": run.duration.as_millis() as u64,
+                                "success": run.result.is_ok(),
+                                "message": match &run.result {
+                                    Ok(message) | Err(message) => message,
+                                },
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    json!({
+                        "name": task.name(),
+                        "schedule": task.expression,
+                        "nextRunIn": task.schedule.time_to_next().map(|next| next.as_secs()),
+                        "runs": runs,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json_response(json!({ "tasks": tasks }))
+        }
+        ("POST", "/housekeeper/run") => {
+            let task = match params.get("task").and_then(|name| {
+                core.housekeeper
+                    .tasks
+                    .iter()
+                    .find(|t| t.name() == name.as_str())
+            }) {
+                Some(task) => task.clone(),
+                None => return HttpResponse::bad_request("Unknown or missing 'task' parameter."),
+            };
+            info!("Administrator requested run of task {}.", task.name());
+            match task.run(&core).await {
+                Ok(message) => json_response(json!({ "message": message })),
+                Err(err) => HttpResponse::text(500, err),
+            }
+        }
+        ("POST", "/housekeeper/delete-account") => {
+            let account_id = match params.get("account_id") {
+                Some(account_id) if !account_id.is_empty() => account_id.to_string(),
+                _ => return HttpResponse::bad_request("Missing 'account_id' parameter."),
+            };
+            info!(
+                "Administrator requested deletion of account {}.",
+                account_id
+            );
+            match core.delete_account(account_id).await {
+                Ok(_) => json_response(json!({ "deleted": true })),
+                Err(_) => HttpResponse::text(500, "Failed to delete account."),
+            }
+        }
+        (
+            _,
+            "/sessions"
+            | "/sessions/terminate"
+            | "/housekeeper/purge-deleted-ids"
+            | "/housekeeper/tasks"
+            | "/housekeeper/run"
+            | "/housekeeper/delete-account",
+        ) => HttpResponse::text(405, "Method not allowed"),
+        _ => HttpResponse::not_found(),
+    }
+}
+
+fn json_response(value: serde_json::Value) -> HttpResponse {
+    HttpResponse::new(200, "application/json", value.to_string())
+}
+
+pub fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
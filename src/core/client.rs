@@ -21,9 +21,8 @@
  * for more details.
 */
 
-use std::{iter::Peekable, net::SocketAddr, sync::Arc, vec::IntoIter};
+use std::{future::Future, iter::Peekable, net::SocketAddr, sync::Arc, vec::IntoIter};
 
-use jmap_client::client::Client;
 use tokio::{
     io::WriteHalf,
     net::TcpStream,
@@ -35,9 +34,11 @@ use tracing::debug;
 use crate::{commands::search::SavedSearch, protocol::ProtocolVersion};
 
 use super::{
-    mailbox::Account,
+    mailbox::MailboxSync,
     message::{MailboxData, MailboxId},
+    metrics::METRICS,
     receiver::{self, Receiver, Request},
+    session_cache::UserSession,
     writer, Command, Core, StatusResponse,
 };
 
@@ -52,13 +53,17 @@ pub struct Session {
     pub is_qresync: bool,
     pub writer: mpsc::Sender<writer::Event>,
     pub idle_tx: Option<watch::Sender<bool>>,
+    pub session_id: u64,
+    pub terminate_rx: watch::Receiver<bool>,
+    pub metrics_state: usize,
 }
 
 pub struct SessionData {
-    pub client: Client,
+    pub user: Arc<UserSession>,
     pub core: Arc<Core>,
     pub writer: mpsc::Sender<writer::Event>,
-    pub mailboxes: parking_lot::Mutex<Vec<Account>>,
+    pub session_id: u64,
+    pub mailbox_queue: Arc<parking_lot::Mutex<MailboxSync>>,
 }
 
 pub struct SelectedMailbox {
@@ -84,14 +89,21 @@ pub enum State {
 
 impl Session {
     pub fn new(core: Arc<Core>, peer_addr: SocketAddr, is_tls: bool) -> Self {
+        let state = State::NotAuthenticated { auth_failures: 0 };
+        let (session_id, terminate_rx) = core.sessions.register("imap", peer_addr, is_tls);
+        METRICS.session_state(None, state.metrics_id().into());
         Session {
-            receiver: Receiver::with_max_request_size(core.max_request_size),
+            receiver: Receiver::with_max_request_size(core.max_request_size())
+                .with_spool(core.literal_spool.clone()),
             version: ProtocolVersion::Rev1,
-            state: State::NotAuthenticated { auth_failures: 0 },
+            metrics_state: state.metrics_id(),
+            state,
             peer_addr,
             is_tls,
             writer: writer::spawn_writer(),
             idle_tx: None,
+            session_id,
+            terminate_rx,
             is_condstore: false,
             is_qresync: false,
             core,
@@ -150,121 +162,65 @@ impl Session {
                 }
             }
         }
+        self.receiver.write_spooled().await;
 
         let mut requests = requests.into_iter().peekable();
         while let Some(request) = requests.next() {
-            match request.command {
-                Command::List | Command::Lsub => {
-                    self.handle_list(request).await?;
-                }
-                Command::Select | Command::Examine => {
-                    self.handle_select(request).await?;
-                }
-                Command::Create => {
-                    self.handle_create(group_requests(&mut requests, vec![request]))
-                        .await?;
-                }
-                Command::Delete => {
-                    self.handle_delete(group_requests(&mut requests, vec![request]))
-                        .await?;
-                }
-                Command::Rename => {
-                    self.handle_rename(request).await?;
-                }
-                Command::Status => {
-                    self.handle_status(request).await?;
-                }
-                Command::Append => {
-                    self.handle_append(request).await?;
-                }
-                Command::Close => {
-                    self.handle_close(request).await?;
-                }
-                Command::Unselect => {
-                    self.handle_unselect(request).await?;
-                }
-                Command::Expunge(is_uid) => {
-                    self.handle_expunge(request, is_uid).await?;
-                }
-                Command::Search(is_uid) => {
-                    self.handle_search(request, false, is_uid).await?;
-                }
-                Command::Fetch(is_uid) => {
-                    self.handle_fetch(request, is_uid).await?;
-                }
-                Command::Store(is_uid) => {
-                    self.handle_store(request, is_uid).await?;
-                }
-                Command::Copy(is_uid) => {
-                    self.handle_copy_move(request, false, is_uid).await?;
-                }
-                Command::Move(is_uid) => {
-                    self.handle_copy_move(request, true, is_uid).await?;
-                }
-                Command::Sort(is_uid) => {
-                    self.handle_search(request, true, is_uid).await?;
-                }
-                Command::Thread(is_uid) => {
-                    self.handle_thread(request, is_uid).await?;
-                }
-                Command::Idle => {
-                    self.handle_idle(request).await?;
-                }
-                Command::Subscribe => {
-                    self.handle_subscribe(request, true).await?;
-                }
-                Command::Unsubscribe => {
-                    self.handle_subscribe(request, false).await?;
-                }
-                Command::Namespace => {
-                    self.handle_namespace(request).await?;
-                }
-                Command::Authenticate => {
-                    self.handle_authenticate(request).await?;
-                }
-                Command::Login => {
-                    self.handle_login(request).await?;
-                }
-                Command::Capability => {
-                    self.handle_capability(request).await?;
-                }
-                Command::Enable => {
-                    self.handle_enable(request).await?;
+            let command = request.command;
+            self.start_command(&request.tag, command).await?;
+
+            let result = match command {
+                Command::List | Command::Lsub => self.handle_list(request).await,
+                Command::Select | Command::Examine => self.handle_select(request).await,
+                Command::Create | Command::Delete => {
+                    let grouped = group_requests(&mut requests, vec![request]);
+                    for request in &grouped[1..] {
+                        self.start_command(&request.tag, command).await?;
+                    }
+                    if command == Command::Create {
+                        self.handle_create(grouped).await
+                    } else {
+                        self.handle_delete(grouped).await
+                    }
                 }
+                Command::Rename => self.handle_rename(request).await,
+                Command::Status => self.handle_status(request).await,
+                Command::Append => self.handle_append(request).await,
+                Command::Close => self.handle_close(request).await,
+                Command::Unselect => self.handle_unselect(request).await,
+                Command::Expunge(is_uid) => self.handle_expunge(request, is_uid).await,
+                Command::Search(is_uid) => self.handle_search(request, false, is_uid).await,
+                Command::Fetch(is_uid) => self.handle_fetch(request, is_uid).await,
+                Command::Store(is_uid) => self.handle_store(request, is_uid).await,
+                Command::Copy(is_uid) => self.handle_copy_move(request, false, is_uid).await,
+                Command::Move(is_uid) => self.handle_copy_move(request, true, is_uid).await,
+                Command::Sort(is_uid) => self.handle_search(request, true, is_uid).await,
+                Command::Thread(is_uid) => self.handle_thread(request, is_uid).await,
+                Command::Idle => self.handle_idle(request).await,
+                Command::Subscribe => self.handle_subscribe(request, true).await,
+                Command::Unsubscribe => self.handle_subscribe(request, false).await,
+                Command::Namespace => self.handle_namespace(request).await,
+                Command::Authenticate => self.handle_authenticate(request).await,
+                Command::Login => self.handle_login(request).await,
+                Command::Capability => self.handle_capability(request).await,
+                Command::Enable => self.handle_enable(request).await,
                 Command::StartTls => {
                     return self.handle_starttls(request).await;
                 }
-                Command::Noop => {
-                    self.handle_noop(request, false).await?;
-                }
-                Command::Check => {
-                    self.handle_noop(request, true).await?;
-                }
-                Command::Logout => {
-                    self.handle_logout(request).await?;
-                }
-                Command::SetAcl => {
-                    self.handle_set_acl(request).await?;
-                }
-                Command::DeleteAcl => {
-                    self.handle_delete_acl(request).await?;
-                }
-                Command::GetAcl => {
-                    self.handle_get_acl(request).await?;
-                }
-                Command::ListRights => {
-                    self.handle_list_rights(request).await?;
-                }
-                Command::MyRights => {
-                    self.handle_my_rights(request).await?;
-                }
-                Command::Unauthenticate => {
-                    self.handle_unauthenticate(request).await?;
-                }
-                Command::Id => {
-                    self.handle_id(request).await?;
-                }
-            }
+                Command::Noop => self.handle_noop(request, false).await,
+                Command::Check => self.handle_noop(request, true).await,
+                Command::Logout => self.handle_logout(request).await,
+                Command::SetAcl => self.handle_set_acl(request).await,
+                Command::DeleteAcl => self.handle_delete_acl(request).await,
+                Command::GetAcl => self.handle_get_acl(request).await,
+                Command::ListRights => self.handle_list_rights(request).await,
+                Command::MyRights => self.handle_my_rights(request).await,
+                Command::Unauthenticate => self.handle_unauthenticate(request).await,
+                Command::Id => self.handle_id(request).await,
+            };
+
+            self.update_state();
+            result?;
         }
 
         if let Some(needs_literal) = needs_literal {
@@ -276,6 +232,52 @@ impl Session {
     }
 }
 
+impl Session {
+    pub fn spawn_task(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let guard = self.core.sessions.start_task(self.session_id);
+        tokio::spawn(async move {
+            task.await;
+            drop(guard);
+        });
+    }
+
+    pub fn update_state(&mut self) {
+        let state = self.state.metrics_id();
+        let (user, data, mailbox) = match &self.state {
+            State::NotAuthenticated { .. } => (None, None, None),
+            State::Authenticated { data } => (
+                data.user.client.session().username().to_string().into(),
+                data.clone().into(),
+                None,
+            ),
+            State::Selected { data, mailbox } => (
+                data.user.client.session().username().to_string().into(),
+                data.clone().into(),
+                (data.clone(), mailbox.clone()).into(),
+            ),
+        };
+        let is_tls = self.is_tls;
+        let is_idle = self.idle_tx.is_some();
+
+        METRICS.session_state(self.metrics_state.into(), state.into());
+        self.metrics_state = state;
+        self.core.sessions.update(self.session_id, |info| {
+            info.user = user;
+            info.data = data;
+            info.mailbox = mailbox;
+            info.is_tls = is_tls;
+            info.is_idle = is_idle;
+        });
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        METRICS.session_state(self.metrics_state.into(), None);
+        self.core.sessions.unregister(self.session_id);
+    }
+}
+
 pub fn group_requests(
     requests: &mut Peekable<IntoIter<Request<Command>>>,
     mut grouped_requests: Vec<Request<Command>>,
@@ -430,4 +432,12 @@ impl State {
     pub fn is_mailbox_selected(&self) -> bool {
         matches!(self, State::Selected { .. })
     }
+
+    pub fn metrics_id(&self) -> usize {
+        match self {
+            State::NotAuthenticated { .. } => 0,
+            State::Authenticated { .. } => 1,
+            State::Selected { .. } => 2,
+        }
+    }
 }
@@ -21,26 +21,84 @@
  * for more details.
 */
 
-use std::{fs::File, io::BufReader, sync::Arc};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
-use rustls::{Certificate, PrivateKey};
+use ahash::AHashSet;
+use rustls::{
+    sign::{any_supported_type, CertifiedKey},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use tracing::warn;
+use tracing::{debug, info, warn};
 
-use super::{env_settings::EnvSettings, Core};
+use super::{
+    env_settings::EnvSettings,
+    housekeeper::Housekeeper,
+    metadata_cache::MetadataCache,
+    proxy::IpNetwork,
+    push::PushRegistry,
+    receiver::LiteralSpool,
+    registry::SessionRegistry,
+    router::{Backend, Balance, DomainMatch, Route, Router},
+    session_cache::SessionCache,
+    store::{
+        memory_store::MemoryStore, remote_store::RemoteStore, sled_store::SledStore,
+        sqlite_store::SqliteStore, UidStore,
+    },
+    tls::SniCertResolver,
+    uid_map::UidMapCache,
+    upgrade::{is_upgrade, Listeners},
+    Core,
+};
 
 pub const DEFAULT_JMAP_URL: &str = "http://127.0.0.1:8080";
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 50 * 1024 * 1024;
+pub const DEFAULT_SESSION_CACHE_TTL: u64 = 300;
+pub const DEFAULT_METADATA_CACHE_SIZE: u64 = 512 * 1024 * 1024;
+pub const DEFAULT_LITERAL_SPOOL_SIZE: usize = 1024 * 1024;
+pub const DEFAULT_UPGRADE_DRAIN_TIMEOUT: u64 = 10;
+
+// Settings that are applied to new connections when SIGHUP is received.
+const RELOADABLE_SETTINGS: &[&str] = &[
+    "cert-path",
+    "key-path",
+    "cert-dir",
+    "max-request-size",
+    "proxy-trusted-networks",
+];
 
 pub fn build_core(settings: &EnvSettings) -> Core {
-    Core {
-        db: Arc::new(
-            sled::open(
+    let db = Arc::new(
+        open_db(
+            &settings
+                .get("cache-dir")
+                .failed_to("start server: Missing cache-dir parameter."),
+            Duration::from_secs(
                 settings
-                    .get("cache-dir")
-                    .failed_to("start server: Missing cache-dir parameter."),
-            )
-            .failed_to("open database"),
-        ),
+                    .parse::<u64>("upgrade-drain-timeout")
+                    .unwrap_or(DEFAULT_UPGRADE_DRAIN_TIMEOUT)
+                    + 30,
+            ),
+        )
+        .failed_to("open database"),
+    );
+
+    Core {
+        uid_store: parse_uid_store(settings, &db).failed_to("open UID cache"),
+        metadata_cache: MetadataCache::open(
+            &db,
+            settings
+                .parse("metadata-cache-size")
+                .unwrap_or(DEFAULT_METADATA_CACHE_SIZE),
+        )
+        .failed_to("open metadata cache"),
+        db,
         worker_pool: rayon::ThreadPoolBuilder::new()
             .num_threads(
                 settings
@@ -50,12 +108,161 @@ pub fn build_core(settings: &EnvSettings) -> Core {
             )
             .build()
             .unwrap(),
-        tls_acceptor: tokio_rustls::TlsAcceptor::from(Arc::new(load_tls_config(settings))),
-        jmap_url: if let Some(jmap_url) = settings.get("jmap-url") {
-            jmap_url
-        } else {
+        tls_acceptor: parking_lot::RwLock::new(tokio_rustls::TlsAcceptor::from(Arc::new(
+            load_tls_config(settings).failed_to("load TLS config"),
+        ))),
+        router: parse_router(settings).failed_to("parse JMAP routes"),
+        max_request_size: settings
+            .parse("max-request-size")
+            .unwrap_or(DEFAULT_MAX_REQUEST_SIZE)
+            .into(),
+        proxy_networks: parking_lot::RwLock::new(
+            parse_proxy_networks(settings).failed_to("parse 'proxy-trusted-networks' parameter"),
+        ),
+        sessions: SessionRegistry::default(),
+        admin_secret: settings.get("admin-secret"),
+        cache_secret: settings.get("cache-secret"),
+        listeners: Listeners::from_env(),
+        session_cache: SessionCache::new(Duration::from_secs(
+            settings
+                .parse("session-cache-ttl")
+                .unwrap_or(DEFAULT_SESSION_CACHE_TTL),
+        )),
+        push_hubs: PushRegistry::default(),
+        uid_maps: UidMapCache::default(),
+        literal_spool: match settings
+            .parse("literal-spool-size")
+            .unwrap_or(DEFAULT_LITERAL_SPOOL_SIZE)
+        {
+            0 => None,
+            min_size => LiteralSpool::open(
+                settings
+                    .get("spool-dir")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| std::env::temp_dir().join("stalwart-imap-spool")),
+                min_size,
+            )
+            .failed_to("open literal spool directory")
+            .into(),
+        },
+        housekeeper: Housekeeper::parse(settings).failed_to("parse housekeeper settings"),
+    }
+}
+
+impl Core {
+    pub fn reload(&self, old_settings: &EnvSettings, settings: &EnvSettings) -> Result<(), String> {
+        // Validate all settings before applying any of them
+        let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(load_tls_config(settings)?));
+        let max_request_size = settings
+            .try_parse("max-request-size")?
+            .unwrap_or(DEFAULT_MAX_REQUEST_SIZE);
+        let proxy_networks = parse_proxy_networks(settings)?;
+
+        *self.tls_acceptor.write() = tls_acceptor;
+        self.max_request_size
+            .store(max_request_size, Ordering::Relaxed);
+        *self.proxy_networks.write() = proxy_networks;
+
+        let mut changed_keys = old_settings
+            .args
+            .keys()
+            .chain(settings.args.keys())
+            .filter(|key| old_settings.args.get(*key) != settings.args.get(*key))
+            .collect::<AHashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        changed_keys.sort_unstable();
+
+        for key in changed_keys {
+            if RELOADABLE_SETTINGS.contains(&key.as_str()) {
+                info!(
+                    "Setting '{}' changed to {:?}.",
+                    key,
+                    settings.args.get(key).map(|v| v.as_str()).unwrap_or("")
+                );
+            } else {
+                warn!(
+                    "Setting '{}' changed but requires a restart to take effect.",
+                    key
+                );
+            }
+        }
+        info!("Configuration reloaded, TLS certificates refreshed.");
+
+        Ok(())
+    }
+}
+
+// The cache can only be opened by one process, so on upgrade wait until the previous
+// instance has drained its sessions and released it.
+fn open_db(path: &str, timeout: Duration) -> sled::Result<sled::Db> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(_)) if is_upgrade() && Instant::now() < deadline => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            result => return result,
+        }
+    }
+}
+
+pub fn parse_uid_store(
+    settings: &EnvSettings,
+    db: &Arc<sled::Db>,
+) -> Result<Arc<dyn UidStore>, String> {
+    match settings.get("cache-store").as_deref().unwrap_or("sled") {
+        "sled" => Ok(Arc::new(SledStore::new(db.clone()))),
+        "sqlite" => Ok(Arc::new(SqliteStore::open(
+            settings
+                .get("cache-sqlite-path")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| {
+                    PathBuf::from(settings.get("cache-dir").unwrap_or_default())
+                        .join("uids.sqlite3")
+                }),
+        )?)),
+        "memory" => Ok(Arc::new(MemoryStore::default())),
+        "remote" => {
+            let addr = settings
+                .get("cache-remote-addr")
+                .ok_or("Missing 'cache-remote-addr' parameter.")?;
+            let server_name = settings.get("cache-remote-name").unwrap_or_else(|| {
+                addr.rsplit_once(':')
+                    .map_or(addr.as_str(), |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .to_string()
+            });
+            Ok(Arc::new(RemoteStore::new(
+                addr,
+                settings
+                    .get("cache-secret")
+                    .ok_or("Missing 'cache-secret' parameter.")?,
+                Arc::new(load_client_tls_config(settings)?),
+                ServerName::try_from(server_name.as_str())
+                    .map_err(|_| format!("Invalid 'cache-remote-name' value: {}", server_name))?,
+            )))
+        }
+        store => Err(format!("Invalid 'cache-store' value: {}", store)),
+    }
+}
+
+pub fn parse_router(settings: &EnvSettings) -> Result<Router, String> {
+    let mut router = Router::new(Route {
+        backends: parse_backends(settings.parse_list("jmap-url").unwrap_or_else(|| {
             warn!("No jmap-url specified, using default: {}", DEFAULT_JMAP_URL);
-            DEFAULT_JMAP_URL.to_string()
+            vec![DEFAULT_JMAP_URL.to_string()]
+        })),
+        balance: parse_balance(settings, "jmap-balance")?.unwrap_or(Balance::RoundRobin),
+        next_backend: 0.into(),
+        trusted_hosts: if let Some(trusted_hosts) = settings.get("jmap-trusted-hosts") {
+            trusted_hosts
+                .split(';')
+                .map(|host| host.to_string())
+                .collect()
+        } else {
+            vec!["127.0.0.1".to_string()]
         },
         folder_shared: if let Some(folder_shared) = settings.get("name-shared") {
             folder_shared
@@ -67,65 +274,184 @@ pub fn build_core(settings: &EnvSettings) -> Core {
         } else {
             "All Mail".to_string()
         },
-        max_request_size: settings
-            .parse("max-request-size")
-            .unwrap_or(50 * 1024 * 1024),
-        trusted_hosts: if let Some(folder_shared) = settings.get("jmap-trusted-hosts") {
-            folder_shared
-                .split(';')
-                .into_iter()
-                .map(|host| host.to_string())
-                .collect()
-        } else {
-            vec!["127.0.0.1".to_string()]
-        },
+        namespace: None,
+    });
+
+    // Routes are defined as route.<name>.<setting> and inherit unset values from the default route
+    let mut names = AHashSet::new();
+    for name in settings.parse_list("jmap-routes").unwrap_or_default() {
+        if !names.insert(name.trim().to_string()) {
+            return Err(format!(
+                "Route '{}' is defined more than once.",
+                name.trim()
+            ));
+        }
+        let key = |setting: &str| format!("route.{}.{}", name.trim(), setting);
+        let domains = settings
+            .parse_list(&key("domains"))
+            .ok_or_else(|| format!("Missing '{}' parameter.", key("domains")))?
+            .iter()
+            .map(|domain| DomainMatch::parse(domain.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let route = Route {
+            backends: parse_backends(
+                settings
+                    .parse_list(&key("jmap-url"))
+                    .ok_or_else(|| format!("Missing '{}' parameter.", key("jmap-url")))?,
+            ),
+            balance: parse_balance(settings, &key("jmap-balance"))?
+                .unwrap_or(router.default.balance),
+            next_backend: 0.into(),
+            trusted_hosts: if let Some(trusted_hosts) = settings.get(&key("jmap-trusted-hosts")) {
+                trusted_hosts
+                    .split(';')
+                    .map(|host| host.to_string())
+                    .collect()
+            } else {
+                router.default.trusted_hosts.clone()
+            },
+            folder_shared: settings
+                .get(&key("name-shared"))
+                .unwrap_or_else(|| router.default.folder_shared.clone()),
+            folder_all: settings
+                .get(&key("name-all"))
+                .unwrap_or_else(|| router.default.folder_all.clone()),
+            namespace: name.trim().to_string().into(),
+        };
+        router.add(domains, route);
     }
+
+    Ok(router)
+}
+
+fn parse_backends(urls: Vec<String>) -> Vec<Arc<Backend>> {
+    urls.into_iter()
+        .map(|url| Arc::new(Backend::new(url.trim().to_string())))
+        .collect()
 }
 
-pub fn load_tls_config(settings: &EnvSettings) -> rustls::ServerConfig {
+fn parse_balance(settings: &EnvSettings, key: &str) -> Result<Option<Balance>, String> {
+    settings.try_parse(key).map_err(|_| {
+        format!(
+            "Invalid '{}' parameter, expected 'round-robin' or 'least-connections'.",
+            key
+        )
+    })
+}
+
+pub fn parse_proxy_networks(settings: &EnvSettings) -> Result<Vec<IpNetwork>, String> {
+    settings
+        .parse_list("proxy-trusted-networks")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|network| network.parse())
+        .collect()
+}
+
+pub fn load_tls_config(settings: &EnvSettings) -> Result<rustls::ServerConfig, String> {
     let (cert_path, key_path) = if let (Some(cert_path), Some(key_path)) =
         (settings.get("cert-path"), settings.get("key-path"))
     {
         (cert_path, key_path)
     } else {
-        failed_to("load TLS config: Missing 'cert-path' and/or 'key-path' parameters.");
+        return Err("Missing 'cert-path' and/or 'key-path' parameters.".to_string());
     };
+    let mut resolver = SniCertResolver::new(load_certified_key(&cert_path, &key_path)?);
+
+    // Load SNI certificates, named <hostname>.crt and <hostname>.key
+    if let Some(cert_dir) = settings.get("cert-dir") {
+        for entry in std::fs::read_dir(&cert_dir)
+            .map_err(|err| format!("Failed to read directory {}: {}", cert_dir, err))?
+        {
+            let cert_path = entry
+                .map_err(|err| format!("Failed to read directory {}: {}", cert_dir, err))?
+                .path();
+            let hostname = match (
+                cert_path.extension().and_then(|ext| ext.to_str()),
+                cert_path.file_stem().and_then(|stem| stem.to_str()),
+            ) {
+                (Some("crt"), Some(hostname)) => hostname.to_string(),
+                _ => continue,
+            };
+            let key_path = cert_path.with_extension("key");
+            if !key_path.exists() {
+                return Err(format!("Missing private key file {}", key_path.display()));
+            }
+
+            debug!("Loading TLS certificate for {}.", hostname);
+            resolver.add(
+                &hostname,
+                load_certified_key(&cert_path.to_string_lossy(), &key_path.to_string_lossy())?,
+            );
+        }
+    }
+
+    Ok(rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver)))
+}
+
+// The node serving the UID cache is verified against the certificates in
+// 'cache-remote-ca' rather than any public CA.
+pub fn load_client_tls_config(settings: &EnvSettings) -> Result<ClientConfig, String> {
+    let ca_path = settings
+        .get("cache-remote-ca")
+        .ok_or("Missing 'cache-remote-ca' parameter.")?;
+    let mut root_store = RootCertStore::empty();
+    for certificate in certs(&mut BufReader::new(
+        File::open(&ca_path)
+            .map_err(|err| format!("Failed to open certificate {}: {}", ca_path, err))?,
+    ))
+    .map_err(|err| format!("Invalid certificate file {}: {}", ca_path, err))?
+    {
+        root_store
+            .add(&Certificate(certificate))
+            .map_err(|err| format!("Invalid certificate in file {}: {}", ca_path, err))?;
+    }
+
+    if root_store.is_empty() {
+        return Err(format!("No certificates found in file {}", ca_path));
+    }
+
+    Ok(ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth())
+}
 
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> Result<CertifiedKey, String> {
     let certificates: Vec<Certificate> = certs(&mut BufReader::new(
-        File::open(&cert_path).failed_to("open certificate path"),
+        File::open(cert_path)
+            .map_err(|err| format!("Failed to open certificate {}: {}", cert_path, err))?,
     ))
-    .failed_to("load TLS config: Invalid certificate file")
+    .map_err(|err| format!("Invalid certificate file {}: {}", cert_path, err))?
     .into_iter()
     .map(Certificate)
     .collect();
 
     let mut private_keys: Vec<PrivateKey> = pkcs8_private_keys(&mut BufReader::new(
-        File::open(&key_path).failed_to("open private key path"),
+        File::open(key_path)
+            .map_err(|err| format!("Failed to open private key {}: {}", key_path, err))?,
     ))
-    .failed_to("load TLS config: Invalid private key file")
+    .map_err(|err| format!("Invalid private key file {}: {}", key_path, err))?
     .into_iter()
     .map(PrivateKey)
     .collect();
 
     if certificates.is_empty() {
-        failed_to(&format!(
-            "load TLS config: No certificates found in file {}",
-            &cert_path
-        ));
+        return Err(format!("No certificates found in file {}", cert_path));
     }
 
     if private_keys.is_empty() {
-        failed_to(&format!(
-            "load TLS config: No private keys found in file {}",
-            &key_path
-        ));
+        return Err(format!("No private keys found in file {}", key_path));
     }
 
-    rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certificates, private_keys.remove(0))
-        .failed_to("load TLS configuration")
+    Ok(CertifiedKey::new(
+        certificates,
+        any_supported_type(&private_keys.remove(0))
+            .map_err(|_| format!("Unsupported private key in file {}", key_path))?,
+    ))
 }
 
 pub trait UnwrapFailure<T> {
@@ -41,6 +41,7 @@ pub async fn handle_conn(
 ) {
     let mut buf = vec![0; 4096];
     let (mut stream_rx, stream_tx) = tokio::io::split(stream);
+    let mut is_draining = false;
 
     if !session.set_stream(stream_tx).await {
         return;
@@ -64,7 +65,7 @@ pub async fn handle_conn(
                                         Ok(Some(stream_tx)) => {
                                             debug!("TLS upgrade requested.");
                                             handle_conn_tls(
-                                                match session.core.tls_acceptor.accept(stream_rx.unsplit(stream_tx)).await {
+                                                match session.core.tls_acceptor().accept(stream_rx.unsplit(stream_tx)).await {
                                                     Ok(stream) => stream,
                                                     Err(e) => {
                                                         debug!("Failed to accept TLS connection: {}", e);
@@ -89,6 +90,7 @@ pub async fn handle_conn(
                                         debug!("Stopping IDLE.");
                                         idle_tx.send(false).ok();
                                         session.idle_tx = None;
+                                        session.update_state();
                                     }
                                 },
                             }
@@ -108,12 +110,42 @@ pub async fn handle_conn(
                     }
                 }
             },
-            _ = shutdown_rx.changed() => {
-                session.write_bytes(b"* BYE Server shutting down.\r\n".to_vec()).await.ok();
-                debug!("IMAP connection with peer {} shutting down.", session.peer_addr);
+            _ = shutdown_rx.changed(), if !is_draining => {
+                if session.receiver.is_receiving() && session.idle_tx.is_none() {
+                    // Let the client finish sending the current request
+                    debug!("IMAP connection with peer {} draining.", session.peer_addr);
+                    is_draining = true;
+                } else {
+                    // Commands still running write their responses first
+                    session.core.sessions.wait_for_tasks(session.session_id).await;
+                    session.write_bytes(b"* BYE Server shutting down.\r\n".to_vec()).await.ok();
+                    debug!("IMAP connection with peer {} shutting down.", session.peer_addr);
+                    return;
+                }
+            },
+            _ = session.terminate_rx.changed() => {
+                session.write_bytes(b"* BYE Session terminated by administrator.\r\n".to_vec()).await.ok();
+                debug!("IMAP connection with peer {} terminated by administrator.", session.peer_addr);
                 return;
             }
         };
+
+        if is_draining && !session.receiver.is_receiving() {
+            session
+                .core
+                .sessions
+                .wait_for_tasks(session.session_id)
+                .await;
+            session
+                .write_bytes(b"* BYE Server shutting down.\r\n".to_vec())
+                .await
+                .ok();
+            debug!(
+                "IMAP connection with peer {} shutting down.",
+                session.peer_addr
+            );
+            return;
+        }
     }
 }
 
@@ -124,6 +156,7 @@ pub async fn handle_conn_tls(
 ) {
     let mut buf = vec![0; 4096];
     let (mut stream_rx, stream_tx) = tokio::io::split(stream);
+    let mut is_draining = false;
 
     if !session.set_stream_tls(stream_tx).await {
         return;
@@ -153,6 +186,7 @@ pub async fn handle_conn_tls(
                                         debug!("Stopping IDLE.");
                                         idle_tx.send(false).ok();
                                         session.idle_tx = None;
+                                        session.update_state();
                                     }
                                 },
                             }
@@ -172,11 +206,41 @@ pub async fn handle_conn_tls(
                     }
                 }
             },
-            _ = shutdown_rx.changed() => {
-                session.write_bytes(b"* BYE Server shutting down.\r\n".to_vec()).await.ok();
-                debug!("IMAP connection with peer {} shutting down.", session.peer_addr);
+            _ = shutdown_rx.changed(), if !is_draining => {
+                if session.receiver.is_receiving() && session.idle_tx.is_none() {
+                    // Let the client finish sending the current request
+                    debug!("IMAP connection with peer {} draining.", session.peer_addr);
+                    is_draining = true;
+                } else {
+                    // Commands still running write their responses first
+                    session.core.sessions.wait_for_tasks(session.session_id).await;
+                    session.write_bytes(b"* BYE Server shutting down.\r\n".to_vec()).await.ok();
+                    debug!("IMAP connection with peer {} shutting down.", session.peer_addr);
+                    return;
+                }
+            },
+            _ = session.terminate_rx.changed() => {
+                session.write_bytes(b"* BYE Session terminated by administrator.\r\n".to_vec()).await.ok();
+                debug!("IMAP connection with peer {} terminated by administrator.", session.peer_addr);
                 return;
             }
         };
+
+        if is_draining && !session.receiver.is_receiving() {
+            session
+                .core
+                .sessions
+                .wait_for_tasks(session.session_id)
+                .await;
+            session
+                .write_bytes(b"* BYE Server shutting down.\r\n".to_vec())
+                .await
+                .ok();
+            debug!(
+                "IMAP connection with peer {} shutting down.",
+                session.peer_addr
+            );
+            return;
+        }
     }
 }
@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::time::Duration;
+
+use jmap_client::client::Credentials;
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, RANGE},
+    redirect::Policy,
+    StatusCode,
+};
+use tracing::debug;
+
+use super::{metrics::measure_jmap, session_cache::UserSession};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Blob being downloaded from the JMAP server. Chunks are handed out as they
+// arrive, so memory use does not depend on the size of the blob.
+pub struct BlobStream {
+    response: reqwest::Response,
+    skip: usize,
+    remaining: usize,
+    size: usize,
+}
+
+impl UserSession {
+    // Starts downloading a blob, optionally limited to a (start, length) range.
+    // Returns None when the size of the blob is not known in advance, in which
+    // case it has to be downloaded in full.
+    pub async fn download_stream(
+        &self,
+        account_id: &str,
+        blob_id: &str,
+        partial: Option<(u32, u32)>,
+    ) -> Option<BlobStream> {
+        let url = self
+            .client
+            .session()
+            .download_url()
+            .replace("{accountId}", account_id)
+            .replace("{blobId}", blob_id)
+            .replace("{name}", "message.eml")
+            .replace("{type}", "message%2Frfc822");
+        let mut request = self.http.get(&url);
+        if let Some((start, length)) = partial.filter(|(_, length)| *length > 0) {
+            request = request.header(
+                RANGE,
+                format!("bytes={}-{}", start, start as u64 + length as u64 - 1),
+            );
+        }
+
+        let response = match measure_jmap(request.send()).await {
+            Ok(response) => response,
+            Err(err) => {
+                debug!("Failed to download blob {:?}: {}", blob_id, err);
+                return None;
+            }
+        };
+        let content_length = response.content_length()? as usize;
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT if partial.is_some() => Some(BlobStream {
+                response,
+                skip: 0,
+                remaining: content_length,
+                size: content_length,
+            }),
+            StatusCode::OK => {
+                // The server does not support ranges, skip to the requested offset
+                let (skip, remaining) = if let Some((start, length)) = partial {
+                    let skip = std::cmp::min(start as usize, content_length);
+                    (skip, std::cmp::min(length as usize, content_length - skip))
+                } else {
+                    (0, content_length)
+                };
+                Some(BlobStream {
+                    response,
+                    skip,
+                    remaining,
+                    size: remaining,
+                })
+            }
+            StatusCode::RANGE_NOT_SATISFIABLE if partial.is_some() => Some(BlobStream {
+                response,
+                skip: 0,
+                remaining: 0,
+                size: 0,
+            }),
+            status => {
+                debug!("Failed to download blob {:?}: HTTP {}", blob_id, status);
+                None
+            }
+        }
+    }
+}
+
+impl BlobStream {
+    // Number of bytes that will be returned by the stream.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub async fn next_chunk(&mut self) -> reqwest::Result<Option<Vec<u8>>> {
+        while self.remaining > 0 {
+            let chunk = if let Some(chunk) = self.response.chunk().await? {
+                chunk
+            } else {
+                break;
+            };
+            if chunk.len() <= self.skip {
+                self.skip -= chunk.len();
+                continue;
+            }
+            let end = std::cmp::min(chunk.len(), self.skip + self.remaining);
+            let chunk = chunk[self.skip..end].to_vec();
+            self.skip = 0;
+            self.remaining -= chunk.len();
+            return Ok(Some(chunk));
+        }
+        Ok(None)
+    }
+}
+
+// Client used for blob downloads, authenticated with the user's credentials.
+pub fn http_client(credentials: &Credentials) -> reqwest::Client {
+    let mut headers = HeaderMap::new();
+    if let Ok(mut value) = HeaderValue::from_str(&match credentials {
+        Credentials::Basic(basic) => format!("Basic {}", basic),
+        Credentials::Bearer(token) => format!("Bearer {}", token),
+    }) {
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+
+    reqwest::Client::builder()
+        .default_headers(headers)
+        .redirect(Policy::none())
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .unwrap_or_default()
+}
@@ -43,66 +43,65 @@ impl Default for EnvSettings {
 
 impl EnvSettings {
     pub fn new() -> Self {
+        Self::try_new().unwrap_or_else(|err| soft_panic(&err))
+    }
+
+    pub fn try_new() -> Result<Self, String> {
+        Self::try_from_args(env::args().skip(1))
+    }
+
+    pub fn try_from_args(cmd_args: impl IntoIterator<Item = String>) -> Result<Self, String> {
         let mut args = AHashMap::default();
         let mut current_key: Option<String> = None;
 
-        for arg in env::args().into_iter().skip(1) {
+        for arg in cmd_args {
             if let Some((key, value)) = arg.split_once('=') {
                 if let Some(key) = key.strip_prefix("--") {
                     args.insert(key.to_lowercase(), value.to_string());
                 } else {
-                    soft_panic(&format!("Invalid command line argument: {}", key));
+                    return Err(format!("Invalid command line argument: {}", key));
                 }
             } else if let Some(key) = std::mem::take(&mut current_key) {
                 args.insert(key, arg);
             } else if let Some(key) = arg.strip_prefix("--") {
                 current_key = Some(key.to_lowercase());
             } else {
-                soft_panic(&format!("Invalid command line argument: {}", arg));
+                return Err(format!("Invalid command line argument: {}", arg));
             }
         }
 
         // Read config file if it was provided
         if let Some(config_path) = args.remove("config") {
-            std::fs::read(&config_path)
-                .unwrap_or_else(|err| {
-                    soft_panic(&format!(
-                        "Failed to read config file {}: {}",
-                        config_path, err
-                    ));
-                })
-                .lines()
-                .for_each(|line| {
-                    let line = line.unwrap_or_else(|err| {
-                        soft_panic(&format!(
-                            "Failed to read config file {}: {}",
-                            config_path, err
-                        ));
-                    });
-                    let line = line.trim();
-                    if !line.is_empty() && !line.starts_with('#') {
-                        if let Some((key, value)) = line.split_once(':') {
-                            let key = key.trim();
-                            if !args.contains_key(key) {
-                                let value = value
-                                    .rsplit_once(" #")
-                                    .or_else(|| value.split_once("\t#"))
-                                    .map(|v| v.0)
-                                    .unwrap_or(value)
-                                    .trim();
-
-                                if !value.is_empty() {
-                                    args.insert(key.to_string(), value.to_string());
-                                }
+            let config = std::fs::read(&config_path)
+                .map_err(|err| format!("Failed to read config file {}: {}", config_path, err))?;
+            for line in config.lines() {
+                let line = line.map_err(|err| {
+                    format!("Failed to read config file {}: {}", config_path, err)
+                })?;
+                let line = line.trim();
+                if !line.is_empty() && !line.starts_with('#') {
+                    if let Some((key, value)) = line.split_once(':') {
+                        let key = key.trim();
+                        if !args.contains_key(key) {
+                            let value = value
+                                .rsplit_once(" #")
+                                .or_else(|| value.split_once("\t#"))
+                                .map(|v| v.0)
+                                .unwrap_or(value)
+                                .trim();
+
+                            if !value.is_empty() {
+                                args.insert(key.to_string(), value.to_string());
                             }
-                        } else {
-                            soft_panic(&format!("Invalid config file line: {}", line));
                         }
+                    } else {
+                        return Err(format!("Invalid config file line: {}", line));
                     }
-                });
+                }
+            }
         }
 
-        EnvSettings { args }
+        Ok(EnvSettings { args })
     }
 
     pub fn get(&self, name: &str) -> Option<String> {
@@ -120,17 +119,24 @@ impl EnvSettings {
     }
 
     pub fn parse<T>(&self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+    {
+        self.try_parse(name).unwrap_or_else(|err| soft_panic(&err))
+    }
+
+    pub fn try_parse<T>(&self, name: &str) -> Result<Option<T>, String>
     where
         T: FromStr,
     {
         if let Some(value) = self.get(name) {
             if let Ok(value) = value.parse::<T>() {
-                Some(value)
+                Ok(Some(value))
             } else {
-                soft_panic(&format!("Failed to parse argument: {}", name));
+                Err(format!("Failed to parse argument: {}", name))
             }
         } else {
-            None
+            Ok(None)
         }
     }
 
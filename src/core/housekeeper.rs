@@ -21,112 +21,482 @@
  * for more details.
 */
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    hash::{BuildHasher, Hasher},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use chrono::{Datelike, TimeZone};
+use ahash::RandomState;
+use chrono::{Datelike, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use parking_lot::Mutex;
 use tokio::sync::watch;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 
 use super::{
-    config::{failed_to, UnwrapFailure},
     env_settings::EnvSettings,
+    metrics::{serialize_metrics, METRICS},
     Core,
 };
 
-enum SimpleCron {
-    EveryDay { hour: u32, minute: u32 },
-    EveryWeek { day: u32, hour: u32, minute: u32 },
+pub const DEFAULT_REMOVED_ID_TTL: u64 = 2592000;
+const RUN_LOG_SIZE: usize = 10;
+
+const MONTH_NAMES: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Default)]
+pub struct Housekeeper {
+    pub tasks: Vec<Arc<Task>>,
+    pub jitter: Duration,
 }
 
-pub fn spawn_housekeeper(core: Arc<Core>, settings: &EnvSettings, mut rx: watch::Receiver<bool>) {
-    let purge_cache_at = SimpleCron::parse(
-        &settings
-            .get("cache-purge-every")
-            .unwrap_or_else(|| "0 3 *".to_string()),
-    );
-    let cache_ttl: u64 = settings.parse("cache-removed-id-ttl").unwrap_or(2592000);
+pub struct Task {
+    pub task: TaskType,
+    pub expression: String,
+    pub schedule: Cron,
+    pub runs: Mutex<VecDeque<TaskRun>>,
+}
 
-    tokio::spawn(async move {
-        debug!("Housekeeper task started.");
-        loop {
-            match tokio::time::timeout(purge_cache_at.time_to_next(), rx.changed()).await {
-                Ok(_) => {
-                    debug!("Housekeeper task exiting.");
-                    return;
-                }
-                Err(_) => {
-                    // Time to purge!
-                    info!("Running housekeeper task...");
-                    core.purge_deleted_ids(cache_ttl).await.ok();
-                }
+pub enum TaskType {
+    PurgeDeletedIds { ttl: u64 },
+    PurgeInactiveAccounts { ttl: u64 },
+    CompactCache,
+    MetricsSnapshot { path: PathBuf },
+}
+
+pub struct TaskRun {
+    pub started_at: u64,
+    pub duration: Duration,
+    pub result: Result<String, String>,
+}
+
+// Standard five-field cron expression: minute, hour, day of month, month and
+// day of week, each field stored as a bitmap of the allowed values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // When both day fields are restricted, either of them has to match.
+    match_any_day: bool,
+}
+
+impl Housekeeper {
+    pub fn parse(settings: &EnvSettings) -> Result<Self, String> {
+        let mut tasks = vec![(
+            TaskType::PurgeDeletedIds {
+                ttl: settings
+                    .try_parse("cache-removed-id-ttl")?
+                    .unwrap_or(DEFAULT_REMOVED_ID_TTL),
+            },
+            // Older configurations use 'minute hour day-of-week'
+            settings
+                .get("cache-purge-every")
+                .map(|value| {
+                    let fields = value.split_whitespace().collect::<Vec<_>>();
+                    if fields.len() == 3 {
+                        format!("{} {} * * {}", fields[0], fields[1], fields[2])
+                    } else {
+                        value
+                    }
+                })
+                .unwrap_or_else(|| "0 3 * * *".to_string()),
+        )];
+        // sled can only be compacted offline and remote stores are compacted
+        // by the node serving them.
+        match settings.get("cache-store").as_deref().unwrap_or("sled") {
+            "sqlite" => tasks.push((TaskType::CompactCache, "30 4 * * sun".to_string())),
+            "sled"
+                if settings
+                    .get("housekeeper-compact-cache")
+                    .map_or(false, |expression| expression != "off") =>
+            {
+                warn!(
+                    "The sled cache cannot be compacted while the server is running, \
+                     stop it and run 'stalwart-imap cache compact' instead."
+                );
             }
+            _ => (),
+        }
+        if let Some(ttl) = settings.try_parse("cache-inactive-account-ttl")? {
+            tasks.push((
+                TaskType::PurgeInactiveAccounts { ttl },
+                "0 4 * * *".to_string(),
+            ));
         }
-    });
+        if let Some(path) = settings.get("metrics-snapshot-path") {
+            tasks.push((
+                TaskType::MetricsSnapshot { path: path.into() },
+                "*/5 * * * *".to_string(),
+            ));
+        }
+
+        let mut housekeeper = Housekeeper {
+            tasks: Vec::with_capacity(tasks.len()),
+            jitter: Duration::from_secs(settings.try_parse("housekeeper-jitter")?.unwrap_or(0)),
+        };
+        for (task, default_expression) in tasks {
+            let key = format!("housekeeper-{}", task.name());
+            let expression = settings.get(&key).unwrap_or(default_expression);
+            if expression == "off" {
+                continue;
+            }
+            housekeeper.tasks.push(Arc::new(Task {
+                task,
+                schedule: Cron::parse(&expression)
+                    .map_err(|err| format!("Invalid '{}' value: {}", key, err))?,
+                expression,
+                runs: Mutex::new(VecDeque::with_capacity(RUN_LOG_SIZE)),
+            }));
+        }
+
+        Ok(housekeeper)
+    }
 }
 
-impl SimpleCron {
-    pub fn parse(value: &str) -> Self {
-        let mut hour = 0;
-        let mut minute = 0;
+pub fn spawn_housekeeper(core: Arc<Core>, rx: watch::Receiver<bool>) {
+    for task in &core.housekeeper.tasks {
+        let task = task.clone();
+        let core = core.clone();
+        let mut rx = rx.clone();
 
-        for (pos, value) in value.split(' ').enumerate() {
-            if pos == 0 {
-                minute = value.parse::<u32>().failed_to("parse minute.");
-                if !(0..=59).contains(&minute) {
-                    failed_to(&format!("parse minute, invalid value: {}", minute));
-                }
-            } else if pos == 1 {
-                hour = value.parse::<u32>().failed_to("parse hour.");
-                if !(0..=23).contains(&hour) {
-                    failed_to(&format!("parse hour, invalid value: {}", hour));
-                }
-            } else if pos == 2 {
-                if value.as_bytes().first().failed_to("parse weekday") == &b'*' {
-                    return SimpleCron::EveryDay { hour, minute };
-                } else {
-                    let day = value.parse::<u32>().failed_to("parse weekday.");
-                    if !(1..=7).contains(&hour) {
-                        failed_to(&format!(
-                            "parse weekday, invalid value: {}, range is 1 (Monday) to 7 (Sunday).",
-                            hour,
-                        ));
+        tokio::spawn(async move {
+            debug!("Housekeeper task {} started.", task.name());
+            loop {
+                let time_to_next = match task.schedule.time_to_next() {
+                    Some(time_to_next) => time_to_next + random_delay(core.housekeeper.jitter),
+                    None => {
+                        warn!(
+                            "Housekeeper task {} has no upcoming runs, exiting.",
+                            task.name()
+                        );
+                        return;
+                    }
+                };
+                match tokio::time::timeout(time_to_next, rx.changed()).await {
+                    Ok(_) => {
+                        debug!("Housekeeper task {} exiting.", task.name());
+                        return;
                     }
+                    Err(_) => {
+                        task.run(&core).await.ok();
+                    }
+                }
+            }
+        });
+    }
+}
+
+impl Task {
+    pub fn name(&self) -> &'static str {
+        self.task.name()
+    }
 
-                    return SimpleCron::EveryWeek { day, hour, minute };
+    pub async fn run(&self, core: &Core) -> Result<String, String> {
+        info!("Running housekeeper task {}...", self.name());
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let started = Instant::now();
+        let result = match &self.task {
+            TaskType::PurgeDeletedIds { ttl } => core
+                .purge_deleted_ids(*ttl)
+                .await
+                .map(|purged| format!("Purged {} deleted ids.", purged))
+                .map_err(|_| "Failed to purge deleted ids.".to_string()),
+            TaskType::PurgeInactiveAccounts { ttl } => core
+                .purge_inactive_accounts(*ttl)
+                .await
+                .map(|account_ids| {
+                    if !account_ids.is_empty() {
+                        format!(
+                            "Purged {} inactive accounts: {}.",
+                            account_ids.len(),
+                            account_ids.join(", ")
+                        )
+                    } else {
+                        "No inactive accounts found.".to_string()
+                    }
+                })
+                .map_err(|_| "Failed to purge inactive accounts.".to_string()),
+            TaskType::CompactCache => {
+                let store = core.uid_store.clone();
+                core.spawn_worker(move || {
+                    store.compact()?;
+                    Ok(store.size_on_disk())
+                })
+                .await
+                .map(|size| format!("Compacted cache to {} bytes.", size))
+                .map_err(|_| "Failed to compact cache.".to_string())
+            }
+            TaskType::MetricsSnapshot { path } => {
+                // Write to a temporary file first so readers never see a partial snapshot
+                let mut temp_path = path.as_os_str().to_owned();
+                temp_path.push(".tmp");
+                let temp_path = PathBuf::from(temp_path);
+                let metrics = serialize_metrics(core).await;
+                match tokio::fs::write(&temp_path, &metrics).await {
+                    Ok(_) => tokio::fs::rename(&temp_path, path).await,
+                    Err(err) => Err(err),
                 }
+                .map(|_| format!("Wrote {} bytes to {}.", metrics.len(), path.display()))
+                .map_err(|err| format!("Failed to write {}: {}", path.display(), err))
             }
+        };
+        let duration = started.elapsed();
+        METRICS.housekeeper_run(duration, result.is_ok());
+
+        match &result {
+            Ok(message) => info!(
+                "Housekeeper task {} finished in {} ms: {}",
+                self.name(),
+                duration.as_millis(),
+                message
+            ),
+            Err(err) => error!("Housekeeper task {} failed: {}", self.name(), err),
+        }
+        let mut runs = self.runs.lock();
+        if runs.len() == RUN_LOG_SIZE {
+            runs.pop_front();
         }
+        runs.push_back(TaskRun {
+            started_at,
+            duration,
+            result: result.clone(),
+        });
 
-        failed_to("parse cron expression.");
+        result
     }
+}
 
-    pub fn time_to_next(&self) -> Duration {
-        let now = chrono::Local::now();
-        let next = match self {
-            SimpleCron::EveryDay { hour, minute } => {
-                let next = chrono::Local
-                    .ymd(now.year(), now.month(), now.day())
-                    .and_hms(*hour, *minute, 0);
-                if next < now {
-                    next + chrono::Duration::days(1)
+impl TaskType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskType::PurgeDeletedIds { .. } => "purge-deleted-ids",
+            TaskType::PurgeInactiveAccounts { .. } => "purge-inactive-accounts",
+            TaskType::CompactCache => "compact-cache",
+            TaskType::MetricsSnapshot { .. } => "metrics-snapshot",
+        }
+    }
+}
+
+fn random_delay(max_delay: Duration) -> Duration {
+    if !max_delay.is_zero() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+        );
+        Duration::from_millis(hasher.finish() % (max_delay.as_millis() as u64 + 1))
+    } else {
+        Duration::ZERO
+    }
+}
+
+impl Cron {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let fields = value.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!(
+                "expected 5 fields (minute hour day month weekday), found {}.",
+                fields.len()
+            ));
+        }
+
+        // Sunday is both 0 and 7
+        let mut weekdays = parse_field(fields[4], 0, 7, WEEKDAY_NAMES)?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, MONTH_NAMES)?,
+            weekdays,
+            match_any_day: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+
+    // Returns the first matching minute after the given time, looking up to
+    // eight years ahead to find leap days.
+    pub fn next_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start_date = time.date();
+        let mut date = start_date;
+
+        for _ in 0..366 * 8 {
+            if self.matches_date(date) {
+                let (start_hour, start_minute) = if date == start_date {
+                    (time.hour(), time.minute() + 1)
                 } else {
-                    next
+                    (0, 0)
+                };
+                for hour in start_hour..24 {
+                    if self.hours & (1 << hour) == 0 {
+                        continue;
+                    }
+                    let start_minute = if hour == start_hour { start_minute } else { 0 };
+                    if let Some(minute) = next_bit(self.minutes, start_minute) {
+                        return date.and_hms_opt(hour, minute, 0);
+                    }
                 }
             }
-            SimpleCron::EveryWeek { day, hour, minute } => {
-                let next = chrono::Local
-                    .ymd(now.year(), now.month(), now.day())
-                    .and_hms(*hour, *minute, 0);
-                if next < now {
-                    next + chrono::Duration::days(
-                        (7 - now.weekday().number_from_monday() + *day).into(),
-                    )
-                } else {
-                    next
-                }
+            date = date.succ_opt()?;
+        }
+
+        None
+    }
+
+    pub fn time_to_next(&self) -> Option<Duration> {
+        let now = chrono::Local::now();
+        let mut time = now.naive_local();
+
+        // Skip times that do not exist due to daylight saving changes
+        loop {
+            time = self.next_after(time)?;
+            if let Some(next) = chrono::Local.from_local_datetime(&time).earliest() {
+                return (next - now).to_std().ok();
             }
+        }
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & (1 << date.month()) == 0 {
+            return false;
+        }
+        let matches_day = self.days & (1 << date.day()) != 0;
+        let matches_weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        if self.match_any_day {
+            matches_day || matches_weekday
+        } else {
+            matches_day && matches_weekday
+        }
+    }
+}
+
+fn next_bit(bits: u64, from: u32) -> Option<u32> {
+    if from < 64 && bits >> from != 0 {
+        Some(from + (bits >> from).trailing_zeros())
+    } else {
+        None
+    }
+}
+
+// Parses a comma separated list of values, ranges and steps (e.g. "1-5,*/15")
+// into a bitmap.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let parse_value = |value: &str| -> Result<u32, String> {
+        let value = if let Some(pos) = names
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(value))
+        {
+            pos as u32 + min
+        } else {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("invalid value '{}'.", value))?
         };
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(format!(
+                "value {} out of range, expected {} to {}.",
+                value, min, max
+            ))
+        }
+    };
 
-        (next - now).to_std().unwrap()
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step '{}'.", step))?,
+            ),
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            (parse_value(from)?, parse_value(to)?)
+        } else {
+            let value = parse_value(range)?;
+            (value, if part.contains('/') { max } else { value })
+        };
+        if from > to {
+            return Err(format!("invalid range '{}'.", range));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::Cron;
+
+    #[test]
+    fn cron_schedule() {
+        let time = |year, month, day, hour, minute| {
+            NaiveDate::from_ymd_opt(year, month, day)
+                .unwrap()
+                .and_hms_opt(hour, minute, 0)
+                .unwrap()
+        };
+        // Thursday
+        let now = time(2022, 12, 1, 10, 30);
+
+        for (expression, next) in [
+            ("* * * * *", time(2022, 12, 1, 10, 31)),
+            ("0 3 * * *", time(2022, 12, 2, 3, 0)),
+            ("45 10 * * *", time(2022, 12, 1, 10, 45)),
+            ("*/20 * * * *", time(2022, 12, 1, 10, 40)),
+            ("5-10/5 12-14 * * *", time(2022, 12, 1, 12, 5)),
+            ("0 0 * * 7", time(2022, 12, 4, 0, 0)),
+            ("0 0 * * mon-wed", time(2022, 12, 5, 0, 0)),
+            ("0 0 1 jan *", time(2023, 1, 1, 0, 0)),
+            ("0 0 15 * fri", time(2022, 12, 2, 0, 0)),
+            ("0 0 29 2 *", time(2024, 2, 29, 0, 0)),
+            ("30 10 1 12 *", time(2023, 12, 1, 10, 30)),
+        ] {
+            assert_eq!(
+                Cron::parse(expression).unwrap().next_after(now),
+                Some(next),
+                "{}",
+                expression
+            );
+        }
+        assert_eq!(Cron::parse("0 0 30 2 *").unwrap().next_after(now), None);
+
+        for expression in [
+            "0 3 *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "10-5 * * * *",
+            "a * * * *",
+        ] {
+            assert!(Cron::parse(expression).is_err(), "{}", expression);
+        }
     }
 }
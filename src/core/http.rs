@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use ahash::AHashMap;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+};
+use tracing::{debug, error};
+
+use super::{config::failed_to, Core};
+
+const MAX_HEADER_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub headers: AHashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+pub async fn spawn_http_listener<F, Fut>(
+    bind_addr: SocketAddr,
+    core: Arc<Core>,
+    mut shutdown_rx: watch::Receiver<bool>,
+    handler: F,
+) where
+    F: Fn(Arc<Core>, HttpRequest) -> Fut + Send + Sync + Copy + 'static,
+    Fut: Future<Output = HttpResponse> + Send,
+{
+    // Start listening for HTTP connections.
+    let (listener, mut stop_rx) = core.listeners.bind(bind_addr).unwrap_or_else(|e| {
+        failed_to(&format!("bind to {}: {}", bind_addr, e));
+    });
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                stream = listener.accept() => {
+                    match stream {
+                        Ok((mut stream, peer_addr)) => {
+                            let core = core.clone();
+
+                            tokio::spawn(async move {
+                                let response = match tokio::time::timeout(
+                                    HTTP_TIMEOUT,
+                                    read_request(&mut stream)
+                                ).await {
+                                    Ok(Ok(request)) => handler(core, request).await,
+                                    Ok(Err(err)) => {
+                                        debug!("Invalid HTTP request from {}: {}", peer_addr, err);
+                                        HttpResponse::bad_request(err.to_string())
+                                    }
+                                    Err(_) => {
+                                        debug!("HTTP request from {} timed out.", peer_addr);
+                                        return;
+                                    }
+                                };
+
+                                if let Err(err) = stream.write_all(&response.into_bytes()).await {
+                                    debug!("Failed to write HTTP response to {}: {}", peer_addr, err);
+                                }
+                                stream.shutdown().await.ok();
+                            });
+                        }
+                        Err(err) => {
+                            error!("Failed to accept TCP connection: {}", err);
+                        }
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    debug!("HTTP listener shutting down.");
+                    break;
+                },
+                _ = stop_rx.changed() => {
+                    debug!("HTTP listener stopped accepting connections.");
+                    break;
+                }
+            };
+        }
+    });
+}
+
+pub async fn read_request(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<HttpRequest> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    let header_end = loop {
+        let bytes_read = stream.read(&mut chunk).await?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..bytes_read]);
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos;
+        } else if buf.len() > MAX_HEADER_SIZE {
+            return Err(invalid_request("Request headers too large"));
+        }
+    };
+
+    let mut lines = std::str::from_utf8(&buf[..header_end])
+        .map_err(|_| invalid_request("Invalid request encoding"))?
+        .split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, target) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(target)) if !method.is_empty() && target.starts_with('/') => {
+            (method.to_uppercase(), target)
+        }
+        _ => return Err(invalid_request("Invalid request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = AHashMap::new();
+    for line in lines {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| invalid_request("Invalid header"))?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let content_length = headers
+        .get("content-length")
+        .map(|value| value.parse::<usize>())
+        .transpose()
+        .map_err(|_| invalid_request("Invalid Content-Length"))?
+        .unwrap_or(0);
+    if content_length > MAX_BODY_SIZE {
+        return Err(invalid_request("Request body too large"));
+    }
+
+    let mut body = buf[header_end + 4..].to_vec();
+    while body.len() < content_length {
+        let bytes_read = stream.read(&mut chunk).await?;
+        if bytes_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        body.extend_from_slice(&chunk[..bytes_read]);
+    }
+    body.truncate(content_length);
+
+    Ok(HttpRequest {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+        body,
+    })
+}
+
+pub fn parse_query(query: &str) -> AHashMap<String, String> {
+    query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            (decode_component(name), decode_component(value))
+        })
+        .collect()
+}
+
+fn decode_component(value: &str) -> String {
+    let mut bytes = value.bytes();
+    let mut result = Vec::with_capacity(value.len());
+    while let Some(ch) = bytes.next() {
+        match ch {
+            b'+' => result.push(b' '),
+            b'%' => {
+                let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(ch) => result.push(ch),
+                    None => {
+                        result.push(b'%');
+                        result.extend(hex.iter().filter(|ch| **ch != 0));
+                    }
+                }
+            }
+            _ => result.push(ch),
+        }
+    }
+    String::from_utf8(result)
+        .unwrap_or_else(|err| String::from_utf8_lossy(err.as_bytes()).into_owned())
+}
+
+fn invalid_request(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl HttpResponse {
+    pub fn new(status: u16, content_type: &'static str, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse {
+            status,
+            content_type,
+            body: body.into(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        HttpResponse::new(status, "text/plain; charset=utf-8", body)
+    }
+
+    pub fn bad_request(message: impl Into<Vec<u8>>) -> Self {
+        HttpResponse::text(400, message)
+    }
+
+    pub fn not_found() -> Self {
+        HttpResponse::text(404, "Not found")
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.body.len() + 128);
+        buf.extend_from_slice(
+            format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                self.status,
+                match self.status {
+                    200 => "OK",
+                    400 => "Bad Request",
+                    401 => "Unauthorized",
+                    404 => "Not Found",
+                    405 => "Method Not Allowed",
+                    _ => "Internal Server Error",
+                },
+                self.content_type,
+                self.body.len()
+            )
+            .as_bytes(),
+        );
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_query, read_request};
+
+    #[tokio::test]
+    async fn parse_http_request() {
+        let request = read_request(
+            &mut &b"post /sessions?user=jdoe HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello world"[..],
+        )
+        .await
+        .unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/sessions");
+        assert_eq!(request.query, "user=jdoe");
+        assert_eq!(request.headers.get("host").unwrap(), "localhost");
+        assert_eq!(request.body, b"hello");
+
+        let params = parse_query("user=jdoe%40example.com&ip=10.0.0.1&name=John+Doe&flag");
+        assert_eq!(params.get("user").unwrap(), "jdoe@example.com");
+        assert_eq!(params.get("ip").unwrap(), "10.0.0.1");
+        assert_eq!(params.get("name").unwrap(), "John Doe");
+        assert_eq!(params.get("flag").unwrap(), "");
+
+        for invalid_request in [
+            &b"GET\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nHost\r\n\r\n"[..],
+            &b"GET / HTTP/1.1\r\nContent-Length: 10\r\n\r\nhello"[..],
+            &b"GET / HTTP/1.1\r\n"[..],
+        ] {
+            assert!(
+                read_request(&mut &invalid_request[..]).await.is_err(),
+                "{:?}",
+                String::from_utf8_lossy(invalid_request)
+            );
+        }
+    }
+}
@@ -23,7 +23,7 @@
 
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::{io::AsyncWriteExt, net::TcpListener, sync::watch};
+use tokio::{io::AsyncWriteExt, sync::watch};
 use tracing::{debug, error};
 
 use crate::{
@@ -49,7 +49,7 @@ pub async fn spawn_listener(
     mut shutdown_rx: watch::Receiver<bool>,
 ) {
     // Start listening for IMAP connections.
-    let listener = TcpListener::bind(bind_addr).await.unwrap_or_else(|e| {
+    let (listener, mut stop_rx) = core.listeners.bind(bind_addr).unwrap_or_else(|e| {
         failed_to(&format!("bind to {}: {}", bind_addr, e));
     });
 
@@ -80,10 +80,13 @@ pub async fn spawn_listener(
                             let greeting_tls = greeting_tls.clone();
 
                             tokio::spawn(async move {
-                                let peer_addr = stream.peer_addr().unwrap();
+                                let peer_addr = match core.peer_addr(&mut stream).await {
+                                    Some(peer_addr) => peer_addr,
+                                    None => return,
+                                };
 
                                 if is_tls {
-                                    let mut stream = match core.tls_acceptor.accept(stream).await {
+                                    let mut stream = match core.tls_acceptor().accept(stream).await {
                                         Ok(stream) => stream,
                                         Err(e) => {
                                             debug!("Failed to accept TLS connection: {}", e);
@@ -125,6 +128,10 @@ pub async fn spawn_listener(
                 _ = shutdown_rx.changed() => {
                     debug!("IMAP listener shutting down.");
                     break;
+                },
+                _ = stop_rx.changed() => {
+                    debug!("IMAP listener stopped accepting connections.");
+                    break;
                 }
             };
         }
@@ -21,21 +21,14 @@
  * for more details.
 */
 
-use super::{
-    client::SessionData,
-    message::{
-        increment_uid, serialize_highestmodseq, serialize_modseq, MailboxId, MODSEQ_TO_STATE,
-        STATE_TO_MODSEQ,
-    },
-    Core,
-};
+use super::{client::SessionData, message::MailboxId, metrics::measure_jmap, Core};
 use ahash::AHashMap;
 use jmap_client::{
     client::Client,
     mailbox::{Property, Role},
 };
 use std::collections::BTreeMap;
-use tracing::{debug, error};
+use tracing::debug;
 
 #[derive(Debug, Default)]
 pub struct Mailbox {
@@ -60,13 +53,41 @@ pub struct Account {
     pub modseq: Option<u32>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct MailboxSync {
     pub added: Vec<String>,
     pub changed: Vec<String>,
     pub deleted: Vec<String>,
 }
 
+impl MailboxSync {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.deleted.is_empty()
+    }
+
+    // Adds changes that happened after the ones already in this list.
+    pub fn merge(&mut self, changes: &MailboxSync) {
+        for mailbox_name in &changes.deleted {
+            self.added.retain(|name| name != mailbox_name);
+            self.changed.retain(|name| name != mailbox_name);
+            if !self.deleted.contains(mailbox_name) {
+                self.deleted.push(mailbox_name.to_string());
+            }
+        }
+        for mailbox_name in &changes.added {
+            self.deleted.retain(|name| name != mailbox_name);
+            if !self.added.contains(mailbox_name) {
+                self.added.push(mailbox_name.to_string());
+            }
+        }
+        for mailbox_name in &changes.changed {
+            if !self.added.contains(mailbox_name) && !self.changed.contains(mailbox_name) {
+                self.changed.push(mailbox_name.to_string());
+            }
+        }
+    }
+}
+
 impl Core {
     pub async fn fetch_mailboxes(
         &self,
@@ -155,7 +176,9 @@ impl Core {
                 Property::UnreadEmails,
             ]);
 
-            let mut response = request.send().await?.unwrap_method_responses();
+            let mut response = measure_jmap(request.send())
+                .await?
+                .unwrap_method_responses();
             if response.len() != 2 {
                 return Err(jmap_client::Error::Internal(
                     "Invalid response while fetching mailboxes".to_string(),
@@ -265,22 +288,24 @@ impl SessionData {
         return_changes: bool,
         force_session_refresh: bool,
     ) -> jmap_client::Result<Option<MailboxSync>> {
-        let mut changes = if return_changes {
-            MailboxSync::default().into()
-        } else {
-            None
-        };
+        // Changes are also tracked when this user has other connections open
+        let mut changes =
+            if return_changes || self.user.mailbox_queues.has_listeners(&self.mailbox_queue) {
+                MailboxSync::default().into()
+            } else {
+                None
+            };
 
         // Shared mailboxes might have changed
         let mut added_accounts = Vec::new();
-        if force_session_refresh || !self.client.is_session_updated() {
-            self.client.refresh_session().await?;
-            let session = self.client.session();
+        if force_session_refresh || !self.user.client.is_session_updated() {
+            measure_jmap(self.user.client.refresh_session()).await?;
+            let session = self.user.client.session();
 
             // Remove unlinked shared accounts
             let mut added_account_ids = Vec::new();
             {
-                let mut mailboxes = self.mailboxes.lock();
+                let mut mailboxes = self.user.mailboxes.lock();
                 let mut new_accounts = Vec::with_capacity(mailboxes.len());
                 for (pos, account) in mailboxes.drain(..).enumerate() {
                     if pos == 0 || session.account(&account.account_id).is_some() {
@@ -299,7 +324,7 @@ impl SessionData {
 
                 // Add new shared account ids
                 for account_id in session.accounts() {
-                    if account_id != self.client.default_account_id()
+                    if account_id != self.user.client.default_account_id()
                         && !new_accounts
                             .iter()
                             .skip(1)
@@ -316,12 +341,12 @@ impl SessionData {
             for account_id in added_account_ids {
                 let prefix = format!(
                     "{}/{}",
-                    self.core.folder_shared,
+                    self.user.route.folder_shared,
                     session.account(&account_id).unwrap().name()
                 );
                 match self
                     .core
-                    .fetch_account_mailboxes(&self.client, account_id, prefix.into())
+                    .fetch_account_mailboxes(&self.user.client, account_id, prefix.into())
                     .await
                 {
                     Ok(account) => {
@@ -335,15 +360,18 @@ impl SessionData {
         }
 
         // Fetch mailbox changes for all accounts
-        let mut request = self.client.build();
-        for account in self.mailboxes.lock().iter() {
+        let mut request = self.user.client.build();
+        for account in self.user.mailboxes.lock().iter() {
             request
                 .changes_mailbox(&account.mailbox_state)
                 .account_id(&account.account_id);
         }
 
         let mut changed_account_ids = Vec::new();
-        for response in request.send().await?.unwrap_method_responses() {
+        for response in measure_jmap(request.send())
+            .await?
+            .unwrap_method_responses()
+        {
             let mut response = match response.unwrap_changes_mailbox() {
                 Ok(response) => response,
                 Err(err) => {
@@ -367,7 +395,7 @@ impl SessionData {
                     true
                 };
 
-                for account in self.mailboxes.lock().iter_mut() {
+                for account in self.user.mailboxes.lock().iter_mut() {
                     if account.account_id == response.account_id() {
                         account.mailbox_state = response.take_new_state();
                         if reset_stats {
@@ -389,11 +417,12 @@ impl SessionData {
         // Fetch mailbox data for all changed accounts
         let mut changed_accounts = Vec::with_capacity(changed_account_ids.len());
         for account_id in changed_account_ids {
-            let mailbox_prefix = if account_id != self.client.default_account_id() {
+            let mailbox_prefix = if account_id != self.user.client.default_account_id() {
                 format!(
                     "{}/{}",
-                    self.core.folder_shared,
-                    self.client
+                    self.user.route.folder_shared,
+                    self.user
+                        .client
                         .session()
                         .account(&account_id)
                         .map(|a| a.name())
@@ -405,7 +434,7 @@ impl SessionData {
             };
             match self
                 .core
-                .fetch_account_mailboxes(&self.client, account_id, mailbox_prefix)
+                .fetch_account_mailboxes(&self.user.client, account_id, mailbox_prefix)
                 .await
             {
                 Ok(account_mailboxes) => {
@@ -419,7 +448,7 @@ impl SessionData {
 
         // Update mailboxes
         if !changed_accounts.is_empty() || !added_accounts.is_empty() {
-            let mut mailboxes = self.mailboxes.lock();
+            let mut mailboxes = self.user.mailboxes.lock();
 
             for changed_account in changed_accounts {
                 if let Some(pos) = mailboxes
@@ -482,12 +511,31 @@ impl SessionData {
             }
         }
 
-        Ok(changes)
+        // Queue the changes for the user's other connections
+        if let Some(changes) = changes.as_ref().filter(|changes| !changes.is_empty()) {
+            self.user
+                .mailbox_queues
+                .publish(&self.mailbox_queue, changes);
+            let _ = self.user.mailbox_changes.send(self.session_id);
+        }
+
+        if return_changes {
+            // Changes found by other connections happened first
+            let mut pending = self.take_mailbox_changes();
+            pending.merge(&changes.unwrap_or_default());
+            Ok(Some(pending))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn take_mailbox_changes(&self) -> MailboxSync {
+        std::mem::take(&mut *self.mailbox_queue.lock())
     }
 
     pub fn get_mailbox_by_name(&self, mailbox_name: &str) -> Option<MailboxId> {
         if !self.is_all_mailbox(mailbox_name) {
-            for account in self.mailboxes.lock().iter() {
+            for account in self.user.mailboxes.lock().iter() {
                 if account
                     .prefix
                     .as_ref()
@@ -507,66 +555,41 @@ impl SessionData {
             None
         } else {
             MailboxId {
-                account_id: self.client.default_account_id().to_string(),
+                account_id: self.user.client.default_account_id().to_string(),
                 mailbox_id: None,
             }
             .into()
         }
     }
 
+    pub fn get_mailbox_name(&self, id: &MailboxId) -> Option<String> {
+        if let Some(mailbox_id) = &id.mailbox_id {
+            for account in self.user.mailboxes.lock().iter() {
+                if account.account_id == id.account_id {
+                    for (mailbox_name_, mailbox_id_) in account.mailbox_names.iter() {
+                        if mailbox_id_ == mailbox_id {
+                            return mailbox_name_.to_string().into();
+                        }
+                    }
+                }
+            }
+            None
+        } else {
+            self.user.route.folder_all.clone().into()
+        }
+    }
+
     pub fn is_all_mailbox(&self, mailbox_name: &str) -> bool {
-        self.core.folder_all == mailbox_name
+        self.user.route.folder_all == mailbox_name
     }
 }
 
 impl Core {
     pub async fn state_to_modseq(&self, account_id: &str, state: String) -> Result<u32, ()> {
-        let highestmodseq_key = serialize_highestmodseq(account_id.as_bytes());
-        let modseq_key = serialize_modseq(account_id.as_bytes(), state.as_bytes(), STATE_TO_MODSEQ);
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            let modseq = if let Some(modseq) = db.get(&modseq_key).map_err(|err| {
-                error!("Failed to get key: {}", err);
-            })? {
-                modseq
-            } else {
-                // Obtain highestmodseq.
-                let highestmodseq = db
-                    .update_and_fetch(&highestmodseq_key, increment_uid)
-                    .map_err(|err| {
-                        error!("Failed to increment HIGHESTMODSEQ: {}", err);
-                    })?
-                    .ok_or_else(|| {
-                        error!("Failed to generate HIGHESTMODSEQ.");
-                    })?;
-
-                // Insert state-to-modseq key
-                db.insert(modseq_key, &highestmodseq).map_err(|err| {
-                    error!("Failed to insert key: {}", err);
-                })?;
-                // Insert modseq-to-state key
-                db.insert(
-                    serialize_modseq(
-                        &highestmodseq_key[..highestmodseq_key.len() - 2],
-                        &highestmodseq[..],
-                        MODSEQ_TO_STATE,
-                    ),
-                    state.as_bytes(),
-                )
-                .map_err(|err| {
-                    error!("Failed to insert key: {}", err);
-                })?;
-
-                highestmodseq
-            };
-
-            Ok(u32::from_be_bytes((&modseq[..]).try_into().map_err(
-                |err| {
-                    error!("Failed to decode UID validity: {}", err);
-                },
-            )?))
-        })
-        .await
+        let store = self.uid_store.clone();
+        let account_id = account_id.to_string();
+        self.spawn_worker(move || store.state_to_modseq(&account_id, &state))
+            .await
     }
 
     pub async fn modseq_to_state(
@@ -574,27 +597,9 @@ impl Core {
         account_id: &str,
         modseq: u32,
     ) -> Result<Option<String>, ()> {
-        let modseq_key = serialize_modseq(
-            account_id.as_bytes(),
-            &modseq.to_be_bytes(),
-            MODSEQ_TO_STATE,
-        );
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            Ok(
-                if let Some(state) = db.get(&modseq_key).map_err(|err| {
-                    error!("Failed to get key: {}", err);
-                })? {
-                    String::from_utf8(state.to_vec())
-                        .map_err(|err| {
-                            error!("Failed to convert state to string: {}", err);
-                        })?
-                        .into()
-                } else {
-                    None
-                },
-            )
-        })
-        .await
+        let store = self.uid_store.clone();
+        let account_id = account_id.to_string();
+        self.spawn_worker(move || store.modseq_to_state(&account_id, modseq))
+            .await
     }
 }
@@ -21,7 +21,7 @@
  * for more details.
 */
 
-use std::{sync::Arc, time::SystemTime};
+use std::sync::Arc;
 
 use ahash::{AHashMap, AHashSet};
 use jmap_client::email::query::Filter;
@@ -32,11 +32,13 @@ use crate::protocol::Sequence;
 
 use super::{
     client::{SelectedMailbox, SessionData},
-    mailbox::Account,
+    metrics::measure_jmap,
+    store::now,
+    uid_map::UidMap,
     Core, IntoStatusResponse, StatusResponse,
 };
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 pub struct MailboxId {
     pub account_id: String,
     pub mailbox_id: Option<String>,
@@ -46,38 +48,170 @@ pub struct MailboxId {
 pub struct MailboxData {
     pub uid_next: u32,
     pub uid_validity: u32,
-    pub jmap_ids: Vec<String>,
-    pub imap_uids: Vec<u32>,
+    pub ids: Arc<UidMap>,
     pub total_messages: usize,
     pub last_state: String,
 }
 
+// Messages expunged or flagged by one of the user's sessions
+#[derive(Debug)]
+pub struct MessageChanges {
+    pub session_id: u64,
+    pub mailbox: Arc<MailboxId>,
+    pub expunged: Vec<u32>,
+    pub flags_changed: Vec<u32>,
+    // JMAP Email state right after the change
+    pub state: String,
+}
+
 #[derive(Debug, Clone, Copy, Default)]
 pub struct ImapId {
     pub uid: u32,
     pub seqnum: u32,
 }
 
-pub const JMAP_TO_UID: u8 = 0;
-pub const UID_TO_JMAP: u8 = 1;
-pub const UID_NEXT: u8 = 2;
-pub const UID_VALIDITY: u8 = 3;
-pub const MODSEQ_TO_STATE: u8 = 4;
-pub const STATE_TO_MODSEQ: u8 = 5;
-pub const HIGHEST_MODSEQ: u8 = 6;
-pub const JMAP_DELETED_IDS: u8 = 7;
+pub enum MailboxIds {
+    // All the ids currently in the mailbox
+    All(Vec<String>),
+    // Ids removed from and added to the mailbox since the last query state
+    Changes {
+        removed: Vec<String>,
+        added: Vec<String>,
+    },
+}
 
 impl SessionData {
     pub async fn synchronize_messages(
         &self,
         mailbox: Arc<MailboxId>,
     ) -> Result<MailboxData, StatusResponse> {
+        self.synchronize_changes(mailbox, None)
+            .await
+            .map(|(state, _)| state)
+    }
+
+    // Brings the mailbox ids and the JMAP Email state up to date in a single
+    // request. When the Email state of the previous synchronization is given,
+    // the ids of the messages created or updated since then are also returned.
+    pub async fn synchronize_changes(
+        &self,
+        mailbox: Arc<MailboxId>,
+        since_state: Option<&str>,
+    ) -> Result<(MailboxData, Vec<String>), StatusResponse> {
+        let query_state = self
+            .core
+            .query_state(self.cache_id(&mailbox))
+            .await
+            .map_err(|_| StatusResponse::database_failure())?;
+
+        let mut request = self.user.client.build().account_id(&mailbox.account_id);
+        if let Some(since_state) = since_state {
+            request
+                .changes_email(since_state)
+                .account_id(&mailbox.account_id);
+        } else {
+            request
+                .get_email()
+                .account_id(&mailbox.account_id)
+                .ids(Vec::<&str>::new());
+        }
+        if let Some(query_state) = query_state {
+            let changes_request = request.query_email_changes(query_state);
+            if let Some(mailbox_id) = &mailbox.mailbox_id {
+                changes_request.filter(Filter::in_mailbox(mailbox_id));
+            }
+        }
+        let mut responses = measure_jmap(request.send())
+            .await
+            .map_err(|err| err.into_status_response())?
+            .unwrap_method_responses()
+            .into_iter();
+
+        // Obtain the new Email state and the messages changed since the last one
+        let mut last_state = None;
+        let mut changed_ids = Vec::new();
+        if let Some(response) = responses.next() {
+            if since_state.is_some() {
+                match response.unwrap_changes_email() {
+                    Ok(mut response) => {
+                        last_state = response.take_new_state().into();
+                        changed_ids = response.take_created();
+                        changed_ids.extend(response.take_updated());
+                    }
+                    Err(err) => {
+                        debug!("Failed to obtain email changes for {:?}: {}", mailbox, err);
+                    }
+                }
+            } else {
+                last_state = response
+                    .unwrap_get_email()
+                    .map_err(|err| err.into_status_response())?
+                    .take_state()
+                    .into();
+            }
+        }
+
+        // Apply the changes to the ids since the last synchronization
+        let mut state = None;
+        if let Some(response) = responses.next() {
+            match response.unwrap_query_changes_email() {
+                Ok(response) => {
+                    state = self
+                        .core
+                        .apply_uid_changes(
+                            self.cache_id(&mailbox),
+                            MailboxIds::Changes {
+                                removed: response.removed().to_vec(),
+                                added: response
+                                    .added()
+                                    .iter()
+                                    .map(|item| item.id().to_string())
+                                    .collect(),
+                            },
+                            response.new_query_state().to_string().into(),
+                        )
+                        .await
+                        .map_err(|_| StatusResponse::database_failure())?
+                        .into();
+                }
+                Err(err) => {
+                    debug!(
+                        "Cannot calculate changes for {:?}, fetching all ids: {}",
+                        mailbox, err
+                    );
+                }
+            }
+        }
+        let mut state = match state {
+            Some(state) => state,
+            None => self.query_all_ids(&mailbox).await?,
+        };
+
+        // Without the changes every message has to be considered as changed
+        state.last_state = match last_state {
+            Some(last_state) => last_state,
+            None => {
+                changed_ids = state
+                    .ids
+                    .iter()
+                    .map(|(_, jmap_id)| jmap_id.to_string())
+                    .collect();
+                self.get_jmap_state(&mailbox.account_id).await?
+            }
+        };
+
+        Ok((state, changed_ids))
+    }
+
+    async fn query_all_ids(&self, mailbox: &Arc<MailboxId>) -> Result<MailboxData, StatusResponse> {
         let mut valid_ids = Vec::new();
         let mut position = 0;
+        let mut query_state: Option<String> = None;
+        let mut has_state_changed = false;
 
         // Fetch all ids in the mailbox.
         for _ in 0..100 {
-            let mut request = self.client.build().account_id(&mailbox.account_id);
+            let mut request = self.user.client.build().account_id(&mailbox.account_id);
             let query_request = request
                 .query_email()
                 .calculate_total(true)
@@ -86,13 +220,22 @@ impl SessionData {
                 query_request.filter(Filter::in_mailbox(mailbox_id));
             }
 
-            let mut response = request
-                .send_query_email()
+            let mut response = measure_jmap(request.send_query_email())
                 .await
                 .map_err(|err| err.into_status_response())?;
             let total_messages = response.total().unwrap_or(0);
             let emails = response.take_ids();
 
+            // The mailbox changed while paging, so the state cannot be used for syncing
+            match &query_state {
+                Some(query_state) => {
+                    has_state_changed |= query_state != response.query_state();
+                }
+                None => {
+                    query_state = response.query_state().to_string().into();
+                }
+            }
+
             let emails_len = emails.len();
             if emails_len > 0 {
                 valid_ids.extend(emails);
@@ -106,28 +249,65 @@ impl SessionData {
 
         // Update mailbox
         self.core
-            .update_uids(mailbox, valid_ids)
+            .apply_uid_changes(
+                self.cache_id(mailbox),
+                MailboxIds::All(valid_ids),
+                query_state.filter(|_| !has_state_changed),
+            )
             .await
             .map_err(|_| StatusResponse::database_failure())
     }
 
     pub async fn get_jmap_state(&self, account_id: &str) -> Result<String, StatusResponse> {
-        let mut request = self.client.build();
+        let mut request = self.user.client.build();
         request
             .get_email()
             .account_id(account_id)
             .ids(Vec::<&str>::new());
-        request
-            .send_get_email()
+        measure_jmap(request.send_get_email())
             .await
             .map_err(|err| err.into_status_response())
             .map(|mut r| r.take_state())
     }
 
+    pub fn publish_message_changes(
+        &self,
+        mailbox: &SelectedMailbox,
+        expunged: Vec<u32>,
+        flags_changed: Vec<u32>,
+    ) {
+        if (!expunged.is_empty() || !flags_changed.is_empty())
+            && self.user.message_changes.receiver_count() > 0
+        {
+            let _ = self.user.message_changes.send(Arc::new(MessageChanges {
+                session_id: self.session_id,
+                mailbox: mailbox.id.clone(),
+                expunged,
+                flags_changed,
+                state: mailbox.state.lock().last_state.clone(),
+            }));
+        }
+    }
+
+    // Cache keys are namespaced by route, see Route::cache_key.
+    pub fn cache_id(&self, mailbox: &Arc<MailboxId>) -> Arc<MailboxId> {
+        if self.user.route.namespace.is_some() {
+            Arc::new(MailboxId {
+                account_id: self.user.route.cache_key(&mailbox.account_id),
+                mailbox_id: mailbox.mailbox_id.clone(),
+            })
+        } else {
+            mailbox.clone()
+        }
+    }
+
     pub async fn synchronize_state(&self, account_id: &str) -> Result<u32, StatusResponse> {
         // Update modseq
         self.core
-            .state_to_modseq(account_id, self.get_jmap_state(account_id).await?)
+            .state_to_modseq(
+                &self.user.route.cache_key(account_id),
+                self.get_jmap_state(account_id).await?,
+            )
             .await
             .map_err(|_| StatusResponse::database_failure())
     }
@@ -145,106 +325,89 @@ impl Core {
     pub async fn update_uids(
         &self,
         mailbox: Arc<MailboxId>,
-        mut update_jmap_ids: Vec<String>,
+        jmap_ids: Vec<String>,
+    ) -> Result<MailboxData, ()> {
+        self.apply_uid_changes(mailbox, MailboxIds::All(jmap_ids), None)
+            .await
+    }
+
+    pub async fn apply_uid_changes(
+        &self,
+        mailbox: Arc<MailboxId>,
+        changes: MailboxIds,
+        query_state: Option<String>,
     ) -> Result<MailboxData, ()> {
-        let db = self.db.clone();
+        let store = self.uid_store.clone();
+        let mailbox_ = mailbox.clone();
         self.spawn_worker(move || {
             // Obtain/generate UIDVALIDITY
-            let uid_validity = db.uid_validity(&mailbox)?;
+            let uid_validity = store.uid_validity(&mailbox)?;
+
+            // Ids to keep, remove and add. Ids that changed position in the query
+            // are reported both as removed and added.
+            let (is_full_sync, removed_ids, mut added_ids) = match &changes {
+                MailboxIds::All(ids) => (true, AHashSet::new(), ids_to_map(ids)),
+                MailboxIds::Changes { removed, added } => (
+                    false,
+                    removed
+                        .iter()
+                        .map(|id| id.as_str())
+                        .collect::<AHashSet<_>>(),
+                    ids_to_map(added),
+                ),
+            };
+            let mut imap_uids = Vec::with_capacity(added_ids.len());
+            let mut jmap_ids = Vec::with_capacity(added_ids.len());
+            let mut deleted_ids = Vec::new();
 
             // Remove from cache messages no longer present in the mailbox.
-            let mut jmap_ids_map = update_jmap_ids
-                .iter()
-                .enumerate()
-                .map(|(pos, id)| (id.as_bytes(), pos))
-                .collect::<AHashMap<_, _>>();
-            let mut imap_uids = Vec::with_capacity(update_jmap_ids.len());
-            let mut jmap_ids = Vec::with_capacity(update_jmap_ids.len());
-            let mut found_ids = vec![0u8; update_jmap_ids.len()];
-
-            let prefix = serialize_key_prefix(&mailbox, UID_TO_JMAP);
-            let mut batch = sled::Batch::default();
-            let mut has_deletions = false;
-
-            for kv_result in db.scan_prefix(&prefix) {
-                let (key, value) = kv_result.map_err(|err| {
-                    error!("Failed to scan db: {}", err);
-                })?;
-                if key.len() > prefix.len() {
-                    let imap_uid = &key[prefix.len()..];
-                    let jmap_id = &value[..];
-
-                    if let Some(pos) = jmap_ids_map.remove(jmap_id) {
-                        imap_uids.push(u32::from_be_bytes(imap_uid.try_into().map_err(|_| {
-                            error!("Failed to convert bytes to u32.");
-                        })?));
-                        jmap_ids.push(String::from_utf8(value.to_vec()).map_err(|_| {
-                            error!("Failed to convert bytes to string.");
-                        })?);
-                        found_ids[pos] = 1;
-                    } else {
-                        // Add UID to deleted messages
-                        let mut buf = Vec::with_capacity(
-                            std::mem::size_of::<u32>() + std::mem::size_of::<u64>(),
-                        );
-                        buf.extend_from_slice(imap_uid);
-                        buf.extend_from_slice(
-                            &SystemTime::now()
-                                .duration_since(SystemTime::UNIX_EPOCH)
-                                .map(|d| d.as_secs())
-                                .unwrap_or(0)
-                                .to_be_bytes(),
-                        );
-                        batch.insert(serialize_key(&mailbox, JMAP_DELETED_IDS, jmap_id), buf);
-
-                        // Delete mappings from cache
-                        batch.remove(key);
-                        batch.remove(sled::IVec::from(serialize_key(
-                            &mailbox,
-                            JMAP_TO_UID,
-                            jmap_id,
-                        )));
-
-                        has_deletions = true;
-                    }
+            for (imap_uid, jmap_id) in store.mailbox_ids(&mailbox)? {
+                if added_ids.remove(jmap_id.as_str()).is_some()
+                    || (!is_full_sync && !removed_ids.contains(jmap_id.as_str()))
+                {
+                    imap_uids.push(imap_uid);
+                    jmap_ids.push(jmap_id);
+                } else {
+                    deleted_ids.push(jmap_id);
                 }
             }
+            if !deleted_ids.is_empty() {
+                store.delete_ids(&mailbox, &deleted_ids)?;
+            }
 
-            if has_deletions {
-                db.apply_batch(batch).map_err(|err| {
-                    error!("Failed to delete batch: {}", err);
-                })?;
+            // Store the query state the ids belong to
+            if query_state.is_some() || is_full_sync {
+                store.set_query_state(&mailbox, query_state.as_deref())?;
             }
 
-            // Add to the db any new ids.
-            if !jmap_ids_map.is_empty() {
-                let uid_next_key = serialize_uid_next_key(&mailbox);
+            // Add to the db any new ids, in the order they were received.
+            if !added_ids.is_empty() {
+                let mut added_ids = added_ids.into_iter().collect::<Vec<_>>();
+                added_ids.sort_unstable_by_key(|(_, pos)| *pos);
 
-                for (pos, found) in found_ids.into_iter().enumerate() {
-                    if found == 0 {
-                        let jmap_id = std::mem::take(update_jmap_ids.get_mut(pos).unwrap());
-                        let imap_uid =
-                            db.insert_jmap_id(&mailbox, jmap_id.as_bytes(), &uid_next_key)?;
-                        jmap_ids.push(jmap_id);
-                        imap_uids.push(u32::from_be_bytes((&imap_uid[..]).try_into().map_err(
-                            |_| {
-                                error!("Failed to convert bytes to u32.");
-                            },
-                        )?));
-                    }
+                for (jmap_id, _) in added_ids {
+                    imap_uids.push(store.insert_jmap_id(&mailbox, jmap_id)?);
+                    jmap_ids.push(jmap_id.to_string());
                 }
             }
 
-            Ok(MailboxData {
+            Ok((uid_validity, store.uid_next(&mailbox)?, imap_uids, jmap_ids))
+        })
+        .await
+        .map(
+            |(uid_validity, uid_next, imap_uids, jmap_ids)| MailboxData {
                 uid_validity,
-                uid_next: db.uid_next(&mailbox)?,
+                uid_next,
                 total_messages: imap_uids.len(),
-                jmap_ids,
-                imap_uids,
+                ids: self.uid_maps.update(&mailbox_, &imap_uids, &jmap_ids),
                 last_state: String::new(),
-            })
-        })
-        .await
+            },
+        )
+    }
+
+    pub async fn query_state(&self, mailbox: Arc<MailboxId>) -> Result<Option<String>, ()> {
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.query_state(&mailbox)).await
     }
 
     pub async fn jmap_to_imap(
@@ -253,60 +416,28 @@ impl Core {
         update_jmap_ids: Vec<String>,
         options: MappingOptions,
     ) -> Result<(Vec<String>, Vec<u32>), ()> {
-        let db = self.db.clone();
+        let store = self.uid_store.clone();
         self.spawn_worker(move || {
             let mut jmap_ids = Vec::with_capacity(update_jmap_ids.len());
             let mut imap_uids = Vec::with_capacity(update_jmap_ids.len());
-            let mut uid_next_key = None;
 
             for jmap_id in update_jmap_ids {
-                let jmap_id_bytes = jmap_id.as_bytes();
-
                 if options != MappingOptions::OnlyIncludeDeleted {
-                    if let Some(uid) = db
-                        .get(serialize_key(&mailbox, JMAP_TO_UID, jmap_id_bytes))
-                        .map_err(|err| {
-                            error!("Failed to get key: {}", err);
-                        })?
-                    {
+                    if let Some(uid) = store.jmap_to_uid(&mailbox, &jmap_id)? {
                         jmap_ids.push(jmap_id);
-                        imap_uids.push(u32::from_be_bytes((&uid[..]).try_into().map_err(
-                            |_| {
-                                error!("Failed to convert bytes to u32.");
-                            },
-                        )?));
+                        imap_uids.push(uid);
                         continue;
                     } else if options == MappingOptions::AddIfMissing {
-                        let uid = db.insert_jmap_id(
-                            &mailbox,
-                            jmap_id_bytes,
-                            uid_next_key.get_or_insert_with(|| serialize_uid_next_key(&mailbox)),
-                        )?;
+                        imap_uids.push(store.insert_jmap_id(&mailbox, &jmap_id)?);
                         jmap_ids.push(jmap_id);
-                        imap_uids.push(u32::from_be_bytes((&uid[..]).try_into().map_err(
-                            |_| {
-                                error!("Failed to convert bytes to u32.");
-                            },
-                        )?));
                         continue;
                     } else if options != MappingOptions::IncludeDeleted {
                         continue;
                     }
                 }
 
-                if let Some(uid) = db
-                    .get(serialize_key(&mailbox, JMAP_DELETED_IDS, jmap_id_bytes))
-                    .map_err(|err| {
-                        error!("Failed to get key: {}", err);
-                    })?
-                {
-                    imap_uids.push(u32::from_be_bytes(
-                        (&uid[..std::mem::size_of::<u32>()])
-                            .try_into()
-                            .map_err(|_| {
-                                error!("Failed to convert bytes to u32.");
-                            })?,
-                    ));
+                if let Some(uid) = store.deleted_uid(&mailbox, &jmap_id)? {
+                    imap_uids.push(uid);
                 }
             }
 
@@ -321,21 +452,14 @@ impl Core {
         mailbox: Arc<MailboxId>,
         imap_ids: Vec<u32>,
     ) -> Result<(Vec<String>, Vec<u32>), ()> {
-        let db = self.db.clone();
+        let store = self.uid_store.clone();
         self.spawn_worker(move || {
             let mut jmap_ids = Vec::with_capacity(imap_ids.len());
             let mut imap_uids = Vec::with_capacity(imap_ids.len());
             for uid in imap_ids {
-                if let Some(jmap_id) = db
-                    .get(serialize_key(&mailbox, UID_TO_JMAP, &uid.to_be_bytes()[..]))
-                    .map_err(|err| {
-                        error!("Failed to get key: {}", err);
-                    })?
-                {
+                if let Some(jmap_id) = store.uid_to_jmap(&mailbox, uid)? {
                     imap_uids.push(uid);
-                    jmap_ids.push(String::from_utf8(jmap_id.to_vec()).map_err(|_| {
-                        error!("Failed to convert bytes to string.");
-                    })?);
+                    jmap_ids.push(jmap_id);
                 }
             }
             Ok((jmap_ids, imap_uids))
@@ -348,194 +472,102 @@ impl Core {
         mailbox: Arc<MailboxId>,
         jmap_ids: Vec<String>,
     ) -> Result<(), ()> {
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            let mut batch = sled::Batch::default();
-            let mut has_deletions = false;
-
-            for jmap_id in jmap_ids {
-                let jmap_id = jmap_id.as_bytes();
-                let key = serialize_key(&mailbox, JMAP_TO_UID, jmap_id);
-
-                if let Some(imap_uid) = db.get(&key).map_err(|err| {
-                    error!("Failed to get key: {}", err);
-                })? {
-                    // Add UID to deleted messages
-                    let mut buf =
-                        Vec::with_capacity(std::mem::size_of::<u32>() + std::mem::size_of::<u64>());
-                    buf.extend_from_slice(&imap_uid[..]);
-                    buf.extend_from_slice(
-                        &SystemTime::now()
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .map(|d| d.as_secs())
-                            .unwrap_or(0)
-                            .to_be_bytes(),
-                    );
-                    batch.insert(serialize_key(&mailbox, JMAP_DELETED_IDS, jmap_id), buf);
-
-                    // Delete mappings from cache
-                    batch.remove(key);
-                    batch.remove(sled::IVec::from(serialize_key(
-                        &mailbox,
-                        UID_TO_JMAP,
-                        &imap_uid[..],
-                    )));
-
-                    has_deletions = true;
-                }
-            }
-
-            if has_deletions {
-                db.apply_batch(batch).map_err(|err| {
-                    error!("Failed to delete batch: {}", err);
-                })?;
-            }
-
-            Ok(())
-        })
-        .await
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.delete_ids(&mailbox, &jmap_ids))
+            .await
     }
 
     pub async fn delete_account(&self, account_id: String) -> Result<(), ()> {
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            let mut batch = sled::Batch::default();
-
-            for kv_result in db.scan_prefix(serialize_key_account_prefix(&account_id)) {
-                let (key, _) = kv_result.map_err(|err| {
-                    error!("Failed to scan db: {}", err);
-                })?;
-                batch.remove(key);
-            }
-
-            db.apply_batch(batch).map_err(|err| {
-                error!("Failed to delete batch: {}", err);
-            })?;
-
-            Ok(())
-        })
-        .await
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.delete_account(&account_id))
+            .await
     }
 
     pub async fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
-        let mut prefix = serialize_key_account_prefix(account_id);
-        prefix.extend_from_slice(mailbox_id.as_bytes());
-
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            let mut batch = sled::Batch::default();
-
-            for kv_result in db.scan_prefix(&prefix) {
-                let (key, _) = kv_result.map_err(|err| {
-                    error!("Failed to scan db: {}", err);
-                })?;
-                if key.len() > prefix.len() && key[prefix.len()] <= UID_VALIDITY {
-                    batch.remove(key);
-                }
-            }
-
-            db.apply_batch(batch).map_err(|err| {
-                error!("Failed to delete batch: {}", err);
-            })?;
-
-            Ok(())
-        })
-        .await
+        let store = self.uid_store.clone();
+        let account_id = account_id.to_string();
+        let mailbox_id = mailbox_id.to_string();
+        self.spawn_worker(move || store.delete_mailbox(&account_id, &mailbox_id))
+            .await
     }
 
     pub async fn uids(&self, mailbox: Arc<MailboxId>) -> Result<(u32, u32), ()> {
-        let db = self.db.clone();
-        self.spawn_worker(move || Ok((db.uid_validity(&mailbox)?, db.uid_next(&mailbox)?)))
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || Ok((store.uid_validity(&mailbox)?, store.uid_next(&mailbox)?)))
             .await
     }
 
-    pub async fn purge_deleted_mailboxes(&self, account: &Account) -> Result<(), ()> {
-        if account.mailbox_data.is_empty() {
+    pub async fn purge_deleted_mailboxes(
+        &self,
+        account_id: String,
+        mailbox_ids: AHashSet<String>,
+    ) -> Result<(), ()> {
+        if mailbox_ids.is_empty() {
             debug!(
                 "No mailboxes found for account '{}', skipping purge.",
-                account.account_id
+                account_id
             );
             return Ok(());
         }
-        let account_prefix = serialize_key_account_prefix(&account.account_id);
-        let mailbox_keys = account
-            .mailbox_data
-            .keys()
-            .map(|id| id.as_bytes().to_vec())
-            .collect::<AHashSet<_>>();
 
-        let db = self.db.clone();
-        self.spawn_worker(move || {
-            let mut has_deletions = false;
-            let mut batch = sled::Batch::default();
-
-            for kv_result in db.scan_prefix(&account_prefix) {
-                let (key, _) = kv_result.map_err(|err| {
-                    error!("Failed to scan db: {}", err);
-                })?;
-                let key_part = &key[account_prefix.len()..];
-                if let Some(pos) = key_part.iter().position(|&ch| ch <= UID_VALIDITY) {
-                    if pos > 0 && !mailbox_keys.contains(&key_part[..pos]) {
-                        batch.remove(key);
-                        has_deletions = true;
-                    }
-                }
-            }
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.purge_deleted_mailboxes(&account_id, &mailbox_ids))
+            .await
+    }
 
-            if has_deletions {
-                db.apply_batch(batch).map_err(|err| {
-                    error!("Failed to delete batch: {}", err);
-                })?;
-            }
+    pub async fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.purge_deleted_ids(ttl))
+            .await
+    }
 
+    pub async fn set_last_login(&self, account_ids: Vec<String>) -> Result<(), ()> {
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || {
+            let now = now();
+            for account_id in account_ids {
+                store.set_last_login(&account_id, now)?;
+            }
             Ok(())
         })
         .await
     }
 
-    pub async fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .map(|d| d.as_secs())
-            .map_err(|err| {
-                error!("Failed to obtain current time: {}", err);
-            })?;
-
-        let db = self.db.clone();
+    pub async fn purge_inactive_accounts(&self, ttl: u64) -> Result<Vec<String>, ()> {
+        // Logins are only recorded when a session starts, so the accounts of
+        // the sessions still connected are marked as active first.
+        let active_ids = self
+            .sessions
+            .list()
+            .into_iter()
+            .filter_map(|(_, info)| info.data)
+            .flat_map(|data| {
+                let account_ids = data
+                    .user
+                    .mailboxes
+                    .lock()
+                    .iter()
+                    .map(|account| data.user.route.cache_key(&account.account_id))
+                    .collect::<Vec<_>>();
+                account_ids
+            })
+            .collect::<AHashSet<_>>();
+        let store = self.uid_store.clone();
         self.spawn_worker(move || {
-            let mut num_deletions = 0;
-            let mut batch = sled::Batch::default();
-
-            for kv_result in db.scan_prefix([]) {
-                let (key, value) = kv_result.map_err(|err| {
-                    error!("Failed to scan db: {}", err);
-                })?;
-                if value.len() == std::mem::size_of::<u32>() + std::mem::size_of::<u64>() {
-                    let insert_time = u64::from_be_bytes(
-                        (&value[std::mem::size_of::<u32>()..])
-                            .try_into()
-                            .map_err(|_| {
-                                error!("Failed to convert bytes to u32.");
-                            })?,
-                    );
-                    if insert_time < now && (now - insert_time) >= ttl {
-                        batch.remove(key);
-                        num_deletions += 1;
-                    }
-                }
+            let now = now();
+            for account_id in active_ids {
+                store.set_last_login(&account_id, now)?;
             }
-
-            if num_deletions > 0 {
-                db.apply_batch(batch).map_err(|err| {
-                    error!("Failed to delete batch: {}", err);
-                })?;
-            }
-
-            Ok(num_deletions)
+            store.purge_inactive_accounts(ttl)
         })
         .await
     }
 
+    pub async fn flush_uid_store(&self) -> Result<(), ()> {
+        let store = self.uid_store.clone();
+        self.spawn_worker(move || store.flush()).await
+    }
+
     pub async fn spawn_worker<U, V>(&self, f: U) -> Result<V, ()>
     where
         U: FnOnce() -> Result<V, ()> + Send + 'static,
@@ -562,14 +594,14 @@ impl SelectedMailbox {
         if !sequence.is_saved_search() {
             let mut ids = AHashMap::new();
             let state = self.state.lock();
-            if state.imap_uids.is_empty() {
+            if state.ids.is_empty() {
                 return Ok(ids);
             }
 
-            let max_uid = state.imap_uids.last().copied().unwrap_or(0);
-            let max_seqnum = state.imap_uids.len() as u32;
+            let max_uid = state.ids.last_uid().unwrap_or(0);
+            let max_seqnum = state.ids.len() as u32;
 
-            for (pos, &uid) in state.imap_uids.iter().enumerate() {
+            for (pos, (uid, jmap_id)) in state.ids.iter().enumerate() {
                 if uid != 0 {
                     let matched = if is_uid {
                         sequence.contains(uid, max_uid)
@@ -577,10 +609,7 @@ impl SelectedMailbox {
                         sequence.contains((pos + 1) as u32, max_seqnum)
                     };
                     if matched {
-                        ids.insert(
-                            state.jmap_ids[pos].clone(),
-                            ImapId::new(uid, (pos + 1) as u32),
-                        );
+                        ids.insert(jmap_id.to_string(), ImapId::new(uid, (pos + 1) as u32));
                     }
                 }
             }
@@ -595,11 +624,8 @@ impl SelectedMailbox {
             let state = self.state.lock();
 
             for imap_id in saved_ids.iter() {
-                if state.imap_uids.contains(&imap_id.uid) {
-                    ids.insert(
-                        state.jmap_ids[imap_id.seqnum.saturating_sub(1) as usize].clone(),
-                        *imap_id,
-                    );
+                if let Some(pos) = state.ids.uid_to_pos(imap_id.uid) {
+                    ids.insert(state.ids.jmap_id(pos).to_string(), *imap_id);
                 }
             }
 
@@ -612,8 +638,8 @@ impl SelectedMailbox {
         let state = self.state.lock();
 
         for jmap_id in jmap_ids {
-            if let Some(seqnum) = state.jmap_ids.iter().position(|id| id == jmap_id) {
-                imap_ids.push(ImapId::new(state.imap_uids[seqnum], (seqnum + 1) as u32));
+            if let Some(pos) = state.ids.jmap_id_to_pos(jmap_id) {
+                imap_ids.push(ImapId::new(state.ids.uid(pos), (pos + 1) as u32));
             }
         }
 
@@ -625,8 +651,8 @@ impl SelectedMailbox {
         let state = self.state.lock();
 
         for imap_id in imap_ids {
-            if let Some(pos) = state.imap_uids.iter().position(|uid| *uid == imap_id.uid) {
-                jmap_ids.push(state.jmap_ids[pos].clone());
+            if let Some(pos) = state.ids.uid_to_pos(imap_id.uid) {
+                jmap_ids.push(state.ids.jmap_id(pos).to_string());
             }
         }
 
@@ -636,64 +662,45 @@ impl SelectedMailbox {
     pub fn is_in_sync(&self, jmap_ids: &[String]) -> bool {
         let state = self.state.lock();
 
-        for jmap_id in jmap_ids {
-            if !state.jmap_ids.contains(jmap_id) {
-                return false;
-            }
-        }
-        true
+        jmap_ids
+            .iter()
+            .all(|jmap_id| state.ids.contains_jmap_id(jmap_id))
     }
 
     pub fn synchronize_uids(
         &self,
-        jmap_ids: Vec<String>,
-        imap_uids: Vec<u32>,
+        data: &SessionData,
+        new_ids: &Arc<UidMap>,
         remove_missing: bool,
     ) -> (Option<usize>, Option<Vec<ImapId>>) {
         let mut state = self.state.lock();
-        let mut has_inserts = false;
+        if Arc::ptr_eq(&state.ids, new_ids) {
+            return (None, None);
+        }
 
         let deletions = if remove_missing {
-            let mut deletions = Vec::new();
-
-            let mut new_jmap_ids = Vec::with_capacity(state.jmap_ids.len());
-            let mut new_imap_uids = Vec::with_capacity(state.imap_uids.len());
-
-            for (pos, (jmap_id, imap_uid)) in std::mem::take(&mut state.jmap_ids)
-                .into_iter()
-                .zip(std::mem::take(&mut state.imap_uids))
-                .enumerate()
-            {
-                if imap_uids.contains(&imap_uid) {
-                    new_jmap_ids.push(jmap_id);
-                    new_imap_uids.push(imap_uid);
-                } else {
-                    deletions.push(ImapId::new(imap_uid, (pos + 1) as u32));
-                }
-            }
-
-            state.jmap_ids = new_jmap_ids;
-            state.imap_uids = new_imap_uids;
-
-            if !deletions.is_empty() {
-                state.total_messages = state.total_messages.saturating_sub(deletions.len());
-                deletions.into()
-            } else {
-                None
-            }
+            let (_, deletions) = state.ids.remove(|uid, _| !new_ids.contains_uid(uid));
+            Some(deletions).filter(|deletions| !deletions.is_empty())
         } else {
             None
         };
 
-        for (pos, jmap_id) in jmap_ids.into_iter().enumerate() {
-            let uid = imap_uids[pos];
-            if !state.imap_uids.contains(&uid) {
-                state.imap_uids.push(uid);
-                state.jmap_ids.push(jmap_id);
-                state.total_messages += 1;
-                has_inserts = true;
-            }
+        // Messages added after the last synchronization have higher UIDs, so when
+        // removing missing messages the new snapshot can be used as is.
+        let has_inserts = new_ids
+            .uids()
+            .iter()
+            .any(|&uid| !state.ids.contains_uid(uid));
+        if remove_missing {
+            state.ids = new_ids.clone();
+        } else if let Some(merged_ids) = state.ids.merge(new_ids) {
+            state.ids = data
+                .core
+                .uid_maps
+                .intern(&data.cache_id(&self.id), merged_ids);
         }
+        state.total_messages = state.ids.len();
+
         (
             if has_inserts || deletions.is_some() {
                 state.total_messages.into()
@@ -703,197 +710,50 @@ impl SelectedMailbox {
             deletions,
         )
     }
-}
 
-impl ImapId {
-    pub fn new(uid: u32, seqnum: u32) -> Self {
-        ImapId { uid, seqnum }
+    // Removes messages expunged by another session, returning their ids before removal.
+    pub fn remove_uids(&self, data: &SessionData, uids: &[u32]) -> Vec<ImapId> {
+        self.remove_ids(data, |uid, _| uids.contains(&uid))
     }
-}
 
-trait ImapUtils {
-    fn insert_jmap_id(
+    pub fn remove_ids(
         &self,
-        mailbox: &MailboxId,
-        jmap_id: &[u8],
-        uid_next_key: &[u8],
-    ) -> Result<sled::IVec, ()>;
-    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()>;
-    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()>;
-}
-
-impl ImapUtils for sled::Db {
-    fn insert_jmap_id(
-        &self,
-        mailbox: &MailboxId,
-        jmap_id: &[u8],
-        uid_next_key: &[u8],
-    ) -> Result<sled::IVec, ()> {
-        // Obtain next UID.
-        let uid = self
-            .update_and_fetch(uid_next_key, increment_uid)
-            .map_err(|err| {
-                error!("Failed to increment UID: {}", err);
-            })?
-            .ok_or_else(|| {
-                error!("Failed to generate UID.");
-            })?;
-
-        // Write keys
-        for result in [
-            self.insert(serialize_key(mailbox, JMAP_TO_UID, jmap_id), &uid),
-            self.insert(serialize_key(mailbox, UID_TO_JMAP, &uid), jmap_id),
-        ] {
-            result.map_err(|err| {
-                error!("Failed to insert key: {}", err);
-            })?;
+        data: &SessionData,
+        filter: impl Fn(u32, &str) -> bool,
+    ) -> Vec<ImapId> {
+        let mut state = self.state.lock();
+        let (ids, deletions) = state.ids.remove(filter);
+        if !deletions.is_empty() {
+            state.ids = data.core.uid_maps.intern(&data.cache_id(&self.id), ids);
+            state.total_messages = state.ids.len();
         }
-        Ok(uid)
-    }
-
-    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()> {
-        Ok(
-            if let Some(uid_bytes) = self.get(serialize_uid_next_key(mailbox)).map_err(|err| {
-                error!("Failed to read key: {}", err);
-            })? {
-                u32::from_be_bytes((&uid_bytes[..]).try_into().map_err(|err| {
-                    error!("Failed to decode UID next: {}", err);
-                })?) + 1
-            } else {
-                1
-            },
-        )
+        deletions
     }
-
-    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()> {
-        // Obtain/generate UIDVALIDITY
-        let uid_validity_key = serialize_uid_validity_key(mailbox);
-        Ok(
-            if let Some(uid_bytes) = self.get(&uid_validity_key).map_err(|err| {
-                error!("Failed to read key: {}", err);
-            })? {
-                u32::from_be_bytes((&uid_bytes[..]).try_into().map_err(|err| {
-                    error!("Failed to decode UID validity: {}", err);
-                })?)
-            } else {
-                // Number of hours since January 1st, 2000
-                let uid_validity = (SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .map(|d| d.as_secs())
-                    .unwrap_or(0)
-                    .saturating_sub(946684800)
-                    / 3600) as u32;
-                self.insert(uid_validity_key, &uid_validity.to_be_bytes()[..])
-                    .map_err(|err| {
-                        error!("Failed to insert key: {}", err);
-                    })?;
-                uid_validity
-            },
-        )
-    }
-}
-
-fn serialize_key(mailbox: &MailboxId, separator: u8, value: &[u8]) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(
-        mailbox.account_id.len()
-            + mailbox.mailbox_id.as_ref().map_or(0, |m| m.len())
-            + value.len()
-            + 2,
-    );
-    buf.extend_from_slice(mailbox.account_id.as_bytes());
-    buf.push(0);
-    if let Some(mailbox_id) = mailbox.mailbox_id.as_ref() {
-        buf.extend_from_slice(mailbox_id.as_bytes());
-    }
-    buf.push(separator);
-    buf.extend_from_slice(value);
-    buf
 }
 
-fn serialize_key_prefix(mailbox: &MailboxId, separator: u8) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(
-        mailbox.account_id.len() + mailbox.mailbox_id.as_ref().map_or(0, |m| m.len()) + 2,
-    );
-    buf.extend_from_slice(mailbox.account_id.as_bytes());
-    buf.push(0);
-    if let Some(mailbox_id) = mailbox.mailbox_id.as_ref() {
-        buf.extend_from_slice(mailbox_id.as_bytes());
-    }
-    buf.push(separator);
-    buf
-}
-
-fn serialize_key_account_prefix(account_id: &str) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(account_id.len() + 1);
-    buf.extend_from_slice(account_id.as_bytes());
-    buf.push(0);
-    buf
-}
-
-fn serialize_uid_next_key(mailbox: &MailboxId) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(
-        mailbox.account_id.len() + mailbox.mailbox_id.as_ref().map_or(0, |m| m.len()) + 2,
-    );
-    buf.extend_from_slice(mailbox.account_id.as_bytes());
-    buf.push(0);
-    if let Some(mailbox_id) = mailbox.mailbox_id.as_ref() {
-        buf.extend_from_slice(mailbox_id.as_bytes());
-    }
-    buf.push(UID_NEXT);
-    buf
-}
-
-fn serialize_uid_validity_key(mailbox: &MailboxId) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(
-        mailbox.account_id.len() + mailbox.mailbox_id.as_ref().map_or(0, |m| m.len()) + 2,
-    );
-    buf.extend_from_slice(mailbox.account_id.as_bytes());
-    buf.push(0);
-    if let Some(mailbox_id) = mailbox.mailbox_id.as_ref() {
-        buf.extend_from_slice(mailbox_id.as_bytes());
+impl ImapId {
+    pub fn new(uid: u32, seqnum: u32) -> Self {
+        ImapId { uid, seqnum }
     }
-    buf.push(UID_VALIDITY);
-    buf
-}
-
-pub fn serialize_modseq(account_id: &[u8], value: &[u8], separator: u8) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(account_id.len() + value.len() + 2);
-    buf.extend_from_slice(account_id);
-    buf.push(0);
-    buf.extend_from_slice(value);
-    buf.push(separator);
-    buf
 }
 
-pub fn serialize_highestmodseq(account_id: &[u8]) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(account_id.len() + 2);
-    buf.extend_from_slice(account_id);
-    buf.push(0);
-    buf.push(HIGHEST_MODSEQ);
-    buf
-}
-
-pub fn increment_uid(old: Option<&[u8]>) -> Option<Vec<u8>> {
-    match old {
-        Some(bytes) => u32::from_be_bytes(bytes.try_into().ok()?) + 1,
-        None => 1,
-    }
-    .to_be_bytes()
-    .to_vec()
-    .into()
+fn ids_to_map(ids: &[String]) -> AHashMap<&str, usize> {
+    ids.iter()
+        .enumerate()
+        .map(|(pos, id)| (id.as_str(), pos))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::BTreeMap, sync::Arc, time::Duration};
+    use std::{sync::Arc, time::Duration};
 
-    use ahash::AHashMap;
+    use ahash::AHashSet;
 
     use crate::{
         core::{
             config::build_core,
-            mailbox::{Account, Mailbox},
-            message::MappingOptions,
+            message::{MailboxIds, MappingOptions},
         },
         tests::init_settings,
     };
@@ -1087,14 +947,10 @@ mod tests {
             .unwrap();
         assert_eq!(update_result.uid_next, 11);
 
-        core.purge_deleted_mailboxes(&Account {
-            account_id: "john".to_string(),
-            prefix: None,
-            mailbox_names: BTreeMap::new(),
-            mailbox_data: AHashMap::from_iter([("folder_id".to_string(), Mailbox::default())]),
-            mailbox_state: String::new(),
-            modseq: None,
-        })
+        core.purge_deleted_mailboxes(
+            "john".to_string(),
+            AHashSet::from_iter(["folder_id".to_string()]),
+        )
         .await
         .unwrap();
         let (uid_validity, uid_next) = core.uids(mailbox.clone()).await.unwrap();
@@ -1116,6 +972,32 @@ mod tests {
             Vec::<String>::new()
         );
 
+        // Apply incremental changes, ids that moved within the query are kept
+        let update_result = core
+            .apply_uid_changes(
+                mailbox_2.clone(),
+                MailboxIds::Changes {
+                    removed: vec!["b01".to_string(), "c02".to_string(), "d03".to_string()],
+                    added: vec!["c02".to_string(), "k10".to_string()],
+                },
+                "s1".to_string().into(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(update_result.uid_next, 12);
+        assert_eq!(update_result.ids.uids(), [1, 3, 5, 6, 7, 8, 9, 10, 11]);
+        assert_eq!(update_result.ids.jmap_id(1), "c02");
+        assert_eq!(update_result.ids.jmap_id(8), "k10");
+        assert_eq!(
+            core.query_state(mailbox_2.clone())
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("s1")
+        );
+        core.update_uids(mailbox_2.clone(), vec![]).await.unwrap();
+        assert_eq!(core.query_state(mailbox_2.clone()).await.unwrap(), None);
+
         // Delete temporary directory
         if temp_dir.exists() {
             std::fs::remove_dir_all(&temp_dir).unwrap();
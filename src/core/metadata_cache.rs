@@ -0,0 +1,318 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use parking_lot::Mutex;
+use tracing::error;
+
+use super::Core;
+
+// Access times are only refreshed when older than this many seconds,
+// which keeps cache hits from turning into disk writes.
+const TOUCH_INTERVAL: u64 = 3600;
+
+// Parsed metadata of a message, keyed by its blob id. Blobs are immutable
+// so entries never need to be invalidated, only evicted. ENVELOPE is not
+// stored as it is built from the Email/get properties.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MessageMetadata {
+    // Serialized BODY and BODYSTRUCTURE data items
+    pub body: Vec<u8>,
+    pub body_structure: Vec<u8>,
+    // Header block of the root part, including the trailing empty line
+    pub header: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct MetadataCache {
+    entries: sled::Tree,
+    lru: sled::Tree,
+    size: Arc<AtomicU64>,
+    max_size: u64,
+    evict_lock: Arc<Mutex<()>>,
+}
+
+impl MetadataCache {
+    pub fn open(db: &sled::Db, max_size: u64) -> sled::Result<Self> {
+        let entries = db.open_tree("metadata")?;
+        let lru = db.open_tree("metadata_lru")?;
+        let mut size = 0;
+        for entry in entries.iter() {
+            let (key, value) = entry?;
+            size += (key.len() + value.len()) as u64;
+        }
+
+        Ok(MetadataCache {
+            entries,
+            lru,
+            size: Arc::new(size.into()),
+            max_size,
+            evict_lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    pub fn get(&self, blob_id: &str) -> sled::Result<Option<MessageMetadata>> {
+        let value = if let Some(value) = self.entries.get(blob_id.as_bytes())? {
+            value
+        } else {
+            return Ok(None);
+        };
+        let metadata = if let Some((last_access, metadata)) = deserialize_entry(&value) {
+            let now = now();
+            if last_access + TOUCH_INTERVAL < now {
+                let mut entry = Vec::with_capacity(value.len());
+                entry.extend_from_slice(&now.to_be_bytes());
+                entry.extend_from_slice(&value[std::mem::size_of::<u64>()..]);
+
+                // A concurrent eviction might have removed the entry already
+                if self
+                    .entries
+                    .compare_and_swap(blob_id.as_bytes(), Some(value), Some(entry))?
+                    .is_ok()
+                {
+                    self.lru.remove(lru_key(last_access, blob_id))?;
+                    self.lru.insert(lru_key(now, blob_id), &[] as &[u8])?;
+                }
+            }
+            metadata
+        } else {
+            error!("Corrupted metadata cache entry for blob {:?}.", blob_id);
+            self.remove(blob_id)?;
+            return Ok(None);
+        };
+
+        Ok(Some(metadata))
+    }
+
+    pub fn insert(&self, blob_id: &str, metadata: &MessageMetadata) -> sled::Result<()> {
+        let now = now();
+        let entry = serialize_entry(now, metadata);
+        let entry_size = (blob_id.len() + entry.len()) as u64;
+        if self
+            .entries
+            .compare_and_swap(blob_id.as_bytes(), None as Option<&[u8]>, Some(entry))?
+            .is_err()
+        {
+            // Entries never change, keep the existing one
+            return Ok(());
+        }
+        self.lru.insert(lru_key(now, blob_id), &[] as &[u8])?;
+
+        if self.size.fetch_add(entry_size, Ordering::Relaxed) + entry_size > self.max_size {
+            self.evict()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&self, blob_id: &str) -> sled::Result<bool> {
+        if let Some(value) = self.entries.remove(blob_id.as_bytes())? {
+            self.size
+                .fetch_sub((blob_id.len() + value.len()) as u64, Ordering::Relaxed);
+            if let Some(last_access) = value
+                .get(..std::mem::size_of::<u64>())
+                .and_then(|bytes| bytes.try_into().ok())
+                .map(u64::from_be_bytes)
+            {
+                self.lru.remove(lru_key(last_access, blob_id))?;
+            }
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // Removes the least recently used entries until the cache is
+    // back under 90% of its maximum size.
+    fn evict(&self) -> sled::Result<()> {
+        let _lock = if let Some(lock) = self.evict_lock.try_lock() {
+            lock
+        } else {
+            // Another worker is already evicting entries
+            return Ok(());
+        };
+
+        let target_size = self.max_size - self.max_size / 10;
+        while self.size() > target_size {
+            let key = if let Some((key, _)) = self.lru.pop_min()? {
+                key
+            } else {
+                break;
+            };
+            if let Some(blob_id) = key
+                .get(std::mem::size_of::<u64>()..)
+                .and_then(|bytes| std::str::from_utf8(bytes).ok())
+            {
+                self.remove(blob_id)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Core {
+    pub async fn get_metadata(&self, blob_id: String) -> Result<Option<MessageMetadata>, ()> {
+        let cache = self.metadata_cache.clone();
+        self.spawn_worker(move || {
+            cache.get(&blob_id).map_err(|err| {
+                error!("Failed to read metadata cache: {}", err);
+            })
+        })
+        .await
+    }
+
+    pub async fn set_metadata(&self, blob_id: String, metadata: MessageMetadata) -> Result<(), ()> {
+        let cache = self.metadata_cache.clone();
+        self.spawn_worker(move || {
+            cache.insert(&blob_id, &metadata).map_err(|err| {
+                error!("Failed to write metadata cache: {}", err);
+            })
+        })
+        .await
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn lru_key(last_access: u64, blob_id: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(std::mem::size_of::<u64>() + blob_id.len());
+    key.extend_from_slice(&last_access.to_be_bytes());
+    key.extend_from_slice(blob_id.as_bytes());
+    key
+}
+
+fn serialize_entry(last_access: u64, metadata: &MessageMetadata) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(
+        std::mem::size_of::<u64>()
+            + (2 * std::mem::size_of::<u32>())
+            + metadata.body.len()
+            + metadata.body_structure.len()
+            + metadata.header.len(),
+    );
+    entry.extend_from_slice(&last_access.to_be_bytes());
+    for item in [&metadata.body, &metadata.body_structure] {
+        entry.extend_from_slice(&(item.len() as u32).to_be_bytes());
+        entry.extend_from_slice(item);
+    }
+    entry.extend_from_slice(&metadata.header);
+    entry
+}
+
+fn deserialize_entry(bytes: &[u8]) -> Option<(u64, MessageMetadata)> {
+    if bytes.len() < std::mem::size_of::<u64>() {
+        return None;
+    }
+    let (last_access, mut bytes) = bytes.split_at(std::mem::size_of::<u64>());
+    let mut items = [Vec::new(), Vec::new()];
+    for item in &mut items {
+        if bytes.len() < std::mem::size_of::<u32>() {
+            return None;
+        }
+        let (len, rest) = bytes.split_at(std::mem::size_of::<u32>());
+        let len = u32::from_be_bytes(len.try_into().ok()?) as usize;
+        if rest.len() < len {
+            return None;
+        }
+        let (contents, rest) = rest.split_at(len);
+        *item = contents.to_vec();
+        bytes = rest;
+    }
+    let [body, body_structure] = items;
+
+    Some((
+        u64::from_be_bytes(last_access.try_into().ok()?),
+        MessageMetadata {
+            body,
+            body_structure,
+            header: bytes.to_vec(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MessageMetadata, MetadataCache};
+
+    #[test]
+    fn metadata_cache() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cache = MetadataCache::open(&db, 1024).unwrap();
+        let metadata = MessageMetadata {
+            body: b"BODY (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 100 3)".to_vec(),
+            body_structure:
+                b"BODYSTRUCTURE (\"TEXT\" \"PLAIN\" NIL NIL NIL \"7BIT\" 100 3 NIL NIL NIL NIL)"
+                    .to_vec(),
+            header: b"Subject: test\r\nFrom: john@example.org\r\n\r\n".to_vec(),
+        };
+
+        // Entries are returned as inserted
+        assert_eq!(cache.get("blob01").unwrap(), None);
+        cache.insert("blob01", &metadata).unwrap();
+        assert_eq!(cache.get("blob01").unwrap(), Some(metadata.clone()));
+        let size = cache.size();
+        assert!(size > 0);
+
+        // Inserting an existing blob does not change its size
+        cache.insert("blob01", &metadata).unwrap();
+        assert_eq!(cache.size(), size);
+
+        // Least recently used entries are evicted once the cache is full
+        for blob_num in 2..=10 {
+            cache
+                .insert(&format!("blob{:02}", blob_num), &metadata)
+                .unwrap();
+        }
+        assert!(cache.size() <= 1024);
+        assert_eq!(cache.get("blob01").unwrap(), None);
+        assert_eq!(cache.get("blob10").unwrap(), Some(metadata.clone()));
+
+        // Sizes are recalculated when reopening the cache
+        let size = cache.size();
+        assert_eq!(MetadataCache::open(&db, 1024).unwrap().size(), size);
+
+        // Removing entries frees their space
+        assert!(cache.remove("blob10").unwrap());
+        assert!(!cache.remove("blob10").unwrap());
+        assert!(cache.size() < size);
+    }
+}
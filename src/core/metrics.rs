@@ -0,0 +1,482 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    fmt::{Display, Write},
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use parking_lot::Mutex;
+
+use super::{
+    http::{HttpRequest, HttpResponse},
+    Command, Core, ResponseType,
+};
+
+pub static METRICS: Metrics = Metrics::new();
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const SESSION_STATES: [&str; 3] = ["not_authenticated", "authenticated", "selected"];
+const RESPONSE_TYPES: [&str; 5] = ["ok", "no", "bad", "preauth", "bye"];
+
+pub struct Metrics {
+    pub sessions: [AtomicU64; 3],
+    pub sieve_sessions: AtomicU64,
+    pub idle_sessions: AtomicU64,
+    pub push_streams: AtomicU64,
+    pub responses: [AtomicU64; 5],
+    pub commands: Mutex<Vec<(Command, Histogram)>>,
+    pub jmap_connect: Mutex<Histogram>,
+    pub jmap_requests: Mutex<Histogram>,
+    pub jmap_errors: Mutex<Vec<(&'static str, u64)>>,
+    pub housekeeper_runs: AtomicU64,
+    pub housekeeper_failures: AtomicU64,
+    pub housekeeper_last_run: AtomicU64,
+    pub housekeeper_last_duration: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            sessions: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+            sieve_sessions: AtomicU64::new(0),
+            idle_sessions: AtomicU64::new(0),
+            push_streams: AtomicU64::new(0),
+            responses: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            commands: parking_lot::const_mutex(Vec::new()),
+            jmap_connect: parking_lot::const_mutex(Histogram::new()),
+            jmap_requests: parking_lot::const_mutex(Histogram::new()),
+            jmap_errors: parking_lot::const_mutex(Vec::new()),
+            housekeeper_runs: AtomicU64::new(0),
+            housekeeper_failures: AtomicU64::new(0),
+            housekeeper_last_run: AtomicU64::new(0),
+            housekeeper_last_duration: AtomicU64::new(0),
+        }
+    }
+
+    pub fn session_state(&self, old_state: Option<usize>, new_state: Option<usize>) {
+        if old_state != new_state {
+            if let Some(old_state) = old_state {
+                self.sessions[old_state].fetch_sub(1, Ordering::Relaxed);
+            }
+            if let Some(new_state) = new_state {
+                self.sessions[new_state].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn command(&self, command: Command, duration: Duration) {
+        let mut commands = self.commands.lock();
+        if let Some((_, histogram)) = commands.iter_mut().find(|(c, _)| *c == command) {
+            histogram.observe(duration);
+        } else {
+            let mut histogram = Histogram::new();
+            histogram.observe(duration);
+            commands.push((command, histogram));
+        }
+    }
+
+    pub fn response(&self, rtype: &ResponseType) {
+        self.responses[match rtype {
+            ResponseType::Ok => 0,
+            ResponseType::No => 1,
+            ResponseType::Bad => 2,
+            ResponseType::PreAuth => 3,
+            ResponseType::Bye => 4,
+        }]
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn jmap_connect(&self, duration: Duration) {
+        self.jmap_connect.lock().observe(duration);
+    }
+
+    pub fn jmap_request(&self, duration: Duration) {
+        self.jmap_requests.lock().observe(duration);
+    }
+
+    pub fn jmap_error(&self, error: &'static str) {
+        let mut errors = self.jmap_errors.lock();
+        if let Some((_, count)) = errors.iter_mut().find(|(e, _)| *e == error) {
+            *count += 1;
+        } else {
+            errors.push((error, 1));
+        }
+    }
+
+    pub fn housekeeper_run(&self, duration: Duration, is_success: bool) {
+        self.housekeeper_runs.fetch_add(1, Ordering::Relaxed);
+        if !is_success {
+            self.housekeeper_failures.fetch_add(1, Ordering::Relaxed);
+        }
+        self.housekeeper_last_run.store(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            Ordering::Relaxed,
+        );
+        self.housekeeper_last_duration
+            .store(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn serialize(&self, buf: &mut String) {
+        write_header(
+            buf,
+            "imap_sessions",
+            "Active IMAP sessions by state.",
+            "gauge",
+        );
+        for (state, count) in SESSION_STATES.iter().zip(self.sessions.iter()) {
+            writeln!(
+                buf,
+                "imap_sessions{{state=\"{}\"}} {}",
+                state,
+                count.load(Ordering::Relaxed)
+            )
+            .ok();
+        }
+        write_value(
+            buf,
+            "imap_idle_sessions",
+            "IMAP sessions currently in IDLE.",
+            "gauge",
+            self.idle_sessions.load(Ordering::Relaxed),
+        );
+        write_value(
+            buf,
+            "jmap_push_streams",
+            "JMAP EventSource connections shared by idling sessions.",
+            "gauge",
+            self.push_streams.load(Ordering::Relaxed),
+        );
+        write_value(
+            buf,
+            "managesieve_sessions",
+            "Active ManageSieve sessions.",
+            "gauge",
+            self.sieve_sessions.load(Ordering::Relaxed),
+        );
+
+        let commands = self.commands.lock();
+        write_header(
+            buf,
+            "imap_commands_total",
+            "IMAP commands processed by command type.",
+            "counter",
+        );
+        for (command, histogram) in commands.iter() {
+            writeln!(
+                buf,
+                "imap_commands_total{{command=\"{}\"}} {}",
+                command, histogram.count
+            )
+            .ok();
+        }
+        write_header(
+            buf,
+            "imap_command_duration_seconds",
+            "Time from receiving an IMAP command until its tagged response was written.",
+            "histogram",
+        );
+        for (command, histogram) in commands.iter() {
+            histogram.serialize(buf, "imap_command_duration_seconds", "command", command);
+        }
+        drop(commands);
+
+        write_header(
+            buf,
+            "imap_responses_total",
+            "Tagged IMAP responses by response type.",
+            "counter",
+        );
+        for (rtype, count) in RESPONSE_TYPES.iter().zip(self.responses.iter()) {
+            writeln!(
+                buf,
+                "imap_responses_total{{type=\"{}\"}} {}",
+                rtype,
+                count.load(Ordering::Relaxed)
+            )
+            .ok();
+        }
+
+        write_header(
+            buf,
+            "jmap_connect_duration_seconds",
+            "Time taken to authenticate and fetch the JMAP session.",
+            "histogram",
+        );
+        self.jmap_connect
+            .lock()
+            .serialize(buf, "jmap_connect_duration_seconds", "", "");
+        write_header(
+            buf,
+            "jmap_request_duration_seconds",
+            "Round trip time of JMAP API calls and blob transfers.",
+            "histogram",
+        );
+        self.jmap_requests
+            .lock()
+            .serialize(buf, "jmap_request_duration_seconds", "", "");
+        write_header(
+            buf,
+            "jmap_errors_total",
+            "JMAP request errors by error type.",
+            "counter",
+        );
+        for (error, count) in self.jmap_errors.lock().iter() {
+            writeln!(buf, "jmap_errors_total{{type=\"{}\"}} {}", error, count).ok();
+        }
+
+        write_value(
+            buf,
+            "housekeeper_runs_total",
+            "Housekeeper task executions.",
+            "counter",
+            self.housekeeper_runs.load(Ordering::Relaxed),
+        );
+        write_value(
+            buf,
+            "housekeeper_failures_total",
+            "Housekeeper task executions that failed.",
+            "counter",
+            self.housekeeper_failures.load(Ordering::Relaxed),
+        );
+        write_value(
+            buf,
+            "housekeeper_last_run_timestamp_seconds",
+            "Time of the last housekeeper run.",
+            "gauge",
+            self.housekeeper_last_run.load(Ordering::Relaxed),
+        );
+        write_header(
+            buf,
+            "housekeeper_last_duration_seconds",
+            "Duration of the last housekeeper run.",
+            "gauge",
+        );
+        writeln!(
+            buf,
+            "housekeeper_last_duration_seconds {}",
+            self.housekeeper_last_duration.load(Ordering::Relaxed) as f64 / 1000.0
+        )
+        .ok();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, duration: Duration) {
+        let value = duration.as_secs_f64();
+        if let Some(pos) = LATENCY_BUCKETS.iter().position(|bucket| value <= *bucket) {
+            self.buckets[pos] += 1;
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    pub fn serialize(&self, buf: &mut String, name: &str, label: &str, value: impl Display) {
+        let labels = if !label.is_empty() {
+            format!("{}=\"{}\",", label, value)
+        } else {
+            String::new()
+        };
+        let mut total = 0;
+        for (bucket, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            total += count;
+            writeln!(
+                buf,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bucket, total
+            )
+            .ok();
+        }
+        writeln!(
+            buf,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )
+        .ok();
+        let labels = labels.trim_end_matches(',');
+        if !labels.is_empty() {
+            writeln!(buf, "{}_sum{{{}}} {}", name, labels, self.sum).ok();
+            writeln!(buf, "{}_count{{{}}} {}", name, labels, self.count).ok();
+        } else {
+            writeln!(buf, "{}_sum {}", name, self.sum).ok();
+            writeln!(buf, "{}_count {}", name, self.count).ok();
+        }
+    }
+}
+
+pub async fn measure_jmap<T>(request: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let result = request.await;
+    METRICS.jmap_request(started.elapsed());
+    result
+}
+
+fn write_header(buf: &mut String, name: &str, help: &str, mtype: &str) {
+    writeln!(buf, "# HELP {} {}\n# TYPE {} {}", name, help, name, mtype).ok();
+}
+
+fn write_value(buf: &mut String, name: &str, help: &str, mtype: &str, value: u64) {
+    write_header(buf, name, help, mtype);
+    writeln!(buf, "{} {}", name, value).ok();
+}
+
+pub async fn handle_metrics_request(core: Arc<Core>, request: HttpRequest) -> HttpResponse {
+    if request.path != "/metrics" {
+        return HttpResponse::not_found();
+    } else if request.method != "GET" {
+        return HttpResponse::text(405, "Method not allowed");
+    }
+
+    HttpResponse::new(
+        200,
+        "text/plain; version=0.0.4",
+        serialize_metrics(&core).await,
+    )
+}
+
+pub async fn serialize_metrics(core: &Core) -> String {
+    // Walks the cache directory, or asks another node for remote stores.
+    let store = core.uid_store.clone();
+    let cache_size = core
+        .spawn_worker(move || Ok(store.size_on_disk()))
+        .await
+        .unwrap_or(0);
+
+    let mut buf = String::with_capacity(4096);
+    METRICS.serialize(&mut buf);
+    write_value(
+        &mut buf,
+        "cache_size_bytes",
+        "Size on disk of the UID cache.",
+        "gauge",
+        cache_size,
+    );
+    write_header(
+        &mut buf,
+        "jmap_backend_up",
+        "Whether the JMAP backend passed its last health check.",
+        "gauge",
+    );
+    for route in core
+        .router
+        .routes
+        .iter()
+        .map(|(_, route)| route)
+        .chain([&core.router.default])
+    {
+        for backend in &route.backends {
+            writeln!(
+                buf,
+                "jmap_backend_up{{url=\"{}\"}} {}",
+                backend.url,
+                backend.is_healthy.load(Ordering::Relaxed) as u64
+            )
+            .ok();
+        }
+    }
+
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::core::Command;
+
+    use super::Metrics;
+
+    #[test]
+    fn serialize_metrics() {
+        let metrics = Metrics::new();
+        metrics.session_state(None, Some(0));
+        metrics.session_state(None, Some(0));
+        metrics.session_state(Some(0), Some(2));
+        metrics.command(Command::Fetch(true), Duration::from_millis(3));
+        metrics.command(Command::Fetch(true), Duration::from_millis(200));
+        metrics.command(Command::Noop, Duration::from_secs(60));
+        metrics.jmap_request(Duration::from_millis(40));
+        metrics.jmap_error("transport");
+        metrics.jmap_error("transport");
+
+        let mut buf = String::new();
+        metrics.serialize(&mut buf);
+
+        for expected in [
+            "imap_sessions{state=\"not_authenticated\"} 1",
+            "imap_sessions{state=\"selected\"} 1",
+            "imap_commands_total{command=\"UID FETCH\"} 2",
+            "imap_command_duration_seconds_bucket{command=\"UID FETCH\",le=\"0.005\"} 1",
+            "imap_command_duration_seconds_bucket{command=\"UID FETCH\",le=\"0.25\"} 2",
+            "imap_command_duration_seconds_bucket{command=\"NOOP\",le=\"30\"} 0",
+            "imap_command_duration_seconds_bucket{command=\"NOOP\",le=\"+Inf\"} 1",
+            "imap_command_duration_seconds_count{command=\"NOOP\"} 1",
+            "jmap_connect_duration_seconds_count 0",
+            "jmap_request_duration_seconds_bucket{le=\"0.05\"} 1",
+            "jmap_errors_total{type=\"transport\"} 2",
+        ] {
+            assert!(
+                buf.contains(expected),
+                "{:?} not found in:\n{}",
+                expected,
+                buf
+            );
+        }
+    }
+}
@@ -21,19 +21,40 @@
  * for more details.
 */
 
+pub mod admin;
 pub mod client;
 pub mod config;
 pub mod connection;
+pub mod download;
 pub mod env_settings;
 pub mod housekeeper;
+pub mod http;
 pub mod listener;
 pub mod mailbox;
 pub mod message;
+pub mod metadata_cache;
+pub mod metrics;
+pub mod proxy;
+pub mod push;
 pub mod receiver;
+pub mod registry;
+pub mod router;
+pub mod session_cache;
+pub mod store;
+pub mod tls;
+pub mod uid_map;
+pub mod upgrade;
+pub mod upload;
 pub mod utf7;
 pub mod writer;
 
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use jmap_client::core::{
     error::{JMAPError, MethodErrorType, ProblemType},
@@ -42,15 +63,40 @@ use jmap_client::core::{
 
 use crate::protocol::capability::Capability;
 
+use self::{
+    housekeeper::Housekeeper, metadata_cache::MetadataCache, metrics::METRICS, proxy::IpNetwork,
+    push::PushRegistry, receiver::LiteralSpool, registry::SessionRegistry, router::Router,
+    session_cache::SessionCache, store::UidStore, uid_map::UidMapCache, upgrade::Listeners,
+};
+
 pub struct Core {
-    pub tls_acceptor: tokio_rustls::TlsAcceptor,
+    pub tls_acceptor: parking_lot::RwLock<tokio_rustls::TlsAcceptor>,
     pub db: Arc<sled::Db>,
+    pub uid_store: Arc<dyn UidStore>,
     pub worker_pool: rayon::ThreadPool,
-    pub jmap_url: String,
-    pub trusted_hosts: Vec<String>,
-    pub folder_shared: String,
-    pub folder_all: String,
-    pub max_request_size: usize,
+    pub router: Router,
+    pub max_request_size: AtomicUsize,
+    pub proxy_networks: parking_lot::RwLock<Vec<IpNetwork>>,
+    pub sessions: SessionRegistry,
+    pub admin_secret: Option<String>,
+    pub cache_secret: Option<String>,
+    pub listeners: Listeners,
+    pub session_cache: SessionCache,
+    pub push_hubs: PushRegistry,
+    pub uid_maps: UidMapCache,
+    pub metadata_cache: MetadataCache,
+    pub literal_spool: Option<LiteralSpool>,
+    pub housekeeper: Housekeeper,
+}
+
+impl Core {
+    pub fn tls_acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        self.tls_acceptor.read().clone()
+    }
+
+    pub fn max_request_size(&self) -> usize {
+        self.max_request_size.load(Ordering::Relaxed)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -292,6 +338,17 @@ pub trait IntoStatusResponse {
 
 impl IntoStatusResponse for jmap_client::Error {
     fn into_status_response(self) -> StatusResponse {
+        METRICS.jmap_error(match &self {
+            jmap_client::Error::Transport(_) => "transport",
+            jmap_client::Error::Parse(_) => "parse",
+            jmap_client::Error::Internal(_) => "internal",
+            jmap_client::Error::Problem(_) => "problem",
+            jmap_client::Error::Server(_) => "server",
+            jmap_client::Error::Method(_) => "method",
+            jmap_client::Error::Set(_) => "set",
+            jmap_client::Error::WebSocket(_) => "websocket",
+        });
+
         let (code, message) = match self {
             jmap_client::Error::Transport(_) => (
                 ResponseCode::ContactAdmin,
@@ -0,0 +1,299 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use tokio::{io::AsyncReadExt, net::TcpStream};
+use tracing::debug;
+
+use super::Core;
+
+const PROXY_V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V1_MAX_LEN: usize = 107;
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+impl Core {
+    pub async fn peer_addr(&self, stream: &mut TcpStream) -> Option<SocketAddr> {
+        let peer_addr = match stream.peer_addr() {
+            Ok(peer_addr) => peer_addr,
+            Err(err) => {
+                debug!("Failed to obtain peer address: {}", err);
+                return None;
+            }
+        };
+
+        // Only trusted load balancers are allowed to send a PROXY header
+        let is_trusted = self
+            .proxy_networks
+            .read()
+            .iter()
+            .any(|network| network.contains(&peer_addr.ip()));
+        if !is_trusted {
+            return Some(peer_addr);
+        }
+
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream)).await {
+            Ok(Ok(Some(client_addr))) => {
+                debug!("Connection from {} proxied by {}.", client_addr, peer_addr);
+                Some(client_addr)
+            }
+            Ok(Ok(None)) => Some(peer_addr),
+            Ok(Err(err)) => {
+                debug!("Invalid PROXY header received from {}: {}", peer_addr, err);
+                None
+            }
+            Err(_) => {
+                debug!("Timed out waiting for PROXY header from {}.", peer_addr);
+                None
+            }
+        }
+    }
+}
+
+pub async fn read_proxy_header(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    // The shortest valid v1 header, "PROXY UNKNOWN\r\n", is longer than the v2 signature.
+    let mut header = [0u8; 16];
+    stream.read_exact(&mut header[..12]).await?;
+
+    if &header[..12] == PROXY_V2_SIGNATURE {
+        stream.read_exact(&mut header[12..]).await?;
+        let mut payload = vec![0u8; u16::from_be_bytes([header[14], header[15]]) as usize];
+        stream.read_exact(&mut payload).await?;
+        parse_proxy_v2(&header, &payload)
+    } else if header.starts_with(b"PROXY ") {
+        // Read one byte at a time to avoid consuming data past the header
+        let mut line = header[..12].to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= PROXY_V1_MAX_LEN {
+                return Err(invalid_header("PROXY v1 header is too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_proxy_v1(&line)
+    } else {
+        Err(invalid_header("PROXY protocol header not found"))
+    }
+}
+
+pub fn parse_proxy_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| invalid_header("Invalid PROXY v1 header"))?;
+    let mut parts = line.split(' ');
+
+    if parts.next() != Some("PROXY") {
+        return Err(invalid_header("Invalid PROXY v1 header"));
+    }
+    let is_ipv6 = match parts.next() {
+        Some("TCP4") => false,
+        Some("TCP6") => true,
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid_header("Unsupported PROXY v1 protocol")),
+    };
+
+    let src_addr = parts
+        .next()
+        .and_then(|addr| addr.parse::<IpAddr>().ok())
+        .filter(|addr| addr.is_ipv6() == is_ipv6)
+        .ok_or_else(|| invalid_header("Invalid PROXY v1 source address"))?;
+    let _dst_addr = parts
+        .next()
+        .and_then(|addr| addr.parse::<IpAddr>().ok())
+        .filter(|addr| addr.is_ipv6() == is_ipv6)
+        .ok_or_else(|| invalid_header("Invalid PROXY v1 destination address"))?;
+    let src_port = parts
+        .next()
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or_else(|| invalid_header("Invalid PROXY v1 source port"))?;
+
+    Ok(Some(SocketAddr::new(src_addr, src_port)))
+}
+
+pub fn parse_proxy_v2(header: &[u8; 16], payload: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if header[12] >> 4 != 2 {
+        return Err(invalid_header("Unsupported PROXY protocol version"));
+    }
+
+    match header[12] & 0x0f {
+        // LOCAL command, used by load balancers for health checks
+        0x00 => return Ok(None),
+        0x01 => (),
+        _ => return Err(invalid_header("Unsupported PROXY v2 command")),
+    }
+
+    match header[13] >> 4 {
+        0x01 if payload.len() >= 12 => Ok(Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(
+                payload[0], payload[1], payload[2], payload[3],
+            )),
+            u16::from_be_bytes([payload[8], payload[9]]),
+        ))),
+        0x02 if payload.len() >= 36 => {
+            let mut src_addr = [0u8; 16];
+            src_addr.copy_from_slice(&payload[..16]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(src_addr)),
+                u16::from_be_bytes([payload[32], payload[33]]),
+            )))
+        }
+        0x01 | 0x02 => Err(invalid_header("PROXY v2 address block is too short")),
+        // Unspecified or UNIX sockets, keep the connection's address
+        _ => Ok(None),
+    }
+}
+
+fn invalid_header(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl IpNetwork {
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (&self.addr, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(*network) & mask == u32::from(*addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(*network) & mask == u128::from(*addr) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(addr)) => addr
+                .to_ipv4_mapped()
+                .map_or(false, |addr| self.contains(&IpAddr::V4(addr))),
+            (IpAddr::V6(_), IpAddr::V4(addr)) => self.contains(&IpAddr::V6(addr.to_ipv6_mapped())),
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        let (addr, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("Invalid network address {:?}: {}", value, err))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = if !prefix.is_empty() {
+            prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("Invalid network prefix {:?}", value))?
+        } else {
+            max_prefix
+        };
+
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, SocketAddr};
+
+    use super::{parse_proxy_v1, parse_proxy_v2, IpNetwork, PROXY_V2_SIGNATURE};
+
+    #[test]
+    fn parse_proxy_header() {
+        for (header, expected_result) in [
+            (
+                "PROXY TCP4 192.168.0.1 192.168.0.11 56324 143\r\n",
+                Some("192.168.0.1:56324"),
+            ),
+            (
+                "PROXY TCP6 ffff:f...f:ffff ffff:f...f:ffff 65535 65535\r\n",
+                None,
+            ),
+            (
+                "PROXY TCP6 2001:db8::1 2001:db8::2 4000 993\r\n",
+                Some("[2001:db8::1]:4000"),
+            ),
+            ("PROXY UNKNOWN\r\n", None),
+            ("PROXY TCP4 2001:db8::1 192.168.0.11 56324 143\r\n", None),
+        ] {
+            let result = parse_proxy_v1(header.as_bytes());
+            match expected_result {
+                Some(expected_addr) => assert_eq!(
+                    result.unwrap(),
+                    Some(expected_addr.parse::<SocketAddr>().unwrap()),
+                    "{:?}",
+                    header
+                ),
+                None if header.contains("UNKNOWN") => {
+                    assert_eq!(result.unwrap(), None, "{:?}", header)
+                }
+                None => assert!(result.is_err(), "{:?}", header),
+            }
+        }
+
+        let mut header = [0u8; 16];
+        header[..12].copy_from_slice(PROXY_V2_SIGNATURE);
+        header[12] = 0x21;
+        header[13] = 0x11;
+        header[15] = 12;
+        assert_eq!(
+            parse_proxy_v2(&header, &[10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 143]).unwrap(),
+            Some("10.0.0.1:8080".parse::<SocketAddr>().unwrap())
+        );
+        header[12] = 0x20;
+        assert_eq!(parse_proxy_v2(&header, &[]).unwrap(), None);
+        header[12] = 0x11;
+        assert!(parse_proxy_v2(&header, &[]).is_err());
+
+        for (network, addr, expected_result) in [
+            ("10.0.0.0/8", "10.1.2.3", true),
+            ("10.0.0.0/8", "11.1.2.3", false),
+            ("192.168.1.10", "192.168.1.10", true),
+            ("192.168.1.10", "192.168.1.11", false),
+            ("0.0.0.0/0", "1.2.3.4", true),
+            ("2001:db8::/32", "2001:db8:1::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("127.0.0.1", "::ffff:127.0.0.1", true),
+        ] {
+            assert_eq!(
+                network
+                    .parse::<IpNetwork>()
+                    .unwrap()
+                    .contains(&addr.parse::<IpAddr>().unwrap()),
+                expected_result,
+                "{} contains {}",
+                network,
+                addr
+            );
+        }
+    }
+}
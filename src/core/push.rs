@@ -0,0 +1,186 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+
+use ahash::AHashMap;
+use futures::StreamExt;
+use jmap_client::TypeState;
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use super::{metrics::METRICS, session_cache::UserSession};
+
+const PUSH_BUFFER: usize = 32;
+const PUSH_PING_SECS: u32 = 30;
+const PUSH_IDLE_CHECK: Duration = Duration::from_secs(60);
+
+// Account ids and the data types that changed in each of them
+pub type PushChanges = Arc<Vec<(String, TypeState)>>;
+
+// A single EventSource per user, shared by all of the user's idling sessions.
+#[derive(Default)]
+pub struct PushHub {
+    channel: Mutex<Option<(u64, broadcast::Sender<PushChanges>)>>,
+    next_id: AtomicU64,
+}
+
+// Hubs are looked up by user rather than kept only on the cached UserSession,
+// so connections that ended up with different sessions (other credentials or
+// an expired session cache entry) still share the same EventSource.
+#[derive(Default)]
+pub struct PushRegistry {
+    hubs: Mutex<AHashMap<String, Weak<PushHub>>>,
+}
+
+impl PushRegistry {
+    pub fn get(&self, user: String) -> Arc<PushHub> {
+        let mut hubs = self.hubs.lock();
+        if let Some(hub) = hubs.get(&user).and_then(|hub| hub.upgrade()) {
+            hub
+        } else {
+            hubs.retain(|_, hub| hub.strong_count() > 0);
+            let hub = Arc::new(PushHub::default());
+            hubs.insert(user, Arc::downgrade(&hub));
+            hub
+        }
+    }
+}
+
+impl UserSession {
+    pub async fn subscribe_push(
+        self: &Arc<Self>,
+    ) -> jmap_client::Result<broadcast::Receiver<PushChanges>> {
+        if let Some((_, tx)) = self.push.channel.lock().as_ref() {
+            return Ok(tx.subscribe());
+        }
+
+        let mut changes = self
+            .client
+            .event_source(
+                vec![TypeState::Email, TypeState::Mailbox].into(),
+                false,
+                PUSH_PING_SECS.into(),
+                None,
+            )
+            .await?;
+
+        // Another session might have started the event source in the meantime
+        let (push_id, tx, rx) = {
+            let mut channel = self.push.channel.lock();
+            if let Some((_, tx)) = channel.as_ref() {
+                return Ok(tx.subscribe());
+            }
+            let push_id = self.push.next_id.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = broadcast::channel(PUSH_BUFFER);
+            *channel = Some((push_id, tx.clone()));
+            (push_id, tx, rx)
+        };
+
+        let hub = Arc::downgrade(&self.push);
+        tokio::spawn(async move {
+            METRICS.push_streams.fetch_add(1, Ordering::Relaxed);
+            let mut idle_check = tokio::time::interval(PUSH_IDLE_CHECK);
+            idle_check.tick().await;
+
+            loop {
+                tokio::select! {
+                    changes = changes.next() => {
+                        match changes {
+                            Some(Ok(changes)) => {
+                                let changes = changes
+                                    .into_inner()
+                                    .into_iter()
+                                    .flat_map(|(account_id, changes)| {
+                                        changes
+                                            .into_keys()
+                                            .map(move |type_state| (account_id.clone(), type_state))
+                                    })
+                                    .collect::<Vec<_>>();
+                                if tx.send(Arc::new(changes)).is_err() {
+                                    break;
+                                }
+                            }
+                            Some(Err(err)) => {
+                                debug!("EventSource error: {}", err);
+                            }
+                            None => {
+                                debug!("EventSource connection unexpectedly closed.");
+                                break;
+                            }
+                        }
+                    },
+                    _ = idle_check.tick() => {
+                        if tx.receiver_count() == 0 {
+                            break;
+                        }
+                    }
+                };
+            }
+
+            // Close the channel so subscribers stop waiting for changes
+            stop_push(&hub, push_id);
+            METRICS.push_streams.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        Ok(rx)
+    }
+}
+
+fn stop_push(hub: &Weak<PushHub>, push_id: u64) {
+    if let Some(hub) = hub.upgrade() {
+        let mut channel = hub.channel.lock();
+        if channel.as_ref().map_or(false, |(id, _)| *id == push_id) {
+            *channel = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::PushRegistry;
+
+    #[test]
+    fn push_registry() {
+        let registry = PushRegistry::default();
+        let john = registry.get("john".to_string());
+        let jane = registry.get("jane".to_string());
+        assert!(Arc::ptr_eq(&john, &registry.get("john".to_string())));
+        assert!(!Arc::ptr_eq(&john, &jane));
+
+        // Hubs are only kept while a session holds them
+        drop(john);
+        let _bill = registry.get("bill".to_string());
+        assert_eq!(registry.hubs.lock().len(), 2);
+        assert!(!registry.hubs.lock().contains_key("john"));
+    }
+}
@@ -21,7 +21,18 @@
  * for more details.
 */
 
-use std::{borrow::Cow, fmt::Display};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+use tokio::{io::AsyncWriteExt, sync::mpsc, task::JoinHandle};
 
 use super::{ResponseCode, ResponseType, StatusResponse};
 
@@ -42,6 +53,9 @@ pub struct Request<T: CommandParser> {
 pub trait CommandParser: Sized + Default {
     fn parse(bytes: &[u8], is_uid: bool) -> Option<Self>;
     fn tokenize_brackets(&self) -> bool;
+    fn spool_literals(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -55,8 +69,32 @@ pub enum Token {
     Gt,               // >
     Dot,              // .
     Nil,              // NIL
+    Spooled(Arc<SpooledLiteral>),
+}
+
+// Large literals are written to disk as they are received instead of
+// being buffered in memory.
+#[derive(Debug, Clone)]
+pub struct LiteralSpool {
+    pub dir: PathBuf,
+    pub min_size: usize,
 }
 
+// Literal stored in a spool file, which is deleted once no longer referenced.
+// The file is written by a separate task as the literal is received.
+#[derive(Debug)]
+pub struct SpooledLiteral {
+    pub path: PathBuf,
+    pub size: usize,
+    writer: Mutex<Option<JoinHandle<std::io::Result<()>>>>,
+}
+
+static NEXT_SPOOL_ID: AtomicU64 = AtomicU64::new(0);
+
+// Chunks queued for the spool writer, reading from the socket waits once
+// the disk falls this far behind.
+const SPOOL_QUEUE_SIZE: usize = 4;
+
 impl<T: CommandParser> Default for Request<T> {
     fn default() -> Self {
         Self {
@@ -81,6 +119,9 @@ pub enum State {
 
 pub struct Receiver<T: CommandParser> {
     buf: Vec<u8>,
+    spool: Option<LiteralSpool>,
+    spooled: Option<(mpsc::Sender<Vec<u8>>, SpooledLiteral)>,
+    spool_chunks: Vec<(mpsc::Sender<Vec<u8>>, Vec<u8>)>,
     pub request: Request<T>,
     pub state: State,
     pub max_request_size: usize,
@@ -109,6 +150,24 @@ impl<T: CommandParser> Receiver<T> {
         }
     }
 
+    pub fn with_spool(mut self, spool: Option<LiteralSpool>) -> Self {
+        self.spool = spool;
+        self
+    }
+
+    // Hands the literal bytes received by parse over to the spool writers,
+    // waiting for the disk when they fall behind.
+    pub async fn write_spooled(&mut self) {
+        for (writer, chunk) in self.spool_chunks.drain(..) {
+            // Write errors are reported by SpooledLiteral::written
+            writer.send(chunk).await.ok();
+        }
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.state != self.start_state || !self.buf.is_empty() || !self.request.tag.is_empty()
+    }
+
     pub fn error_reset(&mut self, message: impl Into<Cow<'static, str>>) -> Error {
         let request = std::mem::take(&mut self.request);
         let err = Error::err(
@@ -120,6 +179,7 @@ impl<T: CommandParser> Receiver<T> {
             message,
         );
         self.buf = Vec::with_capacity(10);
+        self.spooled = None;
         self.state = self.start_state;
         self.current_request_size = 0;
         err
@@ -332,7 +392,16 @@ impl<T: CommandParser> Receiver<T> {
                                     )));
                                 }
                                 self.state = State::LiteralSeek { size, non_sync };
-                                self.buf = Vec::with_capacity(size as usize);
+                                if let Some(spool) = self.spool.as_ref().filter(|spool| {
+                                    size as usize >= spool.min_size
+                                        && self.request.command.spool_literals()
+                                }) {
+                                    self.spooled =
+                                        Some(SpooledLiteral::create(spool, size as usize));
+                                    self.buf.clear();
+                                } else {
+                                    self.buf = Vec::with_capacity(size as usize);
+                                }
                             } else {
                                 return Err(self.error_reset("Invalid empty literal."));
                             }
@@ -378,6 +447,35 @@ impl<T: CommandParser> Receiver<T> {
                         );
                     }
                 }
+                State::LiteralData { remaining } if self.spooled.is_some() => {
+                    // Write all the available literal bytes at once
+                    let bytes_ = bytes.as_slice();
+                    let len = std::cmp::min(bytes_.len(), remaining as usize - 1);
+                    let mut chunk = Vec::with_capacity(len + 1);
+                    chunk.push(ch);
+                    chunk.extend_from_slice(&bytes_[..len]);
+                    let (writer, _) = self.spooled.as_ref().unwrap();
+                    if writer.is_closed() {
+                        // The writer task stops on the first error
+                        return Err(self.error_reset("Failed to store literal."));
+                    }
+                    self.spool_chunks.push((writer.clone(), chunk));
+                    if len > 0 {
+                        bytes.nth(len - 1);
+                    }
+
+                    let remaining = remaining - 1 - len as u32;
+                    if remaining > 0 {
+                        self.state = State::LiteralData { remaining };
+                    } else {
+                        // Closing the channel once the queued chunks are written
+                        // lets the writer task finish the file
+                        let (_, literal) = self.spooled.take().unwrap();
+                        self.current_request_size += literal.size;
+                        self.request.tokens.push(Token::Spooled(Arc::new(literal)));
+                        self.state = State::Argument { last_ch: b' ' };
+                    }
+                }
                 State::LiteralData { remaining } => {
                     self.buf.push(ch);
                     if remaining > 1 {
@@ -402,6 +500,7 @@ impl Token {
             Token::Argument(value) => {
                 String::from_utf8(value).map_err(|_| "Invalid UTF-8 in argument.".into())
             }
+            Token::Spooled(_) => Err("Argument is too long.".into()),
             other => Ok(other.to_string()),
         }
     }
@@ -409,6 +508,8 @@ impl Token {
     pub fn unwrap_bytes(self) -> Vec<u8> {
         match self {
             Token::Argument(value) => value,
+            // Only the messages of APPEND are spooled and its parser takes them as is
+            Token::Spooled(_) => unreachable!("Spooled literals are not read back."),
             other => other.to_string().into_bytes(),
         }
     }
@@ -424,6 +525,7 @@ impl Token {
             Token::Lt => bytes.eq(b"<"),
             Token::Dot => bytes.eq(b"."),
             Token::Nil => bytes.is_empty(),
+            Token::Spooled(_) => false,
         }
     }
 
@@ -468,6 +570,7 @@ impl Display for Token {
             Token::Lt => write!(f, "<"),
             Token::Dot => write!(f, "."),
             Token::Nil => write!(f, ""),
+            Token::Spooled(literal) => write!(f, "{{{}}}", literal.size),
         }
     }
 }
@@ -485,10 +588,117 @@ impl Error {
     }
 }
 
+impl LiteralSpool {
+    pub fn open(dir: PathBuf, min_size: usize) -> std::io::Result<Self> {
+        // Literals may hold private messages, keep them away from other users
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(not(target_env = "msvc"))]
+        {
+            use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+            builder.mode(0o700).create(&dir)?;
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        #[cfg(target_env = "msvc")]
+        builder.create(&dir)?;
+
+        // Remove literals left behind by an earlier process with the same id. Files
+        // of other processes are left alone, as they might still be in use by the
+        // instance being replaced during an upgrade.
+        let prefix = spool_prefix();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                std::fs::remove_file(entry.path()).ok();
+            }
+        }
+
+        Ok(LiteralSpool { dir, min_size })
+    }
+}
+
+impl SpooledLiteral {
+    fn create(spool: &LiteralSpool, size: usize) -> (mpsc::Sender<Vec<u8>>, Self) {
+        let path = spool.dir.join(format!(
+            "{}{}",
+            spool_prefix(),
+            NEXT_SPOOL_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(SPOOL_QUEUE_SIZE);
+        let path_ = path.clone();
+        let writer = tokio::spawn(async move {
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(not(target_env = "msvc"))]
+            options.mode(0o600);
+            let mut file = options.open(&path_).await?;
+            while let Some(chunk) = rx.recv().await {
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await
+        });
+
+        (
+            tx,
+            SpooledLiteral {
+                path,
+                size,
+                writer: Mutex::new(Some(writer)),
+            },
+        )
+    }
+
+    // Waits until the whole literal has been written to disk.
+    pub async fn written(&self) -> std::io::Result<()> {
+        let writer = self.writer.lock().take();
+        if let Some(writer) = writer {
+            writer
+                .await
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl PartialEq for SpooledLiteral {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.size == other.size
+    }
+}
+
+impl Eq for SpooledLiteral {}
+
+impl Drop for SpooledLiteral {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let writer = self.writer.get_mut().take();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                // Wait for the writer so the file is not created after its removal
+                if let Some(writer) = writer {
+                    writer.await.ok();
+                }
+                tokio::fs::remove_file(&path).await.ok();
+            });
+        } else {
+            std::fs::remove_file(&path).ok();
+        }
+    }
+}
+
+fn spool_prefix() -> String {
+    format!("literal-{}-", std::process::id())
+}
+
 impl<T: CommandParser> Default for Receiver<T> {
     fn default() -> Self {
         Self {
             buf: Vec::with_capacity(10),
+            spool: None,
+            spooled: None,
+            spool_chunks: Vec::new(),
             request: Default::default(),
             state: State::Start,
             start_state: State::Start,
@@ -607,7 +817,7 @@ mod tests {
 
     use crate::core::receiver::State;
 
-    use super::{Error, Receiver, Request, Token};
+    use super::{Error, LiteralSpool, Receiver, Request, Token};
 
     #[test]
     fn receiver_parse_ok() {
@@ -1082,6 +1292,96 @@ mod tests {
         }
     }
 
+    #[test]
+    fn receiver_is_receiving() {
+        let mut receiver = Receiver::<crate::core::Command>::new();
+        assert!(!receiver.is_receiving());
+
+        for (frame, is_complete) in [
+            ("a001 NO", false),
+            ("OP\r\n", true),
+            ("a002 APPEND INBOX {5+}\r\nhel", false),
+            ("lo\r\n", true),
+        ] {
+            match receiver.parse(&mut frame.as_bytes().iter()) {
+                Ok(_) if is_complete => assert!(!receiver.is_receiving()),
+                Err(Error::NeedsMoreData) if !is_complete => assert!(receiver.is_receiving()),
+                result => panic!("Unexpected result for {:?}: {:?}", frame, result),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn receiver_spool_literals() {
+        let dir = std::env::temp_dir().join(format!("imap-spool-test-{}", std::process::id()));
+        let mut receiver = Receiver::<crate::core::Command>::new()
+            .with_spool(LiteralSpool::open(dir.clone(), 10).unwrap().into());
+
+        // Literals below the threshold and on other commands stay in memory
+        for frame in [
+            "a001 APPEND INBOX {5+}\r\nhello\r\n",
+            "a002 LOGIN {12+}\r\nhello world!\r\n",
+        ] {
+            let request = receiver.parse(&mut frame.as_bytes().iter()).unwrap();
+            assert!(request
+                .tokens
+                .iter()
+                .all(|token| !matches!(token, Token::Spooled(_))));
+        }
+
+        let mut request = None;
+        for frame in [
+            "a003 APPEND INBOX (\\Seen) {26}\r\n",
+            "hello ",
+            "spooled",
+            " world\r\n",
+            "again\r\n",
+        ] {
+            match receiver.parse(&mut frame.as_bytes().iter()) {
+                Ok(request_) => request = request_.into(),
+                Err(Error::NeedsMoreData) | Err(Error::NeedsLiteral { .. }) => (),
+                Err(err) => panic!("Unexpected error: {:?}", err),
+            }
+            receiver.write_spooled().await;
+        }
+        let literal = match request.unwrap().tokens.pop() {
+            Some(Token::Spooled(literal)) => literal,
+            token => panic!("Expected spooled literal, got {:?}", token),
+        };
+        assert_eq!(literal.size, 26);
+        literal.written().await.unwrap();
+        assert_eq!(
+            std::fs::read(&literal.path).unwrap(),
+            b"hello spooled world\r\nagain"
+        );
+        #[cfg(not(target_env = "msvc"))]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            for path in [&dir, &literal.path] {
+                let mode = std::fs::metadata(path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o077, 0, "{:?} is accessible to others", path);
+            }
+        }
+
+        let path = literal.path.clone();
+        drop(literal);
+        for _ in 0..100 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!path.exists());
+
+        // Spool files of other processes are not removed
+        let other_path = dir.join("literal-0-0");
+        std::fs::write(&other_path, b"in use").unwrap();
+        LiteralSpool::open(dir.clone(), 10).unwrap();
+        assert!(other_path.exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn receiver_parse_managesieve() {
         use crate::managesieve::Command;
@@ -0,0 +1,266 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use tokio::sync::watch;
+
+use super::client::{SelectedMailbox, SessionData};
+
+#[derive(Default)]
+pub struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Arc<Mutex<AHashMap<u64, SessionEntry>>>,
+}
+
+struct SessionEntry {
+    info: SessionInfo,
+    terminate_tx: watch::Sender<bool>,
+    tasks: watch::Sender<usize>,
+    is_closed: bool,
+}
+
+// Keeps a session registered until a command spawned by it has completed.
+pub struct SessionTask {
+    sessions: Arc<Mutex<AHashMap<u64, SessionEntry>>>,
+    id: u64,
+}
+
+#[derive(Clone)]
+pub struct SessionInfo {
+    pub protocol: &'static str,
+    pub peer_addr: SocketAddr,
+    pub is_tls: bool,
+    pub is_idle: bool,
+    pub user: Option<String>,
+    pub data: Option<Arc<SessionData>>,
+    pub mailbox: Option<(Arc<SessionData>, Arc<SelectedMailbox>)>,
+    pub connected_at: u64,
+}
+
+impl SessionRegistry {
+    pub fn register(
+        &self,
+        protocol: &'static str,
+        peer_addr: SocketAddr,
+        is_tls: bool,
+    ) -> (u64, watch::Receiver<bool>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (terminate_tx, terminate_rx) = watch::channel(false);
+        self.sessions.lock().insert(
+            id,
+            SessionEntry {
+                info: SessionInfo {
+                    protocol,
+                    peer_addr,
+                    is_tls,
+                    is_idle: false,
+                    user: None,
+                    data: None,
+                    mailbox: None,
+                    connected_at: SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |d| d.as_secs()),
+                },
+                terminate_tx,
+                tasks: watch::channel(0).0,
+                is_closed: false,
+            },
+        );
+        (id, terminate_rx)
+    }
+
+    pub fn update(&self, id: u64, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(entry) = self.sessions.lock().get_mut(&id) {
+            f(&mut entry.info);
+        }
+    }
+
+    pub fn unregister(&self, id: u64) {
+        let mut sessions = self.sessions.lock();
+        match sessions.get_mut(&id) {
+            Some(entry) if *entry.tasks.borrow() > 0 => {
+                entry.is_closed = true;
+            }
+            Some(_) => {
+                sessions.remove(&id);
+            }
+            None => (),
+        }
+    }
+
+    pub fn start_task(&self, id: u64) -> SessionTask {
+        if let Some(entry) = self.sessions.lock().get_mut(&id) {
+            let tasks = *entry.tasks.borrow() + 1;
+            entry.tasks.send_replace(tasks);
+        }
+        SessionTask {
+            sessions: self.sessions.clone(),
+            id,
+        }
+    }
+
+    // Waits until the commands spawned by a session have completed.
+    pub async fn wait_for_tasks(&self, id: u64) {
+        let tasks = self
+            .sessions
+            .lock()
+            .get(&id)
+            .map(|entry| entry.tasks.subscribe());
+        if let Some(mut tasks) = tasks {
+            while *tasks.borrow() > 0 {
+                if tasks.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    pub fn list(&self) -> Vec<(u64, SessionInfo)> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .iter()
+            .map(|(id, entry)| (*id, entry.info.clone()))
+            .collect::<Vec<_>>();
+        sessions.sort_unstable_by_key(|(id, _)| *id);
+        sessions
+    }
+
+    pub fn terminate(&self, filter: impl Fn(u64, &SessionInfo) -> bool) -> usize {
+        let mut count = 0;
+        for (id, entry) in self.sessions.lock().iter() {
+            if filter(*id, &entry.info) && entry.terminate_tx.send(true).is_ok() {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.lock().is_empty()
+    }
+}
+
+impl Drop for SessionTask {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock();
+        if let Some(entry) = sessions.get_mut(&self.id) {
+            let tasks = entry.tasks.borrow().saturating_sub(1);
+            entry.tasks.send_replace(tasks);
+            if tasks == 0 && entry.is_closed {
+                sessions.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::SessionRegistry;
+
+    #[test]
+    fn terminate_sessions() {
+        let registry = SessionRegistry::default();
+        let (id1, rx1) = registry.register("imap", "10.0.0.1:1000".parse().unwrap(), true);
+        let (_, rx2) = registry.register("imap", "10.0.0.2:1000".parse().unwrap(), false);
+        let (id3, rx3) = registry.register("managesieve", "10.0.0.1:1001".parse().unwrap(), true);
+        registry.update(id3, |info| info.user = "jdoe".to_string().into());
+        assert_eq!(registry.len(), 3);
+
+        assert_eq!(
+            registry.terminate(|_, info| info.user.as_deref() == Some("jdoe")),
+            1
+        );
+        assert!(!*rx1.borrow() && !*rx2.borrow() && *rx3.borrow());
+
+        assert_eq!(
+            registry
+                .terminate(|_, info| info.peer_addr.ip()
+                    == "10.0.0.1".parse::<std::net::IpAddr>().unwrap()),
+            2
+        );
+        assert!(*rx1.borrow() && !*rx2.borrow());
+
+        registry.unregister(id1);
+        assert_eq!(
+            registry
+                .list()
+                .into_iter()
+                .map(|(_, info)| info.protocol)
+                .collect::<Vec<_>>(),
+            vec!["imap", "managesieve"]
+        );
+    }
+
+    #[test]
+    fn unregister_after_tasks() {
+        let registry = SessionRegistry::default();
+        let (id, _rx) = registry.register("imap", "10.0.0.1:1000".parse().unwrap(), false);
+        let task1 = registry.start_task(id);
+        let task2 = registry.start_task(id);
+
+        registry.unregister(id);
+        assert_eq!(registry.len(), 1);
+        drop(task1);
+        assert!(!registry.is_empty());
+        drop(task2);
+        assert!(registry.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wait_for_tasks() {
+        let registry = Arc::new(SessionRegistry::default());
+        let (id, _rx) = registry.register("imap", "10.0.0.1:1000".parse().unwrap(), false);
+        registry.wait_for_tasks(id).await;
+
+        let task = registry.start_task(id);
+        let waiter = tokio::spawn({
+            let registry = registry.clone();
+            async move { registry.wait_for_tasks(id).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(task);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use jmap_client::client::Credentials;
+use regex::Regex;
+use reqwest::StatusCode;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+
+use super::Core;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Route {
+    pub backends: Vec<Arc<Backend>>,
+    pub balance: Balance,
+    pub next_backend: AtomicUsize,
+    pub trusted_hosts: Vec<String>,
+    pub folder_shared: String,
+    pub folder_all: String,
+    pub namespace: Option<String>,
+}
+
+pub struct Backend {
+    pub url: String,
+    pub is_healthy: AtomicBool,
+    pub connections: AtomicUsize,
+}
+
+// Keeps track of the sessions connected to a backend
+pub struct BackendConnection {
+    pub backend: Arc<Backend>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+pub enum DomainMatch {
+    Exact(String),
+    Regex(Regex),
+}
+
+pub struct Router {
+    pub routes: Vec<(Vec<DomainMatch>, Arc<Route>)>,
+    pub default: Arc<Route>,
+}
+
+impl Router {
+    pub fn new(default: Route) -> Self {
+        Router {
+            routes: Vec::new(),
+            default: Arc::new(default),
+        }
+    }
+
+    pub fn add(&mut self, domains: Vec<DomainMatch>, route: Route) {
+        self.routes.push((domains, Arc::new(route)));
+    }
+
+    pub fn route(&self, credentials: &Credentials) -> Arc<Route> {
+        if let Some(domain) = login_domain(credentials) {
+            for (domains, route) in &self.routes {
+                if domains.iter().any(|d| d.matches(&domain)) {
+                    return route.clone();
+                }
+            }
+        }
+        self.default.clone()
+    }
+}
+
+impl Route {
+    // Backends behind different routes may hand out the same ids, so anything
+    // cached for a named route is keyed under the route's name.
+    pub fn cache_key(&self, id: &str) -> String {
+        if let Some(namespace) = &self.namespace {
+            format!("{}:{}", namespace, id)
+        } else {
+            id.to_string()
+        }
+    }
+
+    // Returns the healthy backends in the order they should be tried.
+    pub fn backends(&self) -> Vec<Arc<Backend>> {
+        let mut backends = self
+            .backends
+            .iter()
+            .filter(|backend| backend.is_healthy.load(Ordering::Relaxed))
+            .cloned()
+            .collect::<Vec<_>>();
+        if !backends.is_empty() {
+            match self.balance {
+                Balance::RoundRobin => {
+                    let next = self.next_backend.fetch_add(1, Ordering::Relaxed) % backends.len();
+                    backends.rotate_left(next);
+                }
+                Balance::LeastConnections => {
+                    backends.sort_by_key(|backend| backend.connections.load(Ordering::Relaxed));
+                }
+            }
+        }
+        backends
+    }
+}
+
+impl Backend {
+    pub fn new(url: String) -> Self {
+        Backend {
+            url,
+            is_healthy: AtomicBool::new(true),
+            connections: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn connect(self: &Arc<Self>) -> BackendConnection {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        BackendConnection {
+            backend: self.clone(),
+        }
+    }
+
+    pub fn set_healthy(&self, is_healthy: bool) {
+        if self.is_healthy.swap(is_healthy, Ordering::Relaxed) != is_healthy {
+            if is_healthy {
+                info!("JMAP backend {} is up.", self.url);
+            } else {
+                warn!("JMAP backend {} is down.", self.url);
+            }
+        }
+    }
+
+    // Requests the JMAP session without credentials, which a backend that is up
+    // answers with the session or an authentication challenge.
+    pub async fn check_health(&self, http: &reqwest::Client) -> bool {
+        if let Some(session_url) = session_url(&self.url) {
+            match http.get(&session_url).send().await {
+                Ok(response)
+                    if response.status().is_success()
+                        || response.status() == StatusCode::UNAUTHORIZED =>
+                {
+                    true
+                }
+                Ok(response) => {
+                    debug!(
+                        "Health check for {} failed: {}",
+                        self.url,
+                        response.status()
+                    );
+                    false
+                }
+                Err(err) => {
+                    debug!("Health check for {} failed: {}", self.url, err);
+                    false
+                }
+            }
+        } else {
+            debug!("Invalid JMAP backend URL {}.", self.url);
+            false
+        }
+    }
+}
+
+impl Drop for BackendConnection {
+    fn drop(&mut self) {
+        self.backend.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl FromStr for Balance {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-connections" => Ok(Balance::LeastConnections),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn spawn_health_checks(
+    core: Arc<Core>,
+    interval: Duration,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let http = reqwest::Client::builder()
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    tokio::spawn(async move {
+        loop {
+            for route in core
+                .router
+                .routes
+                .iter()
+                .map(|(_, route)| route)
+                .chain([&core.router.default])
+            {
+                for backend in &route.backends {
+                    backend.set_healthy(backend.check_health(&http).await);
+                }
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {},
+                _ = shutdown_rx.changed() => {
+                    debug!("Health checker shutting down.");
+                    break;
+                }
+            };
+        }
+    });
+}
+
+// Returns the JMAP session endpoint of a backend URL
+fn session_url(url: &str) -> Option<String> {
+    let authority = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?
+        .split('/')
+        .next()?;
+    if !authority.is_empty() {
+        Some(format!("{}/.well-known/jmap", url.trim_end_matches('/')))
+    } else {
+        None
+    }
+}
+
+impl DomainMatch {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(regex) = value.strip_prefix('~') {
+            Regex::new(regex)
+                .map(DomainMatch::Regex)
+                .map_err(|err| format!("Invalid domain expression {:?}: {}", regex, err))
+        } else {
+            Ok(DomainMatch::Exact(value.to_lowercase()))
+        }
+    }
+
+    pub fn matches(&self, domain: &str) -> bool {
+        match self {
+            DomainMatch::Exact(exact) => exact == domain,
+            DomainMatch::Regex(regex) => regex.is_match(domain),
+        }
+    }
+}
+
+pub fn clone_credentials(credentials: &Credentials) -> Credentials {
+    match credentials {
+        Credentials::Basic(basic) => Credentials::Basic(basic.clone()),
+        Credentials::Bearer(token) => Credentials::Bearer(token.clone()),
+    }
+}
+
+// Bearer tokens carry no login name, so they are always sent to the default route.
+fn login_domain(credentials: &Credentials) -> Option<String> {
+    if let Credentials::Basic(basic) = credentials {
+        let basic = String::from_utf8(base64::decode(basic).ok()?).ok()?;
+        let (username, _) = basic.split_once(':')?;
+        username
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_client::client::Credentials;
+
+    use std::sync::{atomic::Ordering, Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{session_url, Backend, Balance, DomainMatch, Route, Router};
+
+    fn route(urls: &[&str], balance: Balance) -> Route {
+        Route {
+            backends: urls
+                .iter()
+                .map(|url| Arc::new(Backend::new(url.to_string())))
+                .collect(),
+            balance,
+            next_backend: 0.into(),
+            trusted_hosts: vec![],
+            folder_shared: "Shared Folders".to_string(),
+            folder_all: "All Mail".to_string(),
+            namespace: None,
+        }
+    }
+
+    #[test]
+    fn route_by_domain() {
+        let route = |url: &str| route(&[url], Balance::RoundRobin);
+        let mut router = Router::new(route("https://default"));
+        router.add(
+            vec![
+                DomainMatch::parse("Example.org").unwrap(),
+                DomainMatch::parse("~^.*\\.example\\.net$").unwrap(),
+            ],
+            route("https://tenant1"),
+        );
+        router.add(
+            vec![DomainMatch::parse("example.com").unwrap()],
+            route("https://tenant2"),
+        );
+
+        for (credentials, expected_url) in [
+            (
+                Credentials::basic("john@example.org", "secret"),
+                "https://tenant1",
+            ),
+            (
+                Credentials::basic("john@EXAMPLE.ORG", "secret"),
+                "https://tenant1",
+            ),
+            (
+                Credentials::basic("jane@mail.example.net", "pass"),
+                "https://tenant1",
+            ),
+            (
+                Credentials::basic("jane@example.net", "pass"),
+                "https://default",
+            ),
+            (
+                Credentials::basic("bill@example.com", "a:b"),
+                "https://tenant2",
+            ),
+            (Credentials::basic("bill", "secret"), "https://default"),
+            (Credentials::Bearer("abc".to_string()), "https://default"),
+        ] {
+            assert_eq!(router.route(&credentials).backends[0].url, expected_url);
+        }
+
+        let mut tenant = route("https://tenant3");
+        assert_eq!(tenant.cache_key("a1"), "a1");
+        tenant.namespace = "tenant3".to_string().into();
+        assert_eq!(tenant.cache_key("a1"), "tenant3:a1");
+    }
+
+    #[test]
+    fn select_backend() {
+        let urls = |route: &Route| {
+            route
+                .backends()
+                .into_iter()
+                .map(|backend| backend.url.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let rr = route(&["a", "b", "c"], Balance::RoundRobin);
+        assert_eq!(urls(&rr), ["a", "b", "c"]);
+        assert_eq!(urls(&rr), ["b", "c", "a"]);
+        rr.backends[2].set_healthy(false);
+        assert_eq!(urls(&rr), ["a", "b"]);
+        rr.backends[0].set_healthy(false);
+        rr.backends[1].set_healthy(false);
+        assert!(rr.backends().is_empty());
+
+        let lc = route(&["a", "b", "c"], Balance::LeastConnections);
+        let _conn_a = lc.backends[0].connect();
+        let conn_b = (lc.backends[1].connect(), lc.backends[1].connect());
+        assert_eq!(urls(&lc), ["c", "a", "b"]);
+        drop(conn_b);
+        assert_eq!(lc.backends[1].connections.load(Ordering::Relaxed), 0);
+        assert_eq!(urls(&lc), ["b", "c", "a"]);
+
+        for (url, expected_url) in [
+            (
+                "https://jmap.example.org",
+                Some("https://jmap.example.org/.well-known/jmap"),
+            ),
+            (
+                "http://127.0.0.1:8080/jmap/",
+                Some("http://127.0.0.1:8080/jmap/.well-known/jmap"),
+            ),
+            (
+                "https://[::1]:8443",
+                Some("https://[::1]:8443/.well-known/jmap"),
+            ),
+            ("https:///jmap", None),
+            ("ftp://example.org", None),
+        ] {
+            assert_eq!(session_url(url).as_deref(), expected_url);
+        }
+    }
+
+    #[tokio::test]
+    async fn check_health() {
+        let http = reqwest::Client::new();
+        for (status, is_healthy) in [
+            ("200 OK", true),
+            ("401 Unauthorized", true),
+            ("404 Not Found", false),
+            ("503 Service Unavailable", false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let backend = Backend::new(format!("http://{}", listener.local_addr().unwrap()));
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let bytes_read = stream.read(&mut buf).await.unwrap();
+                    assert!(bytes_read > 0);
+                    request.extend_from_slice(&buf[..bytes_read]);
+                }
+                assert!(request.starts_with(b"GET /.well-known/jmap "));
+                stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            });
+            assert_eq!(backend.check_health(&http).await, is_healthy, "{}", status);
+        }
+
+        // Unreachable backends are down
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::new(format!("http://{}", listener.local_addr().unwrap()));
+        drop(listener);
+        assert!(!backend.check_health(&http).await);
+    }
+}
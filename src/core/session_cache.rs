@@ -0,0 +1,230 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use ahash::AHashMap;
+use jmap_client::client::{Client, Credentials};
+use parking_lot::Mutex;
+use tokio::sync::broadcast;
+
+use super::{
+    download::http_client,
+    mailbox::{Account, MailboxSync},
+    message::MessageChanges,
+    push::PushHub,
+    router::{BackendConnection, Route},
+};
+
+const MAILBOX_CHANGES_BUFFER: usize = 16;
+const MESSAGE_CHANGES_BUFFER: usize = 64;
+
+// JMAP session and mailbox tree shared by all connections of a user.
+pub struct UserSession {
+    pub client: Client,
+    pub http: reqwest::Client,
+    pub mailboxes: Mutex<Vec<Account>>,
+    pub route: Arc<Route>,
+    pub backend: BackendConnection,
+    pub mailbox_queues: MailboxQueues,
+    pub mailbox_changes: broadcast::Sender<u64>,
+    pub message_changes: broadcast::Sender<Arc<MessageChanges>>,
+    pub push: Arc<PushHub>,
+    pub created_at: Instant,
+}
+
+// Mailbox changes not yet reported to each connection of a user.
+#[derive(Default)]
+pub struct MailboxQueues {
+    queues: Mutex<Vec<Weak<Mutex<MailboxSync>>>>,
+}
+
+pub struct SessionCache {
+    pub ttl: Duration,
+    sessions: Mutex<AHashMap<String, Weak<UserSession>>>,
+}
+
+impl UserSession {
+    pub fn new(
+        client: Client,
+        mailboxes: Vec<Account>,
+        route: Arc<Route>,
+        backend: BackendConnection,
+        push: Arc<PushHub>,
+        credentials: &Credentials,
+    ) -> Self {
+        UserSession {
+            client,
+            http: http_client(credentials),
+            mailboxes: Mutex::new(mailboxes),
+            route,
+            backend,
+            mailbox_queues: MailboxQueues::default(),
+            mailbox_changes: broadcast::channel(MAILBOX_CHANGES_BUFFER).0,
+            message_changes: broadcast::channel(MESSAGE_CHANGES_BUFFER).0,
+            push,
+            created_at: Instant::now(),
+        }
+    }
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        SessionCache {
+            ttl,
+            sessions: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    pub fn get(&self, credentials: &Credentials) -> Option<Arc<UserSession>> {
+        if !self.ttl.is_zero() {
+            let key = credentials_key(credentials);
+            let mut sessions = self.sessions.lock();
+            match sessions.get(&key).and_then(|session| session.upgrade()) {
+                Some(session) if session.created_at.elapsed() < self.ttl => Some(session),
+                _ => {
+                    sessions.remove(&key);
+                    None
+                }
+            }
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(&self, credentials: &Credentials, session: &Arc<UserSession>) {
+        if !self.ttl.is_zero() {
+            let mut sessions = self.sessions.lock();
+            sessions.retain(|_, session| session.strong_count() > 0);
+            sessions.insert(credentials_key(credentials), Arc::downgrade(session));
+        }
+    }
+}
+
+impl MailboxQueues {
+    pub fn register(&self) -> Arc<Mutex<MailboxSync>> {
+        let queue = Arc::new(Mutex::new(MailboxSync::default()));
+        let mut queues = self.queues.lock();
+        queues.retain(|queue| queue.strong_count() > 0);
+        queues.push(Arc::downgrade(&queue));
+        queue
+    }
+
+    // Whether any other connection would receive the changes found by this one.
+    pub fn has_listeners(&self, own_queue: &Arc<Mutex<MailboxSync>>) -> bool {
+        self.queues.lock().iter().any(|queue| {
+            queue.strong_count() > 0 && !std::ptr::eq(queue.as_ptr(), Arc::as_ptr(own_queue))
+        })
+    }
+
+    pub fn publish(&self, own_queue: &Arc<Mutex<MailboxSync>>, changes: &MailboxSync) {
+        let mut queues = self.queues.lock();
+        queues.retain(|queue| {
+            if let Some(queue) = queue.upgrade() {
+                if !Arc::ptr_eq(&queue, own_queue) {
+                    queue.lock().merge(changes);
+                }
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+// Sessions are looked up by a digest of the credentials, so a cached session is
+// only reused by clients that present the exact same password or token.
+fn credentials_key(credentials: &Credentials) -> String {
+    match credentials {
+        Credentials::Basic(basic) => format!("b{:x}", md5::compute(basic)),
+        Credentials::Bearer(token) => format!("t{:x}", md5::compute(token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jmap_client::client::Credentials;
+
+    use crate::core::mailbox::MailboxSync;
+
+    use super::{credentials_key, MailboxQueues};
+
+    #[test]
+    fn session_cache_key() {
+        let key = credentials_key(&Credentials::basic("john@example.org", "secret"));
+        assert_eq!(
+            key,
+            credentials_key(&Credentials::basic("john@example.org", "secret"))
+        );
+        assert_ne!(
+            key,
+            credentials_key(&Credentials::basic("john@example.org", "secret2"))
+        );
+        assert_ne!(
+            credentials_key(&Credentials::Basic("abc".to_string())),
+            credentials_key(&Credentials::Bearer("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn session_cache_mailbox_queues() {
+        let queues = MailboxQueues::default();
+        let queue_a = queues.register();
+        assert!(!queues.has_listeners(&queue_a));
+
+        let queue_b = queues.register();
+        assert!(queues.has_listeners(&queue_a));
+
+        // Changes found by one connection are queued for the others only
+        queues.publish(
+            &queue_a,
+            &MailboxSync {
+                added: vec!["Archive".to_string()],
+                changed: vec!["INBOX".to_string()],
+                deleted: vec![],
+            },
+        );
+        queues.publish(
+            &queue_a,
+            &MailboxSync {
+                added: vec![],
+                changed: vec!["INBOX".to_string()],
+                deleted: vec!["Archive".to_string()],
+            },
+        );
+        assert!(queue_a.lock().is_empty());
+        let changes = std::mem::take(&mut *queue_b.lock());
+        assert_eq!(changes.added, Vec::<String>::new());
+        assert_eq!(changes.changed, vec!["INBOX".to_string()]);
+        assert_eq!(changes.deleted, vec!["Archive".to_string()]);
+
+        // Closed connections stop receiving changes
+        drop(queue_b);
+        assert!(!queues.has_listeners(&queue_a));
+        queues.publish(&queue_a, &changes);
+        assert_eq!(queues.queues.lock().len(), 1);
+    }
+}
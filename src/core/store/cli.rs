@@ -0,0 +1,284 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{path::Path, sync::Arc};
+
+use serde_json::{json, Value};
+
+use crate::core::{config::parse_uid_store, env_settings::EnvSettings, message::MailboxId};
+
+use super::{
+    dump::{export_account, import_account},
+    fsck::{connect_jmap, reconcile_account},
+    generate_uid_validity,
+    import::{import_mailbox, read_cyrus_mailbox, read_dovecot_mailbox, read_id_mapping},
+    sled_store::compact_db,
+    UidStore,
+};
+
+const USAGE: &str = "Usage: stalwart-imap cache <command> [<args>] --config=<path>
+
+Commands:
+    accounts                               List the accounts in the cache
+    mailboxes <account>                    List the mailboxes of an account
+    dump <account> [<mailbox>]             Show the UID to JMAP id mappings of a mailbox
+    bump-uidvalidity <account> [<mailbox>] Change the UIDVALIDITY of a mailbox
+    delete-account <account>               Remove all the cached data of an account
+    compact                                Reclaim unused space
+    check                                  Look for inconsistencies in the cache
+    repair                                 Fix the inconsistencies found by check
+    export [<file>]                        Export the cache as JSON
+    import <file>                          Import a cache exported as JSON
+    import-dovecot <account> <mailbox> <maildir> <mapping>
+                                           Import the UIDs of a Dovecot Maildir folder
+    import-cyrus <account> <mailbox> <dir> <mapping>
+                                           Import the UIDs of a Cyrus mailbox directory
+
+Mailboxes are referenced by their JMAP id, omit it to use the All Mail view.
+Accounts reached through a named route are cached as '<route>:<account>'.
+The mapping file of the import commands has one '<message-id or md5> <jmap id>'
+line per message, the MD5 hash of the raw message is used when it has no
+Message-ID.
+When --check-user and --check-password are given, check and repair also
+compare the cached accounts accessible to that user with the JMAP server.
+The server must be stopped before running any of these commands.";
+
+// Offline administration of the UID cache, invoked as `stalwart-imap cache`.
+// The command and its arguments come first, followed by the usual settings.
+pub async fn run_cache_command(args: impl IntoIterator<Item = String>) -> Result<(), String> {
+    let mut args = args.into_iter().peekable();
+    let mut command = Vec::new();
+    while let Some(arg) = args.next_if(|arg| !arg.starts_with("--")) {
+        command.push(arg);
+    }
+    let settings = EnvSettings::try_from_args(args)?;
+    let command = command.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+
+    match command.as_slice() {
+        ["compact"] if settings.get("cache-store").as_deref().unwrap_or("sled") == "sled" => {
+            let (size_before, size_after) = compact_db(Path::new(&cache_dir(&settings)?))?;
+            println!(
+                "Compacted cache from {} to {} bytes.",
+                size_before, size_after
+            );
+            return Ok(());
+        }
+        ["help"] | [] => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let store = open_store(&settings)?;
+    let failed = |_| "Failed to access the cache, check the log for details.".to_string();
+    match command.as_slice() {
+        ["accounts"] => {
+            for account_id in store.account_ids().map_err(failed)? {
+                println!("{}", account_id);
+            }
+        }
+        ["mailboxes", account_id] => {
+            println!(
+                "{:<24} {:>12} {:>10} {:>10}",
+                "MAILBOX", "UIDVALIDITY", "UIDNEXT", "MESSAGES"
+            );
+            for mailbox_id in store.account_mailboxes(account_id).map_err(failed)? {
+                let mailbox = MailboxId {
+                    account_id: account_id.to_string(),
+                    mailbox_id,
+                };
+                println!(
+                    "{:<24} {:>12} {:>10} {:>10}",
+                    mailbox.mailbox_id.as_deref().unwrap_or("(All Mail)"),
+                    display_uid_validity(store.get_uid_validity(&mailbox).map_err(failed)?),
+                    store.uid_next(&mailbox).map_err(failed)?,
+                    store.mailbox_ids(&mailbox).map_err(failed)?.len()
+                );
+            }
+        }
+        ["dump", account_id, mailbox_id @ ..] if mailbox_id.len() <= 1 => {
+            let mailbox = MailboxId {
+                account_id: account_id.to_string(),
+                mailbox_id: mailbox_id.first().map(|id| id.to_string()),
+            };
+            println!(
+                "UIDVALIDITY {}",
+                display_uid_validity(store.get_uid_validity(&mailbox).map_err(failed)?)
+            );
+            println!("UIDNEXT {}", store.uid_next(&mailbox).map_err(failed)?);
+            for (uid, jmap_id) in store.mailbox_ids(&mailbox).map_err(failed)? {
+                println!("{} {}", uid, jmap_id);
+            }
+        }
+        ["bump-uidvalidity", account_id, mailbox_id @ ..] if mailbox_id.len() <= 1 => {
+            let mailbox = MailboxId {
+                account_id: account_id.to_string(),
+                mailbox_id: mailbox_id.first().map(|id| id.to_string()),
+            };
+            let old_uid_validity = store.uid_validity(&mailbox).map_err(failed)?;
+            let uid_validity =
+                std::cmp::max(generate_uid_validity(), old_uid_validity.wrapping_add(1));
+            store
+                .set_uid_validity(&mailbox, uid_validity)
+                .map_err(failed)?;
+            println!(
+                "Changed UIDVALIDITY from {} to {}.",
+                old_uid_validity, uid_validity
+            );
+        }
+        ["delete-account", account_id] => {
+            store.delete_account(account_id).map_err(failed)?;
+            println!("Deleted account {}.", account_id);
+        }
+        ["compact"] => {
+            store.compact().map_err(failed)?;
+            println!("Compacted cache.");
+        }
+        [command @ ("check" | "repair")] => {
+            let repair = *command == "repair";
+            let mut problems = store.verify(repair).map_err(failed)?;
+
+            if let Some((client, route)) = connect_jmap(&settings).await? {
+                let session = client.session();
+                for account_id in store.account_ids().map_err(failed)? {
+                    // Accounts are cached under the route's key of their JMAP id
+                    if let Some(jmap_account_id) = session
+                        .accounts()
+                        .find(|id| route.cache_key(id) == account_id)
+                    {
+                        problems.extend(
+                            reconcile_account(
+                                &client,
+                                store.as_ref(),
+                                &route,
+                                jmap_account_id,
+                                repair,
+                            )
+                            .await?,
+                        );
+                    } else {
+                        println!("Skipped account {}, not accessible to user.", account_id);
+                    }
+                }
+            }
+
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if problems.is_empty() {
+                println!("No problems found.");
+            } else if repair {
+                println!("Repaired {} problems.", problems.len());
+            } else {
+                println!(
+                    "Found {} problems, run 'repair' to fix them.",
+                    problems.len()
+                );
+            }
+        }
+        ["export", path @ ..] if path.len() <= 1 => {
+            let mut accounts = Vec::new();
+            for account_id in store.account_ids().map_err(failed)? {
+                accounts.push(export_account(store.as_ref(), &account_id).map_err(failed)?);
+            }
+            let export = serde_json::to_string_pretty(&json!({ "accounts": accounts }))
+                .map_err(|err| format!("Failed to serialize cache: {}", err))?;
+            if let Some(path) = path.first() {
+                std::fs::write(path, export)
+                    .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+                println!("Exported {} accounts to {}.", accounts.len(), path);
+            } else {
+                println!("{}", export);
+            }
+        }
+        ["import", path] => {
+            let export = serde_json::from_slice::<Value>(
+                &std::fs::read(path).map_err(|err| format!("Failed to read {}: {}", path, err))?,
+            )
+            .map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+            let accounts = export["accounts"]
+                .as_array()
+                .ok_or("Missing 'accounts' in export.")?;
+            for account in accounts {
+                import_account(store.as_ref(), account)?;
+            }
+            println!("Imported {} accounts from {}.", accounts.len(), path);
+        }
+        [command @ ("import-dovecot" | "import-cyrus"), account_id, mailbox_id, path, mapping] => {
+            let source = if *command == "import-dovecot" {
+                read_dovecot_mailbox(Path::new(path))?
+            } else {
+                read_cyrus_mailbox(Path::new(path))?
+            };
+            let result = import_mailbox(
+                store.as_ref(),
+                &MailboxId {
+                    account_id: account_id.to_string(),
+                    mailbox_id: mailbox_id.to_string().into(),
+                },
+                &source,
+                &read_id_mapping(Path::new(mapping))?,
+            )?;
+            for path in &result.unmatched {
+                println!("No JMAP id found for {}", path.display());
+            }
+            for path in &result.duplicates {
+                println!("Duplicate JMAP id for {}", path.display());
+            }
+            println!(
+                "Imported {} of {} messages with UIDVALIDITY {}.",
+                result.imported,
+                source.messages.len(),
+                source.uid_validity
+            );
+        }
+        _ => return Err(format!("Invalid command.\n\n{}", USAGE)),
+    }
+
+    store.flush().map_err(failed)
+}
+
+fn cache_dir(settings: &EnvSettings) -> Result<String, String> {
+    settings
+        .get("cache-dir")
+        .ok_or_else(|| "Missing 'cache-dir' parameter.".to_string())
+}
+
+fn open_store(settings: &EnvSettings) -> Result<Arc<dyn UidStore>, String> {
+    if settings.get("cache-store").as_deref() == Some("memory") {
+        return Err("The memory cache store cannot be accessed offline.".to_string());
+    }
+    let cache_dir = cache_dir(settings)?;
+    let db = Arc::new(sled::open(&cache_dir).map_err(|err| {
+        format!(
+            "Failed to open cache at {} (is the server running?): {}",
+            cache_dir, err
+        )
+    })?);
+    parse_uid_store(settings, &db)
+}
+
+// Mailboxes that were never selected have no UIDVALIDITY yet.
+fn display_uid_validity(uid_validity: Option<u32>) -> String {
+    uid_validity.map_or_else(|| "-".to_string(), |uid_validity| uid_validity.to_string())
+}
@@ -0,0 +1,232 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use serde_json::{json, Value};
+
+use crate::core::message::MailboxId;
+
+use super::UidStore;
+
+// Exports the UIDs, query states and MODSEQs of an account as JSON.
+// Deleted ids are not exported as they are only kept for a limited time.
+#[allow(clippy::result_unit_err)]
+pub fn export_account(store: &dyn UidStore, account_id: &str) -> Result<Value, ()> {
+    let mut mailboxes = Vec::new();
+    for mailbox_id in store.account_mailboxes(account_id)? {
+        let mailbox = MailboxId {
+            account_id: account_id.to_string(),
+            mailbox_id,
+        };
+        mailboxes.push(json!({
+            "mailbox_id": mailbox.mailbox_id,
+            "uid_validity": store.get_uid_validity(&mailbox)?,
+            "uid_next": store.uid_next(&mailbox)?,
+            "query_state": store.query_state(&mailbox)?,
+            "ids": store.mailbox_ids(&mailbox)?,
+        }));
+    }
+
+    let mut modseqs = Vec::new();
+    for modseq in 1..=store.highest_modseq(account_id)? {
+        if let Some(state) = store.modseq_to_state(account_id, modseq)? {
+            modseqs.push(json!([modseq, state]));
+        }
+    }
+
+    Ok(json!({
+        "account_id": account_id,
+        "mailboxes": mailboxes,
+        "modseqs": modseqs,
+        "last_login": store.last_login(account_id)?,
+    }))
+}
+
+struct MailboxDump<'x> {
+    mailbox_id: MailboxId,
+    uid_validity: Option<u32>,
+    uid_next: u32,
+    query_state: Option<&'x str>,
+    ids: Vec<(u32, &'x str)>,
+}
+
+// Replaces the data of an account with an export produced by export_account.
+// The whole export is validated first so a malformed one leaves the account
+// untouched.
+pub fn import_account(store: &dyn UidStore, account: &Value) -> Result<String, String> {
+    let account_id = account["account_id"]
+        .as_str()
+        .ok_or("Missing 'account_id' in account.")?;
+    let invalid = |field: &str| format!("Invalid '{}' in account {}.", field, account_id);
+    let failed = |_| format!("Failed to import account {}.", account_id);
+
+    let mut mailboxes = Vec::new();
+    for mailbox in account["mailboxes"]
+        .as_array()
+        .ok_or_else(|| invalid("mailboxes"))?
+    {
+        mailboxes.push(MailboxDump {
+            mailbox_id: MailboxId {
+                account_id: account_id.to_string(),
+                mailbox_id: match &mailbox["mailbox_id"] {
+                    Value::String(mailbox_id) => Some(mailbox_id.to_string()),
+                    Value::Null => None,
+                    _ => return Err(invalid("mailbox_id")),
+                },
+            },
+            // Mailboxes that were never selected have no UIDVALIDITY
+            uid_validity: match &mailbox["uid_validity"] {
+                Value::Null => None,
+                uid_validity => {
+                    Some(parse_u32(uid_validity).ok_or_else(|| invalid("uid_validity"))?)
+                }
+            },
+            uid_next: parse_u32(&mailbox["uid_next"]).ok_or_else(|| invalid("uid_next"))?,
+            query_state: match &mailbox["query_state"] {
+                Value::String(query_state) => Some(query_state.as_str()),
+                Value::Null => None,
+                _ => return Err(invalid("query_state")),
+            },
+            ids: mailbox["ids"]
+                .as_array()
+                .ok_or_else(|| invalid("ids"))?
+                .iter()
+                .map(parse_pair)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| invalid("ids"))?,
+        });
+    }
+
+    let modseqs = account["modseqs"]
+        .as_array()
+        .ok_or_else(|| invalid("modseqs"))?
+        .iter()
+        .map(parse_pair)
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("modseqs"))?;
+
+    // Exports made before logins were recorded have no last_login
+    let last_login = match &account["last_login"] {
+        Value::Null => None,
+        last_login => Some(last_login.as_u64().ok_or_else(|| invalid("last_login"))?),
+    };
+
+    store.delete_account(account_id).map_err(failed)?;
+
+    for mailbox in mailboxes {
+        if let Some(uid_validity) = mailbox.uid_validity {
+            store
+                .set_uid_validity(&mailbox.mailbox_id, uid_validity)
+                .map_err(failed)?;
+        }
+        for (uid, jmap_id) in mailbox.ids {
+            store
+                .insert_uid(&mailbox.mailbox_id, uid, jmap_id)
+                .map_err(failed)?;
+        }
+        store
+            .set_uid_next(&mailbox.mailbox_id, mailbox.uid_next)
+            .map_err(failed)?;
+        if let Some(query_state) = mailbox.query_state {
+            store
+                .set_query_state(&mailbox.mailbox_id, query_state.into())
+                .map_err(failed)?;
+        }
+    }
+
+    for (modseq, state) in modseqs {
+        store
+            .insert_modseq(account_id, modseq, state)
+            .map_err(failed)?;
+    }
+
+    if let Some(last_login) = last_login {
+        store
+            .set_last_login(account_id, last_login)
+            .map_err(failed)?;
+    }
+
+    Ok(account_id.to_string())
+}
+
+fn parse_u32(value: &Value) -> Option<u32> {
+    value.as_u64().and_then(|value| u32::try_from(value).ok())
+}
+
+fn parse_pair(value: &Value) -> Option<(u32, &str)> {
+    match value.as_array()?.as_slice() {
+        [number, string] => Some((parse_u32(number)?, string.as_str()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::{message::MailboxId, store::memory_store::MemoryStore, store::UidStore};
+
+    use super::{export_account, import_account};
+
+    #[test]
+    fn export_import_account() {
+        let store = MemoryStore::default();
+        let inbox = MailboxId {
+            account_id: "john".to_string(),
+            mailbox_id: "inbox".to_string().into(),
+        };
+        store.set_uid_validity(&inbox, 1234).unwrap();
+        for jmap_id in ["a", "b", "c"] {
+            store.insert_jmap_id(&inbox, jmap_id).unwrap();
+        }
+        store.delete_ids(&inbox, &["b".to_string()]).unwrap();
+        store.insert_jmap_id(&inbox, "d").unwrap();
+        store.set_query_state(&inbox, "q1".into()).unwrap();
+        store.state_to_modseq("john", "s1").unwrap();
+        store.state_to_modseq("john", "s2").unwrap();
+        store.set_last_login("john", 1000).unwrap();
+        let all_mail = MailboxId {
+            account_id: "john".to_string(),
+            mailbox_id: None,
+        };
+        store.insert_jmap_id(&all_mail, "a").unwrap();
+
+        let export = export_account(&store, "john").unwrap();
+        let imported_store = MemoryStore::default();
+        assert_eq!(import_account(&imported_store, &export).unwrap(), "john");
+        assert_eq!(export_account(&imported_store, "john").unwrap(), export);
+        assert_eq!(imported_store.uid_next(&inbox).unwrap(), 5);
+        assert_eq!(
+            imported_store.uid_to_jmap(&inbox, 4).unwrap().as_deref(),
+            Some("d")
+        );
+        assert_eq!(imported_store.state_to_modseq("john", "s3").unwrap(), 3);
+
+        // Malformed exports are rejected without touching the account
+        let export = export_account(&imported_store, "john").unwrap();
+        let mut malformed = export.clone();
+        let last = malformed["mailboxes"].as_array().unwrap().len() - 1;
+        malformed["mailboxes"][last]["ids"][0] = serde_json::json!(["a", 1]);
+        assert!(import_account(&imported_store, &malformed).is_err());
+        let mut malformed = export.clone();
+        malformed["last_login"] = serde_json::json!("yesterday");
+        assert!(import_account(&imported_store, &malformed).is_err());
+        assert_eq!(export_account(&imported_store, "john").unwrap(), export);
+    }
+}
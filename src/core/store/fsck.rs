@@ -0,0 +1,230 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::sync::Arc;
+
+use ahash::AHashSet;
+use jmap_client::{
+    client::{Client, Credentials},
+    email::query::Filter,
+};
+
+use crate::core::{
+    config::parse_router,
+    env_settings::EnvSettings,
+    message::MailboxId,
+    router::{clone_credentials, Route},
+};
+
+use super::{mailbox_name, UidStore};
+
+// Connects to the JMAP server with the credentials passed in the
+// check-user and check-password settings, if any.
+pub async fn connect_jmap(settings: &EnvSettings) -> Result<Option<(Client, Arc<Route>)>, String> {
+    let credentials = match (settings.get("check-user"), settings.get("check-password")) {
+        (Some(user), Some(password)) => Credentials::basic(&user, &password),
+        _ => return Ok(None),
+    };
+    let route = parse_router(settings)?.route(&credentials);
+    let mut last_err = "No JMAP backends configured.".to_string();
+
+    for backend in route.backends() {
+        match Client::new()
+            .follow_redirects(&route.trusted_hosts)
+            .credentials(clone_credentials(&credentials))
+            .connect(&backend.url)
+            .await
+        {
+            Ok(client) => return Ok(Some((client, route))),
+            Err(err) => {
+                last_err = format!("Failed to connect to {}: {}", backend.url, err);
+            }
+        }
+    }
+
+    Err(last_err)
+}
+
+// Compares the cached UIDs of an account with the contents of its mailboxes on
+// the JMAP server. Cached ids of messages that no longer exist are reported
+// and, when repairing, removed so the next session synchronizes all ids.
+// The account is cached under the route's key for its JMAP id.
+pub async fn reconcile_account(
+    client: &Client,
+    store: &dyn UidStore,
+    route: &Route,
+    account_id: &str,
+    repair: bool,
+) -> Result<Vec<String>, String> {
+    let failed = |_| "Failed to access the cache, check the log for details.".to_string();
+    let cache_account_id = route.cache_key(account_id);
+    let jmap_mailbox_ids = query_mailbox_ids(client, account_id).await?;
+    let mut problems = Vec::new();
+    let mut has_deleted_mailboxes = false;
+
+    for mailbox_id in store.account_mailboxes(&cache_account_id).map_err(failed)? {
+        let mailbox = MailboxId {
+            account_id: cache_account_id.clone(),
+            mailbox_id,
+        };
+        if mailbox
+            .mailbox_id
+            .as_ref()
+            .map_or(false, |mailbox_id| !jmap_mailbox_ids.contains(mailbox_id))
+        {
+            problems.push(format!(
+                "{}: Mailbox no longer exists.",
+                mailbox_name(&mailbox)
+            ));
+            has_deleted_mailboxes = true;
+            continue;
+        }
+
+        let jmap_ids = query_email_ids(client, account_id, &mailbox).await?;
+        let phantom_ids = store
+            .mailbox_ids(&mailbox)
+            .map_err(failed)?
+            .into_iter()
+            .filter_map(|(_, jmap_id)| {
+                if !jmap_ids.contains(&jmap_id) {
+                    Some(jmap_id)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if !phantom_ids.is_empty() {
+            problems.push(format!(
+                "{}: {} cached messages no longer exist.",
+                mailbox_name(&mailbox),
+                phantom_ids.len()
+            ));
+            if repair {
+                store.delete_ids(&mailbox, &phantom_ids).map_err(failed)?;
+                store.set_query_state(&mailbox, None).map_err(failed)?;
+            }
+        }
+    }
+
+    if repair && has_deleted_mailboxes {
+        store
+            .purge_deleted_mailboxes(&cache_account_id, &jmap_mailbox_ids)
+            .map_err(failed)?;
+    }
+
+    Ok(problems)
+}
+
+async fn query_mailbox_ids(client: &Client, account_id: &str) -> Result<AHashSet<String>, String> {
+    let mut mailbox_ids = AHashSet::new();
+    let mut position = 0;
+    let mut total_mailboxes = 0;
+
+    for _ in 0..100 {
+        let mut request = client.build().account_id(account_id);
+        request
+            .query_mailbox()
+            .calculate_total(true)
+            .position(position);
+        let mut response = request
+            .send_query_mailbox()
+            .await
+            .map_err(|err| format!("Failed to query mailboxes of {}: {}", account_id, err))?;
+        total_mailboxes = response.total().unwrap_or(0);
+        let ids = response.take_ids();
+
+        let ids_len = ids.len();
+        if ids_len > 0 {
+            mailbox_ids.extend(ids);
+            if mailbox_ids.len() < total_mailboxes {
+                position += ids_len as i32;
+                continue;
+            }
+        }
+        break;
+    }
+
+    // Missing mailboxes would be reported as deleted
+    if mailbox_ids.len() < total_mailboxes {
+        return Err(format!(
+            "Failed to query mailboxes of {}: got {} out of {} ids.",
+            account_id,
+            mailbox_ids.len(),
+            total_mailboxes
+        ));
+    }
+
+    Ok(mailbox_ids)
+}
+
+// Queries the messages of a cached mailbox from the JMAP account it belongs to.
+async fn query_email_ids(
+    client: &Client,
+    account_id: &str,
+    mailbox: &MailboxId,
+) -> Result<AHashSet<String>, String> {
+    let mut email_ids = AHashSet::new();
+    let mut position = 0;
+    let mut total_messages = 0;
+
+    for _ in 0..100 {
+        let mut request = client.build().account_id(account_id);
+        let query_request = request
+            .query_email()
+            .calculate_total(true)
+            .position(position);
+        if let Some(mailbox_id) = &mailbox.mailbox_id {
+            query_request.filter(Filter::in_mailbox(mailbox_id));
+        }
+        let mut response = request.send_query_email().await.map_err(|err| {
+            format!(
+                "Failed to query messages of {}: {}",
+                mailbox_name(mailbox),
+                err
+            )
+        })?;
+        total_messages = response.total().unwrap_or(0);
+        let ids = response.take_ids();
+
+        let ids_len = ids.len();
+        if ids_len > 0 {
+            email_ids.extend(ids);
+            if email_ids.len() < total_messages {
+                position += ids_len as i32;
+                continue;
+            }
+        }
+        break;
+    }
+
+    // Missing messages would be reported as deleted
+    if email_ids.len() < total_messages {
+        return Err(format!(
+            "Failed to query messages of {}: got {} out of {} ids.",
+            mailbox_name(mailbox),
+            email_ids.len(),
+            total_messages
+        ));
+    }
+
+    Ok(email_ids)
+}
@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ahash::{AHashMap, AHashSet};
+use mail_parser::Message;
+
+use crate::core::message::MailboxId;
+
+use super::UidStore;
+
+// A mailbox of the server being migrated from, with the path of each message.
+#[derive(Debug, Default)]
+pub struct SourceMailbox {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+    pub messages: Vec<(u32, PathBuf)>,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportResult {
+    pub imported: usize,
+    pub unmatched: Vec<PathBuf>,
+    pub duplicates: Vec<PathBuf>,
+}
+
+// Reads the dovecot-uidlist file of a Maildir folder and locates the file of
+// each message in its cur and new directories.
+pub fn read_dovecot_mailbox(maildir: &Path) -> Result<SourceMailbox, String> {
+    let uidlist_path = maildir.join("dovecot-uidlist");
+    let uidlist = fs::read_to_string(&uidlist_path)
+        .map_err(|err| format!("Failed to read {}: {}", uidlist_path.display(), err))?;
+    let invalid = |line: &str| format!("Invalid line in {}: {}", uidlist_path.display(), line);
+
+    // Index the messages by their base file name, which excludes the flags.
+    let mut files = AHashMap::new();
+    for dir in ["cur", "new"] {
+        let dir = maildir.join(dir);
+        if !dir.exists() {
+            continue;
+        }
+        for entry in fs::read_dir(&dir)
+            .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?
+        {
+            let path = entry
+                .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?
+                .path();
+            if let Some(file_name) = path.file_name().and_then(|name| name.to_str()) {
+                files.insert(
+                    file_name.split(':').next().unwrap_or_default().to_string(),
+                    path.clone(),
+                );
+            }
+        }
+    }
+
+    let mut lines = uidlist.lines();
+    let header = lines.next().ok_or_else(|| invalid(""))?;
+    let mut mailbox = SourceMailbox::default();
+    let mut header_fields = header.split_ascii_whitespace();
+    let is_v1 = header_fields.next() == Some("1");
+    if is_v1 {
+        // Version 1 headers are "1 <uidvalidity> <nextuid>"
+        mailbox.uid_validity = header_fields
+            .next()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid(header))?;
+        mailbox.uid_next = header_fields
+            .next()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid(header))?;
+    } else {
+        for field in header_fields {
+            if let Some(value) = field.strip_prefix('V') {
+                mailbox.uid_validity = value.parse().map_err(|_| invalid(header))?;
+            } else if let Some(value) = field.strip_prefix('N') {
+                mailbox.uid_next = value.parse().map_err(|_| invalid(header))?;
+            }
+        }
+    }
+    if mailbox.uid_validity == 0 {
+        return Err(invalid(header));
+    }
+
+    // Records are "<uid> <filename>" in version 1 and
+    // "<uid> [<extension fields>] :<filename>" in later versions.
+    for line in lines {
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        let (uid, fields) = line.split_once(' ').ok_or_else(|| invalid(line))?;
+        let uid = uid.parse::<u32>().map_err(|_| invalid(line))?;
+        let file_name = if is_v1 {
+            fields.trim()
+        } else if let Some(file_name) = fields.strip_prefix(':') {
+            file_name
+        } else {
+            fields
+                .split_once(" :")
+                .map(|(_, file_name)| file_name)
+                .ok_or_else(|| invalid(line))?
+        };
+        if let Some(path) = files.remove(file_name.split(':').next().unwrap_or_default()) {
+            mailbox.messages.push((uid, path));
+        }
+        mailbox.uid_next = std::cmp::max(mailbox.uid_next, uid + 1);
+    }
+
+    Ok(mailbox)
+}
+
+// Reads the UIDVALIDITY and last UID from the header of cyrus.index and
+// lists the message files of a Cyrus mailbox directory, named "<uid>.".
+pub fn read_cyrus_mailbox(dir: &Path) -> Result<SourceMailbox, String> {
+    const OFFSET_LAST_UID: usize = 28;
+    const OFFSET_UIDVALIDITY: usize = 44;
+
+    let index_path = dir.join("cyrus.index");
+    let index = fs::read(&index_path)
+        .map_err(|err| format!("Failed to read {}: {}", index_path.display(), err))?;
+    let read_u32 = |offset: usize| {
+        index
+            .get(offset..offset + 4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| format!("Invalid index file {}.", index_path.display()))
+    };
+    let mut mailbox = SourceMailbox {
+        uid_validity: read_u32(OFFSET_UIDVALIDITY)?,
+        uid_next: read_u32(OFFSET_LAST_UID)? + 1,
+        messages: Vec::new(),
+    };
+
+    for entry in
+        fs::read_dir(dir).map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?
+    {
+        let path = entry
+            .map_err(|err| format!("Failed to read {}: {}", dir.display(), err))?
+            .path();
+        if let Some(uid) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_suffix('.'))
+            .and_then(|uid| uid.parse::<u32>().ok())
+        {
+            mailbox.uid_next = std::cmp::max(mailbox.uid_next, uid + 1);
+            mailbox.messages.push((uid, path));
+        }
+    }
+    mailbox.messages.sort_unstable_by_key(|(uid, _)| *uid);
+
+    Ok(mailbox)
+}
+
+// Reads a file mapping Message-IDs, or the MD5 hash of messages without one,
+// to JMAP email ids. Each line contains "<message-id or md5> <jmap id>".
+pub fn read_id_mapping(path: &Path) -> Result<AHashMap<String, String>, String> {
+    let mut mapping = AHashMap::new();
+    for line in fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?
+        .lines()
+    {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_ascii_whitespace().collect::<Vec<_>>().as_slice() {
+            [key, jmap_id] => {
+                mapping.insert(
+                    key.trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string(),
+                    jmap_id.to_string(),
+                );
+            }
+            _ => return Err(format!("Invalid line in {}: {}", path.display(), line)),
+        }
+    }
+    Ok(mapping)
+}
+
+// Replaces the UIDs of a mailbox with the ones of the source mailbox, so
+// clients keep their caches after the migration. Messages that cannot be
+// mapped to a JMAP id get new UIDs when the mailbox is next synchronized.
+pub fn import_mailbox(
+    store: &dyn UidStore,
+    mailbox: &MailboxId,
+    source: &SourceMailbox,
+    mapping: &AHashMap<String, String>,
+) -> Result<ImportResult, String> {
+    let failed = |_| format!("Failed to import mailbox {:?}.", mailbox.mailbox_id);
+    let mut result = ImportResult::default();
+    let mut jmap_ids = AHashSet::new();
+
+    if let Some(mailbox_id) = &mailbox.mailbox_id {
+        store
+            .delete_mailbox(&mailbox.account_id, mailbox_id)
+            .map_err(failed)?;
+    }
+    store
+        .set_uid_validity(mailbox, source.uid_validity)
+        .map_err(failed)?;
+
+    for (uid, path) in &source.messages {
+        let raw_message =
+            fs::read(path).map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+        let jmap_id = Message::parse(&raw_message)
+            .and_then(|message| message.get_message_id().map(|id| id.to_string()))
+            .and_then(|message_id| mapping.get(&message_id))
+            .or_else(|| mapping.get(&format!("{:x}", md5::compute(&raw_message))));
+
+        match jmap_id {
+            Some(jmap_id) if jmap_ids.insert(jmap_id) => {
+                store.insert_uid(mailbox, *uid, jmap_id).map_err(failed)?;
+                result.imported += 1;
+            }
+            Some(_) => result.duplicates.push(path.clone()),
+            None => result.unmatched.push(path.clone()),
+        }
+    }
+
+    // Synchronize all ids on the next session instead of using a query state
+    store
+        .set_uid_next(mailbox, source.uid_next)
+        .map_err(failed)?;
+    store.set_query_state(mailbox, None).map_err(failed)?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::core::{message::MailboxId, store::memory_store::MemoryStore, store::UidStore};
+
+    use super::{import_mailbox, read_cyrus_mailbox, read_dovecot_mailbox, read_id_mapping};
+
+    #[test]
+    fn import_dovecot_cyrus() {
+        let temp_dir = std::env::temp_dir().join("stalwart-imap-import-test");
+        if temp_dir.exists() {
+            fs::remove_dir_all(&temp_dir).unwrap();
+        }
+        let maildir = temp_dir.join("maildir");
+        let cyrus_dir = temp_dir.join("cyrus");
+        for dir in [maildir.join("cur"), maildir.join("new"), cyrus_dir.clone()] {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        let messages = [
+            "Message-ID: <first@example.com>\r\nSubject: 1\r\n\r\nOne\r\n",
+            "Message-ID: <second@example.com>\r\nSubject: 2\r\n\r\nTwo\r\n",
+            "Subject: 3\r\n\r\nThree\r\n",
+            "Message-ID: <unknown@example.com>\r\nSubject: 4\r\n\r\nFour\r\n",
+            "Message-ID: <first@example.com>\r\nSubject: 5\r\n\r\nOne again\r\n",
+        ];
+        let mapping_path = temp_dir.join("mapping.txt");
+        fs::write(
+            &mapping_path,
+            format!(
+                "# Message-ID or MD5 to JMAP id\n<first@example.com> a\nsecond@example.com b\n{:x} c\n",
+                md5::compute(messages[2])
+            ),
+        )
+        .unwrap();
+        let mapping = read_id_mapping(&mapping_path).unwrap();
+        assert_eq!(mapping.len(), 3);
+
+        // Dovecot uidlist in version 3 format
+        let mut uidlist = "3 V1276528487 N20 Gb5c0b5c1d5a3d8492a000078b0bbd8d4\n".to_string();
+        for (pos, message) in messages.iter().enumerate() {
+            let file_name = format!("1276528487.M{}P1.host,S={}", pos, message.len());
+            fs::write(
+                maildir
+                    .join(if pos < 3 { "cur" } else { "new" })
+                    .join(format!("{}:2,S", file_name)),
+                message,
+            )
+            .unwrap();
+            uidlist.push_str(&format!(
+                "{} W{} :{}\n",
+                pos * 2 + 3,
+                message.len(),
+                file_name
+            ));
+        }
+        fs::write(maildir.join("dovecot-uidlist"), uidlist).unwrap();
+
+        let source = read_dovecot_mailbox(&maildir).unwrap();
+        assert_eq!(source.uid_validity, 1276528487);
+        assert_eq!(source.uid_next, 20);
+        assert_eq!(source.messages.len(), 5);
+
+        let store = MemoryStore::default();
+        let mailbox = MailboxId {
+            account_id: "john".to_string(),
+            mailbox_id: "inbox".to_string().into(),
+        };
+        store.insert_jmap_id(&mailbox, "x").unwrap();
+        let result = import_mailbox(&store, &mailbox, &source, &mapping).unwrap();
+        assert_eq!(result.imported, 3);
+        assert_eq!(result.unmatched.len(), 1);
+        assert_eq!(result.duplicates.len(), 1);
+        assert_eq!(store.uid_validity(&mailbox).unwrap(), 1276528487);
+        assert_eq!(store.uid_next(&mailbox).unwrap(), 20);
+        assert_eq!(
+            store.mailbox_ids(&mailbox).unwrap(),
+            vec![
+                (3, "a".to_string()),
+                (5, "b".to_string()),
+                (7, "c".to_string())
+            ]
+        );
+
+        // Cyrus mailbox with UIDVALIDITY 1234 and last UID 8
+        let mut index = vec![0u8; 96];
+        index[28..32].copy_from_slice(&8u32.to_be_bytes());
+        index[44..48].copy_from_slice(&1234u32.to_be_bytes());
+        fs::write(cyrus_dir.join("cyrus.index"), index).unwrap();
+        for (uid, message) in [(2, messages[1]), (6, messages[2])] {
+            fs::write(cyrus_dir.join(format!("{}.", uid)), message).unwrap();
+        }
+        let source = read_cyrus_mailbox(&cyrus_dir).unwrap();
+        assert_eq!(source.uid_validity, 1234);
+        assert_eq!(source.uid_next, 9);
+
+        let result = import_mailbox(&store, &mailbox, &source, &mapping).unwrap();
+        assert_eq!(result.imported, 2);
+        assert_eq!(store.uid_validity(&mailbox).unwrap(), 1234);
+        assert_eq!(
+            store.mailbox_ids(&mailbox).unwrap(),
+            vec![(2, "b".to_string()), (6, "c".to_string())]
+        );
+        assert_eq!(store.uid_next(&mailbox).unwrap(), 9);
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
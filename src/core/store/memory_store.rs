@@ -0,0 +1,362 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::collections::BTreeMap;
+
+use ahash::{AHashMap, AHashSet};
+use parking_lot::Mutex;
+
+use crate::core::message::MailboxId;
+
+use super::{generate_uid_validity, is_expired, now, UidStore};
+
+// Keeps the mappings in memory, they are lost when the server stops.
+// Intended for testing and for deployments where clients can cope with
+// UIDVALIDITY changing on every restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    accounts: Mutex<AHashMap<String, AccountUids>>,
+}
+
+#[derive(Default)]
+struct AccountUids {
+    mailboxes: AHashMap<Option<String>, MailboxUids>,
+    highest_modseq: u32,
+    state_to_modseq: AHashMap<String, u32>,
+    modseq_to_state: AHashMap<u32, String>,
+    last_login: Option<u64>,
+}
+
+#[derive(Default)]
+struct MailboxUids {
+    uid_validity: Option<u32>,
+    last_uid: u32,
+    jmap_to_uid: AHashMap<String, u32>,
+    uid_to_jmap: BTreeMap<u32, String>,
+    deleted_ids: AHashMap<String, (u32, u64)>,
+    query_state: Option<String>,
+}
+
+impl MemoryStore {
+    fn with_mailbox<T>(&self, mailbox: &MailboxId, f: impl FnOnce(&mut MailboxUids) -> T) -> T {
+        f(self
+            .accounts
+            .lock()
+            .entry(mailbox.account_id.clone())
+            .or_default()
+            .mailboxes
+            .entry(mailbox.mailbox_id.clone())
+            .or_default())
+    }
+
+    fn read_mailbox<T>(&self, mailbox: &MailboxId, f: impl FnOnce(&MailboxUids) -> T) -> Option<T> {
+        self.accounts
+            .lock()
+            .get(&mailbox.account_id)
+            .and_then(|account| account.mailboxes.get(&mailbox.mailbox_id))
+            .map(f)
+    }
+}
+
+impl MailboxUids {
+    fn clear_uids(&mut self) {
+        *self = MailboxUids {
+            deleted_ids: std::mem::take(&mut self.deleted_ids),
+            ..Default::default()
+        };
+    }
+}
+
+impl UidStore for MemoryStore {
+    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        Ok(self.with_mailbox(mailbox, |uids| {
+            *uids.uid_validity.get_or_insert_with(generate_uid_validity)
+        }))
+    }
+
+    fn get_uid_validity(&self, mailbox: &MailboxId) -> Result<Option<u32>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| uids.uid_validity)
+            .flatten())
+    }
+
+    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| uids.last_uid + 1)
+            .unwrap_or(1))
+    }
+
+    fn insert_jmap_id(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<u32, ()> {
+        Ok(self.with_mailbox(mailbox, |uids| {
+            if let Some(uid) = uids.jmap_to_uid.get(jmap_id) {
+                return *uid;
+            }
+            uids.last_uid += 1;
+            let uid = uids.last_uid;
+            uids.jmap_to_uid.insert(jmap_id.to_string(), uid);
+            uids.uid_to_jmap.insert(uid, jmap_id.to_string());
+            uid
+        }))
+    }
+
+    fn jmap_to_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| uids.jmap_to_uid.get(jmap_id).copied())
+            .flatten())
+    }
+
+    fn uid_to_jmap(&self, mailbox: &MailboxId, uid: u32) -> Result<Option<String>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| uids.uid_to_jmap.get(&uid).cloned())
+            .flatten())
+    }
+
+    fn deleted_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| {
+                uids.deleted_ids.get(jmap_id).map(|(uid, _)| *uid)
+            })
+            .flatten())
+    }
+
+    fn mailbox_ids(&self, mailbox: &MailboxId) -> Result<Vec<(u32, String)>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| {
+                uids.uid_to_jmap
+                    .iter()
+                    .map(|(uid, jmap_id)| (*uid, jmap_id.clone()))
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn delete_ids(&self, mailbox: &MailboxId, jmap_ids: &[String]) -> Result<(), ()> {
+        let deleted_at = now();
+        self.with_mailbox(mailbox, |uids| {
+            for jmap_id in jmap_ids {
+                if let Some(uid) = uids.jmap_to_uid.remove(jmap_id) {
+                    uids.uid_to_jmap.remove(&uid);
+                    uids.deleted_ids.insert(jmap_id.clone(), (uid, deleted_at));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn query_state(&self, mailbox: &MailboxId) -> Result<Option<String>, ()> {
+        Ok(self
+            .read_mailbox(mailbox, |uids| uids.query_state.clone())
+            .flatten())
+    }
+
+    fn set_query_state(&self, mailbox: &MailboxId, query_state: Option<&str>) -> Result<(), ()> {
+        self.with_mailbox(mailbox, |uids| {
+            uids.query_state = query_state.map(|query_state| query_state.to_string());
+        });
+        Ok(())
+    }
+
+    fn state_to_modseq(&self, account_id: &str, state: &str) -> Result<u32, ()> {
+        let mut accounts = self.accounts.lock();
+        let account = accounts.entry(account_id.to_string()).or_default();
+        Ok(if let Some(modseq) = account.state_to_modseq.get(state) {
+            *modseq
+        } else {
+            account.highest_modseq += 1;
+            let modseq = account.highest_modseq;
+            account.state_to_modseq.insert(state.to_string(), modseq);
+            account.modseq_to_state.insert(modseq, state.to_string());
+            modseq
+        })
+    }
+
+    fn modseq_to_state(&self, account_id: &str, modseq: u32) -> Result<Option<String>, ()> {
+        Ok(self
+            .accounts
+            .lock()
+            .get(account_id)
+            .and_then(|account| account.modseq_to_state.get(&modseq).cloned()))
+    }
+
+    fn highest_modseq(&self, account_id: &str) -> Result<u32, ()> {
+        Ok(self
+            .accounts
+            .lock()
+            .get(account_id)
+            .map_or(0, |account| account.highest_modseq))
+    }
+
+    fn insert_modseq(&self, account_id: &str, modseq: u32, state: &str) -> Result<(), ()> {
+        let mut accounts = self.accounts.lock();
+        let account = accounts.entry(account_id.to_string()).or_default();
+        if let Some(old_state) = account.modseq_to_state.insert(modseq, state.to_string()) {
+            account.state_to_modseq.remove(&old_state);
+        }
+        if let Some(old_modseq) = account.state_to_modseq.insert(state.to_string(), modseq) {
+            if old_modseq != modseq {
+                account.modseq_to_state.remove(&old_modseq);
+            }
+        }
+        account.highest_modseq = std::cmp::max(account.highest_modseq, modseq);
+        Ok(())
+    }
+
+    fn account_ids(&self) -> Result<Vec<String>, ()> {
+        let mut account_ids = self.accounts.lock().keys().cloned().collect::<Vec<_>>();
+        account_ids.sort_unstable();
+        Ok(account_ids)
+    }
+
+    fn account_mailboxes(&self, account_id: &str) -> Result<Vec<Option<String>>, ()> {
+        let mut mailbox_ids = self
+            .accounts
+            .lock()
+            .get(account_id)
+            .map(|account| {
+                account
+                    .mailboxes
+                    .iter()
+                    .filter(|(_, uids)| {
+                        uids.uid_validity.is_some()
+                            || uids.last_uid > 0
+                            || uids.query_state.is_some()
+                    })
+                    .map(|(mailbox_id, _)| mailbox_id.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        mailbox_ids.sort_unstable();
+        Ok(mailbox_ids)
+    }
+
+    fn set_uid_validity(&self, mailbox: &MailboxId, uid_validity: u32) -> Result<(), ()> {
+        self.with_mailbox(mailbox, |uids| {
+            uids.uid_validity = uid_validity.into();
+        });
+        Ok(())
+    }
+
+    fn set_uid_next(&self, mailbox: &MailboxId, uid_next: u32) -> Result<(), ()> {
+        self.with_mailbox(mailbox, |uids| {
+            uids.last_uid = std::cmp::max(uids.last_uid, uid_next.saturating_sub(1));
+        });
+        Ok(())
+    }
+
+    fn insert_uid(&self, mailbox: &MailboxId, uid: u32, jmap_id: &str) -> Result<(), ()> {
+        self.with_mailbox(mailbox, |uids| {
+            if let Some(old_uid) = uids.jmap_to_uid.insert(jmap_id.to_string(), uid) {
+                uids.uid_to_jmap.remove(&old_uid);
+            }
+            if let Some(old_jmap_id) = uids.uid_to_jmap.insert(uid, jmap_id.to_string()) {
+                if old_jmap_id != jmap_id {
+                    uids.jmap_to_uid.remove(&old_jmap_id);
+                }
+            }
+            uids.last_uid = std::cmp::max(uids.last_uid, uid);
+        });
+        Ok(())
+    }
+
+    fn delete_account(&self, account_id: &str) -> Result<(), ()> {
+        self.accounts.lock().remove(account_id);
+        Ok(())
+    }
+
+    fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
+        if let Some(uids) = self
+            .accounts
+            .lock()
+            .get_mut(account_id)
+            .and_then(|account| account.mailboxes.get_mut(&Some(mailbox_id.to_string())))
+        {
+            uids.clear_uids();
+        }
+        Ok(())
+    }
+
+    fn purge_deleted_mailboxes(
+        &self,
+        account_id: &str,
+        mailbox_ids: &AHashSet<String>,
+    ) -> Result<(), ()> {
+        if let Some(account) = self.accounts.lock().get_mut(account_id) {
+            for (mailbox_id, uids) in account.mailboxes.iter_mut() {
+                if mailbox_id
+                    .as_ref()
+                    .map_or(false, |mailbox_id| !mailbox_ids.contains(mailbox_id))
+                {
+                    uids.clear_uids();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
+        let now = now();
+        let mut num_deletions = 0;
+        for account in self.accounts.lock().values_mut() {
+            for uids in account.mailboxes.values_mut() {
+                let len = uids.deleted_ids.len();
+                uids.deleted_ids
+                    .retain(|_, (_, deleted_at)| !is_expired(*deleted_at, now, ttl));
+                num_deletions += len - uids.deleted_ids.len();
+            }
+        }
+        Ok(num_deletions)
+    }
+
+    fn set_last_login(&self, account_id: &str, timestamp: u64) -> Result<(), ()> {
+        self.accounts
+            .lock()
+            .entry(account_id.to_string())
+            .or_default()
+            .last_login = timestamp.into();
+        Ok(())
+    }
+
+    fn last_login(&self, account_id: &str) -> Result<Option<u64>, ()> {
+        Ok(self
+            .accounts
+            .lock()
+            .get(account_id)
+            .and_then(|account| account.last_login))
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        0
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn compact(&self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn verify(&self, _repair: bool) -> Result<Vec<String>, ()> {
+        // All the maps of an account are updated while holding the lock.
+        Ok(Vec::new())
+    }
+}
@@ -0,0 +1,371 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+pub mod cli;
+pub mod dump;
+pub mod fsck;
+pub mod import;
+pub mod memory_store;
+pub mod remote_store;
+pub mod server;
+pub mod sled_store;
+pub mod sqlite_store;
+
+use std::time::SystemTime;
+
+use ahash::AHashSet;
+
+use super::message::MailboxId;
+
+// Persistent storage of the IMAP UIDs assigned to JMAP ids and of the
+// MODSEQs assigned to JMAP states. Implementations are called from the
+// worker pool and may block. Errors are logged by the store.
+#[allow(clippy::result_unit_err)]
+pub trait UidStore: Send + Sync {
+    // Returns the UIDVALIDITY of a mailbox, generating one if missing.
+    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()>;
+
+    // Returns the UIDVALIDITY of a mailbox without generating one.
+    fn get_uid_validity(&self, mailbox: &MailboxId) -> Result<Option<u32>, ()>;
+
+    // Returns the UID that the next message added to a mailbox will get.
+    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()>;
+
+    // Assigns the next UID of the mailbox to a JMAP id, or returns the UID the
+    // id already has. Concurrent callers always get the same UID back.
+    fn insert_jmap_id(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<u32, ()>;
+
+    fn jmap_to_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()>;
+
+    fn uid_to_jmap(&self, mailbox: &MailboxId, uid: u32) -> Result<Option<String>, ()>;
+
+    // Returns the UID a JMAP id had before it was removed from the mailbox.
+    fn deleted_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()>;
+
+    // Returns all the UID to JMAP id mappings of a mailbox sorted by UID.
+    fn mailbox_ids(&self, mailbox: &MailboxId) -> Result<Vec<(u32, String)>, ()>;
+
+    // Removes the mappings of the given JMAP ids, keeping their UIDs as
+    // deleted ids until they are purged.
+    fn delete_ids(&self, mailbox: &MailboxId, jmap_ids: &[String]) -> Result<(), ()>;
+
+    fn query_state(&self, mailbox: &MailboxId) -> Result<Option<String>, ()>;
+
+    fn set_query_state(&self, mailbox: &MailboxId, query_state: Option<&str>) -> Result<(), ()>;
+
+    // Returns the MODSEQ of a JMAP state, assigning the next one to new states.
+    fn state_to_modseq(&self, account_id: &str, state: &str) -> Result<u32, ()>;
+
+    fn modseq_to_state(&self, account_id: &str, modseq: u32) -> Result<Option<String>, ()>;
+
+    // Returns the MODSEQ assigned to the most recent state of an account, or 0.
+    fn highest_modseq(&self, account_id: &str) -> Result<u32, ()>;
+
+    // Maps a state to a given MODSEQ, raising the account's highest MODSEQ if needed.
+    fn insert_modseq(&self, account_id: &str, modseq: u32, state: &str) -> Result<(), ()>;
+
+    // Lists the accounts with data in the store, sorted by account id.
+    fn account_ids(&self) -> Result<Vec<String>, ()>;
+
+    // Lists the mailboxes of an account with UIDs or a query state assigned.
+    fn account_mailboxes(&self, account_id: &str) -> Result<Vec<Option<String>>, ()>;
+
+    fn set_uid_validity(&self, mailbox: &MailboxId, uid_validity: u32) -> Result<(), ()>;
+
+    // Raises the UIDNEXT of a mailbox, it is never lowered.
+    fn set_uid_next(&self, mailbox: &MailboxId, uid_next: u32) -> Result<(), ()>;
+
+    // Maps a JMAP id to a given UID, replacing any previous mapping of either
+    // of them and raising UIDNEXT past the UID if needed.
+    fn insert_uid(&self, mailbox: &MailboxId, uid: u32, jmap_id: &str) -> Result<(), ()>;
+
+    fn delete_account(&self, account_id: &str) -> Result<(), ()>;
+
+    // Removes the UIDs of a mailbox. Its deleted ids are kept until they expire.
+    fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()>;
+
+    // Removes the UIDs of the account's mailboxes not listed in mailbox_ids.
+    fn purge_deleted_mailboxes(
+        &self,
+        account_id: &str,
+        mailbox_ids: &AHashSet<String>,
+    ) -> Result<(), ()>;
+
+    // Removes the deleted ids older than ttl seconds and returns how many were removed.
+    fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()>;
+
+    // Records when a user with access to the account last logged in.
+    fn set_last_login(&self, account_id: &str, timestamp: u64) -> Result<(), ()>;
+
+    fn last_login(&self, account_id: &str) -> Result<Option<u64>, ()>;
+
+    // Deletes the accounts nobody logged into for ttl seconds and returns their
+    // ids. Accounts cached before logins were recorded expire ttl seconds after
+    // they are first seen here.
+    fn purge_inactive_accounts(&self, ttl: u64) -> Result<Vec<String>, ()> {
+        let now = now();
+        let mut purged_ids = Vec::new();
+
+        for account_id in self.account_ids()? {
+            match self.last_login(&account_id)? {
+                Some(last_login) if is_expired(last_login, now, ttl) => {
+                    self.delete_account(&account_id)?;
+                    purged_ids.push(account_id);
+                }
+                Some(_) => (),
+                None => self.set_last_login(&account_id, now)?,
+            }
+        }
+
+        Ok(purged_ids)
+    }
+
+    fn size_on_disk(&self) -> u64;
+
+    fn flush(&self) -> Result<(), ()>;
+
+    // Reclaims unused space, if the backend supports doing so while open.
+    fn compact(&self) -> Result<(), ()>;
+
+    // Checks that the stored mappings are consistent, returning a description
+    // of each problem found. The problems are fixed when repair is set.
+    fn verify(&self, repair: bool) -> Result<Vec<String>, ()>;
+}
+
+// Number of hours since January 1st, 2000
+pub fn generate_uid_validity() -> u32 {
+    (now().saturating_sub(946684800) / 3600) as u32
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn is_expired(deleted_at: u64, now: u64, ttl: u64) -> bool {
+    deleted_at < now && (now - deleted_at) >= ttl
+}
+
+// Name used to refer to a mailbox in reports.
+pub fn mailbox_name(mailbox: &MailboxId) -> String {
+    format!(
+        "{}/{}",
+        mailbox.account_id,
+        mailbox.mailbox_id.as_deref().unwrap_or("(All Mail)")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use ahash::AHashSet;
+
+    use crate::core::message::MailboxId;
+
+    use super::{
+        memory_store::MemoryStore, now, sled_store::SledStore, sqlite_store::SqliteStore, UidStore,
+    };
+
+    fn mailbox(account_id: &str, mailbox_id: Option<&str>) -> MailboxId {
+        MailboxId {
+            account_id: account_id.to_string(),
+            mailbox_id: mailbox_id.map(|id| id.to_string()),
+        }
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    pub(super) fn test_store(store: &dyn UidStore) {
+        let inbox = mailbox("john", "inbox".into());
+        let all_mail = mailbox("john", None);
+        let other = mailbox("jane", "inbox".into());
+
+        // UID allocation
+        assert_eq!(store.get_uid_validity(&inbox).unwrap(), None);
+        let uid_validity = store.uid_validity(&inbox).unwrap();
+        assert_ne!(uid_validity, 0);
+        assert_eq!(store.uid_validity(&inbox).unwrap(), uid_validity);
+        assert_eq!(store.get_uid_validity(&inbox).unwrap(), Some(uid_validity));
+        assert_eq!(store.uid_next(&inbox).unwrap(), 1);
+        for (pos, jmap_id) in ["a", "b", "c", "d"].into_iter().enumerate() {
+            assert_eq!(
+                store.insert_jmap_id(&inbox, jmap_id).unwrap(),
+                pos as u32 + 1
+            );
+        }
+        assert_eq!(store.insert_jmap_id(&all_mail, "a").unwrap(), 1);
+        assert_eq!(store.insert_jmap_id(&other, "z").unwrap(), 1);
+        assert_eq!(store.uid_next(&inbox).unwrap(), 5);
+        assert_eq!(store.uid_next(&all_mail).unwrap(), 2);
+
+        // Id mapping
+        assert_eq!(store.jmap_to_uid(&inbox, "c").unwrap(), Some(3));
+        assert_eq!(store.jmap_to_uid(&inbox, "z").unwrap(), None);
+        assert_eq!(store.uid_to_jmap(&inbox, 2).unwrap().as_deref(), Some("b"));
+        assert_eq!(store.uid_to_jmap(&inbox, 5).unwrap(), None);
+        store.delete_ids(&inbox, &ids(&["b", "d", "x"])).unwrap();
+        assert_eq!(
+            store.mailbox_ids(&inbox).unwrap(),
+            vec![(1, "a".to_string()), (3, "c".to_string())]
+        );
+        assert_eq!(store.jmap_to_uid(&inbox, "b").unwrap(), None);
+        assert_eq!(store.deleted_uid(&inbox, "b").unwrap(), Some(2));
+        assert_eq!(store.deleted_uid(&inbox, "c").unwrap(), None);
+        assert_eq!(store.insert_jmap_id(&inbox, "e").unwrap(), 5);
+
+        // Query state
+        assert_eq!(store.query_state(&inbox).unwrap(), None);
+        store.set_query_state(&inbox, "q1".into()).unwrap();
+        assert_eq!(store.query_state(&inbox).unwrap().as_deref(), Some("q1"));
+        store.set_query_state(&inbox, None).unwrap();
+        assert_eq!(store.query_state(&inbox).unwrap(), None);
+
+        // Modseq to state mapping
+        assert_eq!(store.state_to_modseq("john", "s1").unwrap(), 1);
+        assert_eq!(store.state_to_modseq("john", "s2").unwrap(), 2);
+        assert_eq!(store.state_to_modseq("john", "s1").unwrap(), 1);
+        assert_eq!(store.state_to_modseq("jane", "s3").unwrap(), 1);
+        assert_eq!(
+            store.modseq_to_state("john", 2).unwrap().as_deref(),
+            Some("s2")
+        );
+        assert_eq!(store.modseq_to_state("john", 3).unwrap(), None);
+
+        // Mailbox removal keeps the deleted ids
+        let folder = mailbox("john", "folder".into());
+        store.insert_jmap_id(&folder, "f").unwrap();
+        store.delete_mailbox("john", "inbox").unwrap();
+        assert_eq!(store.uid_next(&inbox).unwrap(), 1);
+        assert_eq!(store.mailbox_ids(&inbox).unwrap(), vec![]);
+        assert_eq!(store.deleted_uid(&inbox, "b").unwrap(), Some(2));
+        store
+            .purge_deleted_mailboxes("john", &AHashSet::from_iter(["inbox".to_string()]))
+            .unwrap();
+        assert_eq!(store.jmap_to_uid(&folder, "f").unwrap(), None);
+        assert_eq!(store.jmap_to_uid(&all_mail, "a").unwrap(), Some(1));
+
+        // Account removal
+        store.delete_account("jane").unwrap();
+        assert_eq!(store.mailbox_ids(&other).unwrap(), vec![]);
+        assert_eq!(store.modseq_to_state("jane", 1).unwrap(), None);
+        assert_eq!(store.state_to_modseq("jane", "s4").unwrap(), 1);
+        assert_eq!(store.jmap_to_uid(&all_mail, "a").unwrap(), Some(1));
+
+        // Explicit UIDs and MODSEQs
+        let jim_inbox = mailbox("jim", "inbox".into());
+        store.set_uid_validity(&jim_inbox, 1234).unwrap();
+        assert_eq!(store.uid_validity(&jim_inbox).unwrap(), 1234);
+        store.insert_uid(&jim_inbox, 10, "m1").unwrap();
+        store.insert_uid(&jim_inbox, 5, "m2").unwrap();
+        assert_eq!(store.uid_next(&jim_inbox).unwrap(), 11);
+        store.insert_uid(&jim_inbox, 7, "m1").unwrap();
+        store.insert_uid(&jim_inbox, 5, "m3").unwrap();
+        assert_eq!(
+            store.mailbox_ids(&jim_inbox).unwrap(),
+            vec![(5, "m3".to_string()), (7, "m1".to_string())]
+        );
+        assert_eq!(store.jmap_to_uid(&jim_inbox, "m2").unwrap(), None);
+        assert_eq!(store.uid_next(&jim_inbox).unwrap(), 11);
+        store.set_uid_next(&jim_inbox, 20).unwrap();
+        store.set_uid_next(&jim_inbox, 15).unwrap();
+        assert_eq!(store.insert_jmap_id(&jim_inbox, "m4").unwrap(), 20);
+        store
+            .set_query_state(&mailbox("jim", None), "q".into())
+            .unwrap();
+        assert_eq!(
+            store.account_mailboxes("jim").unwrap(),
+            vec![None, Some("inbox".to_string())]
+        );
+        assert_eq!(store.highest_modseq("jim").unwrap(), 0);
+        store.insert_modseq("jim", 5, "s5").unwrap();
+        assert_eq!(store.highest_modseq("jim").unwrap(), 5);
+        assert_eq!(store.state_to_modseq("jim", "s5").unwrap(), 5);
+        assert_eq!(store.state_to_modseq("jim", "s6").unwrap(), 6);
+        assert_eq!(
+            store.account_ids().unwrap(),
+            vec!["jane".to_string(), "jim".to_string(), "john".to_string()]
+        );
+
+        // Inactive accounts
+        assert_eq!(store.last_login("jim").unwrap(), None);
+        store.set_last_login("jim", now() - 100).unwrap();
+        store.set_last_login("jack", now()).unwrap();
+        assert_eq!(
+            store.purge_inactive_accounts(200).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_ne!(store.last_login("john").unwrap(), None);
+        assert_eq!(
+            store.purge_inactive_accounts(50).unwrap(),
+            vec!["jim".to_string()]
+        );
+        assert_eq!(store.last_login("jim").unwrap(), None);
+        assert_eq!(store.mailbox_ids(&jim_inbox).unwrap(), vec![]);
+        assert_eq!(
+            store.account_ids().unwrap(),
+            vec!["jack".to_string(), "jane".to_string(), "john".to_string()]
+        );
+    }
+
+    #[test]
+    fn uid_store_backends() {
+        let temp_dir = std::env::temp_dir().join("stalwart-imap-store-test");
+        if temp_dir.exists() {
+            std::fs::remove_dir_all(&temp_dir).unwrap();
+        }
+        std::fs::create_dir_all(&temp_dir).unwrap();
+
+        let stores: Vec<Box<dyn UidStore>> = vec![
+            Box::new(SledStore::new(Arc::new(
+                sled::open(temp_dir.join("sled")).unwrap(),
+            ))),
+            Box::new(SqliteStore::open(temp_dir.join("uids.sqlite3")).unwrap()),
+            Box::new(MemoryStore::default()),
+        ];
+        for store in &stores {
+            test_store(store.as_ref());
+            assert_eq!(store.purge_deleted_ids(1).unwrap(), 0);
+            assert_eq!(store.verify(false).unwrap(), Vec::<String>::new());
+        }
+
+        // Deleted ids expire after their TTL
+        std::thread::sleep(Duration::from_millis(1100));
+        for store in &stores {
+            assert_eq!(store.purge_deleted_ids(1).unwrap(), 2);
+            assert_eq!(
+                store
+                    .deleted_uid(&mailbox("john", "inbox".into()), "b")
+                    .unwrap(),
+                None
+            );
+            store.flush().unwrap();
+        }
+
+        drop(stores);
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
@@ -0,0 +1,1390 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{
+    io::{self, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ahash::AHashSet;
+use parking_lot::Mutex;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, error};
+
+use crate::core::message::MailboxId;
+
+use super::UidStore;
+
+// Requests and responses are sent over TLS as frames made of a big-endian
+// u32 length followed by the payload. The first frame of each connection
+// authenticates it with the shared secret.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+pub const MAX_AUTH_FRAME_SIZE: usize = 1024;
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_ERROR: u8 = 1;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Idle connections are not reused past this point, well before the server
+// closes them.
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+type Connection = StreamOwned<ClientConnection, TcpStream>;
+
+// Forwards all operations to the UID cache server of another proxy node,
+// so every node behind a load balancer sees the same UIDs. UID allocation
+// is atomic because it only happens on the node hosting the cache.
+pub struct RemoteStore {
+    addr: String,
+    secret: String,
+    tls_config: Arc<ClientConfig>,
+    server_name: ServerName,
+    connections: Mutex<Vec<(Connection, Instant)>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StoreRequest {
+    Auth {
+        secret: String,
+    },
+    UidValidity {
+        mailbox: MailboxId,
+    },
+    UidNext {
+        mailbox: MailboxId,
+    },
+    InsertJmapId {
+        mailbox: MailboxId,
+        jmap_id: String,
+    },
+    JmapToUid {
+        mailbox: MailboxId,
+        jmap_id: String,
+    },
+    UidToJmap {
+        mailbox: MailboxId,
+        uid: u32,
+    },
+    DeletedUid {
+        mailbox: MailboxId,
+        jmap_id: String,
+    },
+    MailboxIds {
+        mailbox: MailboxId,
+    },
+    DeleteIds {
+        mailbox: MailboxId,
+        jmap_ids: Vec<String>,
+    },
+    QueryState {
+        mailbox: MailboxId,
+    },
+    SetQueryState {
+        mailbox: MailboxId,
+        query_state: Option<String>,
+    },
+    StateToModseq {
+        account_id: String,
+        state: String,
+    },
+    ModseqToState {
+        account_id: String,
+        modseq: u32,
+    },
+    DeleteAccount {
+        account_id: String,
+    },
+    DeleteMailbox {
+        account_id: String,
+        mailbox_id: String,
+    },
+    PurgeDeletedMailboxes {
+        account_id: String,
+        mailbox_ids: Vec<String>,
+    },
+    PurgeDeletedIds {
+        ttl: u64,
+    },
+    SizeOnDisk,
+    Flush,
+    HighestModseq {
+        account_id: String,
+    },
+    InsertModseq {
+        account_id: String,
+        modseq: u32,
+        state: String,
+    },
+    AccountIds,
+    AccountMailboxes {
+        account_id: String,
+    },
+    SetUidValidity {
+        mailbox: MailboxId,
+        uid_validity: u32,
+    },
+    SetUidNext {
+        mailbox: MailboxId,
+        uid_next: u32,
+    },
+    InsertUid {
+        mailbox: MailboxId,
+        uid: u32,
+        jmap_id: String,
+    },
+    Compact,
+    Verify {
+        repair: bool,
+    },
+    SetLastLogin {
+        account_id: String,
+        timestamp: u64,
+    },
+    LastLogin {
+        account_id: String,
+    },
+    PurgeInactiveAccounts {
+        ttl: u64,
+    },
+    GetUidValidity {
+        mailbox: MailboxId,
+    },
+}
+
+impl StoreRequest {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(64);
+        match self {
+            StoreRequest::Auth { secret } => {
+                buf.push(0);
+                put_str(&mut buf, secret);
+            }
+            StoreRequest::UidValidity { mailbox } => {
+                buf.push(1);
+                put_mailbox(&mut buf, mailbox);
+            }
+            StoreRequest::UidNext { mailbox } => {
+                buf.push(2);
+                put_mailbox(&mut buf, mailbox);
+            }
+            StoreRequest::InsertJmapId { mailbox, jmap_id } => {
+                buf.push(3);
+                put_mailbox(&mut buf, mailbox);
+                put_str(&mut buf, jmap_id);
+            }
+            StoreRequest::JmapToUid { mailbox, jmap_id } => {
+                buf.push(4);
+                put_mailbox(&mut buf, mailbox);
+                put_str(&mut buf, jmap_id);
+            }
+            StoreRequest::UidToJmap { mailbox, uid } => {
+                buf.push(5);
+                put_mailbox(&mut buf, mailbox);
+                buf.extend_from_slice(&uid.to_be_bytes());
+            }
+            StoreRequest::DeletedUid { mailbox, jmap_id } => {
+                buf.push(6);
+                put_mailbox(&mut buf, mailbox);
+                put_str(&mut buf, jmap_id);
+            }
+            StoreRequest::MailboxIds { mailbox } => {
+                buf.push(7);
+                put_mailbox(&mut buf, mailbox);
+            }
+            StoreRequest::DeleteIds { mailbox, jmap_ids } => {
+                buf.push(8);
+                put_mailbox(&mut buf, mailbox);
+                put_list(&mut buf, jmap_ids);
+            }
+            StoreRequest::QueryState { mailbox } => {
+                buf.push(9);
+                put_mailbox(&mut buf, mailbox);
+            }
+            StoreRequest::SetQueryState {
+                mailbox,
+                query_state,
+            } => {
+                buf.push(10);
+                put_mailbox(&mut buf, mailbox);
+                put_opt_str(&mut buf, query_state.as_deref());
+            }
+            StoreRequest::StateToModseq { account_id, state } => {
+                buf.push(11);
+                put_str(&mut buf, account_id);
+                put_str(&mut buf, state);
+            }
+            StoreRequest::ModseqToState { account_id, modseq } => {
+                buf.push(12);
+                put_str(&mut buf, account_id);
+                buf.extend_from_slice(&modseq.to_be_bytes());
+            }
+            StoreRequest::DeleteAccount { account_id } => {
+                buf.push(13);
+                put_str(&mut buf, account_id);
+            }
+            StoreRequest::DeleteMailbox {
+                account_id,
+                mailbox_id,
+            } => {
+                buf.push(14);
+                put_str(&mut buf, account_id);
+                put_str(&mut buf, mailbox_id);
+            }
+            StoreRequest::PurgeDeletedMailboxes {
+                account_id,
+                mailbox_ids,
+            } => {
+                buf.push(15);
+                put_str(&mut buf, account_id);
+                put_list(&mut buf, mailbox_ids);
+            }
+            StoreRequest::PurgeDeletedIds { ttl } => {
+                buf.push(16);
+                buf.extend_from_slice(&ttl.to_be_bytes());
+            }
+            StoreRequest::SizeOnDisk => buf.push(17),
+            StoreRequest::Flush => buf.push(18),
+            StoreRequest::HighestModseq { account_id } => {
+                buf.push(19);
+                put_str(&mut buf, account_id);
+            }
+            StoreRequest::InsertModseq {
+                account_id,
+                modseq,
+                state,
+            } => {
+                buf.push(20);
+                put_str(&mut buf, account_id);
+                buf.extend_from_slice(&modseq.to_be_bytes());
+                put_str(&mut buf, state);
+            }
+            StoreRequest::AccountIds => buf.push(21),
+            StoreRequest::AccountMailboxes { account_id } => {
+                buf.push(22);
+                put_str(&mut buf, account_id);
+            }
+            StoreRequest::SetUidValidity {
+                mailbox,
+                uid_validity,
+            } => {
+                buf.push(23);
+                put_mailbox(&mut buf, mailbox);
+                buf.extend_from_slice(&uid_validity.to_be_bytes());
+            }
+            StoreRequest::SetUidNext { mailbox, uid_next } => {
+                buf.push(24);
+                put_mailbox(&mut buf, mailbox);
+                buf.extend_from_slice(&uid_next.to_be_bytes());
+            }
+            StoreRequest::InsertUid {
+                mailbox,
+                uid,
+                jmap_id,
+            } => {
+                buf.push(25);
+                put_mailbox(&mut buf, mailbox);
+                buf.extend_from_slice(&uid.to_be_bytes());
+                put_str(&mut buf, jmap_id);
+            }
+            StoreRequest::Compact => buf.push(26),
+            StoreRequest::Verify { repair } => {
+                buf.push(27);
+                buf.push(*repair as u8);
+            }
+            StoreRequest::SetLastLogin {
+                account_id,
+                timestamp,
+            } => {
+                buf.push(28);
+                put_str(&mut buf, account_id);
+                buf.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            StoreRequest::LastLogin { account_id } => {
+                buf.push(29);
+                put_str(&mut buf, account_id);
+            }
+            StoreRequest::PurgeInactiveAccounts { ttl } => {
+                buf.push(30);
+                buf.extend_from_slice(&ttl.to_be_bytes());
+            }
+            StoreRequest::GetUidValidity { mailbox } => {
+                buf.push(31);
+                put_mailbox(&mut buf, mailbox);
+            }
+        }
+        buf
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let (&request_type, bytes) = bytes.split_first()?;
+        let mut bytes = Reader { bytes };
+        let request = match request_type {
+            0 => StoreRequest::Auth {
+                secret: bytes.str()?,
+            },
+            1 => StoreRequest::UidValidity {
+                mailbox: bytes.mailbox()?,
+            },
+            2 => StoreRequest::UidNext {
+                mailbox: bytes.mailbox()?,
+            },
+            3 => StoreRequest::InsertJmapId {
+                mailbox: bytes.mailbox()?,
+                jmap_id: bytes.str()?,
+            },
+            4 => StoreRequest::JmapToUid {
+                mailbox: bytes.mailbox()?,
+                jmap_id: bytes.str()?,
+            },
+            5 => StoreRequest::UidToJmap {
+                mailbox: bytes.mailbox()?,
+                uid: bytes.u32()?,
+            },
+            6 => StoreRequest::DeletedUid {
+                mailbox: bytes.mailbox()?,
+                jmap_id: bytes.str()?,
+            },
+            7 => StoreRequest::MailboxIds {
+                mailbox: bytes.mailbox()?,
+            },
+            8 => StoreRequest::DeleteIds {
+                mailbox: bytes.mailbox()?,
+                jmap_ids: bytes.list()?,
+            },
+            9 => StoreRequest::QueryState {
+                mailbox: bytes.mailbox()?,
+            },
+            10 => StoreRequest::SetQueryState {
+                mailbox: bytes.mailbox()?,
+                query_state: bytes.opt_str()?,
+            },
+            11 => StoreRequest::StateToModseq {
+                account_id: bytes.str()?,
+                state: bytes.str()?,
+            },
+            12 => StoreRequest::ModseqToState {
+                account_id: bytes.str()?,
+                modseq: bytes.u32()?,
+            },
+            13 => StoreRequest::DeleteAccount {
+                account_id: bytes.str()?,
+            },
+            14 => StoreRequest::DeleteMailbox {
+                account_id: bytes.str()?,
+                mailbox_id: bytes.str()?,
+            },
+            15 => StoreRequest::PurgeDeletedMailboxes {
+                account_id: bytes.str()?,
+                mailbox_ids: bytes.list()?,
+            },
+            16 => StoreRequest::PurgeDeletedIds { ttl: bytes.u64()? },
+            17 => StoreRequest::SizeOnDisk,
+            18 => StoreRequest::Flush,
+            19 => StoreRequest::HighestModseq {
+                account_id: bytes.str()?,
+            },
+            20 => StoreRequest::InsertModseq {
+                account_id: bytes.str()?,
+                modseq: bytes.u32()?,
+                state: bytes.str()?,
+            },
+            21 => StoreRequest::AccountIds,
+            22 => StoreRequest::AccountMailboxes {
+                account_id: bytes.str()?,
+            },
+            23 => StoreRequest::SetUidValidity {
+                mailbox: bytes.mailbox()?,
+                uid_validity: bytes.u32()?,
+            },
+            24 => StoreRequest::SetUidNext {
+                mailbox: bytes.mailbox()?,
+                uid_next: bytes.u32()?,
+            },
+            25 => StoreRequest::InsertUid {
+                mailbox: bytes.mailbox()?,
+                uid: bytes.u32()?,
+                jmap_id: bytes.str()?,
+            },
+            26 => StoreRequest::Compact,
+            27 => StoreRequest::Verify {
+                repair: bytes.u8()? != 0,
+            },
+            28 => StoreRequest::SetLastLogin {
+                account_id: bytes.str()?,
+                timestamp: bytes.u64()?,
+            },
+            29 => StoreRequest::LastLogin {
+                account_id: bytes.str()?,
+            },
+            30 => StoreRequest::PurgeInactiveAccounts { ttl: bytes.u64()? },
+            31 => StoreRequest::GetUidValidity {
+                mailbox: bytes.mailbox()?,
+            },
+            _ => return None,
+        };
+        if bytes.bytes.is_empty() {
+            Some(request)
+        } else {
+            None
+        }
+    }
+
+    // Runs the request against the local store and serializes its result.
+    #[allow(clippy::result_unit_err)]
+    pub fn execute(self, store: &dyn UidStore) -> Result<Vec<u8>, ()> {
+        let mut buf = Vec::with_capacity(16);
+        match self {
+            StoreRequest::Auth { .. } => (),
+            StoreRequest::UidValidity { mailbox } => {
+                buf.extend_from_slice(&store.uid_validity(&mailbox)?.to_be_bytes());
+            }
+            StoreRequest::UidNext { mailbox } => {
+                buf.extend_from_slice(&store.uid_next(&mailbox)?.to_be_bytes());
+            }
+            StoreRequest::InsertJmapId { mailbox, jmap_id } => {
+                buf.extend_from_slice(&store.insert_jmap_id(&mailbox, &jmap_id)?.to_be_bytes());
+            }
+            StoreRequest::JmapToUid { mailbox, jmap_id } => {
+                put_opt_u32(&mut buf, store.jmap_to_uid(&mailbox, &jmap_id)?);
+            }
+            StoreRequest::UidToJmap { mailbox, uid } => {
+                put_opt_str(&mut buf, store.uid_to_jmap(&mailbox, uid)?.as_deref());
+            }
+            StoreRequest::DeletedUid { mailbox, jmap_id } => {
+                put_opt_u32(&mut buf, store.deleted_uid(&mailbox, &jmap_id)?);
+            }
+            StoreRequest::MailboxIds { mailbox } => {
+                let ids = store.mailbox_ids(&mailbox)?;
+                buf.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+                for (uid, jmap_id) in ids {
+                    buf.extend_from_slice(&uid.to_be_bytes());
+                    put_str(&mut buf, &jmap_id);
+                }
+            }
+            StoreRequest::DeleteIds { mailbox, jmap_ids } => {
+                store.delete_ids(&mailbox, &jmap_ids)?;
+            }
+            StoreRequest::QueryState { mailbox } => {
+                put_opt_str(&mut buf, store.query_state(&mailbox)?.as_deref());
+            }
+            StoreRequest::SetQueryState {
+                mailbox,
+                query_state,
+            } => {
+                store.set_query_state(&mailbox, query_state.as_deref())?;
+            }
+            StoreRequest::StateToModseq { account_id, state } => {
+                buf.extend_from_slice(&store.state_to_modseq(&account_id, &state)?.to_be_bytes());
+            }
+            StoreRequest::ModseqToState { account_id, modseq } => {
+                put_opt_str(
+                    &mut buf,
+                    store.modseq_to_state(&account_id, modseq)?.as_deref(),
+                );
+            }
+            StoreRequest::DeleteAccount { account_id } => {
+                store.delete_account(&account_id)?;
+            }
+            StoreRequest::DeleteMailbox {
+                account_id,
+                mailbox_id,
+            } => {
+                store.delete_mailbox(&account_id, &mailbox_id)?;
+            }
+            StoreRequest::PurgeDeletedMailboxes {
+                account_id,
+                mailbox_ids,
+            } => {
+                store.purge_deleted_mailboxes(
+                    &account_id,
+                    &mailbox_ids.into_iter().collect::<AHashSet<_>>(),
+                )?;
+            }
+            StoreRequest::PurgeDeletedIds { ttl } => {
+                buf.extend_from_slice(&(store.purge_deleted_ids(ttl)? as u64).to_be_bytes());
+            }
+            StoreRequest::SizeOnDisk => {
+                buf.extend_from_slice(&store.size_on_disk().to_be_bytes());
+            }
+            StoreRequest::Flush => {
+                store.flush()?;
+            }
+            StoreRequest::HighestModseq { account_id } => {
+                buf.extend_from_slice(&store.highest_modseq(&account_id)?.to_be_bytes());
+            }
+            StoreRequest::InsertModseq {
+                account_id,
+                modseq,
+                state,
+            } => {
+                store.insert_modseq(&account_id, modseq, &state)?;
+            }
+            StoreRequest::AccountIds => {
+                put_list(&mut buf, &store.account_ids()?);
+            }
+            StoreRequest::AccountMailboxes { account_id } => {
+                let mailbox_ids = store.account_mailboxes(&account_id)?;
+                buf.extend_from_slice(&(mailbox_ids.len() as u32).to_be_bytes());
+                for mailbox_id in mailbox_ids {
+                    put_opt_str(&mut buf, mailbox_id.as_deref());
+                }
+            }
+            StoreRequest::SetUidValidity {
+                mailbox,
+                uid_validity,
+            } => {
+                store.set_uid_validity(&mailbox, uid_validity)?;
+            }
+            StoreRequest::SetUidNext { mailbox, uid_next } => {
+                store.set_uid_next(&mailbox, uid_next)?;
+            }
+            StoreRequest::InsertUid {
+                mailbox,
+                uid,
+                jmap_id,
+            } => {
+                store.insert_uid(&mailbox, uid, &jmap_id)?;
+            }
+            StoreRequest::Compact => {
+                store.compact()?;
+            }
+            StoreRequest::Verify { repair } => {
+                put_list(&mut buf, &store.verify(repair)?);
+            }
+            StoreRequest::SetLastLogin {
+                account_id,
+                timestamp,
+            } => {
+                store.set_last_login(&account_id, timestamp)?;
+            }
+            StoreRequest::LastLogin { account_id } => {
+                if let Some(last_login) = store.last_login(&account_id)? {
+                    buf.push(1);
+                    buf.extend_from_slice(&last_login.to_be_bytes());
+                } else {
+                    buf.push(0);
+                }
+            }
+            StoreRequest::PurgeInactiveAccounts { ttl } => {
+                put_list(&mut buf, &store.purge_inactive_accounts(ttl)?);
+            }
+            StoreRequest::GetUidValidity { mailbox } => {
+                put_opt_u32(&mut buf, store.get_uid_validity(&mailbox)?);
+            }
+        }
+        Ok(buf)
+    }
+}
+
+impl RemoteStore {
+    pub fn new(
+        addr: String,
+        secret: String,
+        tls_config: Arc<ClientConfig>,
+        server_name: ServerName,
+    ) -> Self {
+        RemoteStore {
+            addr,
+            secret,
+            tls_config,
+            server_name,
+            connections: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn connect(&self) -> io::Result<Connection> {
+        let addr =
+            self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Address did not resolve")
+            })?;
+        let stream = TcpStream::connect_timeout(&addr, IO_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let connection = ClientConnection::new(self.tls_config.clone(), self.server_name.clone())
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        let mut stream = StreamOwned::new(connection, stream);
+
+        let response = exchange(
+            &mut stream,
+            &StoreRequest::Auth {
+                secret: self.secret.clone(),
+            }
+            .serialize(),
+        )?;
+        if response.first() == Some(&STATUS_OK) {
+            Ok(stream)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Authentication failed",
+            ))
+        }
+    }
+
+    fn send<T>(
+        &self,
+        request: StoreRequest,
+        parse: impl FnOnce(&mut Reader) -> Option<T>,
+    ) -> Result<T, ()> {
+        let request = request.serialize();
+
+        // Requests such as InsertJmapId allocate ids, so they are never retried
+        // once written as the server might have executed them already. Pooled
+        // connections the server has closed are discarded before sending.
+        let result = match self.pooled() {
+            Some(stream) => Ok(stream),
+            None => self.connect(),
+        }
+        .and_then(|mut stream| exchange(&mut stream, &request).map(|response| (stream, response)));
+        let (stream, response) = result.map_err(|err| {
+            error!("Failed to reach UID cache at {}: {}", self.addr, err);
+        })?;
+        self.connections.lock().push((stream, Instant::now()));
+
+        match response.split_first() {
+            Some((&STATUS_OK, bytes)) => {
+                let mut bytes = Reader { bytes };
+                parse(&mut bytes)
+                    .filter(|_| bytes.bytes.is_empty())
+                    .ok_or_else(|| {
+                        error!("Invalid response received from UID cache at {}.", self.addr);
+                    })
+            }
+            _ => {
+                error!("UID cache at {} failed to process request.", self.addr);
+                Err(())
+            }
+        }
+    }
+
+    fn pooled(&self) -> Option<Connection> {
+        let mut connections = self.connections.lock();
+        while let Some((stream, last_used)) = connections.pop() {
+            if last_used.elapsed() < POOL_IDLE_TIMEOUT && is_open(&stream.sock) {
+                return Some(stream);
+            } else {
+                debug!("Discarding idle connection to {}.", self.addr);
+            }
+        }
+        None
+    }
+}
+
+impl UidStore for RemoteStore {
+    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        self.send(
+            StoreRequest::UidValidity {
+                mailbox: clone_mailbox(mailbox),
+            },
+            |bytes| bytes.u32(),
+        )
+    }
+
+    fn get_uid_validity(&self, mailbox: &MailboxId) -> Result<Option<u32>, ()> {
+        self.send(
+            StoreRequest::GetUidValidity {
+                mailbox: clone_mailbox(mailbox),
+            },
+            |bytes| bytes.opt_u32(),
+        )
+    }
+
+    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        self.send(
+            StoreRequest::UidNext {
+                mailbox: clone_mailbox(mailbox),
+            },
+            |bytes| bytes.u32(),
+        )
+    }
+
+    fn insert_jmap_id(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<u32, ()> {
+        self.send(
+            StoreRequest::InsertJmapId {
+                mailbox: clone_mailbox(mailbox),
+                jmap_id: jmap_id.to_string(),
+            },
+            |bytes| bytes.u32(),
+        )
+    }
+
+    fn jmap_to_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.send(
+            StoreRequest::JmapToUid {
+                mailbox: clone_mailbox(mailbox),
+                jmap_id: jmap_id.to_string(),
+            },
+            |bytes| bytes.opt_u32(),
+        )
+    }
+
+    fn uid_to_jmap(&self, mailbox: &MailboxId, uid: u32) -> Result<Option<String>, ()> {
+        self.send(
+            StoreRequest::UidToJmap {
+                mailbox: clone_mailbox(mailbox),
+                uid,
+            },
+            |bytes| bytes.opt_str(),
+        )
+    }
+
+    fn deleted_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.send(
+            StoreRequest::DeletedUid {
+                mailbox: clone_mailbox(mailbox),
+                jmap_id: jmap_id.to_string(),
+            },
+            |bytes| bytes.opt_u32(),
+        )
+    }
+
+    fn mailbox_ids(&self, mailbox: &MailboxId) -> Result<Vec<(u32, String)>, ()> {
+        self.send(
+            StoreRequest::MailboxIds {
+                mailbox: clone_mailbox(mailbox),
+            },
+            |bytes| {
+                let len = bytes.u32()? as usize;
+                let mut ids = Vec::with_capacity(std::cmp::min(len, 1024));
+                for _ in 0..len {
+                    ids.push((bytes.u32()?, bytes.str()?));
+                }
+                Some(ids)
+            },
+        )
+    }
+
+    fn delete_ids(&self, mailbox: &MailboxId, jmap_ids: &[String]) -> Result<(), ()> {
+        self.send(
+            StoreRequest::DeleteIds {
+                mailbox: clone_mailbox(mailbox),
+                jmap_ids: jmap_ids.to_vec(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn query_state(&self, mailbox: &MailboxId) -> Result<Option<String>, ()> {
+        self.send(
+            StoreRequest::QueryState {
+                mailbox: clone_mailbox(mailbox),
+            },
+            |bytes| bytes.opt_str(),
+        )
+    }
+
+    fn set_query_state(&self, mailbox: &MailboxId, query_state: Option<&str>) -> Result<(), ()> {
+        self.send(
+            StoreRequest::SetQueryState {
+                mailbox: clone_mailbox(mailbox),
+                query_state: query_state.map(|query_state| query_state.to_string()),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn state_to_modseq(&self, account_id: &str, state: &str) -> Result<u32, ()> {
+        self.send(
+            StoreRequest::StateToModseq {
+                account_id: account_id.to_string(),
+                state: state.to_string(),
+            },
+            |bytes| bytes.u32(),
+        )
+    }
+
+    fn modseq_to_state(&self, account_id: &str, modseq: u32) -> Result<Option<String>, ()> {
+        self.send(
+            StoreRequest::ModseqToState {
+                account_id: account_id.to_string(),
+                modseq,
+            },
+            |bytes| bytes.opt_str(),
+        )
+    }
+
+    fn highest_modseq(&self, account_id: &str) -> Result<u32, ()> {
+        self.send(
+            StoreRequest::HighestModseq {
+                account_id: account_id.to_string(),
+            },
+            |bytes| bytes.u32(),
+        )
+    }
+
+    fn insert_modseq(&self, account_id: &str, modseq: u32, state: &str) -> Result<(), ()> {
+        self.send(
+            StoreRequest::InsertModseq {
+                account_id: account_id.to_string(),
+                modseq,
+                state: state.to_string(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn account_ids(&self) -> Result<Vec<String>, ()> {
+        self.send(StoreRequest::AccountIds, |bytes| bytes.list())
+    }
+
+    fn account_mailboxes(&self, account_id: &str) -> Result<Vec<Option<String>>, ()> {
+        self.send(
+            StoreRequest::AccountMailboxes {
+                account_id: account_id.to_string(),
+            },
+            |bytes| {
+                let len = bytes.u32()? as usize;
+                let mut mailbox_ids = Vec::with_capacity(std::cmp::min(len, 1024));
+                for _ in 0..len {
+                    mailbox_ids.push(bytes.opt_str()?);
+                }
+                Some(mailbox_ids)
+            },
+        )
+    }
+
+    fn set_uid_validity(&self, mailbox: &MailboxId, uid_validity: u32) -> Result<(), ()> {
+        self.send(
+            StoreRequest::SetUidValidity {
+                mailbox: clone_mailbox(mailbox),
+                uid_validity,
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn set_uid_next(&self, mailbox: &MailboxId, uid_next: u32) -> Result<(), ()> {
+        self.send(
+            StoreRequest::SetUidNext {
+                mailbox: clone_mailbox(mailbox),
+                uid_next,
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn insert_uid(&self, mailbox: &MailboxId, uid: u32, jmap_id: &str) -> Result<(), ()> {
+        self.send(
+            StoreRequest::InsertUid {
+                mailbox: clone_mailbox(mailbox),
+                uid,
+                jmap_id: jmap_id.to_string(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn delete_account(&self, account_id: &str) -> Result<(), ()> {
+        self.send(
+            StoreRequest::DeleteAccount {
+                account_id: account_id.to_string(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
+        self.send(
+            StoreRequest::DeleteMailbox {
+                account_id: account_id.to_string(),
+                mailbox_id: mailbox_id.to_string(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn purge_deleted_mailboxes(
+        &self,
+        account_id: &str,
+        mailbox_ids: &AHashSet<String>,
+    ) -> Result<(), ()> {
+        self.send(
+            StoreRequest::PurgeDeletedMailboxes {
+                account_id: account_id.to_string(),
+                mailbox_ids: mailbox_ids.iter().cloned().collect(),
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
+        self.send(StoreRequest::PurgeDeletedIds { ttl }, |bytes| {
+            bytes.u64().map(|num_deletions| num_deletions as usize)
+        })
+    }
+
+    fn set_last_login(&self, account_id: &str, timestamp: u64) -> Result<(), ()> {
+        self.send(
+            StoreRequest::SetLastLogin {
+                account_id: account_id.to_string(),
+                timestamp,
+            },
+            |_| Some(()),
+        )
+    }
+
+    fn last_login(&self, account_id: &str) -> Result<Option<u64>, ()> {
+        self.send(
+            StoreRequest::LastLogin {
+                account_id: account_id.to_string(),
+            },
+            |bytes| match bytes.u8()? {
+                0 => Some(None),
+                _ => bytes.u64().map(Some),
+            },
+        )
+    }
+
+    fn purge_inactive_accounts(&self, ttl: u64) -> Result<Vec<String>, ()> {
+        self.send(StoreRequest::PurgeInactiveAccounts { ttl }, |bytes| {
+            bytes.list()
+        })
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        self.send(StoreRequest::SizeOnDisk, |bytes| bytes.u64())
+            .unwrap_or(0)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.send(StoreRequest::Flush, |_| Some(()))
+    }
+
+    fn compact(&self) -> Result<(), ()> {
+        self.send(StoreRequest::Compact, |_| Some(()))
+    }
+
+    fn verify(&self, repair: bool) -> Result<Vec<String>, ()> {
+        self.send(StoreRequest::Verify { repair }, |bytes| bytes.list())
+    }
+}
+
+pub struct Reader<'x> {
+    bytes: &'x [u8],
+}
+
+impl<'x> Reader<'x> {
+    fn take(&mut self, len: usize) -> Option<&'x [u8]> {
+        if self.bytes.len() >= len {
+            let (value, bytes) = self.bytes.split_at(len);
+            self.bytes = bytes;
+            Some(value)
+        } else {
+            None
+        }
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn opt_str(&mut self) -> Option<Option<String>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => self.str().map(Some),
+        }
+    }
+
+    fn opt_u32(&mut self) -> Option<Option<u32>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => self.u32().map(Some),
+        }
+    }
+
+    fn list(&mut self) -> Option<Vec<String>> {
+        let len = self.u32()? as usize;
+        let mut list = Vec::with_capacity(std::cmp::min(len, 1024));
+        for _ in 0..len {
+            list.push(self.str()?);
+        }
+        Some(list)
+    }
+
+    fn mailbox(&mut self) -> Option<MailboxId> {
+        Some(MailboxId {
+            account_id: self.str()?,
+            mailbox_id: self.opt_str()?,
+        })
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value.as_bytes());
+}
+
+fn put_opt_str(buf: &mut Vec<u8>, value: Option<&str>) {
+    if let Some(value) = value {
+        buf.push(1);
+        put_str(buf, value);
+    } else {
+        buf.push(0);
+    }
+}
+
+fn put_opt_u32(buf: &mut Vec<u8>, value: Option<u32>) {
+    if let Some(value) = value {
+        buf.push(1);
+        buf.extend_from_slice(&value.to_be_bytes());
+    } else {
+        buf.push(0);
+    }
+}
+
+fn put_list(buf: &mut Vec<u8>, values: &[String]) {
+    buf.extend_from_slice(&(values.len() as u32).to_be_bytes());
+    for value in values {
+        put_str(buf, value);
+    }
+}
+
+fn put_mailbox(buf: &mut Vec<u8>, mailbox: &MailboxId) {
+    put_str(buf, &mailbox.account_id);
+    put_opt_str(buf, mailbox.mailbox_id.as_deref());
+}
+
+fn clone_mailbox(mailbox: &MailboxId) -> MailboxId {
+    MailboxId {
+        account_id: mailbox.account_id.clone(),
+        mailbox_id: mailbox.mailbox_id.clone(),
+    }
+}
+
+// Nothing is expected from the server while a connection is idle, so any
+// pending data, be it EOF or a TLS close_notify, means it is being closed.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let is_open = matches!(
+        stream.peek(&mut [0u8; 1]),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock
+    );
+    stream.set_nonblocking(false).is_ok() && is_open
+}
+
+fn exchange<T: Read + Write>(stream: &mut T, request: &[u8]) -> io::Result<Vec<u8>> {
+    let mut frame = Vec::with_capacity(request.len() + 4);
+    frame.extend_from_slice(&(request.len() as u32).to_be_bytes());
+    frame.extend_from_slice(request);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Response exceeds maximum frame size",
+        ));
+    }
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response)?;
+    Ok(response)
+}
+
+pub async fn read_frame<T: AsyncReadExt + Unpin>(
+    stream: &mut T,
+    max_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Request exceeds maximum frame size",
+        ));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+pub async fn write_frame<T: AsyncWriteExt + Unpin>(stream: &mut T, frame: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(frame.len() + 4);
+    buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buf.extend_from_slice(frame);
+    stream.write_all(&buf).await?;
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use ahash::AHashSet;
+    use rustls::ServerName;
+    use tokio::sync::watch;
+
+    use crate::{
+        core::{
+            config::{build_core, load_client_tls_config},
+            message::MailboxId,
+            store::server::spawn_store_listener,
+            store::UidStore,
+        },
+        tests::{init_settings, resources_dir},
+    };
+
+    use super::{read_frame, write_frame, RemoteStore, StoreRequest, MAX_AUTH_FRAME_SIZE};
+
+    #[test]
+    fn store_request_serialize() {
+        for request in [
+            StoreRequest::Auth {
+                secret: "secret".to_string(),
+            },
+            StoreRequest::SetQueryState {
+                mailbox: MailboxId {
+                    account_id: "john".to_string(),
+                    mailbox_id: None,
+                },
+                query_state: "abc".to_string().into(),
+            },
+            StoreRequest::DeleteIds {
+                mailbox: MailboxId {
+                    account_id: "john".to_string(),
+                    mailbox_id: "inbox".to_string().into(),
+                },
+                jmap_ids: vec!["a".to_string(), "b".to_string()],
+            },
+            StoreRequest::PurgeDeletedIds { ttl: 86400 },
+            StoreRequest::Flush,
+            StoreRequest::SetLastLogin {
+                account_id: "john".to_string(),
+                timestamp: 1669852800,
+            },
+        ] {
+            let bytes = request.serialize();
+            assert_eq!(StoreRequest::parse(&bytes), Some(request));
+            assert_eq!(StoreRequest::parse(&bytes[..bytes.len() - 1]), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_frame_limit() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        write_frame(&mut client, &[0u8; MAX_AUTH_FRAME_SIZE])
+            .await
+            .unwrap();
+        write_frame(&mut client, &[0u8; MAX_AUTH_FRAME_SIZE + 1])
+            .await
+            .unwrap();
+        assert_eq!(
+            read_frame(&mut server, MAX_AUTH_FRAME_SIZE)
+                .await
+                .unwrap()
+                .map(|frame| frame.len()),
+            Some(MAX_AUTH_FRAME_SIZE)
+        );
+        assert!(read_frame(&mut server, MAX_AUTH_FRAME_SIZE).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn remote_store() {
+        let (mut settings, _) = init_settings(false);
+        let cache_dir = std::env::temp_dir().join("stalwart-imap-remote-store-test");
+        if cache_dir.exists() {
+            std::fs::remove_dir_all(&cache_dir).unwrap();
+        }
+        let resources = resources_dir();
+        for (key, value) in [
+            ("cache-dir", cache_dir.to_str().unwrap()),
+            ("cache-store", "memory"),
+            ("cache-secret", "secret"),
+            (
+                "cert-path",
+                resources.join("cache-cert.pem").to_str().unwrap(),
+            ),
+            (
+                "key-path",
+                resources.join("cache-key.pem").to_str().unwrap(),
+            ),
+            (
+                "cache-remote-ca",
+                resources.join("cache-cert.pem").to_str().unwrap(),
+            ),
+        ] {
+            settings.args.insert(key.to_string(), value.to_string());
+        }
+        let core = Arc::new(build_core(&settings));
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let addr = spawn_store_listener(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            core.clone(),
+            shutdown_rx,
+        )
+        .await
+        .to_string();
+        let tls_config = Arc::new(load_client_tls_config(&settings).unwrap());
+        let server_name = ServerName::try_from("localhost").unwrap();
+
+        // Two proxy nodes sharing the cache served by the first one
+        let node1 = Arc::new(RemoteStore::new(
+            addr.clone(),
+            "secret".to_string(),
+            tls_config.clone(),
+            server_name.clone(),
+        ));
+        let node2 = Arc::new(RemoteStore::new(
+            addr.clone(),
+            "secret".to_string(),
+            tls_config.clone(),
+            server_name.clone(),
+        ));
+
+        tokio::task::spawn_blocking({
+            let node1 = node1.clone();
+            move || {
+                let mailbox = MailboxId {
+                    account_id: "john".to_string(),
+                    mailbox_id: None,
+                };
+
+                // Invalid secrets and server names are rejected
+                assert!(RemoteStore::new(
+                    addr.clone(),
+                    "1234".to_string(),
+                    tls_config.clone(),
+                    server_name,
+                )
+                .uid_next(&mailbox)
+                .is_err());
+                assert!(RemoteStore::new(
+                    addr,
+                    "secret".to_string(),
+                    tls_config,
+                    ServerName::try_from("example.org").unwrap(),
+                )
+                .uid_next(&mailbox)
+                .is_err());
+
+                crate::core::store::tests::test_store(node1.as_ref());
+            }
+        })
+        .await
+        .unwrap();
+
+        // UIDs allocated concurrently from both nodes must be unique
+        let mut tasks = Vec::new();
+        for (node_num, node) in [node1.clone(), node2.clone()].into_iter().enumerate() {
+            for task_num in 0..4 {
+                let node = node.clone();
+                tasks.push(tokio::task::spawn_blocking(move || {
+                    let mailbox = MailboxId {
+                        account_id: "shared".to_string(),
+                        mailbox_id: "inbox".to_string().into(),
+                    };
+                    (0..25)
+                        .map(|id_num| {
+                            (
+                                node.uid_validity(&mailbox).unwrap(),
+                                node.insert_jmap_id(
+                                    &mailbox,
+                                    &format!("{}-{}-{}", node_num, task_num, id_num),
+                                )
+                                .unwrap(),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                }));
+            }
+        }
+        let mut uid_validities = AHashSet::new();
+        let mut uids = AHashSet::new();
+        for task in tasks {
+            for (uid_validity, uid) in task.await.unwrap() {
+                uid_validities.insert(uid_validity);
+                assert!(uids.insert(uid), "UID {} was assigned twice", uid);
+            }
+        }
+        assert_eq!(uid_validities.len(), 1);
+        assert_eq!(uids, (1..=200).collect::<AHashSet<_>>());
+
+        // Nodes that find the same new ids at once get the same UIDs back
+        let mut tasks = Vec::new();
+        for node in [node1.clone(), node2.clone()] {
+            for _ in 0..4 {
+                let node = node.clone();
+                tasks.push(tokio::task::spawn_blocking(move || {
+                    let mailbox = MailboxId {
+                        account_id: "shared".to_string(),
+                        mailbox_id: "inbox".to_string().into(),
+                    };
+                    (0..25)
+                        .map(|id_num| {
+                            node.insert_jmap_id(&mailbox, &format!("same-{}", id_num))
+                                .unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                }));
+            }
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), (201..=225).collect::<Vec<_>>());
+        }
+
+        // Both nodes see the same mappings
+        let mailbox = MailboxId {
+            account_id: "shared".to_string(),
+            mailbox_id: "inbox".to_string().into(),
+        };
+        let ids = tokio::task::spawn_blocking(move || {
+            (
+                node1.mailbox_ids(&mailbox).unwrap(),
+                node2.mailbox_ids(&mailbox).unwrap(),
+                node2.uid_next(&mailbox).unwrap(),
+            )
+        })
+        .await
+        .unwrap();
+        assert_eq!(ids.0.len(), 225);
+        assert_eq!(ids.0, ids.1);
+        assert_eq!(ids.2, 226);
+
+        std::fs::remove_dir_all(&cache_dir).ok();
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::watch,
+};
+use tracing::{debug, error};
+
+use crate::core::{admin::secure_eq, config::failed_to, Core};
+
+use super::remote_store::{
+    read_frame, write_frame, StoreRequest, MAX_AUTH_FRAME_SIZE, MAX_FRAME_SIZE, STATUS_ERROR,
+    STATUS_OK,
+};
+
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const TLS_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Serves the local UID cache to the other proxy nodes, see RemoteStore.
+// Returns the address the listener was bound to.
+pub async fn spawn_store_listener(
+    bind_addr: SocketAddr,
+    core: Arc<Core>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> SocketAddr {
+    let (listener, mut stop_rx) = core.listeners.bind(bind_addr).unwrap_or_else(|e| {
+        failed_to(&format!("bind to {}: {}", bind_addr, e));
+    });
+    let local_addr = listener.local_addr().unwrap_or(bind_addr);
+
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                stream = listener.accept() => {
+                    match stream {
+                        Ok((stream, peer_addr)) => {
+                            let core = core.clone();
+                            let shutdown_rx = shutdown_rx.clone();
+
+                            tokio::spawn(async move {
+                                accept_connection(core, stream, peer_addr, shutdown_rx).await;
+                            });
+                        }
+                        Err(err) => {
+                            error!("Failed to accept TCP connection: {}", err);
+                        }
+                    }
+                },
+                _ = shutdown_rx.changed() => {
+                    debug!("UID cache listener shutting down.");
+                    break;
+                },
+                _ = stop_rx.changed() => {
+                    debug!("UID cache listener stopped accepting connections.");
+                    break;
+                }
+            };
+        }
+    });
+
+    local_addr
+}
+
+// The shared secret and the cached ids cross the network, so connections
+// are always encrypted using the certificate of the IMAP listeners.
+async fn accept_connection(
+    core: Arc<Core>,
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    shutdown_rx: watch::Receiver<bool>,
+) {
+    stream.set_nodelay(true).ok();
+    match tokio::time::timeout(TLS_TIMEOUT, core.tls_acceptor().accept(stream)).await {
+        Ok(Ok(stream)) => handle_connection(core, stream, peer_addr, shutdown_rx).await,
+        Ok(Err(err)) => {
+            debug!(
+                "Failed to accept TLS connection from {}: {}",
+                peer_addr, err
+            );
+        }
+        Err(_) => {
+            debug!("TLS handshake with {} timed out.", peer_addr);
+        }
+    }
+}
+
+async fn handle_connection<T: AsyncRead + AsyncWrite + Unpin>(
+    core: Arc<Core>,
+    mut stream: T,
+    peer_addr: SocketAddr,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let mut is_authenticated = false;
+
+    loop {
+        // Only the short Auth request is accepted until the peer authenticates
+        let max_size = if is_authenticated {
+            MAX_FRAME_SIZE
+        } else {
+            MAX_AUTH_FRAME_SIZE
+        };
+        let frame = tokio::select! {
+            frame = tokio::time::timeout(IDLE_TIMEOUT, read_frame(&mut stream, max_size)) => frame,
+            _ = shutdown_rx.changed() => break,
+        };
+        let request = match frame {
+            Ok(Ok(Some(frame))) => frame,
+            Ok(Ok(None)) => break,
+            Ok(Err(err)) => {
+                debug!(
+                    "Failed to read UID cache request from {}: {}",
+                    peer_addr, err
+                );
+                break;
+            }
+            Err(_) => {
+                debug!("UID cache connection from {} timed out.", peer_addr);
+                break;
+            }
+        };
+
+        let response = match StoreRequest::parse(&request) {
+            Some(StoreRequest::Auth { secret }) => {
+                is_authenticated = core.cache_secret.as_ref().map_or(false, |cache_secret| {
+                    secure_eq(cache_secret.as_bytes(), secret.as_bytes())
+                });
+                if is_authenticated {
+                    vec![STATUS_OK]
+                } else {
+                    debug!("UID cache authentication failed for {}.", peer_addr);
+                    write_frame(&mut stream, &[STATUS_ERROR]).await.ok();
+                    break;
+                }
+            }
+            Some(request) if is_authenticated => {
+                let store = core.uid_store.clone();
+                match core
+                    .spawn_worker(move || request.execute(store.as_ref()))
+                    .await
+                {
+                    Ok(value) => {
+                        let mut response = Vec::with_capacity(value.len() + 1);
+                        response.push(STATUS_OK);
+                        response.extend_from_slice(&value);
+                        response
+                    }
+                    Err(_) => vec![STATUS_ERROR],
+                }
+            }
+            Some(_) => {
+                debug!("Unauthenticated UID cache request from {}.", peer_addr);
+                break;
+            }
+            None => {
+                debug!("Invalid UID cache request from {}.", peer_addr);
+                break;
+            }
+        };
+
+        if let Err(err) = write_frame(&mut stream, &response).await {
+            debug!(
+                "Failed to write UID cache response to {}: {}",
+                peer_addr, err
+            );
+            break;
+        }
+    }
+}
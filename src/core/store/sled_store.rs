@@ -0,0 +1,1011 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use ahash::AHashSet;
+use tracing::error;
+
+use crate::core::message::MailboxId;
+
+use super::{generate_uid_validity, is_expired, mailbox_name, now, UidStore};
+
+pub const JMAP_TO_UID: u8 = 0;
+pub const UID_TO_JMAP: u8 = 1;
+pub const UID_NEXT: u8 = 2;
+pub const UID_VALIDITY: u8 = 3;
+pub const MODSEQ_TO_STATE: u8 = 4;
+pub const STATE_TO_MODSEQ: u8 = 5;
+pub const HIGHEST_MODSEQ: u8 = 6;
+pub const JMAP_DELETED_IDS: u8 = 7;
+pub const QUERY_STATE: u8 = 8;
+pub const LAST_LOGIN: u8 = 9;
+
+// Stores the mappings in the sled database at cache-dir. Keys start with
+// the account id followed by a zero byte, so all the data of an account
+// can be found with a prefix scan.
+pub struct SledStore {
+    db: Arc<sled::Db>,
+}
+
+impl SledStore {
+    pub fn new(db: Arc<sled::Db>) -> Self {
+        SledStore { db }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<sled::IVec>, ()> {
+        self.db.get(key).map_err(|err| {
+            error!("Failed to get key: {}", err);
+        })
+    }
+
+    // Sets a u32 counter to the given value unless it is already higher.
+    fn raise_counter(&self, key: Vec<u8>, value: u32) -> Result<(), ()> {
+        self.db
+            .update_and_fetch(key, |old| {
+                std::cmp::max(
+                    old.and_then(|bytes| bytes.try_into().ok())
+                        .map_or(0, u32::from_be_bytes),
+                    value,
+                )
+                .to_be_bytes()
+                .to_vec()
+                .into()
+            })
+            .map(|_| ())
+            .map_err(|err| {
+                error!("Failed to update counter: {}", err);
+            })
+    }
+
+    fn apply_batch(&self, batch: sled::Batch) -> Result<(), ()> {
+        self.db.apply_batch(batch).map_err(|err| {
+            error!("Failed to delete batch: {}", err);
+        })
+    }
+}
+
+impl UidStore for SledStore {
+    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        // Obtain/generate UIDVALIDITY
+        let uid_validity_key = serialize_uid_validity_key(mailbox);
+        Ok(if let Some(uid_bytes) = self.get(&uid_validity_key)? {
+            deserialize_u32(&uid_bytes)?
+        } else {
+            let uid_validity = generate_uid_validity();
+            self.db
+                .insert(uid_validity_key, &uid_validity.to_be_bytes()[..])
+                .map_err(|err| {
+                    error!("Failed to insert key: {}", err);
+                })?;
+            uid_validity
+        })
+    }
+
+    fn get_uid_validity(&self, mailbox: &MailboxId) -> Result<Option<u32>, ()> {
+        self.get(&serialize_uid_validity_key(mailbox))?
+            .map(|uid_bytes| deserialize_u32(&uid_bytes))
+            .transpose()
+    }
+
+    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        Ok(
+            if let Some(uid_bytes) = self.get(&serialize_uid_next_key(mailbox))? {
+                deserialize_u32(&uid_bytes)? + 1
+            } else {
+                1
+            },
+        )
+    }
+
+    fn insert_jmap_id(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<u32, ()> {
+        let jmap_key = serialize_key(mailbox, JMAP_TO_UID, jmap_id.as_bytes());
+        if let Some(uid) = self.get(&jmap_key)? {
+            return deserialize_u32(&uid);
+        }
+
+        // Obtain next UID.
+        let uid = self
+            .db
+            .update_and_fetch(serialize_uid_next_key(mailbox), increment_uid)
+            .map_err(|err| {
+                error!("Failed to increment UID: {}", err);
+            })?
+            .ok_or_else(|| {
+                error!("Failed to generate UID.");
+            })?;
+
+        // Another request might have mapped the id in the meantime, in which
+        // case its UID is returned and the one just obtained is left unused.
+        if let Err(err) = self
+            .db
+            .compare_and_swap(&jmap_key, None as Option<&[u8]>, Some(uid.clone()))
+            .map_err(|err| {
+                error!("Failed to insert key: {}", err);
+            })?
+        {
+            return err.current.map_or(Err(()), |uid| deserialize_u32(&uid));
+        }
+        self.db
+            .insert(
+                serialize_key(mailbox, UID_TO_JMAP, &uid),
+                jmap_id.as_bytes(),
+            )
+            .map_err(|err| {
+                error!("Failed to insert key: {}", err);
+            })?;
+
+        deserialize_u32(&uid)
+    }
+
+    fn jmap_to_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.get(&serialize_key(mailbox, JMAP_TO_UID, jmap_id.as_bytes()))?
+            .map(|uid| deserialize_u32(&uid))
+            .transpose()
+    }
+
+    fn uid_to_jmap(&self, mailbox: &MailboxId, uid: u32) -> Result<Option<String>, ()> {
+        self.get(&serialize_key(mailbox, UID_TO_JMAP, &uid.to_be_bytes()[..]))?
+            .map(|jmap_id| deserialize_string(&jmap_id))
+            .transpose()
+    }
+
+    fn deleted_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.get(&serialize_key(
+            mailbox,
+            JMAP_DELETED_IDS,
+            jmap_id.as_bytes(),
+        ))?
+        .map(|value| deserialize_u32(value.get(..std::mem::size_of::<u32>()).unwrap_or(&[])))
+        .transpose()
+    }
+
+    fn mailbox_ids(&self, mailbox: &MailboxId) -> Result<Vec<(u32, String)>, ()> {
+        let prefix = serialize_key_prefix(mailbox, UID_TO_JMAP);
+        let mut ids = Vec::new();
+
+        for kv_result in self.db.scan_prefix(&prefix) {
+            let (key, value) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            if key.len() > prefix.len() {
+                ids.push((
+                    deserialize_u32(&key[prefix.len()..])?,
+                    deserialize_string(&value)?,
+                ));
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn delete_ids(&self, mailbox: &MailboxId, jmap_ids: &[String]) -> Result<(), ()> {
+        let mut batch = sled::Batch::default();
+        let mut has_deletions = false;
+        let deleted_at = now().to_be_bytes();
+
+        for jmap_id in jmap_ids {
+            let jmap_id = jmap_id.as_bytes();
+            let key = serialize_key(mailbox, JMAP_TO_UID, jmap_id);
+
+            if let Some(imap_uid) = self.get(&key)? {
+                // Add UID to deleted messages
+                let mut buf =
+                    Vec::with_capacity(std::mem::size_of::<u32>() + std::mem::size_of::<u64>());
+                buf.extend_from_slice(&imap_uid[..]);
+                buf.extend_from_slice(&deleted_at);
+                batch.insert(serialize_key(mailbox, JMAP_DELETED_IDS, jmap_id), buf);
+
+                // Delete mappings from cache
+                batch.remove(key);
+                batch.remove(sled::IVec::from(serialize_key(
+                    mailbox,
+                    UID_TO_JMAP,
+                    &imap_uid[..],
+                )));
+
+                has_deletions = true;
+            }
+        }
+
+        if has_deletions {
+            self.apply_batch(batch)?;
+        }
+
+        Ok(())
+    }
+
+    fn query_state(&self, mailbox: &MailboxId) -> Result<Option<String>, ()> {
+        self.get(&serialize_key(mailbox, QUERY_STATE, &[]))?
+            .map(|value| deserialize_string(&value))
+            .transpose()
+    }
+
+    fn set_query_state(&self, mailbox: &MailboxId, query_state: Option<&str>) -> Result<(), ()> {
+        let key = serialize_key(mailbox, QUERY_STATE, &[]);
+        if let Some(query_state) = query_state {
+            self.db.insert(key, query_state.as_bytes()).map(|_| ())
+        } else {
+            self.db.remove(key).map(|_| ())
+        }
+        .map_err(|err| {
+            error!("Failed to write query state: {}", err);
+        })
+    }
+
+    fn state_to_modseq(&self, account_id: &str, state: &str) -> Result<u32, ()> {
+        let modseq_key = serialize_modseq(account_id.as_bytes(), state.as_bytes(), STATE_TO_MODSEQ);
+        let modseq = if let Some(modseq) = self.get(&modseq_key)? {
+            modseq
+        } else {
+            // Obtain highestmodseq.
+            let highestmodseq = self
+                .db
+                .update_and_fetch(
+                    serialize_highestmodseq(account_id.as_bytes()),
+                    increment_uid,
+                )
+                .map_err(|err| {
+                    error!("Failed to increment HIGHESTMODSEQ: {}", err);
+                })?
+                .ok_or_else(|| {
+                    error!("Failed to generate HIGHESTMODSEQ.");
+                })?;
+
+            // Insert state-to-modseq and modseq-to-state keys
+            for result in [
+                self.db.insert(modseq_key, &highestmodseq),
+                self.db.insert(
+                    serialize_modseq(account_id.as_bytes(), &highestmodseq[..], MODSEQ_TO_STATE),
+                    state.as_bytes(),
+                ),
+            ] {
+                result.map_err(|err| {
+                    error!("Failed to insert key: {}", err);
+                })?;
+            }
+
+            highestmodseq
+        };
+
+        deserialize_u32(&modseq)
+    }
+
+    fn modseq_to_state(&self, account_id: &str, modseq: u32) -> Result<Option<String>, ()> {
+        self.get(&serialize_modseq(
+            account_id.as_bytes(),
+            &modseq.to_be_bytes(),
+            MODSEQ_TO_STATE,
+        ))?
+        .map(|state| deserialize_string(&state))
+        .transpose()
+    }
+
+    fn highest_modseq(&self, account_id: &str) -> Result<u32, ()> {
+        self.get(&serialize_highestmodseq(account_id.as_bytes()))?
+            .map_or(Ok(0), |modseq| deserialize_u32(&modseq))
+    }
+
+    fn insert_modseq(&self, account_id: &str, modseq: u32, state: &str) -> Result<(), ()> {
+        let modseq_bytes = modseq.to_be_bytes();
+        let modseq_key =
+            serialize_modseq(account_id.as_bytes(), &modseq_bytes[..], MODSEQ_TO_STATE);
+        let state_key = serialize_modseq(account_id.as_bytes(), state.as_bytes(), STATE_TO_MODSEQ);
+        let mut batch = sled::Batch::default();
+
+        // Remove any previous mapping of the MODSEQ or the state
+        if let Some(old_state) = self.get(&modseq_key)? {
+            batch.remove(serialize_modseq(
+                account_id.as_bytes(),
+                &old_state,
+                STATE_TO_MODSEQ,
+            ));
+        }
+        if let Some(old_modseq) = self.get(&state_key)? {
+            batch.remove(serialize_modseq(
+                account_id.as_bytes(),
+                &old_modseq,
+                MODSEQ_TO_STATE,
+            ));
+        }
+        batch.insert(modseq_key, state.as_bytes());
+        batch.insert(state_key, &modseq_bytes[..]);
+        self.apply_batch(batch)?;
+
+        self.raise_counter(serialize_highestmodseq(account_id.as_bytes()), modseq)
+    }
+
+    fn account_ids(&self) -> Result<Vec<String>, ()> {
+        let mut account_ids = Vec::new();
+        let mut next_key = Vec::new();
+
+        // Jump from one account prefix to the next one
+        while let Some(kv_result) = self.db.range(next_key.as_slice()..).next() {
+            let (key, _) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            if let Some(pos) = key.iter().position(|&ch| ch == 0) {
+                account_ids.push(deserialize_string(&key[..pos])?);
+                next_key = key[..pos].to_vec();
+                next_key.push(1);
+            } else {
+                next_key = key.to_vec();
+                next_key.push(0);
+            }
+        }
+
+        Ok(account_ids)
+    }
+
+    fn account_mailboxes(&self, account_id: &str) -> Result<Vec<Option<String>>, ()> {
+        let account_prefix = serialize_key_account_prefix(account_id);
+        let mut mailbox_ids = BTreeSet::new();
+
+        for kv_result in self.db.scan_prefix(&account_prefix) {
+            let (key, _) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            if let Some((&separator, mailbox_id)) = key[account_prefix.len()..].split_last() {
+                if [UID_NEXT, UID_VALIDITY, QUERY_STATE].contains(&separator)
+                    && !mailbox_id.iter().any(|&ch| ch <= QUERY_STATE)
+                {
+                    mailbox_ids.insert(if !mailbox_id.is_empty() {
+                        Some(deserialize_string(mailbox_id)?)
+                    } else {
+                        None
+                    });
+                }
+            }
+        }
+
+        Ok(mailbox_ids.into_iter().collect())
+    }
+
+    fn set_uid_validity(&self, mailbox: &MailboxId, uid_validity: u32) -> Result<(), ()> {
+        self.db
+            .insert(
+                serialize_uid_validity_key(mailbox),
+                &uid_validity.to_be_bytes()[..],
+            )
+            .map(|_| ())
+            .map_err(|err| {
+                error!("Failed to insert key: {}", err);
+            })
+    }
+
+    fn set_uid_next(&self, mailbox: &MailboxId, uid_next: u32) -> Result<(), ()> {
+        if uid_next > 1 {
+            self.raise_counter(serialize_uid_next_key(mailbox), uid_next - 1)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn insert_uid(&self, mailbox: &MailboxId, uid: u32, jmap_id: &str) -> Result<(), ()> {
+        let uid_bytes = uid.to_be_bytes();
+        let jmap_key = serialize_key(mailbox, JMAP_TO_UID, jmap_id.as_bytes());
+        let uid_key = serialize_key(mailbox, UID_TO_JMAP, &uid_bytes[..]);
+        let mut batch = sled::Batch::default();
+
+        // Remove any previous mapping of the UID or the JMAP id
+        if let Some(old_uid) = self.get(&jmap_key)? {
+            batch.remove(serialize_key(mailbox, UID_TO_JMAP, &old_uid));
+        }
+        if let Some(old_jmap_id) = self.get(&uid_key)? {
+            batch.remove(serialize_key(mailbox, JMAP_TO_UID, &old_jmap_id));
+        }
+        batch.insert(jmap_key, &uid_bytes[..]);
+        batch.insert(uid_key, jmap_id.as_bytes());
+        self.apply_batch(batch)?;
+
+        self.raise_counter(serialize_uid_next_key(mailbox), uid)
+    }
+
+    fn delete_account(&self, account_id: &str) -> Result<(), ()> {
+        let mut batch = sled::Batch::default();
+
+        for kv_result in self
+            .db
+            .scan_prefix(serialize_key_account_prefix(account_id))
+        {
+            let (key, _) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            batch.remove(key);
+        }
+
+        self.apply_batch(batch)
+    }
+
+    fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
+        let mut prefix = serialize_key_account_prefix(account_id);
+        prefix.extend_from_slice(mailbox_id.as_bytes());
+        let mut batch = sled::Batch::default();
+
+        for kv_result in self.db.scan_prefix(&prefix) {
+            let (key, _) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            if key.len() > prefix.len()
+                && (key[prefix.len()] <= UID_VALIDITY || key[prefix.len()] == QUERY_STATE)
+            {
+                batch.remove(key);
+            }
+        }
+
+        self.apply_batch(batch)
+    }
+
+    fn purge_deleted_mailboxes(
+        &self,
+        account_id: &str,
+        mailbox_ids: &AHashSet<String>,
+    ) -> Result<(), ()> {
+        let account_prefix = serialize_key_account_prefix(account_id);
+        let mut has_deletions = false;
+        let mut batch = sled::Batch::default();
+
+        for kv_result in self.db.scan_prefix(&account_prefix) {
+            let (key, _) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            let key_part = &key[account_prefix.len()..];
+            if let Some(pos) = key_part
+                .iter()
+                .position(|&ch| ch <= UID_VALIDITY || ch == QUERY_STATE)
+            {
+                if pos > 0
+                    && !std::str::from_utf8(&key_part[..pos])
+                        .map_or(false, |mailbox_id| mailbox_ids.contains(mailbox_id))
+                {
+                    batch.remove(key);
+                    has_deletions = true;
+                }
+            }
+        }
+
+        if has_deletions {
+            self.apply_batch(batch)?;
+        }
+
+        Ok(())
+    }
+
+    fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
+        let now = now();
+        let mut num_deletions = 0;
+        let mut batch = sled::Batch::default();
+
+        for kv_result in self.db.scan_prefix([]) {
+            let (key, value) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            if value.len() == std::mem::size_of::<u32>() + std::mem::size_of::<u64>() {
+                let deleted_at =
+                    u64::from_be_bytes((&value[std::mem::size_of::<u32>()..]).try_into().map_err(
+                        |_| {
+                            error!("Failed to convert bytes to u64.");
+                        },
+                    )?);
+                if is_expired(deleted_at, now, ttl) {
+                    batch.remove(key);
+                    num_deletions += 1;
+                }
+            }
+        }
+
+        if num_deletions > 0 {
+            self.apply_batch(batch)?;
+        }
+
+        Ok(num_deletions)
+    }
+
+    fn set_last_login(&self, account_id: &str, timestamp: u64) -> Result<(), ()> {
+        self.db
+            .insert(
+                serialize_last_login_key(account_id),
+                &timestamp.to_be_bytes()[..],
+            )
+            .map(|_| ())
+            .map_err(|err| {
+                error!("Failed to insert key: {}", err);
+            })
+    }
+
+    fn last_login(&self, account_id: &str) -> Result<Option<u64>, ()> {
+        self.get(&serialize_last_login_key(account_id))?
+            .map(|bytes| {
+                (*bytes).try_into().map(u64::from_be_bytes).map_err(|_| {
+                    error!("Failed to convert bytes to u64.");
+                })
+            })
+            .transpose()
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        self.db.size_on_disk().unwrap_or(0)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        self.db.flush().map(|_| ()).map_err(|err| {
+            error!("Failed to flush cache: {}", err);
+        })
+    }
+
+    fn compact(&self) -> Result<(), ()> {
+        // sled cannot be compacted while open, see compact_db.
+        self.flush()
+    }
+
+    fn verify(&self, repair: bool) -> Result<Vec<String>, ()> {
+        let mut problems = Vec::new();
+        let mut batch = sled::Batch::default();
+        let mut account: Option<AccountKeys> = None;
+
+        // Keys are sorted, so the keys of each account are read one after the other.
+        for kv_result in self.db.iter() {
+            let (key, value) = kv_result.map_err(|err| {
+                error!("Failed to scan db: {}", err);
+            })?;
+            let (account_id, key_part) = match key.iter().position(|&ch| ch == 0).and_then(|pos| {
+                std::str::from_utf8(&key[..pos])
+                    .ok()
+                    .map(|account_id| (account_id, &key[pos + 1..]))
+            }) {
+                Some(result) => result,
+                None => {
+                    problems.push(format!("Invalid key {:?}.", key));
+                    batch.remove(key);
+                    continue;
+                }
+            };
+            if !matches!(&account, Some(account) if account.account_id == account_id) {
+                if let Some(account) = account.take() {
+                    account.verify(&mut problems, &mut batch);
+                }
+                account = AccountKeys {
+                    account_id: account_id.to_string(),
+                    ..Default::default()
+                }
+                .into();
+            }
+            if let Some(account) = account.as_mut() {
+                account.add(key_part, value.to_vec(), &mut problems);
+            }
+        }
+        if let Some(account) = account {
+            account.verify(&mut problems, &mut batch);
+        }
+
+        if repair && !problems.is_empty() {
+            self.apply_batch(batch)?;
+        }
+
+        Ok(problems)
+    }
+}
+
+#[derive(Default)]
+struct AccountKeys {
+    account_id: String,
+    mailboxes: BTreeMap<Vec<u8>, MailboxKeys>,
+    state_to_modseq: Vec<(Vec<u8>, Vec<u8>)>,
+    modseq_to_state: Vec<(Vec<u8>, Vec<u8>)>,
+    highest_modseq: u32,
+}
+
+#[derive(Default)]
+struct MailboxKeys {
+    jmap_to_uid: Vec<(Vec<u8>, Vec<u8>)>,
+    uid_to_jmap: Vec<(Vec<u8>, Vec<u8>)>,
+    last_uid: u32,
+}
+
+impl AccountKeys {
+    fn add(&mut self, key_part: &[u8], value: Vec<u8>, problems: &mut Vec<String>) {
+        // Mailbox keys are "<mailbox id><separator><value>", mailbox ids never
+        // contain separator bytes. MODSEQ keys are "<modseq or state><separator>",
+        // a MODSEQ above 2^24 starting with 0x01 cannot be told apart from an
+        // All Mail UID_TO_JMAP key and is treated as such.
+        if key_part == [HIGHEST_MODSEQ] {
+            self.highest_modseq = deserialize_u32(&value).unwrap_or(0);
+        } else if key_part == [LAST_LOGIN] {
+            // Login times are not related to any other key
+        } else if key_part.len() == 5
+            && key_part[4] == MODSEQ_TO_STATE
+            && key_part[0] != UID_TO_JMAP
+        {
+            self.modseq_to_state.push((key_part[..4].to_vec(), value));
+        } else if let Some(pos) = key_part.iter().position(|&ch| ch <= QUERY_STATE) {
+            let (mailbox_id, separator, key_value) =
+                (&key_part[..pos], key_part[pos], &key_part[pos + 1..]);
+            match separator {
+                JMAP_TO_UID => self
+                    .mailbox(mailbox_id)
+                    .jmap_to_uid
+                    .push((key_value.to_vec(), value)),
+                UID_TO_JMAP => self
+                    .mailbox(mailbox_id)
+                    .uid_to_jmap
+                    .push((key_value.to_vec(), value)),
+                UID_NEXT if key_value.is_empty() => {
+                    self.mailbox(mailbox_id).last_uid = deserialize_u32(&value).unwrap_or(0);
+                }
+                STATE_TO_MODSEQ if key_value.is_empty() => {
+                    self.state_to_modseq.push((mailbox_id.to_vec(), value));
+                }
+                UID_VALIDITY | QUERY_STATE | JMAP_DELETED_IDS => (),
+                _ => problems.push(format!(
+                    "{}: Unrecognized key {:?}.",
+                    self.account_id, key_part
+                )),
+            }
+        } else {
+            problems.push(format!(
+                "{}: Unrecognized key {:?}.",
+                self.account_id, key_part
+            ));
+        }
+    }
+
+    fn mailbox(&mut self, mailbox_id: &[u8]) -> &mut MailboxKeys {
+        self.mailboxes.entry(mailbox_id.to_vec()).or_default()
+    }
+
+    fn verify(self, problems: &mut Vec<String>, batch: &mut sled::Batch) {
+        for (mailbox_id, keys) in self.mailboxes {
+            let mailbox = MailboxId {
+                account_id: self.account_id.clone(),
+                mailbox_id: if !mailbox_id.is_empty() {
+                    String::from_utf8_lossy(&mailbox_id).into_owned().into()
+                } else {
+                    None
+                },
+            };
+            let name = mailbox_name(&mailbox);
+            let mut prefix = serialize_key_account_prefix(&self.account_id);
+            prefix.extend_from_slice(&mailbox_id);
+            let key = |separator: u8, value: &[u8]| {
+                let mut key = prefix.clone();
+                key.push(separator);
+                key.extend_from_slice(value);
+                key
+            };
+
+            let max_uid = verify_mappings(
+                &name,
+                "UID",
+                keys.uid_to_jmap,
+                keys.jmap_to_uid,
+                |id| key(UID_TO_JMAP, id),
+                |id| key(JMAP_TO_UID, id),
+                false,
+                problems,
+                batch,
+            );
+            if max_uid > keys.last_uid {
+                problems.push(format!(
+                    "{}: UIDNEXT {} is not above UID {}.",
+                    name,
+                    keys.last_uid + 1,
+                    max_uid
+                ));
+                batch.insert(key(UID_NEXT, &[]), &max_uid.to_be_bytes()[..]);
+            }
+        }
+
+        let account_id = self.account_id.as_bytes();
+        let max_modseq = verify_mappings(
+            &self.account_id,
+            "MODSEQ",
+            self.modseq_to_state,
+            self.state_to_modseq,
+            |modseq| serialize_modseq(account_id, modseq, MODSEQ_TO_STATE),
+            |state| serialize_modseq(account_id, state, STATE_TO_MODSEQ),
+            true,
+            problems,
+            batch,
+        );
+        if max_modseq > self.highest_modseq {
+            problems.push(format!(
+                "{}: HIGHESTMODSEQ {} is below MODSEQ {}.",
+                self.account_id, self.highest_modseq, max_modseq
+            ));
+            batch.insert(
+                serialize_highestmodseq(account_id),
+                &max_modseq.to_be_bytes()[..],
+            );
+        }
+    }
+}
+
+// Checks that a number to string mapping (UID to JMAP id, MODSEQ to state)
+// is unique and mirrored by the reverse mapping, adding the fixes to the
+// batch. When a string has several numbers, the one in the reverse mapping
+// is kept, otherwise the lowest or highest one. Returns the highest number.
+#[allow(clippy::too_many_arguments)]
+fn verify_mappings(
+    name: &str,
+    number_name: &str,
+    forward: Vec<(Vec<u8>, Vec<u8>)>,
+    reverse: Vec<(Vec<u8>, Vec<u8>)>,
+    forward_key: impl Fn(&[u8]) -> Vec<u8>,
+    reverse_key: impl Fn(&[u8]) -> Vec<u8>,
+    keep_highest: bool,
+    problems: &mut Vec<String>,
+    batch: &mut sled::Batch,
+) -> u32 {
+    let reverse = reverse.into_iter().collect::<BTreeMap<_, _>>();
+    let mut numbers = BTreeMap::<Vec<u8>, Vec<u32>>::new();
+
+    for (number_bytes, string) in forward {
+        match deserialize_u32(&number_bytes) {
+            Ok(number) if number > 0 && std::str::from_utf8(&string).is_ok() => {
+                numbers.entry(string).or_default().push(number);
+            }
+            _ => {
+                problems.push(format!(
+                    "{}: Invalid {} entry {:?}.",
+                    name, number_name, number_bytes
+                ));
+                batch.remove(forward_key(&number_bytes));
+            }
+        }
+    }
+
+    let mut max_number = 0;
+    for (string, string_numbers) in numbers.iter_mut() {
+        string_numbers.sort_unstable();
+        let reverse_number = reverse
+            .get(string)
+            .and_then(|number| deserialize_u32(number).ok());
+        let number = match reverse_number {
+            Some(number) if string_numbers.contains(&number) => number,
+            _ if keep_highest => *string_numbers.last().unwrap(),
+            _ => string_numbers[0],
+        };
+        max_number = std::cmp::max(max_number, *string_numbers.last().unwrap());
+
+        let string_name = String::from_utf8_lossy(string);
+        if string_numbers.len() > 1 {
+            problems.push(format!(
+                "{}: '{}' has {}s {:?}, keeping {}.",
+                name, string_name, number_name, string_numbers, number
+            ));
+            for other_number in string_numbers.iter().filter(|n| **n != number) {
+                batch.remove(forward_key(&other_number.to_be_bytes()));
+            }
+        }
+        match reverse_number {
+            Some(reverse_number) if reverse_number == number => (),
+            Some(reverse_number) => problems.push(format!(
+                "{}: '{}' points to {} {} instead of {}.",
+                name, string_name, number_name, reverse_number, number
+            )),
+            None => problems.push(format!(
+                "{}: Missing mapping of '{}' to {} {}.",
+                name, string_name, number_name, number
+            )),
+        }
+        if reverse_number != Some(number) {
+            batch.insert(reverse_key(string), &number.to_be_bytes()[..]);
+        }
+    }
+
+    for (string, number) in reverse {
+        if !numbers.contains_key(&string) {
+            problems.push(format!(
+                "{}: Stale mapping of '{}' to {} {}.",
+                name,
+                String::from_utf8_lossy(&string),
+                number_name,
+                <[u8; 4]>::try_from(number.as_slice())
+                    .map(u32::from_be_bytes)
+                    .unwrap_or(0)
+            ));
+            batch.remove(reverse_key(&string));
+        }
+    }
+
+    max_number
+}
+
+// Rewrites the database at path into a new one, which is the only way to
+// reclaim the space used by removed keys. The database must not be open.
+// Returns its size before and after compacting.
+pub fn compact_db(path: &Path) -> Result<(u64, u64), String> {
+    let with_suffix = |suffix: &str| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let compact_path = with_suffix(".compact");
+    let old_path = with_suffix(".old");
+    for path in [&compact_path, &old_path] {
+        if path.exists() {
+            std::fs::remove_dir_all(path)
+                .map_err(|err| format!("Failed to remove {}: {}", path.display(), err))?;
+        }
+    }
+
+    let db = sled::open(path).map_err(|err| format!("Failed to open database: {}", err))?;
+    let compact_db = sled::open(&compact_path)
+        .map_err(|err| format!("Failed to create {}: {}", compact_path.display(), err))?;
+    compact_db.import(db.export());
+    compact_db
+        .flush()
+        .map_err(|err| format!("Failed to flush database: {}", err))?;
+    let sizes = (
+        db.size_on_disk().unwrap_or(0),
+        compact_db.size_on_disk().unwrap_or(0),
+    );
+    drop(db);
+    drop(compact_db);
+
+    std::fs::rename(path, &old_path)
+        .and_then(|_| std::fs::rename(&compact_path, path))
+        .and_then(|_| std::fs::remove_dir_all(&old_path))
+        .map_err(|err| format!("Failed to replace database: {}", err))?;
+
+    Ok(sizes)
+}
+
+fn deserialize_u32(bytes: &[u8]) -> Result<u32, ()> {
+    bytes.try_into().map(u32::from_be_bytes).map_err(|_| {
+        error!("Failed to convert bytes to u32.");
+    })
+}
+
+fn deserialize_string(bytes: &[u8]) -> Result<String, ()> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| {
+        error!("Failed to convert bytes to string.");
+    })
+}
+
+pub fn serialize_key(mailbox: &MailboxId, separator: u8, value: &[u8]) -> Vec<u8> {
+    let mut buf = serialize_key_prefix(mailbox, separator);
+    buf.extend_from_slice(value);
+    buf
+}
+
+pub fn serialize_key_prefix(mailbox: &MailboxId, separator: u8) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(
+        mailbox.account_id.len() + mailbox.mailbox_id.as_ref().map_or(0, |m| m.len()) + 2,
+    );
+    buf.extend_from_slice(mailbox.account_id.as_bytes());
+    buf.push(0);
+    if let Some(mailbox_id) = mailbox.mailbox_id.as_ref() {
+        buf.extend_from_slice(mailbox_id.as_bytes());
+    }
+    buf.push(separator);
+    buf
+}
+
+pub fn serialize_key_account_prefix(account_id: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(account_id.len() + 1);
+    buf.extend_from_slice(account_id.as_bytes());
+    buf.push(0);
+    buf
+}
+
+pub fn serialize_uid_next_key(mailbox: &MailboxId) -> Vec<u8> {
+    serialize_key_prefix(mailbox, UID_NEXT)
+}
+
+pub fn serialize_uid_validity_key(mailbox: &MailboxId) -> Vec<u8> {
+    serialize_key_prefix(mailbox, UID_VALIDITY)
+}
+
+pub fn serialize_modseq(account_id: &[u8], value: &[u8], separator: u8) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(account_id.len() + value.len() + 2);
+    buf.extend_from_slice(account_id);
+    buf.push(0);
+    buf.extend_from_slice(value);
+    buf.push(separator);
+    buf
+}
+
+pub fn serialize_highestmodseq(account_id: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(account_id.len() + 2);
+    buf.extend_from_slice(account_id);
+    buf.push(0);
+    buf.push(HIGHEST_MODSEQ);
+    buf
+}
+
+pub fn serialize_last_login_key(account_id: &str) -> Vec<u8> {
+    let mut buf = serialize_key_account_prefix(account_id);
+    buf.push(LAST_LOGIN);
+    buf
+}
+
+pub fn increment_uid(old: Option<&[u8]>) -> Option<Vec<u8>> {
+    match old {
+        Some(bytes) => u32::from_be_bytes(bytes.try_into().ok()?) + 1,
+        None => 1,
+    }
+    .to_be_bytes()
+    .to_vec()
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::core::{message::MailboxId, store::UidStore};
+
+    use super::{serialize_highestmodseq, serialize_key, SledStore, JMAP_TO_UID, UID_TO_JMAP};
+
+    #[test]
+    fn verify_and_repair() {
+        let temp_dir = std::env::temp_dir().join("stalwart-imap-verify-test");
+        if temp_dir.exists() {
+            std::fs::remove_dir_all(&temp_dir).unwrap();
+        }
+        let db = Arc::new(sled::open(&temp_dir).unwrap());
+        let store = SledStore::new(db.clone());
+        let inbox = MailboxId {
+            account_id: "john".to_string(),
+            mailbox_id: "inbox".to_string().into(),
+        };
+        for jmap_id in ["a", "b", "c"] {
+            store.insert_jmap_id(&inbox, jmap_id).unwrap();
+        }
+        assert_eq!(store.state_to_modseq("john", "s1").unwrap(), 1);
+        assert_eq!(store.verify(false).unwrap(), Vec::<String>::new());
+
+        // Duplicate UID, stale and missing JMAP id mappings, outdated HIGHESTMODSEQ
+        db.insert(serialize_key(&inbox, UID_TO_JMAP, &4u32.to_be_bytes()), "a")
+            .unwrap();
+        db.insert(
+            serialize_key(&inbox, JMAP_TO_UID, b"z"),
+            &9u32.to_be_bytes(),
+        )
+        .unwrap();
+        db.remove(serialize_key(&inbox, JMAP_TO_UID, b"c")).unwrap();
+        db.insert(serialize_highestmodseq(b"john"), &0u32.to_be_bytes())
+            .unwrap();
+
+        let problems = store.verify(false).unwrap();
+        assert_eq!(problems.len(), 5, "{:?}", problems);
+        assert_eq!(store.verify(false).unwrap(), problems);
+        assert_eq!(store.verify(true).unwrap(), problems);
+        assert_eq!(store.verify(false).unwrap(), Vec::<String>::new());
+
+        assert_eq!(store.uid_to_jmap(&inbox, 4).unwrap(), None);
+        assert_eq!(store.jmap_to_uid(&inbox, "a").unwrap(), Some(1));
+        assert_eq!(store.jmap_to_uid(&inbox, "c").unwrap(), Some(3));
+        assert_eq!(store.jmap_to_uid(&inbox, "z").unwrap(), None);
+        assert_eq!(store.uid_next(&inbox).unwrap(), 5);
+        assert_eq!(store.highest_modseq("john").unwrap(), 1);
+
+        drop(store);
+        drop(db);
+        std::fs::remove_dir_all(&temp_dir).unwrap();
+    }
+}
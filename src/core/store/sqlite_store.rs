@@ -0,0 +1,607 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use std::path::PathBuf;
+
+use ahash::AHashSet;
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use tracing::error;
+
+use crate::core::message::MailboxId;
+
+use super::{generate_uid_validity, mailbox_name, now, UidStore};
+
+// Stores the mappings in a SQLite database. Mailbox ids are stored as an
+// empty string for views that span all mailboxes of an account.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    path: PathBuf,
+}
+
+const SCHEMA: &str = "
+    PRAGMA journal_mode = WAL;
+    CREATE TABLE IF NOT EXISTS mailboxes (
+        account_id TEXT NOT NULL,
+        mailbox_id TEXT NOT NULL,
+        uid_validity INTEGER,
+        last_uid INTEGER NOT NULL DEFAULT 0,
+        query_state TEXT,
+        PRIMARY KEY (account_id, mailbox_id)
+    );
+    CREATE TABLE IF NOT EXISTS uids (
+        account_id TEXT NOT NULL,
+        mailbox_id TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        jmap_id TEXT NOT NULL,
+        PRIMARY KEY (account_id, mailbox_id, uid),
+        UNIQUE (account_id, mailbox_id, jmap_id)
+    );
+    CREATE TABLE IF NOT EXISTS deleted_ids (
+        account_id TEXT NOT NULL,
+        mailbox_id TEXT NOT NULL,
+        jmap_id TEXT NOT NULL,
+        uid INTEGER NOT NULL,
+        deleted_at INTEGER NOT NULL,
+        PRIMARY KEY (account_id, mailbox_id, jmap_id)
+    );
+    CREATE TABLE IF NOT EXISTS modseqs (
+        account_id TEXT NOT NULL,
+        modseq INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        PRIMARY KEY (account_id, modseq),
+        UNIQUE (account_id, state)
+    );
+    CREATE TABLE IF NOT EXISTS accounts (
+        account_id TEXT NOT NULL PRIMARY KEY,
+        last_login INTEGER NOT NULL
+    );
+";
+
+impl SqliteStore {
+    pub fn open(path: PathBuf) -> Result<Self, String> {
+        let conn = Connection::open(&path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        conn.execute_batch(SCHEMA)
+            .map_err(|err| format!("Failed to create tables: {}", err))?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            path,
+        })
+    }
+}
+
+impl UidStore for SqliteStore {
+    fn uid_validity(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(log_error)?;
+        let uid_validity = if let Some(uid_validity) = tx
+            .query_row(
+                "SELECT uid_validity FROM mailboxes WHERE account_id = ? AND mailbox_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox)],
+                |row| row.get::<_, Option<u32>>(0),
+            )
+            .optional()
+            .map_err(log_error)?
+            .flatten()
+        {
+            uid_validity
+        } else {
+            let uid_validity = generate_uid_validity();
+            tx.execute(
+                "INSERT INTO mailboxes (account_id, mailbox_id, uid_validity) VALUES (?, ?, ?)
+                 ON CONFLICT (account_id, mailbox_id) DO UPDATE SET uid_validity = excluded.uid_validity",
+                params![mailbox.account_id, mailbox_key(mailbox), uid_validity],
+            )
+            .map_err(log_error)?;
+            uid_validity
+        };
+        tx.commit().map_err(log_error)?;
+        Ok(uid_validity)
+    }
+
+    fn get_uid_validity(&self, mailbox: &MailboxId) -> Result<Option<u32>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT uid_validity FROM mailboxes WHERE account_id = ? AND mailbox_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox)],
+                |row| row.get::<_, Option<u32>>(0),
+            )
+            .optional()
+            .map(Option::flatten)
+            .map_err(log_error)
+    }
+
+    fn uid_next(&self, mailbox: &MailboxId) -> Result<u32, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT last_uid FROM mailboxes WHERE account_id = ? AND mailbox_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox)],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()
+            .map(|last_uid| last_uid.unwrap_or(0) + 1)
+            .map_err(log_error)
+    }
+
+    fn insert_jmap_id(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<u32, ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(log_error)?;
+        if let Some(uid) = tx
+            .query_row(
+                "SELECT uid FROM uids WHERE account_id = ? AND mailbox_id = ? AND jmap_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox), jmap_id],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()
+            .map_err(log_error)?
+        {
+            return Ok(uid);
+        }
+        let uid = tx
+            .query_row(
+                "INSERT INTO mailboxes (account_id, mailbox_id, last_uid) VALUES (?, ?, 1)
+                 ON CONFLICT (account_id, mailbox_id) DO UPDATE SET last_uid = last_uid + 1
+                 RETURNING last_uid",
+                params![mailbox.account_id, mailbox_key(mailbox)],
+                |row| row.get::<_, u32>(0),
+            )
+            .map_err(log_error)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO uids (account_id, mailbox_id, uid, jmap_id) VALUES (?, ?, ?, ?)",
+            params![mailbox.account_id, mailbox_key(mailbox), uid, jmap_id],
+        )
+        .map_err(log_error)?;
+        tx.commit().map_err(log_error)?;
+        Ok(uid)
+    }
+
+    fn jmap_to_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT uid FROM uids WHERE account_id = ? AND mailbox_id = ? AND jmap_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox), jmap_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(log_error)
+    }
+
+    fn uid_to_jmap(&self, mailbox: &MailboxId, uid: u32) -> Result<Option<String>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT jmap_id FROM uids WHERE account_id = ? AND mailbox_id = ? AND uid = ?",
+                params![mailbox.account_id, mailbox_key(mailbox), uid],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(log_error)
+    }
+
+    fn deleted_uid(&self, mailbox: &MailboxId, jmap_id: &str) -> Result<Option<u32>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT uid FROM deleted_ids WHERE account_id = ? AND mailbox_id = ? AND jmap_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox), jmap_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(log_error)
+    }
+
+    fn mailbox_ids(&self, mailbox: &MailboxId) -> Result<Vec<(u32, String)>, ()> {
+        let conn = self.conn.lock();
+        let mut stmt = conn
+            .prepare_cached(
+                "SELECT uid, jmap_id FROM uids WHERE account_id = ? AND mailbox_id = ? ORDER BY uid",
+            )
+            .map_err(log_error)?;
+        let ids = stmt
+            .query_map(params![mailbox.account_id, mailbox_key(mailbox)], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .map_err(log_error);
+        ids
+    }
+
+    fn delete_ids(&self, mailbox: &MailboxId, jmap_ids: &[String]) -> Result<(), ()> {
+        let deleted_at = now() as i64;
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        for jmap_id in jmap_ids {
+            tx.execute(
+                "INSERT OR REPLACE INTO deleted_ids (account_id, mailbox_id, jmap_id, uid, deleted_at)
+                 SELECT account_id, mailbox_id, jmap_id, uid, ? FROM uids
+                 WHERE account_id = ? AND mailbox_id = ? AND jmap_id = ?",
+                params![deleted_at, mailbox.account_id, mailbox_key(mailbox), jmap_id],
+            )
+            .and_then(|_| {
+                tx.execute(
+                    "DELETE FROM uids WHERE account_id = ? AND mailbox_id = ? AND jmap_id = ?",
+                    params![mailbox.account_id, mailbox_key(mailbox), jmap_id],
+                )
+            })
+            .map_err(log_error)?;
+        }
+        tx.commit().map_err(log_error)
+    }
+
+    fn query_state(&self, mailbox: &MailboxId) -> Result<Option<String>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT query_state FROM mailboxes WHERE account_id = ? AND mailbox_id = ?",
+                params![mailbox.account_id, mailbox_key(mailbox)],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map(Option::flatten)
+            .map_err(log_error)
+    }
+
+    fn set_query_state(&self, mailbox: &MailboxId, query_state: Option<&str>) -> Result<(), ()> {
+        self.conn
+            .lock()
+            .execute(
+                "INSERT INTO mailboxes (account_id, mailbox_id, query_state) VALUES (?, ?, ?)
+                 ON CONFLICT (account_id, mailbox_id) DO UPDATE SET query_state = excluded.query_state",
+                params![mailbox.account_id, mailbox_key(mailbox), query_state],
+            )
+            .map(|_| ())
+            .map_err(log_error)
+    }
+
+    fn state_to_modseq(&self, account_id: &str, state: &str) -> Result<u32, ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(log_error)?;
+        let modseq = if let Some(modseq) = tx
+            .query_row(
+                "SELECT modseq FROM modseqs WHERE account_id = ? AND state = ?",
+                params![account_id, state],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()
+            .map_err(log_error)?
+        {
+            modseq
+        } else {
+            tx.query_row(
+                "INSERT INTO modseqs (account_id, modseq, state)
+                 SELECT ?1, COALESCE(MAX(modseq), 0) + 1, ?2 FROM modseqs WHERE account_id = ?1
+                 RETURNING modseq",
+                params![account_id, state],
+                |row| row.get::<_, u32>(0),
+            )
+            .map_err(log_error)?
+        };
+        tx.commit().map_err(log_error)?;
+        Ok(modseq)
+    }
+
+    fn modseq_to_state(&self, account_id: &str, modseq: u32) -> Result<Option<String>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT state FROM modseqs WHERE account_id = ? AND modseq = ?",
+                params![account_id, modseq],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(log_error)
+    }
+
+    fn highest_modseq(&self, account_id: &str) -> Result<u32, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT COALESCE(MAX(modseq), 0) FROM modseqs WHERE account_id = ?",
+                params![account_id],
+                |row| row.get(0),
+            )
+            .map_err(log_error)
+    }
+
+    fn insert_modseq(&self, account_id: &str, modseq: u32, state: &str) -> Result<(), ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        tx.execute(
+            "DELETE FROM modseqs WHERE account_id = ? AND (modseq = ? OR state = ?)",
+            params![account_id, modseq, state],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "INSERT INTO modseqs (account_id, modseq, state) VALUES (?, ?, ?)",
+                params![account_id, modseq, state],
+            )
+        })
+        .map_err(log_error)?;
+        tx.commit().map_err(log_error)
+    }
+
+    fn account_ids(&self) -> Result<Vec<String>, ()> {
+        self.conn
+            .lock()
+            .prepare(
+                "SELECT account_id FROM mailboxes UNION SELECT account_id FROM uids
+                 UNION SELECT account_id FROM deleted_ids UNION SELECT account_id FROM modseqs
+                 UNION SELECT account_id FROM accounts ORDER BY account_id",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(log_error)
+    }
+
+    fn account_mailboxes(&self, account_id: &str) -> Result<Vec<Option<String>>, ()> {
+        self.conn
+            .lock()
+            .prepare(
+                "SELECT mailbox_id FROM mailboxes WHERE account_id = ?1
+                 UNION SELECT mailbox_id FROM uids WHERE account_id = ?1
+                 ORDER BY mailbox_id",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(params![account_id], |row| {
+                    row.get::<_, String>(0)
+                        .map(|mailbox_id| Some(mailbox_id).filter(|id| !id.is_empty()))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(log_error)
+    }
+
+    fn set_uid_validity(&self, mailbox: &MailboxId, uid_validity: u32) -> Result<(), ()> {
+        self.conn
+            .lock()
+            .execute(
+                "INSERT INTO mailboxes (account_id, mailbox_id, uid_validity) VALUES (?, ?, ?)
+                 ON CONFLICT (account_id, mailbox_id) DO UPDATE SET uid_validity = excluded.uid_validity",
+                params![mailbox.account_id, mailbox_key(mailbox), uid_validity],
+            )
+            .map(|_| ())
+            .map_err(log_error)
+    }
+
+    fn set_uid_next(&self, mailbox: &MailboxId, uid_next: u32) -> Result<(), ()> {
+        if uid_next > 1 {
+            raise_last_uid(&self.conn.lock(), mailbox, uid_next - 1)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn insert_uid(&self, mailbox: &MailboxId, uid: u32, jmap_id: &str) -> Result<(), ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        tx.execute(
+            "DELETE FROM uids WHERE account_id = ? AND mailbox_id = ? AND (uid = ? OR jmap_id = ?)",
+            params![mailbox.account_id, mailbox_key(mailbox), uid, jmap_id],
+        )
+        .and_then(|_| {
+            tx.execute(
+                "INSERT OR REPLACE INTO uids (account_id, mailbox_id, uid, jmap_id) VALUES (?, ?, ?, ?)",
+                params![mailbox.account_id, mailbox_key(mailbox), uid, jmap_id],
+            )
+        })
+        .map_err(log_error)?;
+        raise_last_uid(&tx, mailbox, uid)?;
+        tx.commit().map_err(log_error)
+    }
+
+    fn delete_account(&self, account_id: &str) -> Result<(), ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        for query in [
+            "DELETE FROM mailboxes WHERE account_id = ?",
+            "DELETE FROM uids WHERE account_id = ?",
+            "DELETE FROM deleted_ids WHERE account_id = ?",
+            "DELETE FROM modseqs WHERE account_id = ?",
+            "DELETE FROM accounts WHERE account_id = ?",
+        ] {
+            tx.execute(query, params![account_id]).map_err(log_error)?;
+        }
+        tx.commit().map_err(log_error)
+    }
+
+    fn delete_mailbox(&self, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        delete_mailbox(&tx, account_id, mailbox_id)?;
+        tx.commit().map_err(log_error)
+    }
+
+    fn purge_deleted_mailboxes(
+        &self,
+        account_id: &str,
+        mailbox_ids: &AHashSet<String>,
+    ) -> Result<(), ()> {
+        let mut conn = self.conn.lock();
+        let tx = conn.transaction().map_err(log_error)?;
+        let stored_ids = tx
+            .prepare(
+                "SELECT mailbox_id FROM mailboxes WHERE account_id = ?1 AND mailbox_id != ''
+                 UNION SELECT mailbox_id FROM uids WHERE account_id = ?1 AND mailbox_id != ''",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map(params![account_id], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(log_error)?;
+        for mailbox_id in stored_ids {
+            if !mailbox_ids.contains(&mailbox_id) {
+                delete_mailbox(&tx, account_id, &mailbox_id)?;
+            }
+        }
+        tx.commit().map_err(log_error)
+    }
+
+    fn purge_deleted_ids(&self, ttl: u64) -> Result<usize, ()> {
+        self.conn
+            .lock()
+            .execute(
+                "DELETE FROM deleted_ids WHERE deleted_at < ?1 AND ?1 - deleted_at >= ?2",
+                params![now() as i64, ttl as i64],
+            )
+            .map_err(log_error)
+    }
+
+    fn set_last_login(&self, account_id: &str, timestamp: u64) -> Result<(), ()> {
+        self.conn
+            .lock()
+            .execute(
+                "INSERT INTO accounts (account_id, last_login) VALUES (?, ?)
+                 ON CONFLICT (account_id) DO UPDATE SET last_login = excluded.last_login",
+                params![account_id, timestamp as i64],
+            )
+            .map(|_| ())
+            .map_err(log_error)
+    }
+
+    fn last_login(&self, account_id: &str) -> Result<Option<u64>, ()> {
+        self.conn
+            .lock()
+            .query_row(
+                "SELECT last_login FROM accounts WHERE account_id = ?",
+                params![account_id],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map(|last_login| last_login.map(|last_login| last_login as u64))
+            .map_err(log_error)
+    }
+
+    fn size_on_disk(&self) -> u64 {
+        std::fs::metadata(&self.path)
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    }
+
+    fn flush(&self) -> Result<(), ()> {
+        // Every write is committed to the WAL, just move it to the database file.
+        self.conn
+            .lock()
+            .execute_batch("PRAGMA wal_checkpoint(PASSIVE);")
+            .map_err(log_error)
+    }
+
+    fn compact(&self) -> Result<(), ()> {
+        self.conn
+            .lock()
+            .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")
+            .map_err(log_error)
+    }
+
+    fn verify(&self, repair: bool) -> Result<Vec<String>, ()> {
+        // Mapping uniqueness is enforced by the table constraints, so only
+        // database corruption and the last UID of each mailbox are checked.
+        let conn = self.conn.lock();
+        let mut problems = conn
+            .prepare("PRAGMA integrity_check")
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get::<_, String>(0))?
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(log_error)?
+            .into_iter()
+            .filter(|result| result != "ok")
+            .collect::<Vec<_>>();
+
+        let last_uids = conn
+            .prepare(
+                "SELECT uids.account_id, uids.mailbox_id, MAX(uids.uid), COALESCE(mailboxes.last_uid, 0)
+                 FROM uids LEFT JOIN mailboxes USING (account_id, mailbox_id)
+                 GROUP BY uids.account_id, uids.mailbox_id
+                 HAVING MAX(uids.uid) > COALESCE(mailboxes.last_uid, 0)",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, u32>(2)?,
+                        row.get::<_, u32>(3)?,
+                    ))
+                })?
+                .collect::<Result<Vec<_>, _>>()
+            })
+            .map_err(log_error)?;
+        for (account_id, mailbox_id, max_uid, last_uid) in last_uids {
+            let mailbox = MailboxId {
+                account_id,
+                mailbox_id: Some(mailbox_id).filter(|id| !id.is_empty()),
+            };
+            problems.push(format!(
+                "{}: UIDNEXT {} is not above UID {}.",
+                mailbox_name(&mailbox),
+                last_uid + 1,
+                max_uid
+            ));
+            if repair {
+                raise_last_uid(&conn, &mailbox, max_uid)?;
+            }
+        }
+
+        Ok(problems)
+    }
+}
+
+fn delete_mailbox(conn: &Connection, account_id: &str, mailbox_id: &str) -> Result<(), ()> {
+    for query in [
+        "DELETE FROM mailboxes WHERE account_id = ? AND mailbox_id = ?",
+        "DELETE FROM uids WHERE account_id = ? AND mailbox_id = ?",
+    ] {
+        conn.execute(query, params![account_id, mailbox_id])
+            .map_err(log_error)?;
+    }
+    Ok(())
+}
+
+fn raise_last_uid(conn: &Connection, mailbox: &MailboxId, last_uid: u32) -> Result<(), ()> {
+    conn.execute(
+        "INSERT INTO mailboxes (account_id, mailbox_id, last_uid) VALUES (?, ?, ?)
+         ON CONFLICT (account_id, mailbox_id) DO UPDATE SET last_uid = MAX(last_uid, excluded.last_uid)",
+        params![mailbox.account_id, mailbox_key(mailbox), last_uid],
+    )
+    .map(|_| ())
+    .map_err(log_error)
+}
+
+fn mailbox_key(mailbox: &MailboxId) -> &str {
+    mailbox.mailbox_id.as_deref().unwrap_or("")
+}
+
+fn log_error(err: rusqlite::Error) {
+    error!("SQLite query failed: {}", err);
+}
@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+
+pub struct SniCertResolver {
+    pub default_cert: Arc<CertifiedKey>,
+    pub certs: AHashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniCertResolver {
+    pub fn new(default_cert: CertifiedKey) -> Self {
+        SniCertResolver {
+            default_cert: Arc::new(default_cert),
+            certs: AHashMap::new(),
+        }
+    }
+
+    pub fn add(&mut self, hostname: &str, cert: CertifiedKey) {
+        self.certs.insert(hostname.to_lowercase(), Arc::new(cert));
+    }
+
+    pub fn get(&self, hostname: &str) -> Arc<CertifiedKey> {
+        let hostname = hostname.to_lowercase();
+        self.certs
+            .get(&hostname)
+            .or_else(|| {
+                // Fall back to a wildcard certificate for the parent domain
+                hostname
+                    .split_once('.')
+                    .and_then(|(_, domain)| self.certs.get(&format!("*.{}", domain)))
+            })
+            .unwrap_or(&self.default_cert)
+            .clone()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(if let Some(hostname) = client_hello.server_name() {
+            self.get(hostname)
+        } else {
+            self.default_cert.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::core::config::load_certified_key;
+
+    use super::SniCertResolver;
+
+    #[test]
+    fn sni_resolve() {
+        let mut resources = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        resources.push("src");
+        resources.push("tests");
+        resources.push("resources");
+        let cert_path = resources.join("cert.pem").to_str().unwrap().to_string();
+        let key_path = resources.join("key.pem").to_str().unwrap().to_string();
+        let load_cert = || load_certified_key(&cert_path, &key_path).unwrap();
+
+        let mut resolver = SniCertResolver::new(load_cert());
+        resolver.add("mail.example.org", load_cert());
+        resolver.add("*.example.net", load_cert());
+
+        for (hostname, expected_hostname) in [
+            ("mail.example.org", Some("mail.example.org")),
+            ("MAIL.Example.org", Some("mail.example.org")),
+            ("imap.example.org", None),
+            ("imap.example.net", Some("*.example.net")),
+            ("example.net", None),
+            ("a.imap.example.net", None),
+        ] {
+            let cert = resolver.get(hostname);
+            assert!(
+                Arc::ptr_eq(
+                    &cert,
+                    expected_hostname
+                        .map(|hostname| resolver.certs.get(hostname).unwrap())
+                        .unwrap_or(&resolver.default_cert)
+                ),
+                "{}",
+                hostname
+            );
+        }
+    }
+}
@@ -0,0 +1,420 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::sync::{Arc, Weak};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+
+use super::message::{ImapId, MailboxId};
+
+// Immutable mapping between the UIDs of a mailbox and their JMAP ids.
+// Entries are kept in ascending UID order, so the sequence number of a
+// message is its position plus one. JMAP ids are stored back to back in
+// a single buffer and a second index sorted by id allows binary searching
+// them without a hash table.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct UidMap {
+    uids: Box<[u32]>,
+    id_ends: Box<[u32]>,
+    ids: Box<[u8]>,
+    by_id: Box<[u32]>,
+}
+
+// Snapshots shared by all sessions that have the same mailbox selected.
+// Sessions that have not yet seen the latest changes hold on to older
+// snapshots, so a mailbox may have a few of them alive at once.
+#[derive(Default)]
+pub struct UidMapCache {
+    maps: Mutex<AHashMap<Arc<MailboxId>, Vec<Weak<UidMap>>>>,
+}
+
+impl UidMap {
+    pub fn new<'x>(entries: impl IntoIterator<Item = (u32, &'x str)>) -> Self {
+        let mut entries = entries.into_iter().collect::<Vec<_>>();
+        if !entries.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            entries.sort_unstable_by_key(|(uid, _)| *uid);
+            entries.dedup_by_key(|(uid, _)| *uid);
+        }
+
+        let mut uids = Vec::with_capacity(entries.len());
+        let mut id_ends = Vec::with_capacity(entries.len());
+        let mut ids = Vec::with_capacity(entries.len() * 8);
+        for (uid, jmap_id) in entries {
+            uids.push(uid);
+            ids.extend_from_slice(jmap_id.as_bytes());
+            id_ends.push(ids.len() as u32);
+        }
+
+        let mut map = UidMap {
+            uids: uids.into_boxed_slice(),
+            id_ends: id_ends.into_boxed_slice(),
+            ids: ids.into_boxed_slice(),
+            by_id: Box::default(),
+        };
+        let mut by_id = (0..map.len() as u32).collect::<Vec<_>>();
+        by_id.sort_unstable_by(|&a, &b| map.id_bytes(a as usize).cmp(map.id_bytes(b as usize)));
+        map.by_id = by_id.into_boxed_slice();
+        map
+    }
+
+    pub fn len(&self) -> usize {
+        self.uids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty()
+    }
+
+    pub fn uids(&self) -> &[u32] {
+        &self.uids
+    }
+
+    pub fn uid(&self, pos: usize) -> u32 {
+        self.uids[pos]
+    }
+
+    pub fn last_uid(&self) -> Option<u32> {
+        self.uids.last().copied()
+    }
+
+    pub fn jmap_id(&self, pos: usize) -> &str {
+        // Ids are only ever added from a &str
+        std::str::from_utf8(self.id_bytes(pos)).unwrap_or_default()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        (0..self.len()).map(|pos| (self.uids[pos], self.jmap_id(pos)))
+    }
+
+    pub fn uid_to_pos(&self, uid: u32) -> Option<usize> {
+        self.uids.binary_search(&uid).ok()
+    }
+
+    pub fn jmap_id_to_pos(&self, jmap_id: &str) -> Option<usize> {
+        self.by_id
+            .binary_search_by(|&pos| self.id_bytes(pos as usize).cmp(jmap_id.as_bytes()))
+            .ok()
+            .map(|pos| self.by_id[pos] as usize)
+    }
+
+    pub fn contains_uid(&self, uid: u32) -> bool {
+        self.uid_to_pos(uid).is_some()
+    }
+
+    pub fn contains_jmap_id(&self, jmap_id: &str) -> bool {
+        self.jmap_id_to_pos(jmap_id).is_some()
+    }
+
+    // Returns a copy without the entries matching the filter, along with
+    // the ids they had in this snapshot.
+    pub fn remove(&self, filter: impl Fn(u32, &str) -> bool) -> (UidMap, Vec<ImapId>) {
+        let mut removed = Vec::new();
+        let mut new_pos = Vec::with_capacity(self.len());
+        let mut uids = Vec::with_capacity(self.len());
+        let mut id_ends = Vec::with_capacity(self.len());
+        let mut ids = Vec::with_capacity(self.ids.len());
+
+        for (pos, (uid, jmap_id)) in self.iter().enumerate() {
+            if !filter(uid, jmap_id) {
+                new_pos.push(uids.len() as u32);
+                uids.push(uid);
+                ids.extend_from_slice(jmap_id.as_bytes());
+                id_ends.push(ids.len() as u32);
+            } else {
+                new_pos.push(u32::MAX);
+                removed.push(ImapId::new(uid, (pos + 1) as u32));
+            }
+        }
+
+        // Removing entries does not change the order of the remaining ids
+        let by_id = self
+            .by_id
+            .iter()
+            .map(|&pos| new_pos[pos as usize])
+            .filter(|&pos| pos != u32::MAX)
+            .collect::<Vec<_>>();
+
+        (
+            UidMap {
+                uids: uids.into_boxed_slice(),
+                id_ends: id_ends.into_boxed_slice(),
+                ids: ids.into_boxed_slice(),
+                by_id: by_id.into_boxed_slice(),
+            },
+            removed,
+        )
+    }
+
+    // Returns a copy including the entries of another snapshot that are not
+    // present in this one.
+    pub fn merge(&self, other: &UidMap) -> Option<UidMap> {
+        let added = other
+            .iter()
+            .filter(|(uid, _)| !self.contains_uid(*uid))
+            .collect::<Vec<_>>();
+        if !added.is_empty() {
+            Some(self.append(added))
+        } else {
+            None
+        }
+    }
+
+    // Returns a copy holding exactly the given entries, keeping the ones
+    // this snapshot already has.
+    pub fn rebase(&self, uids: &[u32], jmap_ids: &[String]) -> UidMap {
+        let entries = uids
+            .iter()
+            .copied()
+            .zip(jmap_ids.iter().map(|id| id.as_str()))
+            .collect::<AHashMap<_, _>>();
+        let (map, _) = self.remove(|uid, jmap_id| entries.get(&uid) != Some(&jmap_id));
+        let added = entries
+            .into_iter()
+            .filter(|(uid, _)| !map.contains_uid(*uid))
+            .collect::<Vec<_>>();
+        if !added.is_empty() {
+            map.append(added)
+        } else {
+            map
+        }
+    }
+
+    fn append(&self, mut added: Vec<(u32, &str)>) -> UidMap {
+        added.sort_unstable_by_key(|(uid, _)| *uid);
+        if added.first().map_or(false, |(uid, _)| {
+            self.last_uid().map_or(false, |last_uid| *uid <= last_uid)
+        }) {
+            // Sequence numbers follow the UID order, so the map is rebuilt
+            return UidMap::new(self.iter().chain(added));
+        }
+
+        let mut uids = Vec::with_capacity(self.len() + added.len());
+        let mut id_ends = Vec::with_capacity(self.len() + added.len());
+        let mut ids = Vec::with_capacity(self.ids.len() + added.len() * 8);
+        uids.extend_from_slice(&self.uids);
+        id_ends.extend_from_slice(&self.id_ends);
+        ids.extend_from_slice(&self.ids);
+        for (uid, jmap_id) in added {
+            uids.push(uid);
+            ids.extend_from_slice(jmap_id.as_bytes());
+            id_ends.push(ids.len() as u32);
+        }
+        let mut map = UidMap {
+            uids: uids.into_boxed_slice(),
+            id_ends: id_ends.into_boxed_slice(),
+            ids: ids.into_boxed_slice(),
+            by_id: Box::default(),
+        };
+
+        // Only the new ids are sorted, then merged with the existing index
+        let mut added_by_id = (self.len() as u32..map.len() as u32).collect::<Vec<_>>();
+        added_by_id
+            .sort_unstable_by(|&a, &b| map.id_bytes(a as usize).cmp(map.id_bytes(b as usize)));
+        let mut by_id = Vec::with_capacity(map.len());
+        let mut added_by_id = added_by_id.into_iter().peekable();
+        for &pos in self.by_id.iter() {
+            while let Some(added_pos) =
+                added_by_id.next_if(|&a| map.id_bytes(a as usize) < map.id_bytes(pos as usize))
+            {
+                by_id.push(added_pos);
+            }
+            by_id.push(pos);
+        }
+        by_id.extend(added_by_id);
+        map.by_id = by_id.into_boxed_slice();
+        map
+    }
+
+    pub fn heap_size(&self) -> usize {
+        std::mem::size_of_val(&self.uids[..])
+            + std::mem::size_of_val(&self.id_ends[..])
+            + self.ids.len()
+            + std::mem::size_of_val(&self.by_id[..])
+    }
+
+    fn id_bytes(&self, pos: usize) -> &[u8] {
+        let start = if pos > 0 {
+            self.id_ends[pos - 1] as usize
+        } else {
+            0
+        };
+        &self.ids[start..self.id_ends[pos] as usize]
+    }
+}
+
+impl UidMapCache {
+    // Returns a snapshot in use for this mailbox if it has the same contents.
+    pub fn intern(&self, mailbox: &Arc<MailboxId>, map: UidMap) -> Arc<UidMap> {
+        let mut maps = self.maps.lock();
+        if !maps.contains_key(mailbox) {
+            maps.retain(|_, snapshots| snapshots.iter().any(|map| map.strong_count() > 0));
+        }
+        let snapshots = maps.entry(mailbox.clone()).or_default();
+        snapshots.retain(|map| map.strong_count() > 0);
+        if let Some((pos, current)) = snapshots.iter().enumerate().find_map(|(pos, current)| {
+            current
+                .upgrade()
+                .filter(|current| **current == map)
+                .map(|current| (pos, current))
+        }) {
+            // Keep the most recently used snapshot last
+            let current_ = snapshots.remove(pos);
+            snapshots.push(current_);
+            return current;
+        }
+        let map = Arc::new(map);
+        snapshots.push(Arc::downgrade(&map));
+        map
+    }
+
+    // Returns the snapshot for the entries just read from the store. The latest
+    // snapshot is reused when nothing changed, otherwise the new one is derived
+    // from it rather than built from scratch.
+    pub fn update(
+        &self,
+        mailbox: &Arc<MailboxId>,
+        uids: &[u32],
+        jmap_ids: &[String],
+    ) -> Arc<UidMap> {
+        let latest = self
+            .maps
+            .lock()
+            .get(mailbox)
+            .and_then(|snapshots| snapshots.iter().rev().find_map(|map| map.upgrade()));
+        let map = if let Some(latest) = latest {
+            if latest.uids() == uids
+                && latest
+                    .iter()
+                    .zip(jmap_ids)
+                    .all(|((_, jmap_id), new_jmap_id)| jmap_id == new_jmap_id)
+            {
+                return latest;
+            }
+            latest.rebase(uids, jmap_ids)
+        } else {
+            UidMap::new(
+                uids.iter()
+                    .copied()
+                    .zip(jmap_ids.iter().map(|id| id.as_str())),
+            )
+        };
+        self.intern(mailbox, map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::core::message::MailboxId;
+
+    use super::{UidMap, UidMapCache};
+
+    #[test]
+    fn uid_map() {
+        let map = UidMap::new([(1, "d"), (3, "b"), (7, "ccc"), (8, "a")]);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map.last_uid(), Some(8));
+        assert_eq!(map.jmap_id(2), "ccc");
+        assert_eq!(map.uid_to_pos(7), Some(2));
+        assert_eq!(map.uid_to_pos(2), None);
+        for (pos, (uid, jmap_id)) in map.iter().enumerate() {
+            assert_eq!(map.jmap_id_to_pos(jmap_id), Some(pos));
+            assert_eq!(map.uid_to_pos(uid), Some(pos));
+        }
+        assert_eq!(map.jmap_id_to_pos("e"), None);
+
+        let (removed_map, removed) = map.remove(|uid, jmap_id| uid == 3 || jmap_id == "a");
+        assert_eq!(
+            removed_map.iter().collect::<Vec<_>>(),
+            [(1, "d"), (7, "ccc")]
+        );
+        assert_eq!(
+            removed
+                .iter()
+                .map(|id| (id.uid, id.seqnum))
+                .collect::<Vec<_>>(),
+            [(3, 2), (8, 4)]
+        );
+
+        let merged = removed_map
+            .merge(&UidMap::new([(7, "ccc"), (9, "e")]))
+            .unwrap();
+        assert_eq!(
+            merged.iter().collect::<Vec<_>>(),
+            [(1, "d"), (7, "ccc"), (9, "e")]
+        );
+        assert!(merged.merge(&UidMap::new([(9, "e")])).is_none());
+
+        // UIDs lower than the existing ones are kept in order
+        let merged = merged.merge(&UidMap::new([(2, "f"), (10, "b")])).unwrap();
+        assert_eq!(
+            merged,
+            UidMap::new([(10, "b"), (9, "e"), (7, "ccc"), (2, "f"), (1, "d")])
+        );
+        assert_eq!(merged.jmap_id_to_pos("b"), Some(4));
+
+        let cache = UidMapCache::default();
+        let mailbox = Arc::new(MailboxId {
+            account_id: "a".to_string(),
+            mailbox_id: None,
+        });
+        let shared = cache.intern(&mailbox, UidMap::new([(1, "a")]));
+        assert!(Arc::ptr_eq(
+            &shared,
+            &cache.intern(&mailbox, UidMap::new([(1, "a")]))
+        ));
+        let other = cache.intern(&mailbox, UidMap::new([(2, "a")]));
+        assert!(!Arc::ptr_eq(&shared, &other));
+        assert!(Arc::ptr_eq(
+            &shared,
+            &cache.intern(&mailbox, UidMap::new([(1, "a")]))
+        ));
+    }
+
+    #[test]
+    fn uid_map_update() {
+        let cache = UidMapCache::default();
+        let mailbox = Arc::new(MailboxId {
+            account_id: "a".to_string(),
+            mailbox_id: None,
+        });
+        let ids = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+
+        let first = cache.update(&mailbox, &[1, 2, 3], &ids(&["c", "a", "b"]));
+        assert!(Arc::ptr_eq(
+            &first,
+            &cache.update(&mailbox, &[1, 2, 3], &ids(&["c", "a", "b"]))
+        ));
+
+        // New snapshots are derived from the latest one
+        let second = cache.update(&mailbox, &[1, 3, 4], &ids(&["c", "b", "d"]));
+        assert_eq!(*second, UidMap::new([(1, "c"), (3, "b"), (4, "d")]));
+
+        // Sessions that expunge the same messages end up sharing the snapshot
+        let (expunged, _) = first.remove(|uid, _| uid == 2);
+        let merged = expunged.merge(&second).unwrap();
+        assert!(Arc::ptr_eq(&second, &cache.intern(&mailbox, merged)));
+    }
+}
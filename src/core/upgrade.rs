@@ -0,0 +1,271 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use ahash::AHashMap;
+use parking_lot::Mutex;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::debug;
+
+pub const LISTEN_FDS_VAR: &str = "STALWART_IMAP_LISTEN_FDS";
+pub const CONTROL_SOCKET_VAR: &str = "STALWART_IMAP_CONTROL_SOCKET";
+
+static IS_UPGRADE: AtomicBool = AtomicBool::new(false);
+
+pub struct Listeners {
+    inherited: Mutex<AHashMap<SocketAddr, std::net::TcpListener>>,
+    bound: Mutex<Vec<(SocketAddr, std::net::TcpListener)>>,
+    stop_tx: watch::Sender<bool>,
+}
+
+impl Listeners {
+    pub fn from_env() -> Self {
+        Listeners {
+            inherited: Mutex::new(inherited_listeners()),
+            bound: Mutex::new(Vec::new()),
+            stop_tx: watch::channel(false).0,
+        }
+    }
+
+    pub fn bind(&self, bind_addr: SocketAddr) -> io::Result<(TcpListener, watch::Receiver<bool>)> {
+        let listener = if let Some(listener) = self.inherited.lock().remove(&bind_addr) {
+            debug!("Using inherited socket for {}.", bind_addr);
+            listener
+        } else {
+            std::net::TcpListener::bind(bind_addr)?
+        };
+        listener.set_nonblocking(true)?;
+
+        // Keep a handle to the socket so it can be passed to a new process on upgrade
+        self.bound.lock().push((bind_addr, listener.try_clone()?));
+
+        Ok((TcpListener::from_std(listener)?, self.stop_tx.subscribe()))
+    }
+
+    pub fn stop(&self) {
+        self.stop_tx.send(true).ok();
+    }
+}
+
+// Whether this process was started by a previous instance that is still releasing the cache.
+pub fn is_upgrade() -> bool {
+    IS_UPGRADE.load(Ordering::Relaxed)
+}
+
+#[cfg(not(target_env = "msvc"))]
+fn inherited_listeners() -> AHashMap<SocketAddr, std::net::TcpListener> {
+    use std::os::unix::io::FromRawFd;
+
+    let mut listeners = AHashMap::new();
+    if let Ok(fds) = std::env::var(LISTEN_FDS_VAR) {
+        std::env::remove_var(LISTEN_FDS_VAR);
+        for (addr, fd) in parse_listen_fds(&fds) {
+            // Safety: the file descriptor was passed by the parent process and is not used elsewhere
+            listeners.insert(addr, unsafe { std::net::TcpListener::from_raw_fd(fd) });
+        }
+    }
+    listeners
+}
+
+#[cfg(target_env = "msvc")]
+fn inherited_listeners() -> AHashMap<SocketAddr, std::net::TcpListener> {
+    AHashMap::new()
+}
+
+pub fn parse_listen_fds(value: &str) -> Vec<(SocketAddr, i32)> {
+    value
+        .split(';')
+        .filter_map(|item| {
+            let (addr, fd) = item.rsplit_once('=')?;
+            Some((addr.trim().parse().ok()?, fd.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+#[cfg(not(target_env = "msvc"))]
+mod handoff {
+    use std::{os::unix::io::AsRawFd, sync::atomic::Ordering, time::Duration};
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+        process::Command,
+    };
+    use tracing::{error, info};
+
+    use super::{Listeners, CONTROL_SOCKET_VAR, IS_UPGRADE, LISTEN_FDS_VAR};
+
+    const UPGRADE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    impl Listeners {
+        // Starts a new instance of the server that inherits the listening sockets and
+        // waits until it signals that it is ready to take over.
+        pub async fn handoff(&self) -> Result<UnixStream, String> {
+            let socket_path =
+                std::env::temp_dir().join(format!("stalwart-imap-{}.sock", std::process::id()));
+            std::fs::remove_file(&socket_path).ok();
+            let control = UnixListener::bind(&socket_path).map_err(|err| {
+                format!(
+                    "Failed to bind control socket {}: {}",
+                    socket_path.display(),
+                    err
+                )
+            })?;
+
+            let (listen_fds, fds) = {
+                let bound = self.bound.lock();
+                (
+                    bound
+                        .iter()
+                        .map(|(addr, listener)| format!("{}={}", addr, listener.as_raw_fd()))
+                        .collect::<Vec<_>>()
+                        .join(";"),
+                    bound
+                        .iter()
+                        .map(|(_, listener)| listener.as_raw_fd())
+                        .collect::<Vec<_>>(),
+                )
+            };
+
+            let mut args = std::env::args_os();
+            let program = args
+                .next()
+                .ok_or_else(|| "Failed to obtain executable path.".to_string())?;
+            let mut command = Command::new(&program);
+            command
+                .args(args)
+                .env(LISTEN_FDS_VAR, listen_fds)
+                .env(CONTROL_SOCKET_VAR, &socket_path);
+
+            // Safety: only async-signal-safe functions are called after fork
+            unsafe {
+                command.pre_exec(move || {
+                    for fd in &fds {
+                        let flags = libc::fcntl(*fd, libc::F_GETFD);
+                        if flags < 0
+                            || libc::fcntl(*fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0
+                        {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+
+            let mut child = command
+                .spawn()
+                .map_err(|err| format!("Failed to start {:?}: {}", program, err))?;
+            info!(
+                "Started new process {}, waiting for it to take over...",
+                child.id().unwrap_or_default()
+            );
+
+            let result = tokio::time::timeout(UPGRADE_TIMEOUT, async {
+                tokio::select! {
+                    stream = control.accept() => {
+                        let (stream, _) = stream
+                            .map_err(|err| format!("Failed to accept control connection: {}", err))?;
+                        let mut stream = BufReader::new(stream);
+                        let mut line = String::new();
+                        stream
+                            .read_line(&mut line)
+                            .await
+                            .map_err(|err| format!("Failed to read from control socket: {}", err))?;
+                        if line.trim_end() == "READY" {
+                            Ok(stream.into_inner())
+                        } else {
+                            Err(format!("Unexpected control message {:?}.", line))
+                        }
+                    },
+                    status = child.wait() => {
+                        Err(match status {
+                            Ok(status) => format!("New process exited with {}.", status),
+                            Err(err) => format!("Failed to wait for new process: {}", err),
+                        })
+                    }
+                }
+            })
+            .await
+            .unwrap_or_else(|_| Err("Timed out waiting for new process.".to_string()));
+            std::fs::remove_file(&socket_path).ok();
+
+            if result.is_err() {
+                child.start_kill().ok();
+            }
+            result
+        }
+    }
+
+    // Signals the previous instance that the listening sockets were inherited, after
+    // which it stops accepting connections and releases the cache once drained.
+    pub async fn notify_ready() {
+        let socket_path = if let Some(socket_path) = std::env::var_os(CONTROL_SOCKET_VAR) {
+            std::env::remove_var(CONTROL_SOCKET_VAR);
+            socket_path
+        } else {
+            return;
+        };
+
+        match UnixStream::connect(&socket_path).await {
+            Ok(mut stream) => {
+                if let Err(err) = stream.write_all(b"READY\n").await {
+                    error!(
+                        "Failed to write to control socket {:?}: {}",
+                        socket_path, err
+                    );
+                } else {
+                    IS_UPGRADE.store(true, Ordering::Relaxed);
+                    info!("Taking over listeners from previous instance.");
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Failed to connect to control socket {:?}: {}",
+                    socket_path, err
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(target_env = "msvc"))]
+pub use handoff::notify_ready;
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_listen_fds() {
+        assert_eq!(
+            super::parse_listen_fds("0.0.0.0:143=3;[::]:993=4;invalid;127.0.0.1:9190=x"),
+            vec![
+                ("0.0.0.0:143".parse().unwrap(), 3),
+                ("[::]:993".parse().unwrap(), 4)
+            ]
+        );
+    }
+}
@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2020-2022, Stalwart Labs Ltd.
+ *
+ * This file is part of the Stalwart IMAP Server.
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of
+ * the License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+ * GNU Affero General Public License for more details.
+ * in the LICENSE file at the top-level directory of this distribution.
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ * You can be released from the requirements of the AGPLv3 license by
+ * purchasing a commercial license. Please contact licensing@stalw.art
+ * for more details.
+*/
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use tokio::io::AsyncReadExt;
+
+use super::{metrics::measure_jmap, receiver::SpooledLiteral, session_cache::UserSession};
+
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+impl UserSession {
+    // Uploads a spooled literal to the JMAP server reading it from disk in
+    // chunks, and returns the id of the new blob.
+    pub async fn upload_spooled(
+        &self,
+        account_id: &str,
+        literal: &SpooledLiteral,
+    ) -> Result<String, String> {
+        let url = self
+            .client
+            .session()
+            .upload_url()
+            .replace("{accountId}", account_id);
+        literal
+            .written()
+            .await
+            .map_err(|err| format!("Failed to write {}: {}", literal.path.display(), err))?;
+        let file = tokio::fs::File::open(&literal.path)
+            .await
+            .map_err(|err| format!("Failed to open {}: {}", literal.path.display(), err))?;
+        let body = futures::stream::try_unfold(file, |mut file| async move {
+            let mut chunk = vec![0; UPLOAD_CHUNK_SIZE];
+            let len = file.read(&mut chunk).await?;
+            Ok::<_, std::io::Error>(if len > 0 {
+                chunk.truncate(len);
+                Some((chunk, file))
+            } else {
+                None
+            })
+        });
+
+        let response = measure_jmap(
+            self.http
+                .post(&url)
+                .header(CONTENT_TYPE, "message/rfc822")
+                .header(CONTENT_LENGTH, literal.size)
+                .body(reqwest::Body::wrap_stream(body))
+                .send(),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Upload failed with HTTP {}", response.status()));
+        }
+        let response = response.bytes().await.map_err(|err| err.to_string())?;
+
+        serde_json::from_slice::<serde_json::Value>(&response)
+            .map_err(|err| err.to_string())?
+            .get("blobId")
+            .and_then(|blob_id| blob_id.as_str())
+            .map(|blob_id| blob_id.to_string())
+            .ok_or_else(|| "Upload response is missing a blobId".to_string())
+    }
+}
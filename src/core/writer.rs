@@ -21,6 +21,11 @@
  * for more details.
 */
 
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
 use tokio::{
     io::{AsyncWriteExt, WriteHalf},
     net::TcpStream,
@@ -29,20 +34,40 @@ use tokio::{
 use tokio_rustls::server::TlsStream;
 use tracing::debug;
 
-use super::client::{Session, SessionData};
+use super::{
+    client::{Session, SessionData},
+    metrics::METRICS,
+    Command,
+};
 
 const IPC_CHANNEL_BUFFER: usize = 128;
 
+static ACTIVE_WRITERS: AtomicUsize = AtomicUsize::new(0);
+
 pub enum Event {
     Stream(WriteHalf<TcpStream>),
     StreamTls(WriteHalf<TlsStream<TcpStream>>),
     Bytes(Vec<u8>),
+    Command {
+        tag: String,
+        command: Command,
+        started: Instant,
+    },
     Upgrade(oneshot::Sender<Event>),
+    Shutdown,
+}
+
+#[derive(Default)]
+struct PendingCommands {
+    commands: Vec<(String, Command, Instant)>,
 }
 
 pub fn spawn_writer() -> mpsc::Sender<Event> {
     let (tx, mut rx) = mpsc::channel::<Event>(IPC_CHANNEL_BUFFER);
+    let guard = WriterGuard::new();
     tokio::spawn(async move {
+        let _guard = guard;
+        let mut pending = PendingCommands::default();
         let mut stream = rx.recv().await.unwrap();
         'outer: loop {
             match stream {
@@ -62,7 +87,16 @@ pub fn spawn_writer() -> mpsc::Sender<Event> {
                                     debug!("Failed to write to stream: {}", err);
                                     break 'outer;
                                 }
+                                pending.written(&bytes);
                             }
+                            Event::Command {
+                                tag,
+                                command,
+                                started,
+                            } => {
+                                pending.commands.push((tag, command, started));
+                            }
+                            Event::Shutdown => break,
                             Event::Upgrade(channel) => {
                                 if channel.send(Event::Stream(stream_tx)).is_err() {
                                     debug!("Failed to send stream.");
@@ -81,6 +115,7 @@ pub fn spawn_writer() -> mpsc::Sender<Event> {
                             }
                         }
                     }
+                    stream_tx.shutdown().await.ok();
                     break 'outer;
                 }
                 Event::StreamTls(mut stream_tx) => {
@@ -91,13 +126,23 @@ pub fn spawn_writer() -> mpsc::Sender<Event> {
                                     debug!("Failed to write to stream: {}", err);
                                     break 'outer;
                                 }
+                                pending.written(&bytes);
+                            }
+                            Event::Command {
+                                tag,
+                                command,
+                                started,
+                            } => {
+                                pending.commands.push((tag, command, started));
                             }
+                            Event::Shutdown => break,
                             _ => {
                                 stream = event;
                                 continue 'outer;
                             }
                         }
                     }
+                    stream_tx.shutdown().await.ok();
                     break 'outer;
                 }
                 _ => unreachable!(),
@@ -107,6 +152,46 @@ pub fn spawn_writer() -> mpsc::Sender<Event> {
     tx
 }
 
+impl PendingCommands {
+    // Commands are timed until their tagged response reaches the client, which
+    // for spawned handlers happens well after the handler itself returned.
+    fn written(&mut self, bytes: &[u8]) {
+        if self.commands.is_empty() {
+            return;
+        }
+        let bytes = bytes.strip_suffix(b"\r\n").unwrap_or(bytes);
+        let line = bytes
+            .windows(2)
+            .rposition(|w| w == b"\r\n")
+            .map_or(bytes, |pos| &bytes[pos + 2..]);
+        if let Some(pos) = self.commands.iter().position(|(tag, _, _)| {
+            line.len() > tag.len() && line.starts_with(tag.as_bytes()) && line[tag.len()] == b' '
+        }) {
+            let (_, command, started) = self.commands.remove(pos);
+            METRICS.command(command, started.elapsed());
+        }
+    }
+}
+
+pub fn active_writers() -> usize {
+    ACTIVE_WRITERS.load(Ordering::Relaxed)
+}
+
+struct WriterGuard;
+
+impl WriterGuard {
+    fn new() -> Self {
+        ACTIVE_WRITERS.fetch_add(1, Ordering::Relaxed);
+        WriterGuard
+    }
+}
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        ACTIVE_WRITERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 impl Session {
     pub async fn write_bytes(&self, bytes: Vec<u8>) -> Result<(), ()> {
         /*let tmp = "dd";
@@ -122,9 +207,32 @@ impl Session {
             Ok(())
         }
     }
+
+    pub async fn start_command(&self, tag: &str, command: Command) -> Result<(), ()> {
+        if let Err(err) = self
+            .writer
+            .send(Event::Command {
+                tag: tag.to_string(),
+                command,
+                started: Instant::now(),
+            })
+            .await
+        {
+            debug!("Failed to send command: {}", err);
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl SessionData {
+    // Closes the connection once the bytes already queued are written, for when
+    // the client can no longer be kept in sync with the response stream.
+    pub async fn disconnect(&self) {
+        self.writer.send(Event::Shutdown).await.ok();
+    }
+
     pub async fn write_bytes(&self, bytes: Vec<u8>) -> bool {
         /*let tmp = "dd";
         println!(
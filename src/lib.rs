@@ -31,22 +31,37 @@ pub mod tests;
 
 use crate::{
     core::{
-        config::{build_core, failed_to, UnwrapFailure},
+        admin::handle_admin_request,
+        config::{build_core, failed_to, UnwrapFailure, DEFAULT_UPGRADE_DRAIN_TIMEOUT},
         env_settings::EnvSettings,
         housekeeper::spawn_housekeeper,
+        http::spawn_http_listener,
+        metrics::handle_metrics_request,
+        router::spawn_health_checks,
+        store::server::spawn_store_listener,
+        writer::active_writers,
     },
     managesieve::listener::spawn_managesieve_listener,
 };
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use tokio::sync::watch;
-use tracing::{debug, info, Level};
+use tracing::{debug, error, info, warn, Level};
 
 use crate::core::listener::spawn_listener;
 
 const IMAP4_PORT: u16 = 143;
 const IMAP4_PORT_TLS: u16 = 993;
 const MANAGESIEVE_PORT: u16 = 4190;
+const METRICS_PORT: u16 = 9190;
+const ADMIN_PORT: u16 = 9191;
+const CACHE_PORT: u16 = 9192;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+const DEFAULT_HEALTH_CHECK_INTERVAL: u64 = 10;
 
 pub async fn start_imap_server(settings: EnvSettings) -> std::io::Result<()> {
     // Enable logging
@@ -61,7 +76,17 @@ pub async fn start_imap_server(settings: EnvSettings) -> std::io::Result<()> {
     if !settings.contains_key("bind-port") && !settings.contains_key("bind-port-tls") {
         failed_to("start IMAP listener. Please specify 'bind-port' and/or 'bind-port-tls'.");
     }
-    let core = Arc::new(build_core(&settings));
+
+    // Tell the previous instance to stop accepting connections and release the cache
+    #[cfg(not(target_env = "msvc"))]
+    core::upgrade::notify_ready().await;
+
+    // Opening the cache waits for the previous instance to release it, so it runs
+    // outside the runtime while new connections queue on the inherited listeners.
+    let (core, settings) = tokio::task::spawn_blocking(move || (build_core(&settings), settings))
+        .await
+        .failed_to("open cache");
+    let core = Arc::new(core);
 
     // Start IMAP listeners
     let bind_addr = settings.parse_ipaddr("bind-addr", "0.0.0.0");
@@ -97,32 +122,150 @@ pub async fn start_imap_server(settings: EnvSettings) -> std::io::Result<()> {
         spawn_managesieve_listener(socket_addr, core.clone(), shutdown_rx.clone()).await;
     }
 
+    // Start Prometheus metrics listener
+    if let Some(bind_port) = settings.get("bind-port-metrics") {
+        let socket_addr = SocketAddr::from((
+            settings.parse_ipaddr("bind-addr-metrics", "127.0.0.1"),
+            bind_port.parse().unwrap_or(METRICS_PORT),
+        ));
+        info!("Starting Prometheus metrics endpoint at {}...", socket_addr);
+        spawn_http_listener(
+            socket_addr,
+            core.clone(),
+            shutdown_rx.clone(),
+            handle_metrics_request,
+        )
+        .await;
+    }
+
+    // Start admin API listener
+    if let Some(bind_port) = settings.get("bind-port-admin") {
+        if core.admin_secret.is_none() {
+            failed_to("start admin API. Please specify 'admin-secret'.");
+        }
+        let socket_addr = SocketAddr::from((
+            settings.parse_ipaddr("bind-addr-admin", "127.0.0.1"),
+            bind_port.parse().unwrap_or(ADMIN_PORT),
+        ));
+        info!("Starting admin API at {}...", socket_addr);
+        spawn_http_listener(
+            socket_addr,
+            core.clone(),
+            shutdown_rx.clone(),
+            handle_admin_request,
+        )
+        .await;
+    }
+
+    // Start UID cache listener
+    if let Some(bind_port) = settings.get("bind-port-cache") {
+        if core.cache_secret.is_none() {
+            failed_to("start UID cache listener. Please specify 'cache-secret'.");
+        }
+        let socket_addr = SocketAddr::from((
+            settings.parse_ipaddr("bind-addr-cache", "127.0.0.1"),
+            bind_port.parse().unwrap_or(CACHE_PORT),
+        ));
+        info!("Starting UID cache listener at {}...", socket_addr);
+        spawn_store_listener(socket_addr, core.clone(), shutdown_rx.clone()).await;
+    }
+
+    // Start JMAP backend health checks
+    spawn_health_checks(
+        core.clone(),
+        Duration::from_secs(
+            settings
+                .parse("jmap-health-check-interval")
+                .unwrap_or(DEFAULT_HEALTH_CHECK_INTERVAL),
+        ),
+        shutdown_rx.clone(),
+    );
+
     // Start houskeeper
-    spawn_housekeeper(core, &settings, shutdown_rx);
+    spawn_housekeeper(core.clone(), shutdown_rx);
+
+    let shutdown_timeout = Duration::from_secs(
+        settings
+            .parse("shutdown-timeout")
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+    );
 
     // Wait for shutdown signal
     #[cfg(not(target_env = "msvc"))]
-    {
-        use tokio::signal::unix::{signal, SignalKind};
+    use tokio::signal::unix::{signal, SignalKind};
+    #[cfg(not(target_env = "msvc"))]
+    let mut h_term = signal(SignalKind::terminate()).failed_to("start signal handler");
+    #[cfg(not(target_env = "msvc"))]
+    let mut h_int = signal(SignalKind::interrupt()).failed_to("start signal handler");
 
-        let mut h_term = signal(SignalKind::terminate()).failed_to("start signal handler");
-        let mut h_int = signal(SignalKind::interrupt()).failed_to("start signal handler");
+    #[cfg(not(target_env = "msvc"))]
+    let drain_timeout = {
+        let mut h_hup = signal(SignalKind::hangup()).failed_to("start signal handler");
+        let mut h_usr2 = signal(SignalKind::user_defined2()).failed_to("start signal handler");
+        let mut settings = settings;
 
-        tokio::select! {
-            _ = h_term.recv() => debug!("Received SIGTERM."),
-            _ = h_int.recv() => debug!("Received SIGINT."),
-        };
-    }
+        loop {
+            tokio::select! {
+                _ = h_term.recv() => {
+                    debug!("Received SIGTERM.");
+                    break shutdown_timeout;
+                }
+                _ = h_int.recv() => {
+                    debug!("Received SIGINT.");
+                    break shutdown_timeout;
+                }
+                _ = h_hup.recv() => {
+                    info!("Received SIGHUP, reloading configuration...");
+
+                    // Existing sessions keep the settings they were created with
+                    match EnvSettings::try_new()
+                        .and_then(|new_settings| {
+                            core.reload(&settings, &new_settings).map(|_| new_settings)
+                        }) {
+                        Ok(new_settings) => {
+                            settings = new_settings;
+                        }
+                        Err(err) => {
+                            error!("Failed to reload configuration: {}", err);
+                        }
+                    }
+                }
+                _ = h_usr2.recv() => {
+                    info!("Received SIGUSR2, upgrading...");
+
+                    match core.listeners.handoff().await {
+                        Ok(_) => {
+                            // The new process accepts connections as soon as the cache is released
+                            core.listeners.stop();
+                            info!(
+                                "Listeners handed over, draining {} session(s)...",
+                                core.sessions.len()
+                            );
+                            break Duration::from_secs(
+                                settings
+                                    .parse("upgrade-drain-timeout")
+                                    .unwrap_or(DEFAULT_UPGRADE_DRAIN_TIMEOUT),
+                            );
+                        }
+                        Err(err) => {
+                            error!("Failed to upgrade: {}", err);
+                        }
+                    }
+                }
+            };
+        }
+    };
 
     #[cfg(target_env = "msvc")]
-    {
+    let drain_timeout = {
         match tokio::signal::ctrl_c().await {
             Ok(()) => {}
             Err(err) => {
                 eprintln!("Unable to listen for shutdown signal: {}", err);
             }
         }
-    }
+        shutdown_timeout
+    };
 
     // Shutdown the system;
     info!(
@@ -130,7 +273,54 @@ pub async fn start_imap_server(settings: EnvSettings) -> std::io::Result<()> {
         env!("CARGO_PKG_VERSION")
     );
     shutdown_tx.send(true).unwrap();
-    tokio::time::sleep(Duration::from_secs(1)).await;
 
-    Ok(())
+    // Wait for in-flight commands to complete, a second signal closes sessions right away
+    let deadline = Instant::now() + drain_timeout;
+    while !core.sessions.is_empty() && Instant::now() < deadline {
+        #[cfg(not(target_env = "msvc"))]
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(100)) => (),
+            _ = h_term.recv() => break,
+            _ = h_int.recv() => break,
+        }
+        #[cfg(target_env = "msvc")]
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Close any remaining sessions
+    let forcibly_closed = core.sessions.len();
+    if forcibly_closed > 0 {
+        core.sessions.terminate(|_, _| true);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !core.sessions.is_empty() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    // Flush pending writes and the cache
+    let deadline = Instant::now() + Duration::from_secs(1);
+    while active_writers() > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    core.flush_uid_store().await.ok();
+    if let Err(err) = core.db.flush_async().await {
+        error!("Failed to flush cache: {}", err);
+    }
+
+    if forcibly_closed > 0 {
+        warn!(
+            "Shutdown complete, {} session(s) were forcibly closed.",
+            forcibly_closed
+        );
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "{} session(s) were forcibly closed during shutdown.",
+                forcibly_closed
+            ),
+        ))
+    } else {
+        info!("Shutdown complete, all sessions were closed gracefully.");
+        Ok(())
+    }
 }
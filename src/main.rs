@@ -21,11 +21,25 @@
  * for more details.
 */
 
-use stalwart_imap_proxy::core::env_settings::EnvSettings;
+use stalwart_imap_proxy::core::env_settings::{soft_panic, EnvSettings};
+use stalwart_imap_proxy::core::store::cli::run_cache_command;
 use stalwart_imap_proxy::start_imap_server;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // Start server
-    start_imap_server(EnvSettings::new()).await
+    // Run cache administration commands
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "cache").is_some() {
+        run_cache_command(args)
+            .await
+            .unwrap_or_else(|err| soft_panic(&err));
+        return Ok(());
+    }
+
+    // Start server, exiting with a non-zero status if sessions had to be closed by force
+    if let Err(err) = start_imap_server(EnvSettings::new()).await {
+        soft_panic(&err.to_string());
+    }
+
+    Ok(())
 }
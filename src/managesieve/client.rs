@@ -21,15 +21,24 @@
  * for more details.
 */
 
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc},
+};
 
 use jmap_client::{client::Client, sieve::query::Filter};
-use tokio::{io::WriteHalf, net::TcpStream, sync::mpsc};
+use tokio::{
+    io::WriteHalf,
+    net::TcpStream,
+    sync::{mpsc, watch},
+};
 use tokio_rustls::server::TlsStream;
 use tracing::debug;
 
 use crate::core::{
+    metrics::{measure_jmap, METRICS},
     receiver::{self, Receiver, Request},
+    router::BackendConnection,
     writer::{self, Event},
     Core,
 };
@@ -43,23 +52,34 @@ pub struct Session {
     pub peer_addr: SocketAddr,
     pub is_tls: bool,
     pub writer: mpsc::Sender<writer::Event>,
+    pub session_id: u64,
+    pub terminate_rx: watch::Receiver<bool>,
 }
 
 #[allow(clippy::large_enum_variant)]
 pub enum State {
-    NotAuthenticated { auth_failures: u8 },
-    Authenticated { client: Client },
+    NotAuthenticated {
+        auth_failures: u8,
+    },
+    Authenticated {
+        client: Client,
+        backend: BackendConnection,
+    },
 }
 
 impl Session {
     pub fn new(core: Arc<Core>, peer_addr: SocketAddr, is_tls: bool) -> Self {
+        let (session_id, terminate_rx) = core.sessions.register("managesieve", peer_addr, is_tls);
+        METRICS.sieve_sessions.fetch_add(1, Ordering::Relaxed);
         Session {
-            receiver: Receiver::with_max_request_size(core.max_request_size)
+            receiver: Receiver::with_max_request_size(core.max_request_size())
                 .with_start_state(receiver::State::Command { is_uid: false }),
             state: State::NotAuthenticated { auth_failures: 0 },
             peer_addr,
             is_tls,
             writer: writer::spawn_writer(),
+            session_id,
+            terminate_rx,
             core,
         }
     }
@@ -143,6 +163,7 @@ impl Session {
                     self.write_bytes(response.into_bytes()).await?;
                 }
             }
+            self.update_state();
         }
 
         if let Some(needs_literal) = needs_literal {
@@ -168,8 +189,21 @@ impl Session {
         }
     }
 
+    pub fn update_state(&self) {
+        let user = if let State::Authenticated { client, .. } = &self.state {
+            client.session().username().to_string().into()
+        } else {
+            None
+        };
+        let is_tls = self.is_tls;
+        self.core.sessions.update(self.session_id, |info| {
+            info.user = user;
+            info.is_tls = is_tls;
+        });
+    }
+
     pub fn client(&self) -> &Client {
-        if let State::Authenticated { client } = &self.state {
+        if let State::Authenticated { client, .. } = &self.state {
             client
         } else {
             unreachable!()
@@ -177,16 +211,25 @@ impl Session {
     }
 
     pub async fn get_script_id(&self, name: String) -> Result<String, StatusResponse> {
-        self.client()
-            .sieve_script_query(Filter::name(name).into(), None::<Vec<_>>)
-            .await
-            .map_err(|err| err.into_status_response())?
-            .take_ids()
-            .pop()
-            .ok_or_else(|| {
-                StatusResponse::no("There is no script by that name")
-                    .with_code(ResponseCode::NonExistent)
-            })
+        measure_jmap(
+            self.client()
+                .sieve_script_query(Filter::name(name).into(), None::<Vec<_>>),
+        )
+        .await
+        .map_err(|err| err.into_status_response())?
+        .take_ids()
+        .pop()
+        .ok_or_else(|| {
+            StatusResponse::no("There is no script by that name")
+                .with_code(ResponseCode::NonExistent)
+        })
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        METRICS.sieve_sessions.fetch_sub(1, Ordering::Relaxed);
+        self.core.sessions.unregister(self.session_id);
     }
 }
 
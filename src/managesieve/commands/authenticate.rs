@@ -21,15 +21,21 @@
  * for more details.
 */
 
+use std::time::Instant;
+
 use jmap_client::client::Client;
 use tracing::debug;
 
 use crate::{
     commands::authenticate::{decode_challenge_oauth, decode_challenge_plain},
-    core::receiver::{self, Request},
+    core::{
+        metrics::METRICS,
+        receiver::{self, Request},
+        router::clone_credentials,
+    },
     managesieve::{
         client::{Session, State},
-        Command, StatusResponse,
+        Command, ResponseCode, StatusResponse,
     },
     protocol::authenticate::Mechanism,
 };
@@ -79,18 +85,40 @@ impl Session {
             }
         };
 
-        match Client::new()
-            .follow_redirects(&self.core.trusted_hosts)
-            .forwarded_for(self.peer_addr.ip())
-            .credentials(credentials)
-            .connect(&self.core.jmap_url)
-            .await
-        {
-            Ok(client) => {
+        let route = self.core.router.route(&credentials);
+        let mut result = None;
+        for backend in route.backends() {
+            let started = Instant::now();
+            let response = Client::new()
+                .follow_redirects(&route.trusted_hosts)
+                .forwarded_for(self.peer_addr.ip())
+                .credentials(clone_credentials(&credentials))
+                .connect(&backend.url)
+                .await;
+            METRICS.jmap_connect(started.elapsed());
+
+            // Try the next backend if this one could not be reached
+            match response {
+                Err(jmap_client::Error::Transport(err)) => {
+                    debug!("Failed to connect to {}: {}", backend.url, err);
+                    backend.set_healthy(false);
+                }
+                response => {
+                    result = Some((response, backend));
+                    break;
+                }
+            }
+        }
+
+        match result {
+            Some((Ok(client), backend)) => {
                 // Verify the remote JMAP server supports JMAP for Sieve.
                 if client.session().sieve_capabilities().is_some() {
                     // Create session
-                    self.state = State::Authenticated { client };
+                    self.state = State::Authenticated {
+                        client,
+                        backend: backend.connect(),
+                    };
 
                     self.handle_capability("Authentication successful").await
                 } else {
@@ -103,8 +131,8 @@ impl Session {
                     Ok(false)
                 }
             }
-            Err(err) => {
-                debug!("Failed to connect to {}: {}", self.core.jmap_url, err,);
+            Some((Err(err), backend)) => {
+                debug!("Failed to connect to {}: {}", backend.url, err,);
                 if let State::NotAuthenticated { auth_failures } = &mut self.state {
                     if *auth_failures < 3 {
                         *auth_failures += 1;
@@ -125,6 +153,10 @@ impl Session {
                     unreachable!()
                 }
             }
+            None => Err(
+                StatusResponse::no("JMAP server unavailable, please try again later.")
+                    .with_code(ResponseCode::TryLater),
+            ),
         }
     }
 
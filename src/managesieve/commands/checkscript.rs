@@ -22,7 +22,7 @@
 */
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, StatusResponse},
 };
 
@@ -37,10 +37,12 @@ impl Session {
             return Err(StatusResponse::no("Expected script as a parameter."));
         }
 
-        self.client()
-            .sieve_script_validate(request.tokens.into_iter().next().unwrap().unwrap_bytes())
-            .await
-            .map_err(|err| err.into_status_response())?;
+        measure_jmap(
+            self.client()
+                .sieve_script_validate(request.tokens.into_iter().next().unwrap().unwrap_bytes()),
+        )
+        .await
+        .map_err(|err| err.into_status_response())?;
 
         Ok(self
             .write_bytes(StatusResponse::ok("Script is valid.").into_bytes())
@@ -22,7 +22,7 @@
 */
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, StatusResponse},
 };
 
@@ -40,8 +40,8 @@ impl Session {
             .and_then(|s| s.unwrap_string().ok())
             .ok_or_else(|| StatusResponse::no("Expected script name as a parameter."))?;
 
-        self.client()
-            .sieve_script_destroy(&self.get_script_id(name).await?)
+        let script_id = self.get_script_id(name).await?;
+        measure_jmap(self.client().sieve_script_destroy(&script_id))
             .await
             .map_err(|err| err.into_status_response())?;
 
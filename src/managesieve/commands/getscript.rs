@@ -24,7 +24,7 @@
 use jmap_client::sieve::Property;
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, ResponseCode, StatusResponse},
 };
 
@@ -43,18 +43,18 @@ impl Session {
             .ok_or_else(|| StatusResponse::no("Expected script name as a parameter."))?;
 
         let client = self.client();
-        let script = client
-            .sieve_script_get(&self.get_script_id(name).await?, [Property::BlobId].into())
+        let script_id = self.get_script_id(name).await?;
+        let script = measure_jmap(client.sieve_script_get(&script_id, [Property::BlobId].into()))
             .await
             .map_err(|err| err.into_status_response())?
             .ok_or_else(|| {
                 StatusResponse::no("Script not found").with_code(ResponseCode::NonExistent)
             })?;
-        let script = client
-            .download(script.blob_id().ok_or_else(|| {
-                StatusResponse::no("BlobId not included in response")
-                    .with_code(ResponseCode::NonExistent)
-            })?)
+        let blob_id = script.blob_id().ok_or_else(|| {
+            StatusResponse::no("BlobId not included in response")
+                .with_code(ResponseCode::NonExistent)
+        })?;
+        let script = measure_jmap(client.download(blob_id))
             .await
             .map_err(|err| err.into_status_response())?;
 
@@ -23,7 +23,10 @@
 
 use jmap_client::sieve::Property;
 
-use crate::managesieve::{client::Session, StatusResponse};
+use crate::{
+    core::metrics::measure_jmap,
+    managesieve::{client::Session, StatusResponse},
+};
 
 use super::IntoStatusResponse;
 
@@ -36,8 +39,7 @@ impl Session {
 
         let mut response = Vec::with_capacity(128);
 
-        for script in request
-            .send_get_sieve_script()
+        for script in measure_jmap(request.send_get_sieve_script())
             .await
             .map_err(|err| err.into_status_response())?
             .take_list()
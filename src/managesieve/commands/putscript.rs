@@ -22,7 +22,7 @@
 */
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, StatusResponse},
 };
 
@@ -43,8 +43,7 @@ impl Session {
             .ok_or_else(|| StatusResponse::no("Expected script as a parameter."))?
             .unwrap_bytes();
 
-        self.client()
-            .sieve_script_create(name, script, false)
+        measure_jmap(self.client().sieve_script_create(name, script, false))
             .await
             .map_err(|err| err.into_status_response())?;
 
@@ -22,7 +22,7 @@
 */
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, StatusResponse},
 };
 
@@ -43,10 +43,13 @@ impl Session {
             .and_then(|s| s.unwrap_string().ok())
             .ok_or_else(|| StatusResponse::no("Expected new script name as a parameter."))?;
 
-        self.client()
-            .sieve_script_rename(&self.get_script_id(name).await?, new_name, false)
-            .await
-            .map_err(|err| err.into_status_response())?;
+        let script_id = self.get_script_id(name).await?;
+        measure_jmap(
+            self.client()
+                .sieve_script_rename(&script_id, new_name, false),
+        )
+        .await
+        .map_err(|err| err.into_status_response())?;
 
         Ok(self
             .write_bytes(StatusResponse::ok("Success.").into_bytes())
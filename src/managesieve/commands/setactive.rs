@@ -22,7 +22,7 @@
 */
 
 use crate::{
-    core::receiver::Request,
+    core::{metrics::measure_jmap, receiver::Request},
     managesieve::{client::Session, Command, StatusResponse},
 };
 
@@ -41,11 +41,10 @@ impl Session {
             .ok_or_else(|| StatusResponse::no("Expected script name as a parameter."))?;
 
         (if !name.is_empty() {
-            self.client()
-                .sieve_script_activate(&self.get_script_id(name).await?)
-                .await
+            let script_id = self.get_script_id(name).await?;
+            measure_jmap(self.client().sieve_script_activate(&script_id)).await
         } else {
-            self.client().sieve_script_deactivate().await
+            measure_jmap(self.client().sieve_script_deactivate()).await
         })
         .map_err(|err| err.into_status_response())?;
 
@@ -41,6 +41,7 @@ pub async fn handle_conn(
 ) {
     let mut buf = vec![0; 4096];
     let (mut stream_rx, stream_tx) = tokio::io::split(stream);
+    let mut is_draining = false;
 
     if !session.set_stream(stream_tx).await
         || matches!(
@@ -69,7 +70,7 @@ pub async fn handle_conn(
                                 Ok(Some(stream_tx)) => {
                                     debug!("TLS upgrade requested.");
                                     handle_conn_tls(
-                                        match session.core.tls_acceptor.accept(stream_rx.unsplit(stream_tx)).await {
+                                        match session.core.tls_acceptor().accept(stream_rx.unsplit(stream_tx)).await {
                                             Ok(stream) => stream,
                                             Err(e) => {
                                                 debug!("Failed to accept TLS connection: {}", e);
@@ -104,12 +105,41 @@ pub async fn handle_conn(
                     }
                 }
             },
-            _ = shutdown_rx.changed() => {
-                session.write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec()).await.ok();
-                debug!("ManageSieve connection with peer {} shutting down.", session.peer_addr);
+            _ = shutdown_rx.changed(), if !is_draining => {
+                if session.receiver.is_receiving() {
+                    // Let the client finish sending the current request
+                    debug!("ManageSieve connection with peer {} draining.", session.peer_addr);
+                    is_draining = true;
+                } else {
+                    session.core.sessions.wait_for_tasks(session.session_id).await;
+                    session.write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec()).await.ok();
+                    debug!("ManageSieve connection with peer {} shutting down.", session.peer_addr);
+                    return;
+                }
+            },
+            _ = session.terminate_rx.changed() => {
+                session.write_bytes(b"BYE \"Session terminated by administrator.\"\r\n".to_vec()).await.ok();
+                debug!("ManageSieve connection with peer {} terminated by administrator.", session.peer_addr);
                 return;
             }
         };
+
+        if is_draining && !session.receiver.is_receiving() {
+            session
+                .core
+                .sessions
+                .wait_for_tasks(session.session_id)
+                .await;
+            session
+                .write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec())
+                .await
+                .ok();
+            debug!(
+                "ManageSieve connection with peer {} shutting down.",
+                session.peer_addr
+            );
+            return;
+        }
     }
 }
 
@@ -120,6 +150,7 @@ pub async fn handle_conn_tls(
 ) {
     let mut buf = vec![0; 4096];
     let (mut stream_rx, stream_tx) = tokio::io::split(stream);
+    let mut is_draining = false;
 
     if !session.set_stream_tls(stream_tx).await
         || matches!(
@@ -162,11 +193,40 @@ pub async fn handle_conn_tls(
                     }
                 }
             },
-            _ = shutdown_rx.changed() => {
-                session.write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec()).await.ok();
-                debug!("ManageSieve connection with peer {} shutting down.", session.peer_addr);
+            _ = shutdown_rx.changed(), if !is_draining => {
+                if session.receiver.is_receiving() {
+                    // Let the client finish sending the current request
+                    debug!("ManageSieve connection with peer {} draining.", session.peer_addr);
+                    is_draining = true;
+                } else {
+                    session.core.sessions.wait_for_tasks(session.session_id).await;
+                    session.write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec()).await.ok();
+                    debug!("ManageSieve connection with peer {} shutting down.", session.peer_addr);
+                    return;
+                }
+            },
+            _ = session.terminate_rx.changed() => {
+                session.write_bytes(b"BYE \"Session terminated by administrator.\"\r\n".to_vec()).await.ok();
+                debug!("ManageSieve connection with peer {} terminated by administrator.", session.peer_addr);
                 return;
             }
         };
+
+        if is_draining && !session.receiver.is_receiving() {
+            session
+                .core
+                .sessions
+                .wait_for_tasks(session.session_id)
+                .await;
+            session
+                .write_bytes(b"BYE \"Server shutting down.\"\r\n".to_vec())
+                .await
+                .ok();
+            debug!(
+                "ManageSieve connection with peer {} shutting down.",
+                session.peer_addr
+            );
+            return;
+        }
     }
 }
@@ -23,7 +23,7 @@
 
 use std::{net::SocketAddr, sync::Arc};
 
-use tokio::{net::TcpListener, sync::watch};
+use tokio::sync::watch;
 use tracing::{debug, error};
 
 use crate::{
@@ -37,7 +37,7 @@ pub async fn spawn_managesieve_listener(
     mut shutdown_rx: watch::Receiver<bool>,
 ) {
     // Start listening for ManageSieve connections.
-    let listener = TcpListener::bind(bind_addr).await.unwrap_or_else(|e| {
+    let (listener, mut stop_rx) = core.listeners.bind(bind_addr).unwrap_or_else(|e| {
         failed_to(&format!("bind to {}: {}", bind_addr, e));
     });
 
@@ -46,12 +46,15 @@ pub async fn spawn_managesieve_listener(
             tokio::select! {
                 stream = listener.accept() => {
                     match stream {
-                        Ok((stream, _)) => {
+                        Ok((mut stream, _)) => {
                             let shutdown_rx = shutdown_rx.clone();
                             let core = core.clone();
 
                             tokio::spawn(async move {
-                                let peer_addr = stream.peer_addr().unwrap();
+                                let peer_addr = match core.peer_addr(&mut stream).await {
+                                    Some(peer_addr) => peer_addr,
+                                    None => return,
+                                };
 
                                 handle_conn(
                                     stream,
@@ -68,6 +71,10 @@ pub async fn spawn_managesieve_listener(
                 _ = shutdown_rx.changed() => {
                     debug!("ManageSieve listener shutting down.");
                     break;
+                },
+                _ = stop_rx.changed() => {
+                    debug!("ManageSieve listener stopped accepting connections.");
+                    break;
                 }
             };
         }
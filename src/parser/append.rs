@@ -26,7 +26,7 @@ use crate::{
         receiver::{Request, Token},
         Command, Flag,
     },
-    protocol::append::{self, Message},
+    protocol::append::{self, Literal, Message},
 };
 
 use super::parse_datetime;
@@ -67,19 +67,17 @@ impl Request<Command> {
                         }
                         token => token,
                     };
-                    let (message, received_at) = if tokens.peek().is_some() {
-                        let token_bytes = token.unwrap_bytes();
-                        if token_bytes.len() <= 28 {
+                    let (message, received_at) = match token {
+                        Token::Argument(token_bytes)
+                            if tokens.peek().is_some() && token_bytes.len() <= 28 =>
+                        {
                             if let Ok(date_time) = parse_datetime(&token_bytes) {
-                                (tokens.next().unwrap().unwrap_bytes(), Some(date_time))
+                                (tokens.next().unwrap().into(), Some(date_time))
                             } else {
-                                (token_bytes, None)
+                                (Literal::Bytes(token_bytes), None)
                             }
-                        } else {
-                            (token_bytes, None)
                         }
-                    } else {
-                        (token.unwrap_bytes(), None)
+                        token => (token.into(), None),
                     };
 
                     messages.push(Message {
@@ -107,7 +105,7 @@ mod tests {
             receiver::{Error, Receiver},
             Flag,
         },
-        protocol::append::{self, Message},
+        protocol::append::{self, Literal, Message},
     };
 
     #[test]
@@ -121,7 +119,7 @@ mod tests {
                     tag: "A003".to_string(),
                     mailbox_name: "saved-messages".to_string(),
                     messages: vec![Message {
-                        message: vec![b'a'],
+                        message: Literal::Bytes(vec![b'a']),
                         flags: vec![Flag::Seen],
                         received_at: None,
                     }],
@@ -133,7 +131,7 @@ mod tests {
                     tag: "A003".to_string(),
                     mailbox_name: "hello world".to_string(),
                     messages: vec![Message {
-                        message: vec![b'a'],
+                        message: Literal::Bytes(vec![b'a']),
                         flags: vec![Flag::Seen, Flag::Draft, Flag::MDNSent],
                         received_at: None,
                     }],
@@ -145,7 +143,7 @@ mod tests {
                     tag: "A003".to_string(),
                     mailbox_name: "hi".to_string(),
                     messages: vec![Message {
-                        message: vec![b'a'],
+                        message: Literal::Bytes(vec![b'a']),
                         flags: vec![Flag::Junk],
                         received_at: Some(760689784),
                     }],
@@ -157,7 +155,7 @@ mod tests {
                     tag: "A003".to_string(),
                     mailbox_name: "hi".to_string(),
                     messages: vec![Message {
-                        message: vec![b'a'],
+                        message: Literal::Bytes(vec![b'a']),
                         flags: vec![],
                         received_at: Some(1668977999),
                     }],
@@ -207,7 +205,8 @@ mod tests {
                             mailbox_name: "saved-messages".to_string(),
                             messages: vec![
                                 Message {
-                                    message: concat!(
+                                    message: Literal::Bytes(
+                                        concat!(
                                         "Date: Mon, 7 Feb 1994 21:52:25 -0800 (PST)\r\n",
                                         "From: Fred Foobar <foobar@Blurdybloop.example.COM>\r\n",
                                         "Subject: afternoon meeting\r\n",
@@ -218,24 +217,27 @@ mod tests {
                                         "\r\n",
                                         "Hello Joe, do you think we can meet at 3:30 tomorrow?\r\n",
                                     )
-                                    .as_bytes()
-                                    .to_vec(),
+                                        .as_bytes()
+                                        .to_vec()
+                                    ),
                                     flags: vec![Flag::Seen],
                                     received_at: None,
                                 },
                                 Message {
-                                    message: concat!(
-                                        "Date: Mon, 7 Feb 1994 22:43:04 -0800 (PST)\r\n",
-                                        "From: Joe Mooch <mooch@OWaTaGu.example.net>\r\n",
-                                        "Subject: Re: afternoon meeting\r\n",
-                                        "To: foobar@blurdybloop.example.com\r\n",
-                                        "Message-Id: <a0434793874930@OWaTaGu.example.net>\r\n",
-                                        "MIME-Version: 1.0\r\n",
-                                        "Content-Type: TEXT/PLAIN; CHARSET=US-ASCII\r\n\r\n",
-                                        "3:30 is fine with me.\r\n",
-                                    )
-                                    .as_bytes()
-                                    .to_vec(),
+                                    message: Literal::Bytes(
+                                        concat!(
+                                            "Date: Mon, 7 Feb 1994 22:43:04 -0800 (PST)\r\n",
+                                            "From: Joe Mooch <mooch@OWaTaGu.example.net>\r\n",
+                                            "Subject: Re: afternoon meeting\r\n",
+                                            "To: foobar@blurdybloop.example.com\r\n",
+                                            "Message-Id: <a0434793874930@OWaTaGu.example.net>\r\n",
+                                            "MIME-Version: 1.0\r\n",
+                                            "Content-Type: TEXT/PLAIN; CHARSET=US-ASCII\r\n\r\n",
+                                            "3:30 is fine with me.\r\n",
+                                        )
+                                        .as_bytes()
+                                        .to_vec()
+                                    ),
                                     flags: vec![Flag::Seen],
                                     received_at: Some(760689784),
                                 }
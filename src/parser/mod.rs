@@ -101,6 +101,10 @@ impl CommandParser for Command {
     fn tokenize_brackets(&self) -> bool {
         matches!(self, Command::Fetch(_))
     }
+    #[inline(always)]
+    fn spool_literals(&self) -> bool {
+        matches!(self, Command::Append)
+    }
 }
 
 impl Default for Command {
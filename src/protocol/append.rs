@@ -21,7 +21,12 @@
  * for more details.
 */
 
-use crate::core::Flag;
+use std::sync::Arc;
+
+use crate::core::{
+    receiver::{SpooledLiteral, Token},
+    Flag,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Arguments {
@@ -32,7 +37,22 @@ pub struct Arguments {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Message {
-    pub message: Vec<u8>,
+    pub message: Literal,
     pub flags: Vec<Flag>,
     pub received_at: Option<i64>,
 }
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    Bytes(Vec<u8>),
+    Spooled(Arc<SpooledLiteral>),
+}
+
+impl From<Token> for Literal {
+    fn from(token: Token) -> Self {
+        match token {
+            Token::Spooled(literal) => Literal::Spooled(literal),
+            token => Literal::Bytes(token.unwrap_bytes()),
+        }
+    }
+}
@@ -50,6 +50,14 @@ pub struct FetchItem<'x> {
     pub items: Vec<DataItem<'x>>,
 }
 
+// Data item whose literal is written after the rest of the fetch item,
+// as its contents are streamed from the JMAP server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamedItem {
+    Rfc822,
+    BodySection { origin_octet: Option<u32> },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Attribute {
     Envelope,
@@ -150,6 +158,11 @@ pub enum DataItem<'x> {
     ThreadId {
         thread_id: String,
     },
+    // Data item that was serialized ahead of time, such as a BODYSTRUCTURE
+    // read from the metadata cache.
+    Serialized {
+        contents: Cow<'x, [u8]>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -844,6 +857,9 @@ impl<'x> DataItem<'x> {
                 buf.extend_from_slice(thread_id.as_bytes());
                 buf.push(b')');
             }
+            DataItem::Serialized { contents } => {
+                buf.extend_from_slice(contents);
+            }
         }
     }
 }
@@ -861,6 +877,35 @@ impl<'x> FetchItem<'x> {
         }
         buf.extend_from_slice(b")\r\n");
     }
+
+    // Serializes the fetch item up to the start of the streamed item's literal,
+    // the caller then writes its contents followed by ")\r\n".
+    pub fn serialize_streamed(&self, buf: &mut Vec<u8>, item: &StreamedItem, size: usize) {
+        buf.extend_from_slice(b"* ");
+        buf.extend_from_slice(self.id.to_string().as_bytes());
+        buf.extend_from_slice(b" FETCH (");
+        for item in &self.items {
+            item.serialize(buf);
+            buf.push(b' ');
+        }
+        match item {
+            StreamedItem::Rfc822 => {
+                buf.extend_from_slice(b"RFC822 ");
+            }
+            StreamedItem::BodySection { origin_octet } => {
+                if let Some(origin_octet) = origin_octet {
+                    buf.extend_from_slice(b"BODY[]<");
+                    buf.extend_from_slice(origin_octet.to_string().as_bytes());
+                    buf.extend_from_slice(b"> ");
+                } else {
+                    buf.extend_from_slice(b"BODY[] ");
+                }
+            }
+        }
+        buf.push(b'{');
+        buf.extend_from_slice(size.to_string().as_bytes());
+        buf.extend_from_slice(b"}\r\n");
+    }
 }
 
 impl<'x> ImapResponse for Response<'x> {
@@ -919,7 +964,7 @@ mod tests {
 
     use super::{
         Address, AddressGroup, BodyPart, BodyPartExtension, BodyPartFields, DataItem, EmailAddress,
-        Envelope, FetchItem, Response, Section,
+        Envelope, FetchItem, Response, Section, StreamedItem,
     };
 
     #[test]
@@ -1398,5 +1443,27 @@ mod tests {
                 "RFC822.HEADER {6}\r\nheader)\r\n",
             )
         );
+
+        for (item, expected_response) in [
+            (StreamedItem::Rfc822, "* 5 FETCH (UID 10 RFC822 {120}\r\n"),
+            (
+                StreamedItem::BodySection { origin_octet: None },
+                "* 5 FETCH (UID 10 BODY[] {120}\r\n",
+            ),
+            (
+                StreamedItem::BodySection {
+                    origin_octet: 1024.into(),
+                },
+                "* 5 FETCH (UID 10 BODY[]<1024> {120}\r\n",
+            ),
+        ] {
+            let mut buf = Vec::new();
+            FetchItem {
+                id: 5,
+                items: vec![super::DataItem::Uid { uid: 10 }],
+            }
+            .serialize_streamed(&mut buf, &item, 120);
+            assert_eq!(String::from_utf8(buf).unwrap(), expected_response);
+        }
     }
 }
@@ -26,7 +26,7 @@ use std::{cmp::Ordering, fmt::Display};
 use ahash::AHashSet;
 use jmap_client::core::set::from_timestamp;
 
-use crate::core::{Command, Flag, ResponseCode, ResponseType, StatusResponse};
+use crate::core::{metrics::METRICS, Command, Flag, ResponseCode, ResponseType, StatusResponse};
 
 pub mod acl;
 pub mod append;
@@ -352,6 +352,7 @@ impl ResponseType {
 impl StatusResponse {
     pub fn serialize(self, mut buf: Vec<u8>) -> Vec<u8> {
         if let Some(tag) = &self.tag {
+            METRICS.response(&self.rtype);
             buf.extend_from_slice(tag.as_bytes());
         } else {
             buf.push(b'*');
@@ -100,6 +100,51 @@ pub async fn test(imap: &mut ImapConnection, _imap_check: &mut ImapConnection) {
         .assert_contains("ℌ𝔢𝔩𝔭 𝔪𝔢 𝔢𝔵𝔭𝔬𝔯𝔱 𝔪𝔶 𝔟𝔬𝔬𝔨")
         .assert_contains("Vandelay");
 
+    // Header sections are served from the JMAP headers without the blob
+    imap.send("UID FETCH 10 (RFC822.HEADER BODY.PEEK[HEADER.FIELDS (From)]<10.8>)")
+        .await;
+    imap.assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .assert_contains("RFC822.HEADER {")
+        .assert_contains("BODY[HEADER.FIELDS (FROM)]<10> {8}")
+        .assert_contains("Vandelay");
+
+    // Header sections rebuilt from the JMAP headers match the ones read from
+    // the blob, which is downloaded when BODY[TEXT] is also requested
+    for uid in 1..=10 {
+        let sections = "BODY.PEEK[HEADER] BODY.PEEK[HEADER.FIELDS (From Subject)]";
+        imap.send(&format!("UID FETCH {} ({})", uid, sections))
+            .await;
+        let from_headers = imap.assert_read(Type::Tagged, ResponseType::Ok).await;
+        imap.send(&format!("UID FETCH {} ({} BODY.PEEK[TEXT])", uid, sections))
+            .await;
+        let from_blob = imap.assert_read(Type::Tagged, ResponseType::Ok).await;
+        for item in ["BODY[HEADER]", "BODY[HEADER.FIELDS (FROM SUBJECT)]"] {
+            let expected = literal(&from_blob, item);
+            assert!(!expected.is_empty(), "{} of UID {}", item, uid);
+            assert_eq!(
+                literal(&from_headers, item),
+                expected,
+                "{} of UID {}",
+                item,
+                uid
+            );
+        }
+    }
+
+    // Full messages and ranges are streamed from the JMAP server
+    imap.send("UID FETCH 10 (BODY.PEEK[])").await;
+    imap.assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .assert_contains("BODY[] {1457}")
+        .assert_contains("Vandelay");
+    imap.send("UID FETCH 10 (RFC822.SIZE BODY.PEEK[]<1400.100>)")
+        .await;
+    imap.assert_read(Type::Tagged, ResponseType::Ok)
+        .await
+        .assert_contains("RFC822.SIZE 1457")
+        .assert_contains("BODY[]<1400> {57}");
+
     // We are in EXAMINE mode, fetching body should not set \Seen
     imap.send("UID FETCH 10 (FLAGS)").await;
     imap.assert_read(Type::Tagged, ResponseType::Ok)
@@ -168,3 +213,26 @@ pub async fn test(imap: &mut ImapConnection, _imap_check: &mut ImapConnection) {
         .assert_contains("plain text version of message goes here")
         .assert_contains("This is implicitly typed plain US-ASCII text.");
 }
+
+// Returns the lines of the literal sent for a FETCH item.
+fn literal(lines: &[String], item: &str) -> Vec<String> {
+    let mut literal = Vec::new();
+    let prefix = format!("{} {{", item);
+    let mut lines = lines.iter();
+    let size = lines
+        .by_ref()
+        .find_map(|line| {
+            let (_, size) = line.split_once(&prefix)?;
+            size.strip_suffix('}')?.parse::<usize>().ok()
+        })
+        .unwrap_or_else(|| panic!("Missing literal for {}", item));
+    let mut read = 0;
+    for line in lines {
+        if read >= size {
+            break;
+        }
+        read += line.len() + 2;
+        literal.push(line.to_string());
+    }
+    literal
+}
@@ -392,7 +392,7 @@ impl AssertResult for Vec<String> {
     }
 }
 
-fn resources_dir() -> PathBuf {
+pub fn resources_dir() -> PathBuf {
     let mut resources = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     resources.push("src");
     resources.push("tests");